@@ -0,0 +1,157 @@
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use std::{io, sync::Arc, time::Duration};
+
+use crate::{
+    cell_process::now_ms,
+    global_state::{LagAction, LagThreshold, Registration, Status},
+    rpc_client::{IndexerTip, RpcClient},
+    rpc_server::RpcSearchKey,
+    sink::{BodyFormat, Compression, Sinks},
+    tip_poller::IndexerTipWatch,
+};
+
+/// How often the lag of the registrations with a threshold is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Posted to `alert_webhook_url` when a registration becomes degraded or recovers
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct LagAlert<'a> {
+    /// Always `lag_alert`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    key: &'a RpcSearchKey,
+    status: Status,
+    lag_blocks: u64,
+    /// Unset when the threshold has no `max_secs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lag_secs: Option<u64>,
+    threshold: &'a LagThreshold,
+    /// Summary for chat webhooks, e.g. Slack shows only this
+    text: String,
+}
+
+/// Marks registrations lagging beyond their threshold degraded, or paused with the `pause` action,
+/// and posts an alert on every change, parked registrations aren't lagging
+pub(crate) async fn run(
+    state: Arc<DashMap<RpcSearchKey, Registration>>,
+    client: RpcClient,
+    indexer_tip: IndexerTipWatch,
+    sinks: Sinks,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let watched = state
+            .iter()
+            .filter_map(|kv| {
+                let threshold = kv.value().lag_alert.clone()?;
+                let tip = kv.value().scan_tip.load();
+                (!tip.is_parked()).then(|| (kv.key().clone(), threshold, tip, kv.value().status))
+            })
+            .collect::<Vec<_>>();
+        if watched.is_empty() {
+            continue;
+        }
+        let indexer_tip = match indexer_tip.get().await {
+            Ok(tip) => tip,
+            Err(e) => {
+                log::warn!("Failed to check the lag of registrations, error: {:?}", e);
+                continue;
+            }
+        };
+
+        for (key, threshold, tip, status) in watched {
+            let (lag_blocks, lag_secs) = match lag(&client, &indexer_tip, &tip, &threshold).await {
+                Ok(lag) => lag,
+                Err(e) => {
+                    log::warn!("Failed to check the lag, key: {:?}, error: {:?}", key, e);
+                    continue;
+                }
+            };
+            let exceeded = threshold.max_blocks.is_some_and(|max| lag_blocks > max)
+                || threshold
+                    .max_secs
+                    .zip(lag_secs)
+                    .is_some_and(|(max, secs)| secs > max);
+            let now = match (exceeded, threshold.action) {
+                (false, _) => Status::Ok,
+                (true, LagAction::Pause) => Status::Paused,
+                (true, _) => Status::Degraded,
+            };
+            if now == status {
+                continue;
+            }
+            // the registration may have been deleted or its threshold changed meanwhile
+            match state.get_mut(&key) {
+                Some(mut registration) if registration.lag_alert.is_some() => {
+                    registration.status = now
+                }
+                _ => continue,
+            }
+            let text = match now {
+                Status::Degraded | Status::Paused => format!(
+                    "emitter: {} is {}, {} blocks behind{}",
+                    key.metric_label(),
+                    if now == Status::Paused {
+                        "paused"
+                    } else {
+                        "degraded"
+                    },
+                    lag_blocks,
+                    lag_secs.map_or(String::new(), |secs| format!(", {}s old", secs))
+                ),
+                Status::Ok => format!("emitter: {} recovered", key.metric_label()),
+            };
+            log::warn!("{}", text);
+
+            let url = sinks.settings.read().unwrap().alert_webhook_url.clone();
+            if let Some(url) = url {
+                let alert = LagAlert {
+                    kind: "lag_alert",
+                    key: &key,
+                    status: now,
+                    lag_blocks,
+                    lag_secs,
+                    threshold: &threshold,
+                    text,
+                };
+                if let Err(e) = sinks
+                    .post(
+                        &url,
+                        None,
+                        Compression::None,
+                        BodyFormat::Json,
+                        None,
+                        &alert,
+                    )
+                    .await
+                {
+                    log::warn!("Failed to post lag alert, key: {:?}, error: {:?}", key, e);
+                }
+            }
+        }
+    }
+}
+
+/// Blocks behind the indexer tip and, when the threshold has `max_secs`, age of the scan tip's block
+async fn lag(
+    client: &RpcClient,
+    indexer_tip: &IndexerTip,
+    tip: &IndexerTip,
+    threshold: &LagThreshold,
+) -> io::Result<(u64, Option<u64>)> {
+    let lag_blocks = indexer_tip
+        .block_number
+        .value()
+        .saturating_sub(tip.block_number.value());
+    if threshold.max_secs.is_none() {
+        return Ok((lag_blocks, None));
+    }
+    let header = client.get_header_by_number(tip.block_number).await?;
+    let mined = header.inner.timestamp.value();
+    Ok((lag_blocks, Some(now_ms().saturating_sub(mined) / 1000)))
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Positions of the params holding a secret when passed by position, by name they are masked anyway
+const SECRET_PARAMS: &[(&str, usize)] = &[("set_webhook", 2)];
+
+/// A mutating rpc call, successful or not
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix time in milliseconds the call was answered at
+    pub timestamp: u64,
+    /// Remote ip of the caller, `unix` for the rpc unix socket
+    pub client: String,
+    /// `x-request-id` of the call, when the client sent one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub method: String,
+    /// Secrets of the params are masked
+    pub params: Value,
+    pub error: Option<String>,
+}
+
+/// Append-only ndjson file of the mutating rpc calls
+#[derive(Clone)]
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+        Ok(AuditLog {
+            path: path.as_ref().to_path_buf(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(
+        &self,
+        client: &str,
+        request_id: Option<&str>,
+        method: &str,
+        mut params: Value,
+        error: Option<String>,
+    ) -> io::Result<()> {
+        for (_, position) in SECRET_PARAMS.iter().filter(|(name, _)| *name == method) {
+            if let Some(secret) = params.get_mut(position).filter(|secret| !secret.is_null()) {
+                *secret = Value::String(MASK.to_string());
+            }
+        }
+        mask_secrets(&mut params);
+        let entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            client: client.to_string(),
+            request_id: request_id.map(str::to_string),
+            method: method.to_string(),
+            params,
+            error,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // one write per entry so concurrent emitters appending to the file never interleave lines
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    /// The last `limit` entries, oldest first
+    pub fn recent(&self, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        let mut entries = VecDeque::with_capacity(limit);
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = line?;
+            // a crash may leave a torn last line
+            if let Ok(entry) = serde_json::from_str(&line) {
+                if entries.len() == limit {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+        }
+        Ok(entries.into())
+    }
+}
+
+const MASK: &str = "***";
+
+fn mask_secrets(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if key == "secret" && !value.is_null() {
+                    *value = Value::String(MASK.to_string());
+                } else {
+                    mask_secrets(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
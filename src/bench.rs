@@ -0,0 +1,208 @@
+use ckb_jsonrpc_types::{JsonBytes, Script, ScriptHashType};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cell_process, cli,
+    network::SECP256K1_BLAKE160_SIGHASH_ALL,
+    rpc_client::{RpcClient, ScriptType},
+    rpc_server::RpcSearchKey,
+};
+
+pub(crate) fn command() -> Command {
+    let number = |name: &'static str, help: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .value_parser(clap::value_parser!(u64))
+            .help(help)
+            .action(ArgAction::Set)
+    };
+
+    Command::new("bench")
+        .about("Scans a block range for many synthetic keys at once and reports the throughput and memory")
+        .arg(
+            Arg::new("ckb_uri")
+                .long("ckb-uri")
+                .env("EMITTER_CKB_URI")
+                .default_value("http://127.0.0.1:8114")
+                .help("CKB rpc service uri, a mock node works as well")
+                .action(ArgAction::Set),
+        )
+        .args(cli::ckb_auth_args())
+        .arg(
+            Arg::new("script_file")
+                .long("script-file")
+                .help("Json file with a search key every synthetic key copies, secp256k1 locks with made up args otherwise")
+                .action(ArgAction::Set),
+        )
+        .arg(number("keys", "Number of keys scanned concurrently").default_value("100"))
+        .arg(number("from", "First block number to scan").required(true))
+        .arg(number("to", "Last block number to scan, the indexer tip if omitted"))
+        .arg(
+            number(
+                "rounds",
+                "Repeats the scan, a growing rss across rounds points to a leak",
+            )
+            .default_value("1"),
+        )
+}
+
+struct Round {
+    elapsed: Duration,
+    cells: u64,
+    events: u64,
+}
+
+pub(crate) async fn execute(matches: &ArgMatches) -> io::Result<()> {
+    let client = cli::ckb_client(matches, &Default::default())?;
+    let keys = synthetic_keys(
+        matches.get_one::<String>("script_file"),
+        *matches.get_one::<u64>("keys").unwrap(),
+    )?;
+    let from = *matches.get_one::<u64>("from").unwrap();
+    let to = match matches.get_one::<u64>("to") {
+        Some(to) => *to,
+        None => client.get_indexer_tip().await?.block_number.value(),
+    };
+    if from > to {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--from {} is beyond --to {}", from, to),
+        ));
+    }
+    let blocks = to - from + 1;
+    let rounds = *matches.get_one::<u64>("rounds").unwrap();
+
+    println!(
+        "bench {} keys over blocks {} to {}, {} rounds",
+        keys.len(),
+        from,
+        to,
+        rounds
+    );
+    let mut total = Round {
+        elapsed: Duration::ZERO,
+        cells: 0,
+        events: 0,
+    };
+    for round in 1..=rounds {
+        let res = scan(&client, &keys, [from, to + 1]).await?;
+        println!(
+            "round {}: {:.2?}, {:.1} blocks/s, {:.1} cells/s, rss {}",
+            round,
+            res.elapsed,
+            per_second(blocks * keys.len() as u64, res.elapsed),
+            per_second(res.cells, res.elapsed),
+            memory("VmRSS")
+        );
+        total.elapsed += res.elapsed;
+        total.cells += res.cells;
+        total.events += res.events;
+    }
+
+    println!("keys:          {}", keys.len());
+    println!("blocks/key:    {}", blocks);
+    println!("events:        {}", total.events);
+    println!("cells:         {}", total.cells);
+    println!("elapsed:       {:.2?}", total.elapsed);
+    println!(
+        "blocks/s:      {:.1}",
+        per_second(blocks * keys.len() as u64 * rounds, total.elapsed)
+    );
+    println!(
+        "cells/s:       {:.1}",
+        per_second(total.cells, total.elapsed)
+    );
+    println!("rss:           {}", memory("VmRSS"));
+    println!("peak rss:      {}", memory("VmHWM"));
+    Ok(())
+}
+
+/// Scans the range for every key concurrently, like the registered keys of a running emitter
+async fn scan(client: &RpcClient, keys: &[RpcSearchKey], range: [u64; 2]) -> io::Result<Round> {
+    let start = Instant::now();
+    let tasks: Vec<_> = keys
+        .iter()
+        .map(|key| {
+            let (client, key) = (client.clone(), key.clone());
+            tokio::spawn(async move {
+                cell_process::fetch(&client, &key, [range[0].into(), range[1].into()]).await
+            })
+        })
+        .collect();
+
+    let mut round = Round {
+        elapsed: Duration::ZERO,
+        cells: 0,
+        events: 0,
+    };
+    for task in tasks {
+        let submits = task.await.map_err(io::Error::other)??;
+        round.events += submits.len() as u64;
+        round.cells += submits
+            .values()
+            .map(|submit| (submit.inputs.len() + submit.outputs.len()) as u64)
+            .sum::<u64>();
+    }
+    round.elapsed = start.elapsed();
+    Ok(round)
+}
+
+fn synthetic_keys(script_file: Option<&String>, count: u64) -> io::Result<Vec<RpcSearchKey>> {
+    if let Some(path) = script_file {
+        let key: RpcSearchKey = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+        return Ok(vec![key; count as usize]);
+    }
+    Ok((0..count)
+        .map(|i| RpcSearchKey {
+            script: synthetic_lock(i),
+            script_type: ScriptType::Lock,
+            filter: None,
+            with_data: None,
+            with_uncles: None,
+            with_epochs: None,
+            with_witnesses: None,
+            with_input_cells: None,
+            track_lineage: None,
+            track_live_cells: None,
+            discover: None,
+            correlate: None,
+            summary: None,
+            transactions_only: None,
+            namespace: None,
+        })
+        .collect())
+}
+
+/// The secp256k1 lock of the `i`th synthetic key, the cells of `simnode` have these locks too
+pub(crate) fn synthetic_lock(i: u64) -> Script {
+    let mut args = vec![0u8; 20];
+    args[12..].copy_from_slice(&i.to_be_bytes());
+    Script {
+        code_hash: SECP256K1_BLAKE160_SIGHASH_ALL,
+        hash_type: ScriptHashType::Type,
+        args: JsonBytes::from_vec(args),
+    }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// A memory line of /proc/self/status, e.g. `VmRSS`
+fn memory(field: &str) -> String {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+                .map(|value| value.trim().to_string())
+        })
+        .unwrap_or_else(|| "n/a".to_string())
+}
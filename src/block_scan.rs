@@ -0,0 +1,120 @@
+//! Matching the cells of a key in full blocks from the node, for scans running ahead of a
+//! lagging indexer, and the indexer's part of the search key checked by the emitter
+
+use ckb_jsonrpc_types::{CellOutput, JsonBytes, OutPoint, Script, TransactionView};
+use ckb_types::H256;
+
+use std::{collections::HashMap, io};
+
+use crate::{
+    fee::{spent_data, spent_output, SpentOutputs},
+    rpc_client::{CellType, IndexerTip, RpcClient, ScriptType, TxWithCells},
+    rpc_server::RpcSearchKey,
+};
+
+/// The node's tip when the indexer is more than `lag_blocks` behind it
+pub(crate) async fn lagging_node_tip(
+    client: &RpcClient,
+    indexer_tip: &IndexerTip,
+    lag_blocks: u64,
+) -> io::Result<Option<IndexerTip>> {
+    let node_tip = client.get_tip_header().await?;
+    let lag = node_tip
+        .inner
+        .number
+        .value()
+        .saturating_sub(indexer_tip.block_number.value());
+    Ok((lag > lag_blocks).then_some(IndexerTip {
+        block_hash: node_tip.hash,
+        block_number: node_tip.inner.number,
+    }))
+}
+
+/// The transactions of the blocks in `block_range`, end exclusive, with cells of `key` as the
+/// indexer groups them, and the transactions themselves. Every input is looked up for its cell
+pub(crate) async fn transactions(
+    client: &RpcClient,
+    key: &RpcSearchKey,
+    block_range: [u64; 2],
+    spent: &mut SpentOutputs,
+) -> io::Result<(Vec<TxWithCells>, HashMap<H256, TransactionView>)> {
+    let mut grouped = Vec::new();
+    let mut views = HashMap::new();
+    for number in block_range[0]..block_range[1] {
+        let block = client.get_block_by_number(number.into()).await?;
+        for (tx_index, tx) in block.transactions.into_iter().enumerate() {
+            let mut cells = Vec::new();
+            for (index, input) in tx.inner.inputs.iter().enumerate() {
+                let previous = &input.previous_output;
+                if previous.tx_hash == H256::default() {
+                    continue;
+                }
+                if input_matches(client, key, previous, spent).await? {
+                    cells.push((CellType::Input, (index as u32).into()));
+                }
+            }
+            for (index, output) in tx.inner.outputs.iter().enumerate() {
+                if matches(key, output, &tx.inner.outputs_data[index]) {
+                    cells.push((CellType::Output, (index as u32).into()));
+                }
+            }
+            if !cells.is_empty() {
+                grouped.push(TxWithCells {
+                    tx_hash: tx.hash.clone(),
+                    block_number: number.into(),
+                    tx_index: (tx_index as u32).into(),
+                    cells,
+                });
+                views.insert(tx.hash.clone(), tx);
+            }
+        }
+    }
+    Ok((grouped, views))
+}
+
+/// Whether the indexer would find the cell `previous` spends under the key
+pub(crate) async fn input_matches(
+    client: &RpcClient,
+    key: &RpcSearchKey,
+    previous: &OutPoint,
+    spent: &mut SpentOutputs,
+) -> io::Result<bool> {
+    let output = spent_output(client, previous, spent).await?.clone();
+    let data = spent_data(client, previous, spent).await?;
+    Ok(matches(key, &output, data))
+}
+
+/// Whether the indexer would find the cell under the key, the args of its scripts are prefixes
+pub(crate) fn matches(key: &RpcSearchKey, output: &CellOutput, data: &JsonBytes) -> bool {
+    let (script, other) = match key.script_type {
+        ScriptType::Lock => (Some(&output.lock), output.type_.as_ref()),
+        ScriptType::Type => (output.type_.as_ref(), Some(&output.lock)),
+    };
+    if !script.is_some_and(|script| starts_with(script, &key.script)) {
+        return false;
+    }
+    let filter = match &key.filter {
+        Some(filter) => filter,
+        None => return true,
+    };
+    let within = |range: Option<[ckb_jsonrpc_types::Uint64; 2]>, n: u64| {
+        range.is_none_or(|[from, to]| from.value() <= n && n < to.value())
+    };
+    filter
+        .script
+        .as_ref()
+        .is_none_or(|prefix| other.is_some_and(|other| starts_with(other, prefix)))
+        // of the code hash, hash type and args, 0 without the script
+        && within(
+            filter.script_len_range(),
+            other.map_or(0, |other| 33 + other.args.len() as u64),
+        )
+        && within(filter.output_data_len_range, data.len() as u64)
+        && within(filter.output_capacity_range, output.capacity.value())
+}
+
+fn starts_with(script: &Script, prefix: &Script) -> bool {
+    script.code_hash == prefix.code_hash
+        && script.hash_type == prefix.hash_type
+        && script.args.as_bytes().starts_with(prefix.args.as_bytes())
+}
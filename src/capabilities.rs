@@ -0,0 +1,86 @@
+use ckb_jsonrpc_types::{JsonBytes, Script};
+use serde::Serialize;
+
+use std::io;
+
+use crate::rpc_client::{Order, OutputDataMode, RpcClient, ScriptType, SearchKey, SearchKeyFilter};
+
+/// Which indexer serves the indexer rpc of the node
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexerFlavor {
+    /// The standalone ckb-indexer, which serves no node rpc
+    Standalone,
+    /// The indexer built into ckb
+    #[default]
+    Builtin,
+    /// The sql backed rich-indexer of ckb, it searches data anywhere in the cells
+    Rich,
+}
+
+/// What the indexer was found to support at startup, the emitter checks the filters it doesn't
+/// take on the fetched transactions
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct IndexerCapabilities {
+    pub flavor: IndexerFlavor,
+    /// Of the ckb node, unset for the standalone indexer
+    pub node_version: Option<String>,
+    /// Of the `output_data` filter, none if the indexer doesn't know it
+    pub output_data_modes: Vec<OutputDataMode>,
+}
+
+impl IndexerCapabilities {
+    pub fn filters_output_data(&self, mode: OutputDataMode) -> bool {
+        self.output_data_modes.contains(&mode)
+    }
+}
+
+/// Probes the node and its indexer, an indexer refusing a filter is taken not to support it
+pub(crate) async fn detect(client: &RpcClient) -> io::Result<IndexerCapabilities> {
+    let node_version = match client.local_node_info().await {
+        Ok(node) => Some(node.version),
+        // answered, but not a method of the standalone indexer
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+        Err(e) => return Err(e),
+    };
+    let mut output_data_modes = Vec::new();
+    for mode in [
+        OutputDataMode::Prefix,
+        OutputDataMode::Exact,
+        OutputDataMode::Partial,
+    ] {
+        match client
+            .get_cells(probe_key(mode), Order::Asc, 1.into(), None)
+            .await
+        {
+            Ok(_) => output_data_modes.push(mode),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let flavor = match node_version {
+        None => IndexerFlavor::Standalone,
+        Some(_) if output_data_modes.contains(&OutputDataMode::Partial) => IndexerFlavor::Rich,
+        Some(_) => IndexerFlavor::Builtin,
+    };
+    Ok(IndexerCapabilities {
+        flavor,
+        node_version,
+        output_data_modes,
+    })
+}
+
+/// Matches no cell, only whether the filter is accepted matters
+fn probe_key(mode: OutputDataMode) -> SearchKey {
+    SearchKey {
+        script: Script::default(),
+        script_type: ScriptType::Lock,
+        filter: Some(SearchKeyFilter {
+            output_data: Some(JsonBytes::from_vec(vec![0])),
+            output_data_filter_mode: Some(mode),
+            ..Default::default()
+        }),
+        with_data: Some(false),
+        group_by_transaction: None,
+    }
+}
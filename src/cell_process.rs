@@ -1,136 +1,1342 @@
-use ckb_jsonrpc_types::{CellData, CellInfo, OutPoint};
-use ckb_types::{packed, prelude::Unpack};
-use std::{collections::HashMap, sync::atomic::Ordering};
+use ckb_jsonrpc_types::{BlockNumber, CellData, CellDep, CellInfo, CellOutput, DepType, OutPoint};
+use ckb_types::{
+    packed,
+    prelude::{Entity, Unpack},
+    H256,
+};
+use futures::StreamExt;
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, RwLock};
 
 use crate::{
-    rpc_client::{CellType, IndexerTip, Order, RpcClient, Tx},
-    rpc_server::RpcSearchKey,
-    submit_to_realyer, ScanTip, Submit,
+    block_scan,
+    cheque::{is_cheque, Cheque},
+    config::{BackfillSettings, BlockFallbackSettings},
+    epoch::epoch_starts,
+    event_buffer::EventBuffer,
+    fee::{fee, spent_cell, spent_data, spent_output, SpentOutputs, TxFee},
+    global_state::{LagAction, Registration, ScanFailure, Status},
+    metrics,
+    multisig::{is_multisig, Multisig},
+    network::GenesisPin,
+    rpc_client::{
+        BlockUnavailable, CellType, IndexerTip, Order, RetryPolicy, RpcClient, ScriptType, Tx,
+    },
+    rpc_server::{CellbaseMode, RpcSearchKey},
+    scheduler::{Priority, ScanScheduler},
+    since::InputSince,
+    sink::{
+        Completion, CompletionReason, DeliveryCursor, Heartbeat, SinkConfig, Sinks, Undelivered,
+    },
+    summary::{transaction_hashes, BlockSummary, SummaryMode},
+    tip_poller::IndexerTipWatch,
+    uncle::uncles,
+    ScanTip, Submit,
 };
+/// Backoff of a failing scan, up to 5 minutes between attempts which never stop
+pub(crate) const SCAN_BACKOFF: RetryPolicy = RetryPolicy {
+    retries: u32::MAX,
+    base_delay_ms: 8000,
+    max_delay_ms: 300_000,
+    jitter: true,
+};
+
+/// Time from the start of a scan to the start of the next one
+pub(crate) const SCAN_INTERVAL: Duration = Duration::from_secs(8);
+
+/// Blocks rescanned when the scan tip was forked away, as deep as the confirmations waited for
+const REORG_DEPTH: u64 = 24;
+
+/// Spilled events read from the event log at once while they are delivered
+const RESUME_BATCH: usize = 100;
+
+/// What a scan task did last, served by `/debug/tasks`
+#[derive(Serialize, Default, Clone)]
+pub struct TaskStats {
+    /// Unix time in milliseconds the scan loop last woke up
+    pub last_iteration: Option<u64>,
+    /// Milliseconds the last call took by ckb rpc method, `fetch` covers all calls fetching a block range
+    pub rpc_durations_ms: BTreeMap<&'static str, u64>,
+    /// Fetched events of the running scan waiting for delivery
+    pub pending_events: usize,
+    /// Unix time in milliseconds the scan tip last moved, unset until it moves after startup
+    pub last_advance: Option<u64>,
+    /// Unix time in milliseconds the running scan started at, unset between scans
+    pub scan_started: Option<u64>,
+    /// Unix time in milliseconds the last call of the scan to the node ended at
+    #[serde(skip)]
+    pub last_call: Arc<AtomicU64>,
+    /// Set while the scan waits for a delivery slot or its sinks, a slow sink doesn't stall the scan
+    pub delivering: bool,
+}
+
+/// Marks the scan delivering while held, the last call is stamped when it ends so that the stall
+/// clock restarts from there
+struct Delivering<'a>(&'a Mutex<TaskStats>);
+
+impl<'a> Delivering<'a> {
+    fn start(stats: &'a Mutex<TaskStats>) -> Self {
+        stats.lock().unwrap().delivering = true;
+        Delivering(stats)
+    }
+}
+
+impl Drop for Delivering<'_> {
+    fn drop(&mut self) {
+        let mut stats = self.0.lock().unwrap();
+        stats.delivering = false;
+        stats.last_call.store(now_ms(), Ordering::Release);
+    }
+}
+
 pub(crate) struct CellProcess {
     pub key: RpcSearchKey,
     pub scan_tip: ScanTip,
     pub client: RpcClient,
+    pub indexer_tip: IndexerTipWatch,
+    pub sinks: Sinks,
+    pub state: Arc<dashmap::DashMap<RpcSearchKey, Registration>>,
+    /// Moves the scan tip past blocks the node doesn't serve instead of retrying them
+    pub skip_unavailable: bool,
+    /// Splits the scan of a long block range into shards fetched in parallel
+    pub backfill: BackfillSettings,
+    /// Scans the blocks past a lagging indexer's tip from the node
+    pub block_fallback: BlockFallbackSettings,
+    /// Held while the scan tip moves, see `Lifecycle::tips`
+    pub tips: Arc<RwLock<()>>,
+    pub stats: Arc<Mutex<TaskStats>>,
+    /// Limits the scans running at once when set
+    pub scheduler: Option<ScanScheduler>,
+    /// Limits the registrations delivering at once when set, the scan slot is held meanwhile
+    pub deliveries: Option<ScanScheduler>,
+    /// Caps the events of all registrations held for their delivery when set, see `spill`
+    pub buffer: Option<EventBuffer>,
+    /// Skips the scans while set
+    pub paused: Arc<AtomicBool>,
+    /// Fails the scans unless the node is on the chain the store was pinned to
+    pub genesis: Arc<GenesisPin>,
+    /// Gets the key once the registration completed, to delete it
+    pub completed: mpsc::UnboundedSender<RpcSearchKey>,
 }
 
 impl CellProcess {
     pub async fn run(&mut self) {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(8));
-        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut failures = 0;
         loop {
-            interval.tick().await;
-            self.scan().await;
+            let next = tokio::time::Instant::now() + SCAN_INTERVAL;
+            if let Some(delay) = self.step(&mut failures).await {
+                tokio::time::sleep(delay).await;
+            }
+            tokio::time::sleep_until(next).await;
+        }
+    }
+
+    /// Scans once, returns the backoff to wait before the next scan when it failed.
+    /// `failures` counts the consecutive failed scans
+    pub async fn step(&mut self, failures: &mut u32) -> Option<Duration> {
+        if self.paused.load(Ordering::Acquire)
+            || self.lag_action() == Some(LagAction::Pause)
+            || !self.activated()
+            || self.is_completed()
+        {
+            return None;
         }
+        let _slot = match &self.scheduler {
+            Some(scheduler) => Some(scheduler.acquire(self.priority()).await),
+            None => None,
+        };
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.last_iteration = Some(now_ms());
+            stats.scan_started = stats.last_iteration;
+        }
+        let scanned = self.scan().await;
+        self.stats.lock().unwrap().scan_started = None;
+        match scanned {
+            Ok(()) if *failures > 0 => {
+                log::info!(
+                    "Scan recovered after {} failures, key: {:?}",
+                    failures,
+                    self.key
+                );
+                *failures = 0;
+                self.set_failure(None);
+                None
+            }
+            Ok(()) => None,
+            Err(e) => {
+                let delay = SCAN_BACKOFF.delay(*failures);
+                *failures += 1;
+                log::warn!(
+                    "Failed to scan, retry in {:?}, key: {:?}, error: {}",
+                    delay,
+                    self.key,
+                    e
+                );
+                self.set_failure(Some(ScanFailure {
+                    error: e.to_string(),
+                    failures: *failures,
+                    unavailable_block: BlockUnavailable::of(&e),
+                }));
+                Some(delay)
+            }
+        }
+    }
+
+    async fn timed<T>(
+        &self,
+        method: &'static str,
+        call: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let started = Instant::now();
+        let res = call.await;
+        self.stats
+            .lock()
+            .unwrap()
+            .rpc_durations_ms
+            .insert(method, started.elapsed().as_millis() as u64);
+        res
     }
 
-    async fn scan(&self) {
-        let indexer_tip = self.client.get_indexer_tip().await.unwrap();
+    fn set_failure(&self, failure: Option<ScanFailure>) {
+        if let Some(mut registration) = self.state.get_mut(&self.key) {
+            registration.scan_failure = failure;
+        }
+    }
+
+    async fn scan(&self) -> io::Result<()> {
+        self.genesis.verified()?;
+        match self.try_scan().await {
+            Err(e) if self.skip_unavailable && BlockUnavailable::of(&e).is_some() => {
+                self.skip_to_available(e).await
+            }
+            res => res,
+        }
+    }
+
+    /// Moves the scan tip to the earliest block the node serves, the events of the skipped
+    /// blocks are never emitted. `e` is returned if the scan tip is available already
+    async fn skip_to_available(&self, e: io::Error) -> io::Result<()> {
         let old_tip = unsafe { &*self.scan_tip.0 .0.load(Ordering::Acquire) }.clone();
+        let indexer_tip = self.indexer_tip.get().await?;
+        let earliest = self
+            .client
+            .earliest_header(
+                old_tip.block_number.value(),
+                indexer_tip.block_number.value(),
+            )
+            .await?;
+        if earliest.inner.number.value() <= old_tip.block_number.value() {
+            return Err(e);
+        }
+        log::warn!(
+            "Skip blocks {} to {} the ckb node doesn't serve, key: {:?}",
+            old_tip.block_number.value(),
+            earliest.inner.number.value() - 1,
+            self.key
+        );
+        self.set_tip(IndexerTip {
+            block_hash: earliest.hash,
+            block_number: earliest.inner.number,
+        })
+        .await;
+        Ok(())
+    }
+
+    /// The action of the registration's lag threshold while it is exceeded
+    fn lag_action(&self) -> Option<LagAction> {
+        let registration = self.state.get(&self.key)?;
+        let action = registration.lag_alert.as_ref()?.action;
+        (registration.status != Status::Ok).then_some(action)
+    }
 
-        if indexer_tip.block_number.value().saturating_sub(24) > old_tip.block_number.value() {
-            // use tip - 24 as new tip
+    /// Whether the registration's `activate_at` passed, it is cleared then
+    fn activated(&self) -> bool {
+        let mut registration = match self.state.get_mut(&self.key) {
+            Some(registration) => registration,
+            None => return true,
+        };
+        match registration.activate_at {
+            Some(at) if now_ms() < at => false,
+            Some(_) => {
+                registration.activate_at = None;
+                log::info!("Registration activated, key: {:?}", self.key);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Scans nothing more until the registration is deleted
+    fn is_completed(&self) -> bool {
+        self.state.get(&self.key).is_some_and(|registration| {
+            registration.complete_after == Some(0) || registration.completed_at.is_some()
+        })
+    }
+
+    /// Looked up on every scan so that `set_priority` takes effect at once
+    fn priority(&self) -> Priority {
+        self.state
+            .get(&self.key)
+            .map(|registration| registration.priority)
+            .unwrap_or_default()
+    }
+
+    /// Blocks a scan may advance by under the registration's `max_blocks_per_second`, a scan
+    /// starts every `SCAN_INTERVAL` at most
+    fn blocks_per_scan(&self) -> Option<u64> {
+        let rate = self.state.get(&self.key)?.max_blocks_per_second?;
+        Some((rate * SCAN_INTERVAL.as_secs()).max(1))
+    }
+
+    /// Of the registration, else of the config, looked up on every scan so that
+    /// `set_scan_parallelism` takes effect at once
+    fn scan_parallelism(&self) -> Option<usize> {
+        self.state
+            .get(&self.key)
+            .and_then(|registration| registration.scan_parallelism)
+            .or(self.backfill.shards)
+    }
+
+    /// What is fetched for the key, with summaries instead of the cells while it lags beyond a
+    /// threshold with the `summary` action
+    fn scan_key(&self) -> Cow<'_, RpcSearchKey> {
+        match self.lag_action() {
+            Some(LagAction::Summary) => Cow::Owned(RpcSearchKey {
+                summary: Some(SummaryMode::Instead),
+                ..self.key.clone()
+            }),
+            _ => Cow::Borrowed(&self.key),
+        }
+    }
+
+    async fn try_scan(&self) -> io::Result<()> {
+        let indexer_tip = self.indexer_tip.get().await?;
+        let mut old_tip = unsafe { &*self.scan_tip.0 .0.load(Ordering::Acquire) }.clone();
+
+        if old_tip.is_parked() {
+            if indexer_tip.block_number < old_tip.block_number {
+                return Ok(());
+            }
+            let start = self
+                .client
+                .get_header_by_number(old_tip.block_number)
+                .await?;
+            old_tip = IndexerTip {
+                block_hash: start.hash,
+                block_number: start.inner.number,
+            };
+            log::info!(
+                "Indexer reached start block {}, key: {:?}",
+                old_tip.block_number.value(),
+                self.key
+            );
+            self.set_tip(old_tip.clone()).await;
+        }
+
+        let mut chain_tip = indexer_tip.block_number.value();
+        let mut from_blocks = false;
+        // a scan behind the indexer tip catches up with it first
+        if let Some(lag_blocks) = self
+            .block_fallback
+            .lag_blocks
+            .filter(|_| chain_tip.saturating_sub(24) <= old_tip.block_number.value())
+        {
+            let node_tip = self
+                .timed(
+                    "get_tip_header",
+                    block_scan::lagging_node_tip(&self.client, &indexer_tip, lag_blocks),
+                )
+                .await?;
+            if let Some(node_tip) = node_tip {
+                chain_tip = node_tip.block_number.value();
+                from_blocks = true;
+            }
+        }
+
+        if chain_tip.saturating_sub(24) > old_tip.block_number.value() {
+            // use tip - 24 as new tip, or as far as the key's quota lets the scan advance
+            let mut target = chain_tip.saturating_sub(24);
+            if let Some(quota) = self.blocks_per_scan() {
+                target = target.min(old_tip.block_number.value() + quota);
+            }
+            // the tip past the end block, scanned blocks are below the tip
+            if let Some(end) = self.state.get(&self.key).and_then(|r| r.end_block) {
+                target = target.min(end + 1);
+            }
             let new_tip = {
                 let new = self
-                    .client
-                    .get_header_by_number(
-                        indexer_tip.block_number.value().saturating_sub(24).into(),
+                    .timed(
+                        "get_header_by_number",
+                        self.client.get_header_by_number(target.into()),
                     )
-                    .await
-                    .unwrap();
+                    .await?;
                 IndexerTip {
                     block_hash: new.hash,
                     block_number: new.inner.number,
                 }
             };
 
-            let search_key = self
-                .key
-                .clone()
-                .into_key(Some([old_tip.block_number, new_tip.block_number]));
-
-            let mut cursor = None;
-
-            let mut submits = HashMap::new();
-
-            loop {
-                let txs = self
-                    .client
-                    .get_transactions(search_key.clone(), Order::Asc, 128.into(), cursor)
-                    .await
-                    .unwrap();
-
-                let tx_len = txs.objects.len();
-
-                for tx in txs.objects {
-                    match tx {
-                        Tx::Grouped(tx_with_cells) => {
-                            let tx = self
-                                .client
-                                .get_transaction(&tx_with_cells.tx_hash)
-                                .await
-                                .unwrap()
-                                .unwrap();
-                            let header = self
-                                .client
-                                .get_header_by_number(tx_with_cells.block_number)
-                                .await
-                                .unwrap();
-                            let submit_entry =
-                                submits.entry(header.hash.clone()).or_insert(Submit {
-                                    header,
-                                    inputs: Default::default(),
-                                    outputs: Default::default(),
-                                });
-                            for (ty, idx) in tx_with_cells.cells {
-                                let index = idx.value() as usize;
-                                match ty {
-                                    CellType::Input => {
-                                        let outpoint = OutPoint {
-                                            tx_hash: tx_with_cells.tx_hash.clone(),
-                                            index: idx,
-                                        };
-                                        submit_entry.inputs.push(outpoint)
-                                    }
-                                    CellType::Output => {
-                                        let cell_info = {
-                                            let data = tx.inner.outputs_data.get(index).cloned();
-                                            CellInfo {
-                                                output: tx.inner.outputs[index].clone(),
-                                                data: data.map(|d| CellData {
-                                                    hash: packed::CellOutput::calc_data_hash(
-                                                        d.as_bytes(),
-                                                    )
-                                                    .unpack(),
-                                                    content: d,
-                                                }),
-                                            }
-                                        };
-                                        submit_entry.outputs.push(cell_info);
+            // the block of the stored tip has to still be on the chain of the node, else the events
+            // emitted up to it came from a branch that was forked away
+            let canonical = self
+                .client
+                .get_header_by_number(old_tip.block_number)
+                .await?;
+            if canonical.hash != old_tip.block_hash {
+                return self.rewind(&old_tip).await;
+            }
+
+            let key = self.scan_key();
+            let range = [old_tip.block_number.value(), new_tip.block_number.value()];
+            if let Some(shards) = self
+                .scan_parallelism()
+                .filter(|_| !from_blocks && range[1] - range[0] > self.backfill.shard_blocks())
+            {
+                return self.backfill(&key, range, shards, new_tip).await;
+            }
+
+            let block_range = [old_tip.block_number, new_tip.block_number];
+            let submits = if from_blocks {
+                log::info!(
+                    "Indexer at {} is behind the node, blocks {} to {} fetched from the node, key: {:?}",
+                    indexer_tip.block_number.value(),
+                    range[0],
+                    range[1] - 1,
+                    self.key
+                );
+                self.timed("fetch", fetch_blocks(&self.client, &key, block_range))
+                    .await?
+            } else {
+                self.timed("fetch", fetch(&self.client, &key, block_range))
+                    .await?
+            };
+            self.emit(&key, submits, new_tip).await?;
+        } else if self.spilled_from().is_some() {
+            // the events of a failed delivery don't wait for the next block to be retried
+            let sinks = match self.state.get(&self.key) {
+                Some(registration) => registration.sinks(),
+                None => return Ok(()),
+            };
+            let _delivering = Delivering::start(&self.stats);
+            let _slot = match &self.deliveries {
+                Some(deliveries) => Some(deliveries.acquire(self.priority()).await),
+                None => None,
+            };
+            self.resume(&sinks).await?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the block range in shards, up to `shards` of them at once, and emits them in block
+    /// order, the scan tip moves past every emitted shard. `new_tip` is the end of the range
+    async fn backfill(
+        &self,
+        key: &RpcSearchKey,
+        range: [u64; 2],
+        shards: usize,
+        new_tip: IndexerTip,
+    ) -> io::Result<()> {
+        let shard_blocks = self.backfill.shard_blocks();
+        log::info!(
+            "Backfill blocks {} to {} in shards of {} blocks, key: {:?}",
+            range[0],
+            range[1] - 1,
+            shard_blocks,
+            self.key
+        );
+        let fetches = (range[0]..range[1])
+            .step_by(shard_blocks as usize)
+            .map(|from| {
+                let to = (from + shard_blocks).min(range[1]);
+                let new_tip = new_tip.clone();
+                async move {
+                    let submits = self
+                        .timed("fetch", fetch(&self.client, key, [from.into(), to.into()]))
+                        .await?;
+                    let tip = if to == range[1] {
+                        new_tip
+                    } else {
+                        let header = self.client.get_header_by_number(to.into()).await?;
+                        IndexerTip {
+                            block_hash: header.hash,
+                            block_number: header.inner.number,
+                        }
+                    };
+                    io::Result::Ok((submits, tip))
+                }
+            });
+        // buffered yields in order, a shard fetched early waits for the ones before it
+        let mut shards = futures::stream::iter(fetches).buffered(shards.max(1));
+        while let Some(shard) = shards.next().await {
+            let (submits, tip) = shard?;
+            if !self.emit(key, submits, tip).await? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delivers the events fetched for `key`, the scan key, and moves the scan tip to `tip`, false
+    /// if the registration completed. A failed delivery is an error so the scan backs off, its
+    /// logged events are delivered from the event log like spilled ones.
+    /// Spilled events are delivered first, the events are spilled too while some are left or
+    /// they don't fit in the buffer
+    async fn emit(
+        &self,
+        key: &RpcSearchKey,
+        mut submits: HashMap<H256, Submit>,
+        mut tip: IndexerTip,
+    ) -> io::Result<bool> {
+        // the sinks are looked up on every submit so that `set_webhook` and subscribers
+        // take effect at once
+        let (sinks, complete_after, end_block, request_id) = match self.state.get(&self.key) {
+            Some(registration) => (
+                registration.sinks(),
+                registration.complete_after,
+                registration.end_block,
+                registration.request_id.clone(),
+            ),
+            None => return Ok(false),
+        };
+        for submit in submits.values_mut() {
+            submit.request_id = request_id.clone();
+        }
+        // the events past the last one to log aren't delivered, the tip moves just past it
+        if let Some(next) = complete_after.and_then(|left| keep_first(&mut submits, left)) {
+            let header = self.client.get_header_by_number(next.into()).await?;
+            tip = IndexerTip {
+                block_hash: header.hash,
+                block_number: header.inner.number,
+            };
+        }
+        let _delivering = Delivering::start(&self.stats);
+        let _slot = match &self.deliveries {
+            Some(deliveries) => Some(deliveries.acquire(self.priority()).await),
+            None => None,
+        };
+        // the later events are spilled while the spilled ones wait for the sinks, the scan still
+        // backs off
+        let resumed = self.resume(&sinks).await;
+        let summarized = key.summary != self.key.summary;
+        let held = match &self.buffer {
+            _ if self.spilled_from().is_some() => None,
+            Some(buffer) => buffer.hold(submits.len()).map(Some),
+            None => Some(None),
+        };
+        let res = match held {
+            Some(_held) => {
+                self.stats.lock().unwrap().pending_events = submits.len();
+                let res = self
+                    .sinks
+                    .submit(&self.key, &sinks, submits, summarized)
+                    .await;
+                self.stats.lock().unwrap().pending_events = 0;
+                res
+            }
+            None => self.spill(&sinks, submits, summarized, &tip),
+        };
+        let (logged, error) = match res {
+            Ok(logged) => (logged as u64, None),
+            Err(e) => {
+                log::warn!(
+                    "Failed to deliver events, retry on next scan, key: {:?}, request id: {}, error: {:?}",
+                    self.key,
+                    request_id.as_deref().unwrap_or("-"),
+                    e
+                );
+                // the logged events are delivered from the event log, they aren't logged again
+                let (from, logged) = match Undelivered::of(&e) {
+                    Some(undelivered) => (undelivered.from, undelivered.logged),
+                    None => return Err(e),
+                };
+                self.set_spilled_from(Some(from))?;
+                (logged as u64, Some(e))
+            }
+        };
+        self.set_tip(tip.clone()).await;
+        if let Some(left) = complete_after {
+            let left = left.saturating_sub(logged);
+            if let Some(mut registration) = self.state.get_mut(&self.key) {
+                registration.complete_after = Some(left);
+            }
+            if left == 0 {
+                self.complete(&sinks, CompletionReason::Events, tip).await;
+                return Ok(false);
+            }
+        }
+        if end_block.is_some_and(|end| tip.block_number.value() > end) {
+            self.complete(&sinks, CompletionReason::EndBlock, tip).await;
+            return Ok(false);
+        }
+        match resumed.err().or(error) {
+            Some(e) => Err(e),
+            None => Ok(true),
+        }
+    }
+
+    /// Marks the registration completed at `tip` and tells its sinks, after its `complete_after`
+    /// events it is deleted then
+    async fn complete(&self, sinks: &[SinkConfig], reason: CompletionReason, tip: IndexerTip) {
+        if let Err(e) = self.resume(sinks).await {
+            log::warn!(
+                "Failed to deliver spilled events of the completed registration, they are left in \
+                 the event log, key: {:?}, error: {:?}",
+                self.key,
+                e
+            );
+        }
+        let now = now_ms();
+        let registered_at = match self.state.get_mut(&self.key) {
+            Some(mut registration) => {
+                registration.completed_at = Some(now);
+                registration.registered_at
+            }
+            None => return,
+        };
+        let stats = match self.sinks.storage.load_stats(&self.key) {
+            Ok(stats) => stats,
+            Err(e) => {
+                log::warn!("Failed to load stats, key: {:?}, error: {:?}", self.key, e);
+                Default::default()
+            }
+        };
+        let completion = Completion {
+            heartbeat: Heartbeat {
+                kind: "completion",
+                key: &self.key,
+                tip,
+                timestamp: now,
+            },
+            reason,
+            cells: stats.cells,
+            transactions: stats.transactions,
+            duration_secs: registered_at.map(|at| now.saturating_sub(at) / 1000),
+        };
+        if let Err(e) = self
+            .sinks
+            .deliver_notice(sinks, &completion.heartbeat, &completion)
+            .await
+        {
+            log::warn!(
+                "Failed to deliver completion, key: {:?}, error: {:?}",
+                self.key,
+                e
+            );
+        }
+        log::info!("Registration completed, key: {:?}", self.key);
+        if reason == CompletionReason::Events {
+            let _ = self.completed.send(self.key.clone());
+        }
+    }
+
+    /// Moves the scan tip `REORG_DEPTH` blocks back onto the chain of the node, the events of the
+    /// rescanned blocks are delivered again with the hashes of the new branch
+    async fn rewind(&self, old_tip: &IndexerTip) -> io::Result<()> {
+        let header = self
+            .client
+            .get_header_by_number(
+                old_tip
+                    .block_number
+                    .value()
+                    .saturating_sub(REORG_DEPTH)
+                    .into(),
+            )
+            .await?;
+        // the spilled events may be of the old branch, they are rescanned instead
+        let header = match self.first_spilled()? {
+            Some(first) if first < header.inner.number.value() => {
+                self.client.get_header_by_number(first.into()).await?
+            }
+            _ => header,
+        };
+        let block_number = header.inner.number.value();
+        log::warn!(
+            "Block {} {:#x} of the scan tip is no longer on chain, rescan from block {}, key: {:?}",
+            old_tip.block_number.value(),
+            old_tip.block_hash,
+            block_number,
+            self.key
+        );
+
+        // the delivery cursor would skip the blocks delivered from the old branch, it is moved
+        // before the tip so a crash in between rewinds again. The genesis can't fork
+        let sinks = self
+            .state
+            .get(&self.key)
+            .map(|r| r.sinks())
+            .unwrap_or_default();
+        for sink in sinks.iter().map(SinkConfig::id) {
+            let before = DeliveryCursor {
+                block_number: block_number.saturating_sub(1),
+                index: u32::MAX,
+            };
+            let storage = &self.sinks.storage;
+            let res = storage
+                .load_delivery_cursor(&self.key, &sink)
+                .and_then(|delivered| match delivered {
+                    Some(delivered) if block_number > 0 && delivered > before => {
+                        storage.store_delivery_cursor(&self.key, &sink, before)
+                    }
+                    _ => Ok(()),
+                });
+            if let Err(e) = res {
+                log::warn!(
+                    "Failed to rewind delivery cursor, retry on next scan, key: {:?}, error: {:?}",
+                    self.key,
+                    e
+                );
+                return Ok(());
+            }
+        }
+        if self.spilled_from().is_some() {
+            self.set_spilled_from(None)?;
+        }
+        self.set_tip(IndexerTip {
+            block_hash: header.hash,
+            block_number: header.inner.number,
+        })
+        .await;
+        Ok(())
+    }
+
+    fn spilled_from(&self) -> Option<u64> {
+        self.state.get(&self.key)?.spilled_from
+    }
+
+    /// Persists the registrations at once, the scan tip moves past the spilled events right after
+    fn set_spilled_from(&self, from: Option<u64>) -> io::Result<()> {
+        if let Some(mut registration) = self.state.get_mut(&self.key) {
+            registration.spilled_from = from;
+        }
+        let registrations = self
+            .state
+            .iter()
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect::<Vec<_>>();
+        self.sinks.storage.save_registrations(&registrations)
+    }
+
+    /// Logs the events without delivering them so they don't wait in memory, `resume` delivers
+    /// them from the event log. Returns the events logged
+    fn spill(
+        &self,
+        sinks: &[SinkConfig],
+        submits: HashMap<H256, Submit>,
+        summarized: bool,
+        tip: &IndexerTip,
+    ) -> io::Result<usize> {
+        if submits.is_empty() {
+            return Ok(0);
+        }
+        if self.spilled_from().is_none() {
+            // events logged by other registrations meanwhile are skipped by `resume`
+            let next = self
+                .sinks
+                .storage
+                .event_bounds()?
+                .map_or(0, |(_, newest)| newest + 1);
+            self.set_spilled_from(Some(next))?;
+            log::warn!(
+                "Event buffers full, events from seq {} are delivered from the event log, key: {:?}",
+                next,
+                self.key
+            );
+        }
+        let logged = self
+            .sinks
+            .spill(&self.key, sinks, submits, summarized, tip)?;
+        metrics::inc_by(
+            "emitter_spilled_events_total",
+            &[("registration", self.key.metric_label().as_str())],
+            logged as u64,
+        );
+        Ok(logged)
+    }
+
+    /// Delivers the spilled events, `RESUME_BATCH` at a time, until every sink has them
+    async fn resume(&self, sinks: &[SinkConfig]) -> io::Result<()> {
+        let mut from = match self.spilled_from() {
+            Some(from) => from,
+            None => return Ok(()),
+        };
+        while let Some(next) = self
+            .sinks
+            .resume(&self.key, sinks, from, RESUME_BATCH)
+            .await?
+        {
+            from = next;
+            // persisted with the registrations, a restart before delivers the batch again
+            if let Some(mut registration) = self.state.get_mut(&self.key) {
+                registration.spilled_from = Some(from);
+            }
+        }
+        self.set_spilled_from(None)?;
+        log::info!("Spilled events delivered, key: {:?}", self.key);
+        Ok(())
+    }
+
+    /// Block of the first spilled event left in the event log
+    fn first_spilled(&self) -> io::Result<Option<u64>> {
+        let from = match self.spilled_from() {
+            Some(from) => from,
+            None => return Ok(None),
+        };
+        let first = self
+            .sinks
+            .storage
+            .read_events(from, u64::MAX, 1, &|event| event.key == self.key)?;
+        Ok(first
+            .first()
+            .map(|event| event.submit.header.inner.number.value()))
+    }
+
+    async fn set_tip(&self, tip: IndexerTip) {
+        let _tips = self.tips.read().await;
+        self.stats.lock().unwrap().last_advance = Some(now_ms());
+        if let Err(e) = self.sinks.storage.store_scan_tip(&self.key, &tip) {
+            log::warn!(
+                "Failed to store scan tip, key: {:?}, error: {:?}",
+                self.key,
+                e
+            );
+        }
+        let raw = self
+            .scan_tip
+            .0
+             .0
+            .swap(Box::into_raw(Box::new(tip)), Ordering::AcqRel);
+
+        unsafe {
+            drop(Box::from_raw(raw));
+        }
+    }
+}
+
+/// Keeps the events of the first `max` blocks, returns the block after the last kept one if any
+/// was left out
+fn keep_first(submits: &mut HashMap<H256, Submit>, max: u64) -> Option<u64> {
+    let mut numbers = submits
+        .values()
+        .map(|submit| submit.header.inner.number.value())
+        .collect::<Vec<_>>();
+    if numbers.len() as u64 <= max {
+        return None;
+    }
+    numbers.sort_unstable();
+    let last = numbers[max.saturating_sub(1) as usize];
+    submits.retain(|_, submit| submit.header.inner.number.value() <= last);
+    Some(last + 1)
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Cells of `key` created and consumed within `block_range`, end exclusive, grouped by block hash
+pub(crate) async fn fetch(
+    client: &RpcClient,
+    key: &RpcSearchKey,
+    block_range: [BlockNumber; 2],
+) -> io::Result<HashMap<H256, Submit>> {
+    fetch_from(client, key, block_range, false).await
+}
+
+/// As `fetch`, the cells are matched in the blocks from the node instead of found by the indexer
+pub(crate) async fn fetch_blocks(
+    client: &RpcClient,
+    key: &RpcSearchKey,
+    block_range: [BlockNumber; 2],
+) -> io::Result<HashMap<H256, Submit>> {
+    fetch_from(client, key, block_range, true).await
+}
+
+async fn fetch_from(
+    client: &RpcClient,
+    key: &RpcSearchKey,
+    block_range: [BlockNumber; 2],
+    from_blocks: bool,
+) -> io::Result<HashMap<H256, Submit>> {
+    let mut search_key = key.clone().into_key(Some(block_range), client.indexer());
+    let all_output_data = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.output_data_filter());
+    // the indexer left it out of the query when it can't take the mode
+    let mut output_data = all_output_data
+        .filter(|(_, mode)| from_blocks || !client.indexer().filters_output_data(*mode));
+    // set once the indexer refused the filter, the emitter checks the cells against it
+    let mut check_filter = false;
+    let cell_dep = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.cell_dep.as_ref());
+    let header_dep = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.header_dep.as_ref());
+    let since = key.filter.as_ref().and_then(|filter| filter.since.as_ref());
+    let fee_range = key.filter.as_ref().and_then(|filter| filter.fee_range);
+    let data_pattern = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.data_pattern.as_ref());
+    let args_pattern = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.args_pattern.as_ref());
+    let min_capacity_change = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.min_capacity_change);
+    let min_transactions = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.min_transactions);
+    let cellbase = key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.cellbase)
+        .unwrap_or(CellbaseMode::Include);
+    // the spent cells of other keys are only known by a lookup
+    let cheque_inputs = key.script_type == ScriptType::Lock && is_cheque(&key.script);
+    let multisig_inputs = key.script_type == ScriptType::Lock && is_multisig(&key.script);
+    let mut dep_groups = HashMap::new();
+    let mut spent = SpentOutputs::new();
+
+    let mut cursor = None;
+
+    let mut submits = HashMap::new();
+
+    // the transactions of the fetched blocks with matched cells
+    let mut fetched = HashMap::new();
+
+    loop {
+        let (objects, last_cursor) = if from_blocks {
+            let range = [block_range[0].value(), block_range[1].value()];
+            let (grouped, views) = block_scan::transactions(client, key, range, &mut spent).await?;
+            fetched = views;
+            (grouped.into_iter().map(Tx::Grouped).collect(), None)
+        } else {
+            let txs = match client
+                .get_transactions(search_key.clone(), Order::Asc, 128.into(), cursor.clone())
+                .await
+            {
+                Err(e) if e.kind() == io::ErrorKind::InvalidData && cursor.is_none() => {
+                    match search_key.without_filter().filter(|_| !check_filter) {
+                        Some(unfiltered) => {
+                            log::warn!(
+                                "Indexer refused the filter, the emitter checks it, key: {:?}, error: {}",
+                                key,
+                                e
+                            );
+                            search_key = unfiltered;
+                            output_data = all_output_data;
+                            check_filter = true;
+                            continue;
+                        }
+                        None => return Err(e),
+                    }
+                }
+                txs => txs?,
+            };
+            let last_cursor = (txs.objects.len() == 128).then_some(txs.last_cursor);
+            (txs.objects, last_cursor)
+        };
+
+        for tx in objects {
+            match tx {
+                Tx::Grouped(tx_with_cells) => {
+                    let tx = match fetched.remove(&tx_with_cells.tx_hash) {
+                        Some(tx) => Some(tx),
+                        None => client.get_transaction(&tx_with_cells.tx_hash).await?,
+                    };
+                    let tx = tx.ok_or_else(|| {
+                        // the indexer still knows it, the node pruned its block
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            BlockUnavailable(tx_with_cells.block_number.value()),
+                        )
+                    })?;
+                    if header_dep.is_some_and(|hash| !tx.inner.header_deps.contains(hash)) {
+                        continue;
+                    }
+                    let is_cellbase = tx
+                        .inner
+                        .inputs
+                        .first()
+                        .is_some_and(|input| input.previous_output.tx_hash == H256::default());
+                    if is_cellbase && cellbase == CellbaseMode::Exclude {
+                        continue;
+                    }
+                    let mut cells = tx_with_cells.cells;
+                    if check_filter {
+                        let mut matched = Vec::with_capacity(cells.len());
+                        for (ty, idx) in cells {
+                            let index = idx.value() as usize;
+                            let matches = match ty {
+                                CellType::Input => {
+                                    let previous = &tx.inner.inputs[index].previous_output;
+                                    block_scan::input_matches(client, key, previous, &mut spent)
+                                        .await?
+                                }
+                                CellType::Output => block_scan::matches(
+                                    key,
+                                    &tx.inner.outputs[index],
+                                    &tx.inner.outputs_data[index],
+                                ),
+                            };
+                            if matches {
+                                matched.push((ty, idx));
+                            }
+                        }
+                        if matched.is_empty() {
+                            continue;
+                        }
+                        cells = matched;
+                    }
+                    if let Some(pattern) = args_pattern {
+                        let mut matched = Vec::with_capacity(cells.len());
+                        for (ty, idx) in cells {
+                            let index = idx.value() as usize;
+                            let output = match ty {
+                                CellType::Input => {
+                                    let previous = &tx.inner.inputs[index].previous_output;
+                                    spent_output(client, previous, &mut spent).await?
+                                }
+                                CellType::Output => &tx.inner.outputs[index],
+                            };
+                            if key_args(key, output).is_some_and(|args| pattern.matches_start(args))
+                            {
+                                matched.push((ty, idx));
+                            }
+                        }
+                        if matched.is_empty() {
+                            continue;
+                        }
+                        cells = matched;
+                    }
+                    if let Some((filter, mode)) = output_data {
+                        let mut matched = Vec::with_capacity(cells.len());
+                        for (ty, idx) in cells {
+                            let index = idx.value() as usize;
+                            let data = match ty {
+                                CellType::Input => {
+                                    let previous = &tx.inner.inputs[index].previous_output;
+                                    spent_data(client, previous, &mut spent).await?
+                                }
+                                CellType::Output => &tx.inner.outputs_data[index],
+                            };
+                            if mode.matches(data.as_bytes(), filter.as_bytes()) {
+                                matched.push((ty, idx));
+                            }
+                        }
+                        if matched.is_empty() {
+                            continue;
+                        }
+                        cells = matched;
+                    }
+                    // the pattern is on the created cells' data, the spent cells are kept
+                    if let Some(pattern) = data_pattern {
+                        let mut matched = Vec::with_capacity(cells.len());
+                        for (ty, idx) in cells {
+                            let index = idx.value() as usize;
+                            if ty == CellType::Input
+                                || pattern.matches(tx.inner.outputs_data[index].as_bytes())
+                            {
+                                matched.push((ty, idx));
+                            }
+                        }
+                        if matched.is_empty() {
+                            continue;
+                        }
+                        cells = matched;
+                    }
+                    let input_since = cells
+                        .iter()
+                        .filter(|(ty, _)| *ty == CellType::Input)
+                        .filter_map(|(_, idx)| {
+                            let input = OutPoint {
+                                tx_hash: tx_with_cells.tx_hash.clone(),
+                                index: *idx,
+                            };
+                            InputSince::decode(input, tx.inner.inputs[idx.value() as usize].since)
+                        })
+                        .collect::<Vec<_>>();
+                    if since.is_some_and(|since| !input_since.iter().any(|s| since.matches(s))) {
+                        continue;
+                    }
+                    if let Some(cell_dep) = cell_dep {
+                        if !has_cell_dep(client, &tx.inner.cell_deps, cell_dep, &mut dep_groups)
+                            .await?
+                        {
+                            continue;
+                        }
+                    }
+                    let tx_fee = match fee_range {
+                        Some([from, to]) => match fee(client, &tx, &mut spent).await? {
+                            Some(fee) if from.value() <= fee && fee < to.value() => Some(TxFee {
+                                tx_hash: tx_with_cells.tx_hash.clone(),
+                                fee: fee.into(),
+                            }),
+                            _ => continue,
+                        },
+                        None => None,
+                    };
+                    let header = client
+                        .get_header_by_number(tx_with_cells.block_number)
+                        .await?;
+                    let submit_entry = submits.entry(header.hash.clone()).or_insert(Submit {
+                        capacity_change: (min_capacity_change.is_some() || key.summary.is_some())
+                            .then(Default::default),
+                        ..Submit::new(header)
+                    });
+                    submit_entry
+                        .tx_indexes
+                        .insert(tx_with_cells.tx_hash.clone(), tx_with_cells.tx_index);
+                    submit_entry.since.extend(input_since);
+                    submit_entry.fees.extend(tx_fee);
+                    if let Some(selection) = key.with_witnesses {
+                        submit_entry.witnesses.push(selection.select(
+                            &key.script_type,
+                            tx_with_cells.tx_hash.clone(),
+                            &tx.inner,
+                            &cells,
+                        ));
+                    }
+                    if let Some(rule) = &key.correlate {
+                        submit_entry.correlations.extend(rule.correlate(
+                            tx_with_cells.tx_hash.clone(),
+                            &tx.inner,
+                            &cells,
+                        ));
+                    }
+                    for (ty, idx) in cells {
+                        let index = idx.value() as usize;
+                        match ty {
+                            CellType::Input => {
+                                if let Some(change) = &mut submit_entry.capacity_change {
+                                    let previous = &tx.inner.inputs[index].previous_output;
+                                    let capacity = spent_output(client, previous, &mut spent)
+                                        .await?
+                                        .capacity
+                                        .value();
+                                    change.spent = (change.spent.value() + capacity).into();
+                                }
+                                if key.with_input_cells == Some(true) {
+                                    let previous = &tx.inner.inputs[index].previous_output;
+                                    let with_data = key.with_data != Some(false);
+                                    submit_entry.input_cells.push(
+                                        spent_cell(client, previous, &mut spent, with_data).await?,
+                                    );
+                                }
+                                let outpoint = OutPoint {
+                                    tx_hash: tx_with_cells.tx_hash.clone(),
+                                    index: idx,
+                                };
+                                if cheque_inputs || multisig_inputs {
+                                    let input = &tx.inner.inputs[index];
+                                    let lock =
+                                        &spent_output(client, &input.previous_output, &mut spent)
+                                            .await?
+                                            .lock;
+                                    submit_entry.cheques.extend(Cheque::decode(
+                                        CellType::Input,
+                                        outpoint.clone(),
+                                        lock,
+                                        Some(input.since),
+                                    ));
+                                    submit_entry.multisigs.extend(Multisig::decode(
+                                        CellType::Input,
+                                        outpoint.clone(),
+                                        lock,
+                                        &tx.inner,
+                                    ));
+                                }
+                                submit_entry.inputs.push(outpoint);
+                                submit_entry
+                                    .spent_points
+                                    .push(tx.inner.inputs[index].previous_output.clone());
+                            }
+                            CellType::Output => {
+                                let cell_info = {
+                                    let data = tx
+                                        .inner
+                                        .outputs_data
+                                        .get(index)
+                                        .filter(|_| key.with_data != Some(false))
+                                        .cloned();
+                                    CellInfo {
+                                        output: tx.inner.outputs[index].clone(),
+                                        data: data.map(|d| CellData {
+                                            hash: packed::CellOutput::calc_data_hash(d.as_bytes())
+                                                .unpack(),
+                                            content: d,
+                                        }),
                                     }
+                                };
+                                if let Some(change) = &mut submit_entry.capacity_change {
+                                    change.created = (change.created.value()
+                                        + cell_info.output.capacity.value())
+                                    .into();
                                 }
+                                if is_cellbase && cellbase == CellbaseMode::Tag {
+                                    let index = submit_entry.outputs.len() as u32;
+                                    submit_entry.cellbase_outputs.push(index.into());
+                                }
+                                let outpoint = OutPoint {
+                                    tx_hash: tx_with_cells.tx_hash.clone(),
+                                    index: idx,
+                                };
+                                submit_entry.cheques.extend(Cheque::decode(
+                                    CellType::Output,
+                                    outpoint.clone(),
+                                    &cell_info.output.lock,
+                                    None,
+                                ));
+                                submit_entry.multisigs.extend(Multisig::decode(
+                                    CellType::Output,
+                                    outpoint.clone(),
+                                    &cell_info.output.lock,
+                                    &tx.inner,
+                                ));
+                                submit_entry.outputs.push(cell_info);
+                                submit_entry.output_points.push(outpoint);
                             }
                         }
-                        Tx::Ungrouped(_) => unreachable!(),
                     }
                 }
+                Tx::Ungrouped(_) => unreachable!(),
+            }
+        }
 
-                if tx_len == 128 {
-                    cursor = Some(txs.last_cursor);
-                } else {
-                    break;
-                }
+        match last_cursor {
+            Some(last_cursor) => cursor = Some(last_cursor),
+            None => break,
+        }
+    }
+    if key.with_uncles == Some(true) {
+        for number in block_range[0].value()..block_range[1].value() {
+            let header = client.get_header_by_number(number.into()).await?;
+            let uncles = uncles(client, &header).await?;
+            if !uncles.is_empty() {
+                let hash = header.hash.clone();
+                submits
+                    .entry(hash)
+                    .or_insert_with(|| Submit::new(header))
+                    .uncles = uncles;
             }
+        }
+    }
+    if key.with_epochs == Some(true) {
+        for (header, epoch) in epoch_starts(client, block_range).await? {
+            let hash = header.hash.clone();
+            submits
+                .entry(hash)
+                .or_insert_with(|| Submit::new(header))
+                .epoch = Some(epoch);
+        }
+    }
+    if let Some(min) = min_capacity_change {
+        submits.retain(|_, submit| {
+            submit
+                .capacity_change
+                .as_ref()
+                .is_some_and(|change| change.amount() >= min.value())
+        });
+    }
+    if let Some(min) = min_transactions {
+        submits.retain(|_, submit| transaction_count(submit) >= min.value() as usize);
+    }
+    if key.summary.is_some() {
+        for submit in submits.values_mut() {
+            // the change was only tracked for the summary unless filtering by it
+            let change = match min_capacity_change {
+                Some(_) => submit.capacity_change.clone(),
+                None => submit.capacity_change.take(),
+            };
+            submit.summary = Some(BlockSummary::new(
+                submit,
+                &change.unwrap_or_default(),
+                transaction_count(submit),
+            ));
+        }
+    }
+    Ok(submits)
+}
 
-            submit_to_realyer(submits).await;
-            let raw = self
-                .scan_tip
-                .0
-                 .0
-                .swap(Box::into_raw(Box::new(new_tip)), Ordering::AcqRel);
+/// The transactions with emitted cells in a block
+fn transaction_count(submit: &Submit) -> usize {
+    transaction_hashes(submit).len()
+}
 
-            unsafe {
-                drop(Box::from_raw(raw));
-            }
+/// The args of the key's script in `output`, none for a type key and a cell without type script
+fn key_args<'a>(key: &RpcSearchKey, output: &'a CellOutput) -> Option<&'a [u8]> {
+    match key.script_type {
+        ScriptType::Lock => Some(output.lock.args.as_bytes()),
+        ScriptType::Type => output.type_.as_ref().map(|script| script.args.as_bytes()),
+    }
+}
+
+/// Whether `cell_dep` is one of the cell deps or in one of their dep groups, `dep_groups` keeps
+/// the out points of the dep groups looked up so far
+async fn has_cell_dep(
+    client: &RpcClient,
+    cell_deps: &[CellDep],
+    cell_dep: &OutPoint,
+    dep_groups: &mut HashMap<OutPoint, Vec<OutPoint>>,
+) -> io::Result<bool> {
+    for dep in cell_deps {
+        if dep.out_point == *cell_dep {
+            return Ok(true);
+        }
+        if dep.dep_type != DepType::DepGroup {
+            continue;
+        }
+        if !dep_groups.contains_key(&dep.out_point) {
+            let not_found = || {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "dep group {:#x}:{} not found",
+                        dep.out_point.tx_hash,
+                        dep.out_point.index.value()
+                    ),
+                )
+            };
+            let tx = client
+                .get_transaction(&dep.out_point.tx_hash)
+                .await?
+                .ok_or_else(not_found)?;
+            let data = tx
+                .inner
+                .outputs_data
+                .get(dep.out_point.index.value() as usize)
+                .ok_or_else(not_found)?;
+            let out_points = packed::OutPointVec::from_slice(data.as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            dep_groups.insert(
+                dep.out_point.clone(),
+                out_points.into_iter().map(Into::into).collect(),
+            );
+        }
+        if dep_groups[&dep.out_point].contains(cell_dep) {
+            return Ok(true);
         }
     }
+    Ok(false)
 }
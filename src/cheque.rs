@@ -0,0 +1,70 @@
+use ckb_jsonrpc_types::{JsonBytes, OutPoint, Script, ScriptHashType, Uint64};
+use ckb_types::{h256, H256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{rpc_client::CellType, schema};
+
+/// Type script hashes of the cheque lock on mainnet and testnet
+const CHEQUE: [H256; 2] = [
+    h256!("0xe4d4ecc6e5f9a059bf2f7a82cca292083aebc0c421566a52484fe2ec51a9fb0c"),
+    h256!("0x60d5f39efce409c587cb9ea359cefdead650ca128f0bd9cb3855348f98c70d5b"),
+];
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChequePhase {
+    /// Created by an output
+    Created,
+    /// Spent by the receiver, without `since`
+    Claimed,
+    /// Spent by the sender, which has to wait for a relative `since`
+    Withdrawn,
+}
+
+/// A matched cell locked by the cheque lock
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Cheque {
+    pub io_type: CellType,
+    /// The input as in the event's `inputs`, or the created cell
+    #[schemars(with = "schema::OutPoint")]
+    pub out_point: OutPoint,
+    /// The first 20 bytes of the receiver's lock hash
+    #[schemars(with = "schema::Hex")]
+    pub receiver_lock_hash: JsonBytes,
+    /// The first 20 bytes of the sender's lock hash
+    #[schemars(with = "schema::Hex")]
+    pub sender_lock_hash: JsonBytes,
+    pub phase: ChequePhase,
+}
+
+pub(crate) fn is_cheque(lock: &Script) -> bool {
+    lock.hash_type == ScriptHashType::Type && CHEQUE.contains(&lock.code_hash)
+}
+
+impl Cheque {
+    /// None for a lock with args other than the two lock hashes. `since` is the input's
+    pub fn decode(
+        io_type: CellType,
+        out_point: OutPoint,
+        lock: &Script,
+        since: Option<Uint64>,
+    ) -> Option<Self> {
+        let args = lock.args.as_bytes();
+        if !is_cheque(lock) || args.len() != 40 {
+            return None;
+        }
+        let phase = match since {
+            None => ChequePhase::Created,
+            Some(since) if since.value() == 0 => ChequePhase::Claimed,
+            Some(_) => ChequePhase::Withdrawn,
+        };
+        Some(Cheque {
+            io_type,
+            out_point,
+            receiver_lock_hash: JsonBytes::from_vec(args[..20].to_vec()),
+            sender_lock_hash: JsonBytes::from_vec(args[20..].to_vec()),
+            phase,
+        })
+    }
+}
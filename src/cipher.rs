@@ -0,0 +1,67 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+
+use std::{fs::read_to_string, io};
+
+/// Env var holding the hex encoded key when no key file is given
+pub const KEY_ENV: &str = "EMITTER_ENCRYPTION_KEY";
+
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM with a random nonce per sealed value, stored as `nonce | ciphertext | tag`
+#[derive(Clone)]
+pub(crate) struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    /// Parses a 32 byte key from 64 hex chars
+    pub fn from_hex(hex: &str) -> io::Result<Self> {
+        let hex = hex.trim().trim_start_matches("0x");
+        let mut key = [0u8; 32];
+        if hex.len() != key.len() * 2 {
+            return Err(invalid("encryption key must be 32 bytes, hex encoded"));
+        }
+        faster_hex::hex_decode(hex.as_bytes(), &mut key)
+            .map_err(|e| invalid(&format!("invalid encryption key: {}", e)))?;
+        Ok(Cipher(Aes256Gcm::new_from_slice(&key).unwrap()))
+    }
+
+    /// Reads the key from `key_file`, else from the env, `None` if neither is set
+    pub fn load(key_file: Option<&str>) -> io::Result<Option<Self>> {
+        match key_file {
+            Some(path) => Cipher::from_hex(&read_to_string(path)?).map(Some),
+            None => match std::env::var(KEY_ENV) {
+                Ok(hex) => Cipher::from_hex(&hex).map(Some),
+                Err(_) => Ok(None),
+            },
+        }
+    }
+
+    pub fn seal(&self, plain: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, plain)
+            .map_err(|_| io::Error::other("failed to encrypt"))?;
+        let mut res = Vec::with_capacity(NONCE_LEN + sealed.len());
+        res.extend_from_slice(&nonce);
+        res.extend_from_slice(&sealed);
+        Ok(res)
+    }
+
+    /// Fails on a wrong key or tampered data
+    pub fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid("encrypted value is truncated"));
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| invalid("failed to decrypt, wrong encryption key or corrupted data"))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
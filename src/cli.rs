@@ -0,0 +1,540 @@
+use ckb_jsonrpc_types::{BlockNumber, Script, Uint64};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::de::DeserializeOwned;
+
+use std::{
+    fs::{read_to_string, File},
+    io::{self, BufWriter, Write},
+    process::exit,
+};
+
+use crate::{
+    bench, cell_process,
+    cipher::Cipher,
+    global_state::Registration,
+    metrics, network,
+    rpc_client::{ConnectionPool, Credentials, RpcClient, ScriptType, UpstreamAuth},
+    rpc_server::{Deleted, Lifetime, RpcSearchKey},
+    sink::{Event, SinkConfig},
+    storage,
+};
+
+/// Subcommands operating a running emitter over its rpc, `scan` and `bench` work on the ckb node alone,
+/// `migrate` on a store path, `simnode` with the `testkit` feature serves a simulated node
+pub(crate) fn commands() -> Vec<Command> {
+    let rpc = || {
+        Arg::new("rpc")
+            .long("rpc")
+            .env("EMITTER_RPC")
+            .default_value("http://127.0.0.1:8120")
+            .help("Rpc uri of the running emitter")
+            .action(ArgAction::Set)
+    };
+    let script_file = || {
+        Arg::new("script_file")
+            .long("script-file")
+            .required(true)
+            .help("Json file with a search key, or a bare script tracked by `--script-type`")
+            .action(ArgAction::Set)
+    };
+    let script_type = || {
+        Arg::new("script_type")
+            .long("script-type")
+            .default_value("lock")
+            .value_parser(["lock", "type"])
+            .help("Script type of a bare script in the script file")
+            .action(ArgAction::Set)
+    };
+
+    vec![
+        Command::new("register")
+            .about("Registers a search key, events are emitted from the start block on")
+            .arg(rpc())
+            .arg(script_file())
+            .arg(script_type())
+            .arg(
+                Arg::new("start")
+                    .long("start")
+                    .required(true)
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Block number to start scanning from")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("sink_file")
+                    .long("sink-file")
+                    .help("Json file with the sink config, stdout if omitted")
+                    .conflicts_with("sink_profile")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("sink_profile")
+                    .long("sink-profile")
+                    .help("Name of a sink profile in the emitter's config instead of a sink file")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("client")
+                    .long("client")
+                    .env("EMITTER_CLIENT")
+                    .help("Client the registration counts against the quota of")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("activate_at")
+                    .long("activate-at")
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Unix time in milliseconds the scan starts at, at once if omitted")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("complete_after")
+                    .long("complete-after")
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Events after which the registration completes and is deleted")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("end_block")
+                    .long("end-block")
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Last block to scan, the registration completes past it")
+                    .action(ArgAction::Set),
+            ),
+        Command::new("list")
+            .about("Lists the registered search keys with their scan tips")
+            .arg(rpc()),
+        Command::new("delete")
+            .about("Deletes a registered search key")
+            .arg(rpc())
+            .arg(script_file())
+            .arg(script_type()),
+        Command::new("scan")
+            .about("Scans a block range of the ckb node once and writes the events as json lines, no emitter needed")
+            .arg(
+                Arg::new("ckb_uri")
+                    .long("ckb-uri")
+                    .env("EMITTER_CKB_URI")
+                    .default_value("http://127.0.0.1:8114")
+                    .help("CKB rpc service uri")
+                    .action(ArgAction::Set),
+            )
+            .args(ckb_auth_args())
+            .arg(script_file().visible_alias("script"))
+            .arg(script_type())
+            .arg(
+                Arg::new("from")
+                    .long("from")
+                    .required(true)
+                    .value_parser(clap::value_parser!(u64))
+                    .help("First block number to scan")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("to")
+                    .long("to")
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Last block number to scan, the indexer tip if omitted")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .help("File the events are written to, stdout if omitted")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("pushgateway_url")
+                    .long("pushgateway-url")
+                    .env("EMITTER_PUSHGATEWAY_URL")
+                    .help("Prometheus Pushgateway the metrics of the run are pushed to once it ends")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("pushgateway_job")
+                    .long("pushgateway-job")
+                    .default_value("emitter_scan")
+                    .help("Job the pushed metrics are grouped under, a push replaces the job's last one")
+                    .action(ArgAction::Set),
+            ),
+        bench::command(),
+        Command::new("migrate")
+            .about("Upgrades the state in a store path written by an older emitter in place, stop the emitter first")
+            .arg(
+                Arg::new("store_path")
+                    .short('s')
+                    .env("EMITTER_STORE_PATH")
+                    .required(true)
+                    .help("Store path to upgrade")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("store_backend")
+                    .short('b')
+                    .env("EMITTER_STORE_BACKEND")
+                    .default_value(storage::DEFAULT_BACKEND)
+                    .help("Storage backend of the store")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("encryption_key_file")
+                    .long("encryption-key-file")
+                    .env("EMITTER_ENCRYPTION_KEY_FILE")
+                    .help("Key file of an encrypted store, `EMITTER_ENCRYPTION_KEY` holds the key otherwise")
+                    .action(ArgAction::Set),
+            ),
+        #[cfg(feature = "testkit")]
+        crate::testkit::command(),
+    ]
+}
+
+pub(crate) async fn execute(name: &str, matches: &ArgMatches) {
+    if let Err(e) = try_execute(name, matches).await {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+async fn try_execute(name: &str, matches: &ArgMatches) -> io::Result<()> {
+    match name {
+        "scan" => return scan(matches).await,
+        "bench" => return bench::execute(matches).await,
+        "migrate" => return migrate(matches),
+        #[cfg(feature = "testkit")]
+        "simnode" => return crate::testkit::execute(matches).await,
+        _ => {}
+    }
+    let client = Client::new(matches.get_one::<String>("rpc").unwrap());
+
+    match name {
+        "register" => {
+            let key = search_key(matches)?;
+            let start: BlockNumber = (*matches.get_one::<u64>("start").unwrap()).into();
+            let sink: Option<SinkConfig> = match matches.get_one::<String>("sink_file") {
+                Some(path) => Some(read_json(path)?),
+                None => None,
+            };
+            let id = client
+                .call::<String>(
+                    "register",
+                    serde_json::json!([
+                        key,
+                        start,
+                        sink,
+                        matches.get_one::<String>("client"),
+                        matches.get_one::<String>("sink_profile"),
+                        Lifetime {
+                            activate_at: matches
+                                .get_one::<u64>("activate_at")
+                                .map(|at| Uint64::from(*at)),
+                            complete_after: matches
+                                .get_one::<u64>("complete_after")
+                                .map(|events| Uint64::from(*events)),
+                            end_block: matches
+                                .get_one::<u64>("end_block")
+                                .map(|end| BlockNumber::from(*end)),
+                        }
+                    ]),
+                )
+                .await?;
+            println!("registered {}", id);
+        }
+        "list" => {
+            let registrations = client
+                .call::<Vec<(RpcSearchKey, Registration)>>("info", serde_json::json!([]))
+                .await?;
+            for (key, registration) in registrations {
+                let tip = registration.scan_tip.load();
+                let label = registration
+                    .label
+                    .map(|label| format!(" label {}", label))
+                    .unwrap_or_default();
+                let client = registration
+                    .client
+                    .map(|client| format!(" client {}", client))
+                    .unwrap_or_default();
+                let activate_at = match (registration.activate_at, registration.completed_at) {
+                    (_, Some(_)) => " completed".to_string(),
+                    (Some(at), None) => format!(" activates at {}", at),
+                    (None, None) => String::new(),
+                };
+                println!(
+                    "{} {:#x} {} 0x{} tip {} {:#x} sink {}{}{}{}",
+                    match key.script_type {
+                        ScriptType::Lock => "lock",
+                        ScriptType::Type => "type",
+                    },
+                    key.script.code_hash,
+                    key.script.hash_type,
+                    faster_hex::hex_string(key.script.args.as_bytes()),
+                    tip.block_number.value(),
+                    tip.block_hash,
+                    registration.sink.id(),
+                    label,
+                    client,
+                    activate_at
+                );
+            }
+        }
+        "delete" => {
+            let key = search_key(matches)?;
+            let deleted = client
+                .call::<Deleted>("delete", serde_json::json!([key]))
+                .await?;
+            println!(
+                "deleted at block {}, {} events, {} cells, {} transactions{}",
+                deleted.scan_tip.block_number.value(),
+                deleted.events,
+                deleted.cells,
+                deleted.transactions,
+                deleted
+                    .duration_secs
+                    .map(|secs| format!(", after {}s", secs))
+                    .unwrap_or_default()
+            );
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Events of one bounded scan in block order, `seq` numbers them from 0 as nothing is logged
+async fn scan(matches: &ArgMatches) -> io::Result<()> {
+    let started = std::time::Instant::now();
+    let client = ckb_client(matches, &Default::default())?;
+    let key = search_key(matches)?;
+    network::name_chain(&client.get_header_by_number(0.into()).await?.hash);
+    let from = *matches.get_one::<u64>("from").unwrap();
+    let to = match matches.get_one::<u64>("to") {
+        Some(to) => *to,
+        None => client.get_indexer_tip().await?.block_number.value(),
+    };
+    if from > to {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--from {} is beyond --to {}", from, to),
+        ));
+    }
+
+    let mut submits: Vec<_> = cell_process::fetch(&client, &key, [from.into(), (to + 1).into()])
+        .await?
+        .into_values()
+        .collect();
+    submits.sort_by_key(|submit| submit.header.inner.number.value());
+
+    let mut output: Box<dyn Write> = match matches.get_one::<String>("output") {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    for (seq, submit) in submits.iter().enumerate() {
+        let event = Event {
+            seq: seq as u64,
+            key: &key,
+            submit,
+            part: None,
+        };
+        serde_json::to_writer(&mut output, &event)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+    eprintln!(
+        "scanned blocks {} to {}, {} events",
+        from,
+        to,
+        submits.len()
+    );
+
+    if let Some(url) = matches.get_one::<String>("pushgateway_url") {
+        metrics::set("emitter_scan_run_events", &[], submits.len() as f64);
+        metrics::set(
+            "emitter_scan_run_seconds",
+            &[],
+            started.elapsed().as_secs_f64(),
+        );
+        metrics::push(url, matches.get_one::<String>("pushgateway_job").unwrap()).await?;
+    }
+    Ok(())
+}
+
+fn migrate(matches: &ArgMatches) -> io::Result<()> {
+    let cipher = Cipher::load(
+        matches
+            .get_one::<String>("encryption_key_file")
+            .map(|s| s.as_str()),
+    )?;
+    let from = storage::migrate(
+        matches.get_one::<String>("store_backend").unwrap(),
+        matches.get_one::<String>("store_path").unwrap(),
+        cipher,
+    )?;
+    if from == storage::STATE_VERSION {
+        println!("up to date at state version {}", from);
+    } else {
+        println!(
+            "migrated from state version {} to {}",
+            from,
+            storage::STATE_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn read_json<T: DeserializeOwned>(path: &str) -> io::Result<T> {
+    serde_json::from_str(&read_to_string(path)?).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid json in {}: {}", path, e),
+        )
+    })
+}
+
+/// A full search key, or a bare script combined with `--script-type`
+fn search_key(matches: &ArgMatches) -> io::Result<RpcSearchKey> {
+    let path = matches.get_one::<String>("script_file").unwrap();
+    let value: serde_json::Value = read_json(path)?;
+    if value.get("script").is_some() {
+        return read_json(path);
+    }
+    let script: Script = read_json(path)?;
+    Ok(RpcSearchKey {
+        script,
+        script_type: match matches.get_one::<String>("script_type").unwrap().as_str() {
+            "type" => ScriptType::Type,
+            _ => ScriptType::Lock,
+        },
+        filter: None,
+        with_data: None,
+        with_uncles: None,
+        with_epochs: None,
+        with_witnesses: None,
+        with_input_cells: None,
+        track_lineage: None,
+        track_live_cells: None,
+        discover: None,
+        correlate: None,
+        summary: None,
+        transactions_only: None,
+        namespace: None,
+    })
+}
+
+/// Credentials for the ckb node, shared by everything connecting to one
+pub(crate) fn ckb_auth_args() -> Vec<Arg> {
+    vec![
+        Arg::new("ckb_client_cert")
+            .long("ckb-client-cert")
+            .env("EMITTER_CKB_CLIENT_CERT")
+            .requires("ckb_client_key")
+            .help("PEM client certificate presented to the ckb node over https")
+            .action(ArgAction::Set),
+        Arg::new("ckb_client_key")
+            .long("ckb-client-key")
+            .env("EMITTER_CKB_CLIENT_KEY")
+            .requires("ckb_client_cert")
+            .help("PKCS#8 PEM key of --ckb-client-cert")
+            .action(ArgAction::Set),
+        Arg::new("ckb_ca_cert")
+            .long("ckb-ca-cert")
+            .env("EMITTER_CKB_CA_CERT")
+            .help("PEM root certificate trusted for the ckb node besides the system ones")
+            .action(ArgAction::Set),
+        Arg::new("ckb_basic_auth")
+            .long("ckb-basic-auth")
+            .env("EMITTER_CKB_BASIC_AUTH")
+            .hide_env_values(true)
+            .conflicts_with("ckb_bearer_token")
+            .help("`user:password` sent to the ckb node with basic auth, prefer the env var to keep it out of ps")
+            .action(ArgAction::Set),
+        Arg::new("ckb_bearer_token")
+            .long("ckb-bearer-token")
+            .env("EMITTER_CKB_BEARER_TOKEN")
+            .hide_env_values(true)
+            .help("Token sent to the ckb node as bearer auth, prefer the env var to keep it out of ps")
+            .action(ArgAction::Set),
+    ]
+}
+
+/// Client of the `ckb_uri` node presenting the credentials of `ckb_auth_args`
+pub(crate) fn ckb_client(matches: &ArgMatches, pool: &ConnectionPool) -> io::Result<RpcClient> {
+    let read = |name: &str| -> io::Result<Option<Vec<u8>>> {
+        matches
+            .get_one::<String>(name)
+            .map(|path| {
+                std::fs::read(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+            })
+            .transpose()
+    };
+    let identity = match (read("ckb_client_cert")?, read("ckb_client_key")?) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        _ => None,
+    };
+    let credentials = if let Some(basic) = matches.get_one::<String>("ckb_basic_auth") {
+        let (username, password) = match basic.split_once(':') {
+            Some((username, password)) => (username, Some(password.to_string())),
+            None => (basic.as_str(), None),
+        };
+        Some(Credentials::Basic {
+            username: username.to_string(),
+            password,
+        })
+    } else {
+        matches
+            .get_one::<String>("ckb_bearer_token")
+            .map(|token| Credentials::Bearer(token.clone()))
+    };
+    RpcClient::new(matches.get_one::<String>("ckb_uri").unwrap()).with_auth(
+        UpstreamAuth {
+            identity,
+            ca_cert: read("ckb_ca_cert")?,
+            credentials,
+        },
+        pool,
+    )
+}
+
+struct Client {
+    http: reqwest::Client,
+    uri: String,
+}
+
+impl Client {
+    fn new(uri: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            uri: uri.to_string(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> io::Result<T> {
+        let req = serde_json::json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        });
+        let output = self
+            .http
+            .post(&self.uri)
+            .json(&req)
+            .send()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()))?
+            .json::<jsonrpc_core::response::Output>()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        match output {
+            jsonrpc_core::response::Output::Success(success) => {
+                Ok(serde_json::from_value(success.result)?)
+            }
+            jsonrpc_core::response::Output::Failure(failure) => {
+                Err(io::Error::other(failure.error.message))
+            }
+        }
+    }
+}
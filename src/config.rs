@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_to_string,
+    io,
+    path::Path,
+    str::FromStr,
+    sync::RwLock,
+};
+
+use crate::{
+    logging,
+    rpc_client::{self, ConnectionPool, RetryPolicy},
+    rpc_http,
+    rpc_server::RpcSearchKey,
+    sink::{SinkConfig, SinkSettings},
+};
+
+/// Replaces the secrets shown by `get_config`
+const MASK: &str = "***";
+
+/// Settings read from the `--config` toml file, re-read on SIGHUP
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// `error`, `warn`, `info`, `debug` or `trace`, RUST_LOG decides when unset
+    pub log_level: Option<String>,
+    pub sinks: SinkSettings,
+    /// Reconciled with the registered keys at startup
+    pub registrations: Vec<DeclaredRegistration>,
+    /// Deletes registered keys which aren't declared at startup
+    pub prune_registrations: bool,
+    /// Duplicate registrations are merged at startup and registering a duplicate subscribes to the
+    /// registered key, only read at startup
+    pub merge_duplicates: bool,
+    pub rpc: RpcSettings,
+    /// Backoff of the calls to the ckb node, only read at startup
+    pub retry: RetryPolicy,
+    /// Seconds each attempt of a call to the ckb node may take, 30 when unset, only read at startup
+    pub ckb_timeout_secs: Option<u64>,
+    /// Calls to the ckb node taking longer, retries included, are logged as a warning, only read at startup
+    pub ckb_slow_call_ms: Option<u64>,
+    /// Connections kept to the ckb node, only read at startup
+    pub ckb_connections: ConnectionPool,
+    /// Further ckb nodes checked against the ckb node, scans only advance to an indexer tip whose
+    /// block hash they all have at its height, only read at startup
+    pub verify_nodes: Vec<String>,
+    /// Scans move past blocks the ckb node doesn't serve, e.g. pruned ones, instead of retrying them,
+    /// their events are lost, only read at startup
+    pub skip_unavailable_blocks: bool,
+    /// Tasks running the scans of all registrations, a task per registration when unset, only read at startup
+    pub scan_workers: Option<usize>,
+    /// A scan running as long without a call to the ckb node ending stops the systemd watchdog
+    /// pings, so a wedged emitter is restarted, 600 when unset, only read at startup
+    pub scan_stall_secs: Option<u64>,
+    /// Scans running at once, further ones wait and start by their registration's priority,
+    /// unlimited when unset, only read at startup
+    pub max_concurrent_scans: Option<usize>,
+    /// Registrations delivering events at once, further ones wait and deliver by their priority,
+    /// unlimited when unset, only read at startup
+    pub max_concurrent_deliveries: Option<usize>,
+    /// Fetched events all registrations hold in memory waiting for their delivery, a scan's events
+    /// beyond it are only logged and delivered from the event log once the sinks catch up,
+    /// unlimited when unset, only read at startup
+    pub max_buffered_events: Option<usize>,
+    pub backfill: BackfillSettings,
+    pub block_fallback: BlockFallbackSettings,
+    pub quotas: QuotaSettings,
+    /// Teams sharing the emitter, each sees and changes only the registrations of its namespace,
+    /// only read at startup
+    pub namespaces: HashMap<String, NamespaceSettings>,
+    pub plugin: PluginSettings,
+    pub statsd: StatsdSettings,
+    pub snapshot: SnapshotSettings,
+}
+
+/// A wasm module every event passes before it is logged and delivered, needs the `wasm-plugin` feature,
+/// only read at startup
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct PluginSettings {
+    /// `.wasm` or `.wat` file of the module, no plugin when unset
+    pub path: Option<String>,
+    /// Wasm instructions a single event may take, unlimited when unset
+    pub fuel: Option<u64>,
+}
+
+/// Where the `admin_export_snapshot` rpc writes, only read at startup
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SnapshotSettings {
+    /// Directory the snapshots are written into, `<store_path>/snapshots` when unset
+    pub dir: Option<String>,
+}
+
+/// Where and how often the metrics are sent, off without an address, only read at startup
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StatsdSettings {
+    /// `host:port` of the agent, e.g. `127.0.0.1:8125`
+    pub address: Option<String>,
+    /// Put before every metric name, e.g. `ckb.`
+    pub prefix: String,
+    pub interval_secs: u64,
+    /// Labels as DogStatsD tags, else their values are joined to the name with dots
+    pub dogstatsd: bool,
+}
+
+impl Default for StatsdSettings {
+    fn default() -> Self {
+        StatsdSettings {
+            address: None,
+            prefix: String::new(),
+            interval_secs: 10,
+            dogstatsd: true,
+        }
+    }
+}
+
+/// Splits the scan of a registration far behind the tip into shards fetched in parallel,
+/// only read at startup
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct BackfillSettings {
+    /// Shards fetched at once, a scan fetches its whole range at once when unset
+    pub shards: Option<usize>,
+    /// Blocks of a shard, 10000 when unset, shorter ranges aren't split
+    pub shard_blocks: Option<u64>,
+}
+
+impl BackfillSettings {
+    pub fn shard_blocks(&self) -> u64 {
+        self.shard_blocks.unwrap_or(10_000).max(1)
+    }
+}
+
+/// Scans past the indexer tip by fetching blocks from the node once the indexer is far behind it,
+/// only read at startup
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct BlockFallbackSettings {
+    /// Blocks the indexer tip may be behind the node's tip before the scans which caught up with
+    /// it fetch the blocks after it themselves, off when unset
+    pub lag_blocks: Option<u64>,
+}
+
+/// Caps the registrations made over rpc per client, only read at startup
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct QuotaSettings {
+    /// Of every client without its own quota, registrations naming no client share one, unlimited when unset
+    pub max_registrations: Option<usize>,
+    /// Quotas of single clients
+    pub clients: HashMap<String, usize>,
+    /// Registrations of all clients together, the callers name their client themselves, this caps
+    /// what any of them can take. Unlimited when unset
+    pub max_total: Option<usize>,
+}
+
+impl QuotaSettings {
+    pub fn max_registrations(&self, client: Option<&str>) -> Option<usize> {
+        client
+            .and_then(|client| self.clients.get(client).copied())
+            .or(self.max_registrations)
+    }
+}
+
+/// A namespace of registrations, the calls with its token are scoped to it
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct NamespaceSettings {
+    /// Bearer token of the namespace's calls
+    pub token: String,
+    /// Registrations of the namespace, unlimited when unset
+    pub max_registrations: Option<usize>,
+    /// Sink profiles of the namespace, they shadow the shared ones of the same name
+    pub profiles: HashMap<String, SinkConfig>,
+}
+
+/// Rpc http server settings, only read at startup
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct RpcSettings {
+    /// Used unless `-l` or `EMITTER_LISTEN_URI` is given
+    pub listen_address: Option<String>,
+    /// Caps the requests served at once, further ones are answered with a server busy error
+    pub max_connections: Option<u16>,
+    /// Origins allowed by CORS, `*` for any, every origin is allowed when unset
+    pub cors_allowed_origins: Option<Vec<String>>,
+    /// Bytes of a request body, larger ones are answered with 413, 10 MiB when unset
+    pub max_request_body_size: Option<u32>,
+    /// Calls per second each client may make to a method, e.g. `info = 10`, further calls get an error
+    pub method_quotas: HashMap<String, u32>,
+    /// Serves read-only GraphQL queries on `POST /graphql`, off by default
+    pub graphql: bool,
+}
+
+/// A registration kept in the config file instead of made over rpc
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeclaredRegistration {
+    pub label: Option<String>,
+    #[serde(flatten)]
+    pub key: RpcSearchKey,
+    pub start: u64,
+    #[serde(default)]
+    pub sink: SinkConfig,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let config: Config = toml::from_str(&read_to_string(path)?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid config {:?}: {}", path, e),
+            )
+        })?;
+        config.log_level()?;
+        let mut tokens = HashSet::new();
+        for (name, namespace) in &config.namespaces {
+            if namespace.token.is_empty() || !tokens.insert(&namespace.token) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("namespace {} needs a token of its own", name),
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The config as the emitter runs it, for `get_config`: the current shared sink settings,
+    /// the defaults of unset options filled in and the credentials masked
+    pub fn effective(&self, sinks: &SinkSettings) -> Config {
+        let mut config = self.clone();
+        config.sinks = sinks.clone();
+        let settings = &mut config.sinks;
+        settings.webhook_secret = settings.webhook_secret.as_ref().map(|_| MASK.to_string());
+        // an incoming webhook url is its own credential, only its origin is shown
+        settings.alert_webhook_url =
+            settings
+                .alert_webhook_url
+                .as_ref()
+                .map(|url| match reqwest::Url::parse(url) {
+                    Ok(url) => format!("{}/{}", url.origin().ascii_serialization(), MASK),
+                    Err(_) => MASK.to_string(),
+                });
+        for sink in settings.profiles.values_mut() {
+            *sink = sink.redacted();
+        }
+        for namespace in config.namespaces.values_mut() {
+            namespace.token = MASK.to_string();
+            for sink in namespace.profiles.values_mut() {
+                *sink = sink.redacted();
+            }
+        }
+        for registration in &mut config.registrations {
+            registration.sink = registration.sink.redacted();
+        }
+        for uri in &mut config.verify_nodes {
+            if let Ok(mut url) = reqwest::Url::parse(uri) {
+                if url.password().is_some() {
+                    let _ = url.set_password(Some(MASK));
+                    *uri = url.to_string();
+                }
+            }
+        }
+        config.ckb_timeout_secs = Some(
+            config
+                .ckb_timeout_secs
+                .unwrap_or(rpc_client::DEFAULT_TIMEOUT.as_secs()),
+        );
+        config.ckb_connections.idle_timeout_secs = Some(config.ckb_connections.idle_timeout_secs());
+        config.backfill.shard_blocks = Some(config.backfill.shard_blocks());
+        config.scan_stall_secs = Some(config.scan_stall_secs());
+        config.rpc.max_request_body_size = Some(
+            config
+                .rpc
+                .max_request_body_size
+                .unwrap_or(rpc_http::MAX_REQUEST_BODY_SIZE),
+        );
+        config
+    }
+
+    pub fn scan_stall_secs(&self) -> u64 {
+        self.scan_stall_secs.unwrap_or(600)
+    }
+
+    pub fn log_level(&self) -> io::Result<Option<log::LevelFilter>> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                log::LevelFilter::from_str(level).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid log level: {}", level),
+                    )
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Lets the config decide the log level when it sets one, RUST_LOG module directives still apply
+pub fn init_logger(config: &Config) {
+    logging::init(config.log_level().unwrap());
+}
+
+/// Applies the config to the shared settings, a webhook secret from the command line or env wins
+pub fn apply(config: &Config, webhook_secret: Option<&String>, settings: &RwLock<SinkSettings>) {
+    let mut sinks = config.sinks.clone();
+    if let Some(secret) = webhook_secret {
+        sinks.webhook_secret = Some(secret.clone());
+    }
+    *settings.write().unwrap() = sinks;
+}
+
+/// Re-reads the config file and applies the reloadable settings, scans keep running untouched
+pub fn reload(path: &Path, webhook_secret: Option<&String>, settings: &RwLock<SinkSettings>) {
+    match Config::load(path) {
+        Ok(config) => {
+            if let Ok(Some(level)) = config.log_level() {
+                logging::set_default(level);
+            }
+            apply(&config, webhook_secret, settings);
+            log::info!("reloaded config {:?}", path);
+        }
+        Err(e) => log::error!("failed to reload config, keep the current one: {}", e),
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    event_log::LoggedEvent,
+    rpc_server::RpcSearchKey,
+    storage::{Cursor, Storage},
+};
+
+/// Seqs a pull reads past the consumer's cursor at most, the log is shared by every registration
+const PULL_WINDOW: u64 = 100_000;
+
+/// Last acked event log seq of every consumer, tracked per registration
+#[derive(Clone)]
+pub(crate) struct ConsumerCursors {
+    cursors: Arc<dashmap::DashMap<(String, RpcSearchKey), u64>>,
+    storage: Arc<dyn Storage>,
+    // serializes the saves so an older snapshot never overwrites a newer one
+    save_lock: Arc<Mutex<()>>,
+}
+
+impl ConsumerCursors {
+    pub fn load(storage: Arc<dyn Storage>) -> io::Result<Self> {
+        let cursors = storage
+            .load_cursors()?
+            .into_iter()
+            .map(|(consumer_id, key, seq)| ((consumer_id, key), seq))
+            .collect();
+
+        Ok(ConsumerCursors {
+            cursors: Arc::new(cursors),
+            storage,
+            save_lock: Default::default(),
+        })
+    }
+
+    /// Next events of `key` after the consumer's acked cursor, within `PULL_WINDOW` seqs of it.
+    /// A window without events of `key` moves the cursor past it, the next pull reads on from there
+    pub fn pull(
+        &self,
+        consumer_id: &str,
+        key: &RpcSearchKey,
+        max: usize,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        self.pull_within(consumer_id, key, max, PULL_WINDOW)
+    }
+
+    fn pull_within(
+        &self,
+        consumer_id: &str,
+        key: &RpcSearchKey,
+        max: usize,
+        window: u64,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let from = self
+            .cursors
+            .get(&(consumer_id.to_string(), key.clone()))
+            .map(|seq| *seq + 1)
+            .unwrap_or_default();
+        let to = from.saturating_add(window - 1);
+
+        let events = self
+            .storage
+            .read_events(from, to, max, &|event| &event.key == key)?;
+        let later = self
+            .storage
+            .event_bounds()?
+            .is_some_and(|(_, newest)| newest > to);
+        if events.is_empty() && later {
+            self.advance(consumer_id, key.clone(), to)?;
+        }
+        Ok(events)
+    }
+
+    /// Moves the consumer's cursor of the registration that emitted `seq` forward to it,
+    /// returns false if no such event is logged
+    pub fn ack(&self, consumer_id: &str, seq: u64) -> io::Result<bool> {
+        let key = match self.storage.read_events(seq, seq, 1, &|_| true)?.pop() {
+            Some(event) => event.key,
+            None => return Ok(false),
+        };
+        self.advance(consumer_id, key, seq)?;
+        Ok(true)
+    }
+
+    fn advance(&self, consumer_id: &str, key: RpcSearchKey, seq: u64) -> io::Result<()> {
+        let mut cursor = self
+            .cursors
+            .entry((consumer_id.to_string(), key))
+            .or_insert(seq);
+        if *cursor < seq {
+            *cursor = seq;
+        }
+        drop(cursor);
+
+        let _guard = self.save_lock.lock().unwrap();
+        self.storage.save_cursors(&self.all())
+    }
+
+    /// Logged events of `key` after the cursor of every consumer which acked one of them
+    pub fn unacked(&self, key: &RpcSearchKey) -> io::Result<Vec<(String, usize)>> {
+        let cursors = self
+            .cursors
+            .iter()
+            .filter(|kv| &kv.key().1 == key)
+            .map(|kv| (kv.key().0.clone(), *kv.value()))
+            .collect::<Vec<_>>();
+        let from = match cursors.iter().map(|(_, seq)| *seq).min() {
+            Some(seq) => seq + 1,
+            None => return Ok(Vec::new()),
+        };
+        // counted in one read of the log, the filter takes no event
+        let counts = std::cell::RefCell::new(vec![0; cursors.len()]);
+        self.storage.read_events(from, u64::MAX, 1, &|event| {
+            if &event.key == key {
+                let mut counts = counts.borrow_mut();
+                for (count, (_, seq)) in counts.iter_mut().zip(&cursors) {
+                    *count += usize::from(event.seq > *seq);
+                }
+            }
+            false
+        })?;
+        Ok(cursors
+            .into_iter()
+            .map(|(consumer_id, _)| consumer_id)
+            .zip(counts.into_inner())
+            .collect())
+    }
+
+    pub fn all(&self) -> Vec<Cursor> {
+        self.cursors
+            .iter()
+            .map(|kv| (kv.key().0.clone(), kv.key().1.clone(), *kv.value()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        self,
+        conformance::{key, submit},
+    };
+
+    fn seqs(events: &[LoggedEvent]) -> Vec<u64> {
+        events.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn pulls_skip_other_keys_and_acks_move_the_cursor() {
+        let storage = storage::open("memory", "", None, None).unwrap();
+        storage
+            .append_events(&key(1), vec![submit(1), submit(2)])
+            .unwrap();
+        storage.append_events(&key(2), vec![submit(3)]).unwrap();
+        storage.append_events(&key(1), vec![submit(4)]).unwrap();
+        let cursors = ConsumerCursors::load(storage).unwrap();
+
+        assert_eq!(
+            seqs(&cursors.pull("c", &key(1), 10).unwrap()),
+            vec![0, 1, 3]
+        );
+        assert_eq!(seqs(&cursors.pull("c", &key(1), 1).unwrap()), vec![0]);
+        assert!(cursors.ack("c", 1).unwrap());
+        assert_eq!(seqs(&cursors.pull("c", &key(1), 10).unwrap()), vec![3]);
+        // an older ack doesn't move the cursor back
+        assert!(cursors.ack("c", 0).unwrap());
+        assert_eq!(seqs(&cursors.pull("c", &key(1), 10).unwrap()), vec![3]);
+        // the ack of another key's event moves that key's cursor
+        assert!(cursors.ack("c", 2).unwrap());
+        assert_eq!(seqs(&cursors.pull("c", &key(1), 10).unwrap()), vec![3]);
+        assert!(cursors.pull("c", &key(2), 10).unwrap().is_empty());
+
+        assert!(!cursors.ack("c", 4).unwrap());
+        let mut unacked = cursors.unacked(&key(1)).unwrap();
+        unacked.sort();
+        assert_eq!(unacked, vec![("c".to_string(), 1)]);
+        assert!(cursors.ack("d", 0).unwrap());
+        let mut unacked = cursors.unacked(&key(1)).unwrap();
+        unacked.sort();
+        assert_eq!(unacked, vec![("c".to_string(), 1), ("d".to_string(), 2)]);
+        assert_eq!(
+            seqs(&cursors.pull("other", &key(1), 10).unwrap()),
+            vec![0, 1, 3]
+        );
+    }
+
+    #[test]
+    fn cursors_survive_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || storage::open("file", dir.path(), None, None).unwrap();
+        let storage = open();
+        storage
+            .append_events(&key(1), vec![submit(1), submit(2)])
+            .unwrap();
+        assert!(ConsumerCursors::load(storage).unwrap().ack("c", 0).unwrap());
+
+        let cursors = ConsumerCursors::load(open()).unwrap();
+        assert_eq!(seqs(&cursors.pull("c", &key(1), 10).unwrap()), vec![1]);
+    }
+
+    #[test]
+    fn pulls_read_a_window_past_the_cursor() {
+        let storage = storage::open("memory", "", None, None).unwrap();
+        let others = (0..5).map(submit).collect();
+        storage.append_events(&key(2), others).unwrap();
+        storage.append_events(&key(1), vec![submit(5)]).unwrap();
+        let cursors = ConsumerCursors::load(storage).unwrap();
+        let pull = |args| seqs(&cursors.pull_within("c", &key(args), 10, 4).unwrap());
+
+        // the first window has none of the key's events, the cursor moves past it
+        assert!(pull(1).is_empty());
+        assert_eq!(pull(1), vec![5]);
+        // a window with events of the key leaves the cursor where it is
+        assert_eq!(pull(2), vec![0, 1, 2, 3]);
+        assert_eq!(cursors.all(), vec![("c".to_string(), key(1), 3)]);
+    }
+}
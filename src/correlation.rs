@@ -0,0 +1,89 @@
+use ckb_jsonrpc_types::{CellOutput, Script, Transaction, Uint32};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rpc_client::{CellType, ScriptType},
+    schema,
+};
+
+/// A second search key, matching the cells a transaction consuming the registration's cells
+/// creates. Its args are a prefix as in search keys, it doesn't have to be registered
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CorrelationRule {
+    #[schemars(with = "schema::Script")]
+    pub script: Script,
+    pub script_type: ScriptType,
+}
+
+/// A transaction consuming cells of the registration and creating cells of the rule's key
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Correlation {
+    #[schemars(with = "schema::Hex")]
+    pub tx_hash: H256,
+    /// Indexes in the transaction of the matched inputs, as in the event's `inputs`
+    #[schemars(with = "Vec<schema::Hex>")]
+    pub consumed: Vec<Uint32>,
+    pub created: Vec<CorrelatedCell>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct CorrelatedCell {
+    #[schemars(with = "schema::Hex")]
+    pub index: Uint32,
+    #[schemars(with = "schema::CellOutput")]
+    pub output: CellOutput,
+}
+
+impl CorrelationRule {
+    fn matches(&self, output: &CellOutput) -> bool {
+        let script = match self.script_type {
+            ScriptType::Lock => Some(&output.lock),
+            ScriptType::Type => output.type_.as_ref(),
+        };
+        script.is_some_and(|script| {
+            script.code_hash == self.script.code_hash
+                && script.hash_type == self.script.hash_type
+                && script
+                    .args
+                    .as_bytes()
+                    .starts_with(self.script.args.as_bytes())
+        })
+    }
+
+    /// None unless `tx` both consumes matched cells among `cells` and creates cells of the rule's key
+    pub fn correlate(
+        &self,
+        tx_hash: H256,
+        tx: &Transaction,
+        cells: &[(CellType, Uint32)],
+    ) -> Option<Correlation> {
+        let consumed = cells
+            .iter()
+            .filter(|(ty, _)| *ty == CellType::Input)
+            .map(|(_, idx)| *idx)
+            .collect::<Vec<_>>();
+        if consumed.is_empty() {
+            return None;
+        }
+        let created = tx
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| self.matches(output))
+            .map(|(index, output)| CorrelatedCell {
+                index: (index as u32).into(),
+                output: output.clone(),
+            })
+            .collect::<Vec<_>>();
+        if created.is_empty() {
+            return None;
+        }
+        Some(Correlation {
+            tx_hash,
+            consumed,
+            created,
+        })
+    }
+}
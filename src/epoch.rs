@@ -0,0 +1,48 @@
+use ckb_jsonrpc_types::{BlockNumber, EpochNumber, HeaderView, Uint32};
+use ckb_types::core::EpochNumberWithFraction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::io;
+
+use crate::{rpc_client::RpcClient, schema};
+
+/// The epoch starting at a block, the events of keys asking for epochs carry it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct EpochStart {
+    #[schemars(with = "schema::Hex")]
+    pub number: EpochNumber,
+    #[schemars(with = "schema::Hex")]
+    pub start_number: BlockNumber,
+    /// In blocks
+    #[schemars(with = "schema::Hex")]
+    pub length: BlockNumber,
+    #[schemars(with = "schema::Hex")]
+    pub compact_target: Uint32,
+}
+
+/// The epochs starting within `block_range`, end exclusive, by start block. Only the first block
+/// of the range and the first block of each epoch are fetched
+pub(crate) async fn epoch_starts(
+    client: &RpcClient,
+    block_range: [BlockNumber; 2],
+) -> io::Result<Vec<(HeaderView, EpochStart)>> {
+    let mut starts = Vec::new();
+    let mut number = block_range[0].value();
+    while number < block_range[1].value() {
+        let header = client.get_header_by_number(number.into()).await?;
+        let epoch = EpochNumberWithFraction::from_full_value(header.inner.epoch.value());
+        if epoch.index() == 0 {
+            let start = EpochStart {
+                number: epoch.number().into(),
+                start_number: number.into(),
+                length: epoch.length().into(),
+                compact_target: header.inner.compact_target,
+            };
+            starts.push((header, start));
+        }
+        // a malformed zero length would never leave the epoch
+        number = number - epoch.index() + epoch.length().max(1);
+    }
+    Ok(starts)
+}
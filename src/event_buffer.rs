@@ -0,0 +1,46 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Caps the fetched events all registrations hold in memory at once, a scan whose events don't
+/// fit logs them without delivering them and its sinks get them from the event log later
+#[derive(Clone)]
+pub(crate) struct EventBuffer {
+    max: usize,
+    held: Arc<AtomicUsize>,
+}
+
+/// Events of a scan counted against the buffer until dropped
+pub(crate) struct Held {
+    n: usize,
+    held: Arc<AtomicUsize>,
+}
+
+impl EventBuffer {
+    pub fn new(max: usize) -> Self {
+        EventBuffer {
+            max,
+            held: Default::default(),
+        }
+    }
+
+    /// None if `n` more events would exceed the cap, a scan of more events than the cap never fits
+    pub fn hold(&self, n: usize) -> Option<Held> {
+        self.held
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |held| {
+                held.checked_add(n).filter(|total| *total <= self.max)
+            })
+            .ok()?;
+        Some(Held {
+            n,
+            held: self.held.clone(),
+        })
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.held.fetch_sub(self.n, Ordering::AcqRel);
+    }
+}
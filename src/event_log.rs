@@ -0,0 +1,382 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{cipher::Cipher, rpc_server::RpcSearchKey, sink::Event, Submit};
+
+/// Starts every zstd frame, little endian
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A new segment is started once the current one grows beyond this size
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Every record is `len: u32 | crc32: u32 | json payload`, both integers big-endian,
+/// the payload is zstd compressed when compression is on, then sealed when the log is encrypted
+const RECORD_HEADER: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    pub key: RpcSearchKey,
+    #[serde(flatten)]
+    pub submit: Submit,
+}
+
+impl LoggedEvent {
+    pub fn as_event(&self) -> Event<'_> {
+        Event {
+            seq: self.seq,
+            key: &self.key,
+            submit: &self.submit,
+            part: None,
+        }
+    }
+}
+
+/// Append-only log of every emitted event, split into segments named by their first seq
+#[derive(Clone)]
+pub(crate) struct EventLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    segment: File,
+    segment_len: u64,
+    next_seq: u64,
+    cipher: Option<Cipher>,
+    /// Zstd level new records are compressed with, plain when unset
+    compression: Option<i32>,
+    /// Bytes of the next append written before it fails, to tear it in the tests
+    #[cfg(test)]
+    torn_append: Option<usize>,
+}
+
+impl Inner {
+    fn write(&mut self, records: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(len) = self.torn_append.take() {
+            self.segment.write_all(&records[..len.min(records.len())])?;
+            return Err(io::Error::other("torn append"));
+        }
+        self.segment.write_all(records)?;
+        self.segment.sync_data()
+    }
+}
+
+impl EventLog {
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        cipher: Option<Cipher>,
+        compression: Option<i32>,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        create_dir_all(&dir)?;
+
+        let (segment, segment_len, next_seq) = match segments(&dir)?.pop() {
+            Some((first_seq, path)) => {
+                let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+                let (valid_len, last_seq) = recover(&mut file, &path, cipher.as_ref())?;
+                file.seek(SeekFrom::End(0))?;
+                (
+                    file,
+                    valid_len,
+                    last_seq.map(|s| s + 1).unwrap_or(first_seq),
+                )
+            }
+            None => (create_segment(&dir, 0)?, 0, 0),
+        };
+
+        Ok(EventLog {
+            inner: Arc::new(Mutex::new(Inner {
+                dir,
+                segment,
+                segment_len,
+                next_seq,
+                cipher,
+                compression,
+                #[cfg(test)]
+                torn_append: None,
+            })),
+        })
+    }
+
+    /// Durably appends the events and assigns them consecutive seqs. The events are appended to
+    /// one segment in one write, a failed append is cut off the file so none of them is logged
+    pub fn append(&self, key: &RpcSearchKey, submits: Vec<Submit>) -> io::Result<Vec<LoggedEvent>> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut events = Vec::with_capacity(submits.len());
+        let mut records = Vec::new();
+
+        if inner.segment_len >= SEGMENT_SIZE {
+            inner.segment = create_segment(&inner.dir, inner.next_seq)?;
+            inner.segment_len = 0;
+        }
+        for submit in submits {
+            let event = LoggedEvent {
+                seq: inner.next_seq + events.len() as u64,
+                key: key.clone(),
+                submit,
+            };
+            let mut payload = serde_json::to_vec(&event)?;
+            if let Some(level) = inner.compression {
+                payload = zstd::encode_all(payload.as_slice(), level)?;
+            }
+            if let Some(cipher) = &inner.cipher {
+                payload = cipher.seal(&payload)?;
+            }
+            records.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            records.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+            records.extend_from_slice(&payload);
+            events.push(event);
+        }
+
+        if let Err(e) = inner.write(&records) {
+            // a torn record would stop the reads and the recovery at it
+            let segment_len = inner.segment_len;
+            inner.segment.set_len(segment_len)?;
+            inner.segment.seek(SeekFrom::Start(segment_len))?;
+            return Err(e);
+        }
+        inner.segment_len += records.len() as u64;
+        inner.next_seq += events.len() as u64;
+
+        Ok(events)
+    }
+
+    /// Reads at most `max` events with `from <= seq <= to` accepted by `filter`, in seq order
+    pub fn read_filtered(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let (dir, cipher) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.dir.clone(), inner.cipher.clone())
+        };
+        let segments = segments(&dir)?;
+        let mut events = Vec::new();
+
+        for (i, (first_seq, path)) in segments.iter().enumerate() {
+            let next_first_seq = segments.get(i + 1).map(|(s, _)| *s).unwrap_or(u64::MAX);
+            if *first_seq > to || next_first_seq <= from {
+                continue;
+            }
+            for event in read_segment(path, cipher.as_ref())? {
+                if event.seq >= from && event.seq <= to && filter(&event) {
+                    events.push(event);
+                    if events.len() >= max {
+                        return Ok(events);
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Seqs of the oldest and the newest event in the log
+    pub fn bounds(&self) -> io::Result<Option<(u64, u64)>> {
+        let inner = self.inner.lock().unwrap();
+        let first_seq = segments(&inner.dir)?
+            .first()
+            .map(|(s, _)| *s)
+            .unwrap_or(inner.next_seq);
+        if first_seq >= inner.next_seq {
+            return Ok(None);
+        }
+        Ok(Some((first_seq, inner.next_seq - 1)))
+    }
+
+    /// Removes the segments whose events all have `seq < before`, the current segment is never removed
+    pub fn truncate(&self, before: u64) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let segments = segments(&inner.dir)?;
+        for pair in segments.windows(2) {
+            let ((_, path), (next_first_seq, _)) = (&pair[0], &pair[1]);
+            if *next_first_seq > before {
+                break;
+            }
+            remove_file(path)?;
+            log::info!("Removed event log segment {:?}", path);
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", first_seq))
+}
+
+fn create_segment(dir: &Path, first_seq: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, first_seq))
+}
+
+/// Segments of the log sorted by their first seq
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut res = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|e| e == "log").unwrap_or(false) {
+            if let Some(first_seq) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                res.push((first_seq, path));
+            }
+        }
+    }
+    res.sort_by_key(|(seq, _)| *seq);
+    Ok(res)
+}
+
+/// Reads one record of the `left` bytes left in the segment, `None` at the end of the segment or
+/// on a torn/corrupted tail, a record which can't be decrypted is an error, it is intact but
+/// sealed with another key
+fn read_record<R: Read>(
+    reader: &mut R,
+    left: u64,
+    cipher: Option<&Cipher>,
+) -> io::Result<Option<(usize, LoggedEvent)>> {
+    let mut header = [0u8; RECORD_HEADER];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    // the header isn't checked yet, a torn one mustn't allocate what it claims
+    if len as u64 > left.saturating_sub(RECORD_HEADER as u64) {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0u8; len];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Ok(None);
+    }
+    let mut plain = match cipher {
+        Some(cipher) => cipher.open(&payload)?,
+        None => payload,
+    };
+    // records written with compression off are json, which never starts with the frame magic
+    if plain.starts_with(&ZSTD_MAGIC) {
+        plain = zstd::decode_all(plain.as_slice())?;
+    }
+    // an intact record is never cut off, unreadable ones are most likely sealed
+    match serde_json::from_slice(&plain) {
+        Ok(event) => Ok(Some((len + RECORD_HEADER, event))),
+        Err(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unreadable event log record, is the log encrypted? {}", e),
+        )),
+    }
+}
+
+fn read_segment(path: &Path, cipher: Option<&Cipher>) -> io::Result<Vec<LoggedEvent>> {
+    let file = File::open(path)?;
+    let mut left = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut events = Vec::new();
+    while let Some((len, event)) = read_record(&mut reader, left, cipher)? {
+        left -= len as u64;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Verifies the last segment and cuts off a partially written tail left by a crash
+fn recover(
+    file: &mut File,
+    path: &Path,
+    cipher: Option<&Cipher>,
+) -> io::Result<(u64, Option<u64>)> {
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file.try_clone()?);
+    let mut valid_len = 0;
+    let mut last_seq = None;
+    while let Some((len, event)) = read_record(&mut reader, file_len - valid_len, cipher)? {
+        valid_len += len as u64;
+        last_seq = Some(event.seq);
+    }
+    if valid_len < file_len {
+        log::warn!(
+            "Truncate corrupted event log tail, file: {:?}, from {} to {} bytes",
+            path,
+            file_len,
+            valid_len
+        );
+        file.set_len(valid_len)?;
+    }
+    Ok((valid_len, last_seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::conformance::{key, submit};
+
+    fn seqs(log: &EventLog) -> Vec<u64> {
+        let events = log
+            .read_filtered(0, u64::MAX, usize::MAX, &|_| true)
+            .unwrap();
+        events.iter().map(|event| event.seq).collect()
+    }
+
+    #[test]
+    fn failed_append_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = EventLog::open(dir.path(), None, None).unwrap();
+        log.append(&key(1), vec![submit(1)]).unwrap();
+
+        log.inner.lock().unwrap().torn_append = Some(RECORD_HEADER + 3);
+        assert!(log.append(&key(1), vec![submit(2), submit(3)]).is_err());
+        // the seqs of the failed append are taken again, the events after it are read
+        let appended = log.append(&key(1), vec![submit(4)]).unwrap();
+        assert_eq!(appended[0].seq, 1);
+        assert_eq!(seqs(&log), vec![0, 1]);
+        drop(log);
+        let log = EventLog::open(dir.path(), None, None).unwrap();
+        assert_eq!(log.bounds().unwrap(), Some((0, 1)));
+    }
+
+    #[test]
+    fn oversized_record_len_is_a_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = EventLog::open(dir.path(), None, None).unwrap();
+        log.append(&key(1), vec![submit(1)]).unwrap();
+        drop(log);
+
+        // a header claiming 4 GiB, read before its crc could be checked
+        let path = segment_path(dir.path(), 0);
+        let valid_len = std::fs::metadata(&path).unwrap().len();
+        let mut tail = u32::MAX.to_be_bytes().to_vec();
+        tail.extend_from_slice(&[0; 4 + 16]);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&tail)
+            .unwrap();
+
+        let log = EventLog::open(dir.path(), None, None).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_len);
+        log.append(&key(1), vec![submit(2)]).unwrap();
+        assert_eq!(seqs(&log), vec![0, 1]);
+    }
+}
@@ -0,0 +1,137 @@
+use ckb_jsonrpc_types::{
+    CellData, CellInfo, CellOutput, JsonBytes, OutPoint, Transaction, TransactionView, Uint64,
+};
+use ckb_types::{packed, prelude::Unpack, H256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::{collections::HashMap, io};
+
+use crate::{rpc_client::RpcClient, schema};
+
+/// Fee of a transaction with matched cells, in shannons
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct TxFee {
+    #[schemars(with = "schema::Hex")]
+    pub tx_hash: H256,
+    #[schemars(with = "schema::Hex")]
+    pub fee: Uint64,
+}
+
+/// Capacity of the emitted cells of a block, in shannons, only computed for keys filtering by it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CapacityChange {
+    /// By the outputs
+    #[schemars(with = "schema::Hex")]
+    pub created: Uint64,
+    /// By the inputs, of the cells they spend
+    #[schemars(with = "schema::Hex")]
+    pub spent: Uint64,
+}
+
+impl CapacityChange {
+    /// How much the key's capacity grew or shrank
+    pub fn amount(&self) -> u64 {
+        self.created.value().abs_diff(self.spent.value())
+    }
+}
+
+/// The transactions looked up so far for the cells they created, by transaction hash
+pub(crate) type SpentOutputs = HashMap<H256, Transaction>;
+
+/// The cell `previous` spends and its data, looked up in the transaction creating it
+async fn lookup<'a>(
+    client: &RpcClient,
+    previous: &OutPoint,
+    spent: &'a mut SpentOutputs,
+) -> io::Result<(&'a CellOutput, &'a JsonBytes)> {
+    if !spent.contains_key(&previous.tx_hash) {
+        let created = client
+            .get_transaction(&previous.tx_hash)
+            .await?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("spent transaction {:#x} not found", previous.tx_hash),
+                )
+            })?;
+        spent.insert(previous.tx_hash.clone(), created.inner);
+    }
+    let created = &spent[&previous.tx_hash];
+    let index = previous.index.value() as usize;
+    created
+        .outputs
+        .get(index)
+        .zip(created.outputs_data.get(index))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "spent output {:#x}:{} doesn't exist",
+                    previous.tx_hash,
+                    previous.index.value()
+                ),
+            )
+        })
+}
+
+/// The cell `previous` spends
+pub(crate) async fn spent_output<'a>(
+    client: &RpcClient,
+    previous: &OutPoint,
+    spent: &'a mut SpentOutputs,
+) -> io::Result<&'a CellOutput> {
+    Ok(lookup(client, previous, spent).await?.0)
+}
+
+/// The data of the cell `previous` spends
+pub(crate) async fn spent_data<'a>(
+    client: &RpcClient,
+    previous: &OutPoint,
+    spent: &'a mut SpentOutputs,
+) -> io::Result<&'a JsonBytes> {
+    Ok(lookup(client, previous, spent).await?.1)
+}
+
+/// The cell `previous` spends, with its data unless `with_data` is false
+pub(crate) async fn spent_cell(
+    client: &RpcClient,
+    previous: &OutPoint,
+    spent: &mut SpentOutputs,
+    with_data: bool,
+) -> io::Result<CellInfo> {
+    let (output, data) = lookup(client, previous, spent).await?;
+    Ok(CellInfo {
+        output: output.clone(),
+        data: with_data.then(|| CellData {
+            hash: packed::CellOutput::calc_data_hash(data.as_bytes()).unpack(),
+            content: data.clone(),
+        }),
+    })
+}
+
+/// Capacity of the inputs minus the outputs, none for cellbases which spend nothing
+pub(crate) async fn fee(
+    client: &RpcClient,
+    tx: &TransactionView,
+    spent: &mut SpentOutputs,
+) -> io::Result<Option<u64>> {
+    let mut inputs = 0u64;
+    for input in &tx.inner.inputs {
+        let previous = &input.previous_output;
+        if previous.tx_hash == H256::default() {
+            return Ok(None);
+        }
+        inputs += spent_output(client, previous, spent)
+            .await?
+            .capacity
+            .value();
+    }
+    let outputs = tx
+        .inner
+        .outputs
+        .iter()
+        .map(|output| output.capacity.value())
+        .sum::<u64>();
+    Ok(Some(inputs.saturating_sub(outputs)))
+}
@@ -1,20 +1,168 @@
-use std::{
-    fs::{copy, create_dir_all, remove_file, rename, File, OpenOptions},
-    io::Write,
-    path::{Path, PathBuf},
-    sync::Arc,
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rpc_server::RpcSearchKey, scheduler::Priority, sink::SinkConfig, storage::Storage, ScanTip,
 };
 
-use crate::{cell_process::CellProcess, rpc_client::RpcClient, rpc_server::RpcSearchKey, ScanTip};
+/// Everything tracked for a registered search key
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Registration {
+    #[serde(flatten)]
+    pub scan_tip: ScanTip,
+    #[serde(default)]
+    pub sink: SinkConfig,
+    /// The sink profile of the config the sink was taken from at register time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sink_profile: Option<String>,
+    /// Free-form name, set by registrations declared in the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Who registered it over rpc, counted against the client's quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Set while scanning keeps failing, cleared by the next successful scan
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub scan_failure: Option<ScanFailure>,
+    /// Set once the scan task panicked, it is restarted with the same backoff as a failing scan
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub scan_panic: Option<ScanPanic>,
+    /// Lag beyond which the registration is degraded, or paused, and an alert is sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lag_alert: Option<LagThreshold>,
+    /// Degraded or paused while the lag exceeds `lag_alert`, checked again after a restart
+    #[serde(skip_deserializing)]
+    pub status: Status,
+    /// Decides which waiting scan starts first under `max_concurrent_scans`
+    #[serde(default)]
+    pub priority: Priority,
+    /// Shards a backfill of the registration fetches at once, `[backfill] shards` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan_parallelism: Option<usize>,
+    /// Blocks the scan tip may advance by per second, the scans trail the tip rather than outpace
+    /// a slow consumer. Unlimited when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_blocks_per_second: Option<u64>,
+    /// Unix time in milliseconds the scan starts at, cleared once reached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activate_at: Option<u64>,
+    /// Events left to log before the registration completes and is deleted, counted down as they
+    /// are logged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete_after: Option<u64>,
+    /// Last block scanned, the registration completes past it and stays listed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_block: Option<u64>,
+    /// Unix time in milliseconds of the `register` call, unset for registrations older than it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registered_at: Option<u64>,
+    /// `x-request-id` of the `register` call, carried by the registration's events and logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Unix time in milliseconds the registration completed at, nothing is scanned from then on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<u64>,
+    /// Seq the events logged without delivery while the event buffers were full start from,
+    /// they are delivered from the event log before any newer ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spilled_from: Option<u64>,
+    /// Further clients registering the same key, they share the scan and get its events in their
+    /// own sinks from when they subscribed. `delete` by a client ends only its subscription
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscribers: Vec<Subscriber>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Subscriber {
+    pub client: Option<String>,
+    pub sink: SinkConfig,
+}
+
+impl Registration {
+    /// The registering client's sink first, then the subscribers'
+    pub fn sinks(&self) -> Vec<SinkConfig> {
+        std::iter::once(self.sink.clone())
+            .chain(self.subscribers.iter().map(|s| s.sink.clone()))
+            .collect()
+    }
+
+    /// Whether the client registered the key or subscribed to it
+    pub fn has_client(&self, client: Option<&str>) -> bool {
+        self.client.as_deref() == client
+            || self
+                .subscribers
+                .iter()
+                .any(|s| s.client.as_deref() == client)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct LagThreshold {
+    /// Blocks between the scan tip and the indexer tip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_blocks: Option<u64>,
+    /// Seconds since the block of the scan tip was mined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_secs: Option<u64>,
+    /// Taken while the lag exceeds the threshold, besides the alert
+    #[serde(default)]
+    pub action: LagAction,
+}
+
+/// What a registration lagging beyond its threshold does until it is back under it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LagAction {
+    /// Only the alert, the events keep coming late
+    #[default]
+    Alert,
+    /// The scan stops, no stale events are delivered. It goes on once the lag is under the
+    /// threshold again, after `set_start` skipped ahead or the threshold was raised
+    Pause,
+    /// The events have their block summary instead of the cells, lighter to deliver while catching up
+    Summary,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Ok,
+    Degraded,
+    /// Lagging with the `pause` action, not scanning
+    Paused,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ScanPanic {
+    /// Of the last panic
+    pub message: String,
+    pub backtrace: String,
+    /// Panics since the emitter started
+    pub panics: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ScanFailure {
+    /// Of the last attempt
+    pub error: String,
+    /// Consecutive failed attempts
+    pub failures: u32,
+    /// The block the node didn't serve, when it is what the last attempt failed on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unavailable_block: Option<u64>,
+}
 
 pub(crate) struct GlobalState {
-    pub state: Arc<dashmap::DashMap<RpcSearchKey, ScanTip>>,
-    path: PathBuf,
+    pub state: Arc<dashmap::DashMap<RpcSearchKey, Registration>>,
+    storage: Arc<dyn Storage>,
 }
 
 impl Drop for GlobalState {
     fn drop(&mut self) {
-        self.dump_to_dir(self.path.clone())
+        self.dump()
     }
 }
 
@@ -25,87 +173,25 @@ impl GlobalState {
 
         loop {
             interval.tick().await;
-            self.dump_to_dir(self.path.clone());
+            self.dump();
         }
     }
 
-    pub fn spawn_cells(
-        &self,
-        client: RpcClient,
-    ) -> dashmap::DashMap<RpcSearchKey, tokio::task::JoinHandle<()>> {
-        let res = dashmap::DashMap::with_capacity(self.state.len());
-        if !self.state.is_empty() {
-            for kv in self.state.iter() {
-                let mut cell_process = CellProcess {
-                    key: kv.key().clone(),
-                    scan_tip: kv.value().clone(),
-                    client: client.clone(),
-                };
-
-                let handle = tokio::spawn(async move {
-                    cell_process.run().await;
-                });
-                res.insert(kv.key().clone(), handle);
-            }
-        }
-        res
-    }
+    pub fn load(storage: Arc<dyn Storage>) -> std::io::Result<Self> {
+        let cells = storage.load_registrations()?;
 
-    pub fn load_from_dir(path: PathBuf) -> Self {
-        let db_path = path.join("scan_state");
-
-        let state = match File::open(&db_path) {
-            Ok(f) => {
-                let cells: Vec<(RpcSearchKey, ScanTip)> =
-                    serde_json::from_reader(f).unwrap_or_default();
-
-                Arc::new(cells.into_iter().collect())
-            }
-            Err(e) => {
-                log::warn!(
-                    "Failed to open state db, file: {:?}, error: {:?}",
-                    db_path,
-                    e
-                );
-                Default::default()
-            }
-        };
-
-        GlobalState { state, path }
+        Ok(GlobalState {
+            state: Arc::new(cells.into_iter().collect()),
+            storage,
+        })
     }
 
-    fn dump_to_dir<P: AsRef<Path>>(&self, path: P) {
-        // create dir
-        create_dir_all(&path).unwrap();
-        // dump file to a temporary sub-directory
-        let tmp_dir = path.as_ref().join("tmp");
-        create_dir_all(&tmp_dir).unwrap();
-        let tmp_scan_state = tmp_dir.join("scan_state");
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(&tmp_scan_state)
-            .unwrap();
+    fn dump(&self) {
         let cell_state_iter = self
             .state
             .iter()
             .map(|kv| (kv.key().clone(), kv.value().clone()))
             .collect::<Vec<_>>();
-        // empty file and dump the json string to it
-        file.set_len(0)
-            .and_then(|_| serde_json::to_string(&cell_state_iter).map_err(Into::into))
-            .and_then(|json_string| file.write_all(json_string.as_bytes()))
-            .and_then(|_| file.sync_all())
-            .unwrap();
-        move_file(tmp_scan_state, path.as_ref().join("scan_state")).unwrap();
-    }
-}
-
-fn move_file<P: AsRef<Path>>(src: P, dst: P) -> Result<(), std::io::Error> {
-    if rename(&src, &dst).is_err() {
-        copy(&src, &dst)?;
-        remove_file(&src)?;
+        self.storage.save_registrations(&cell_state_iter).unwrap();
     }
-    Ok(())
 }
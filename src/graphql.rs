@@ -0,0 +1,577 @@
+//! Read-only GraphQL queries over the registrations, their live cells, stats and logged events,
+//! answered by calling the rpc methods. The query language is parsed as far as dashboards use it:
+//! fields, aliases, arguments and variables, no fragments, directives, mutations or subscriptions
+
+use jsonrpsee::core::server::rpc_module::Methods;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{rpc_http::local_call as call, rpc_server::RpcSearchKey};
+
+/// Body of `POST /graphql`
+#[derive(Deserialize)]
+pub(crate) struct Request {
+    query: String,
+    #[serde(default)]
+    variables: Map<String, Value>,
+}
+
+struct Field {
+    alias: Option<String>,
+    name: String,
+    args: Map<String, Value>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    fn arg_u64(&self, name: &str) -> Result<Option<u64>, String> {
+        match self.args.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| format!("{} of {} must be a non-negative Int", name, self.name)),
+        }
+    }
+
+    fn arg_str(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.args.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| format!("{} of {} must be a String", name, self.name)),
+        }
+    }
+}
+
+/// Answers with `data`, or `errors` when the query is invalid or a field failed
+pub(crate) async fn execute(methods: &Methods, body: &[u8]) -> Value {
+    let request = match serde_json::from_slice::<Request>(body) {
+        Ok(request) => request,
+        Err(e) => return failure(format!("invalid request: {}", e)),
+    };
+    let fields = match Parser::new(&request.query, &request.variables).document() {
+        Ok(fields) => fields,
+        Err(e) => return failure(e),
+    };
+    let mut data = Map::new();
+    for field in &fields {
+        let value = match field.name.as_str() {
+            "__typename" => Ok(json!("Query")),
+            "registrations" => registrations(methods, field).await,
+            "registration" => registration(methods, field).await,
+            name => Err(format!("Query has no field {}", name)),
+        };
+        match value {
+            Ok(value) => data.insert(field.key().to_string(), value),
+            Err(e) => return failure(e),
+        };
+    }
+    json!({ "data": data })
+}
+
+fn failure(message: String) -> Value {
+    json!({ "data": null, "errors": [{ "message": message }] })
+}
+
+/// The registrations of `info`, or of `info_for` the ids, with their `id` and `searchKey`, ordered by id
+async fn entries(
+    methods: &Methods,
+    ids: Option<&[&str]>,
+) -> Result<Vec<(RpcSearchKey, Value)>, String> {
+    let info = match ids {
+        Some(ids) => call(methods, "info_for", json!([ids])).await?,
+        None => call(methods, "info", json!([])).await?,
+    };
+    let mut entries = info
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let key = serde_json::from_value::<RpcSearchKey>(entry[0].clone()).ok()?;
+            let mut registration = entry[1].clone();
+            registration["id"] = json!(key.id());
+            registration["search_key"] = entry[0].clone();
+            Some((key, registration))
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|(_, a), (_, b)| a["id"].as_str().cmp(&b["id"].as_str()));
+    Ok(entries)
+}
+
+/// `registrations(status, client, label, first, after)`, `after` is the id the previous page ended with
+async fn registrations(methods: &Methods, field: &Field) -> Result<Value, String> {
+    let status = field.arg_str("status")?;
+    let client = field.arg_str("client")?;
+    let label = field.arg_str("label")?;
+    let after = field.arg_str("after")?;
+    let first = field.arg_u64("first")?.unwrap_or(u64::MAX) as usize;
+    let mut page = Vec::new();
+    for (key, registration) in entries(methods, None)
+        .await?
+        .into_iter()
+        .filter(|(_, registration)| {
+            status.is_none_or(|status| registration["status"] == status)
+                && client.is_none_or(|client| registration["client"] == client)
+                && label.is_none_or(|label| registration["label"] == label)
+                && after.is_none_or(|after| registration["id"].as_str() > Some(after))
+        })
+        .take(first)
+    {
+        page.push(resolve_registration(methods, &key, registration, &field.selection).await?);
+    }
+    Ok(Value::Array(page))
+}
+
+/// `registration(id)`, null if no key has the id
+async fn registration(methods: &Methods, field: &Field) -> Result<Value, String> {
+    let id = field
+        .arg_str("id")?
+        .ok_or("registration needs its id")?
+        .to_string();
+    match entries(methods, Some(&[&id])).await?.into_iter().next() {
+        Some((key, registration)) => {
+            resolve_registration(methods, &key, registration, &field.selection).await
+        }
+        None => Ok(Value::Null),
+    }
+}
+
+async fn resolve_registration(
+    methods: &Methods,
+    key: &RpcSearchKey,
+    registration: Value,
+    selection: &[Field],
+) -> Result<Value, String> {
+    if selection.is_empty() {
+        return Err("Registration needs a selection of its fields".to_string());
+    }
+    let mut out = Map::new();
+    for field in selection {
+        let value = match field.name.as_str() {
+            "__typename" => json!("Registration"),
+            "liveCells" | "live_cells" => {
+                let params = json!([
+                    key,
+                    field.arg_u64("atBlock")?.map(|at| format!("{:#x}", at)),
+                    field
+                        .arg_u64("after")?
+                        .map(|cursor| format!("{:#x}", cursor)),
+                    field.arg_u64("first")?.map(|limit| format!("{:#x}", limit)),
+                ]);
+                project(
+                    &call(methods, "get_live_cells", params).await?,
+                    &field.selection,
+                )
+            }
+            "stats" => project(
+                &call(methods, "stats", json!([key])).await?,
+                &field.selection,
+            ),
+            "summary" => project(
+                &call(methods, "get_summary", json!([key])).await?,
+                &field.selection,
+            ),
+            "events" => {
+                let from = field.arg_u64("fromBlock")?.unwrap_or(0);
+                let to = field.arg_u64("toBlock")?.unwrap_or(u64::MAX);
+                let params = json!([
+                    key,
+                    format!("{:#x}", from),
+                    format!("{:#x}", to),
+                    field.arg_u64("after")?.map(|seq| format!("{:#x}", seq)),
+                    field.arg_u64("first")?.map(|limit| format!("{:#x}", limit)),
+                ]);
+                project(
+                    &call(methods, "query_events", params).await?,
+                    &field.selection,
+                )
+            }
+            name => project(lookup(&registration, name), &field.selection),
+        };
+        out.insert(field.key().to_string(), value);
+    }
+    Ok(Value::Object(out))
+}
+
+/// A field by its name or, for camelCase names, by the snake_case one of the rpc
+fn lookup<'a>(value: &'a Value, name: &str) -> &'a Value {
+    match value.get(name) {
+        Some(value) => value,
+        None => &value[snake_case(name)],
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            out.push('_');
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Keeps the selected fields of a value, lists field by field of each item, a value without a
+/// selection is returned whole
+fn project(value: &Value, selection: &[Field]) -> Value {
+    if selection.is_empty() {
+        return value.clone();
+    }
+    match value {
+        Value::Array(items) => {
+            Value::Array(items.iter().map(|item| project(item, selection)).collect())
+        }
+        Value::Object(_) => Value::Object(
+            selection
+                .iter()
+                .map(|field| {
+                    let value = match field.name.as_str() {
+                        "__typename" => Value::Null,
+                        name => project(lookup(value, name), &field.selection),
+                    };
+                    (field.key().to_string(), value)
+                })
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Nesting of lists, objects and selections a query may have, deeper ones are refused before
+/// the recursion overflows the stack
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    variables: &'a Map<String, Value>,
+    /// Defaults of the declared variables, the given ones win
+    defaults: Map<String, Value>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(query: &'a str, variables: &'a Map<String, Value>) -> Self {
+        Parser {
+            chars: query.chars().peekable(),
+            variables,
+            defaults: Map::new(),
+        }
+    }
+
+    /// A single query, either `{ ... }` or `query Name($var: Type = default) { ... }`
+    fn document(mut self) -> Result<Vec<Field>, String> {
+        self.skip();
+        if self.peek() != Some('{') {
+            match self.name()?.as_str() {
+                "query" => {}
+                "mutation" | "subscription" => return Err("only queries are supported".to_string()),
+                other => return Err(format!("unexpected {}", other)),
+            }
+            self.skip();
+            if self.peek().is_some_and(is_name_start) {
+                self.name()?;
+            }
+            self.skip();
+            if self.peek() == Some('(') {
+                self.variable_definitions()?;
+            }
+        }
+        let fields = self.selection_set(0)?;
+        self.skip();
+        match self.peek() {
+            None => Ok(fields),
+            Some(c) => Err(format!(
+                "unexpected {:?} after the query, one operation only",
+                c
+            )),
+        }
+    }
+
+    fn variable_definitions(&mut self) -> Result<(), String> {
+        self.expect('(')?;
+        loop {
+            self.skip();
+            if self.eat(')') {
+                return Ok(());
+            }
+            self.expect('$')?;
+            let name = self.name()?;
+            self.skip();
+            self.expect(':')?;
+            self.skip();
+            self.type_ref(0)?;
+            self.skip();
+            if self.eat('=') {
+                self.skip();
+                let default = self.value(0)?;
+                self.defaults.insert(name, default);
+            }
+        }
+    }
+
+    /// Types aren't checked, the rpc methods check the values
+    fn type_ref(&mut self, depth: usize) -> Result<(), String> {
+        if self.eat('[') {
+            self.skip();
+            self.type_ref(deeper(depth)?)?;
+            self.skip();
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.skip();
+        self.eat('!');
+        Ok(())
+    }
+
+    fn selection_set(&mut self, depth: usize) -> Result<Vec<Field>, String> {
+        self.skip();
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        loop {
+            self.skip();
+            match self.peek() {
+                Some('}') => {
+                    self.chars.next();
+                    return Ok(fields);
+                }
+                Some('.') => return Err("fragments are not supported".to_string()),
+                Some('@') => return Err("directives are not supported".to_string()),
+                _ => fields.push(self.field(depth)?),
+            }
+        }
+    }
+
+    fn field(&mut self, depth: usize) -> Result<Field, String> {
+        let mut name = self.name()?;
+        let mut alias = None;
+        self.skip();
+        if self.eat(':') {
+            self.skip();
+            alias = Some(std::mem::replace(&mut name, self.name()?));
+            self.skip();
+        }
+        let mut args = Map::new();
+        if self.eat('(') {
+            loop {
+                self.skip();
+                if self.eat(')') {
+                    break;
+                }
+                let arg = self.name()?;
+                self.skip();
+                self.expect(':')?;
+                self.skip();
+                let value = self.value(depth)?;
+                args.insert(arg, value);
+            }
+            self.skip();
+        }
+        let selection = match self.peek() {
+            Some('{') => self.selection_set(deeper(depth)?)?,
+            Some('@') => return Err("directives are not supported".to_string()),
+            _ => Vec::new(),
+        };
+        Ok(Field {
+            alias,
+            name,
+            args,
+            selection,
+        })
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        match self.peek() {
+            Some('$') => {
+                self.chars.next();
+                let name = self.name()?;
+                Ok(self
+                    .variables
+                    .get(&name)
+                    .or_else(|| self.defaults.get(&name))
+                    .cloned()
+                    .unwrap_or(Value::Null))
+            }
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.chars.next();
+                let mut items = Vec::new();
+                loop {
+                    self.skip();
+                    if self.eat(']') {
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value(deeper(depth)?)?);
+                }
+            }
+            Some('{') => {
+                self.chars.next();
+                let mut object = Map::new();
+                loop {
+                    self.skip();
+                    if self.eat('}') {
+                        return Ok(Value::Object(object));
+                    }
+                    let name = self.name()?;
+                    self.skip();
+                    self.expect(':')?;
+                    self.skip();
+                    let value = self.value(deeper(depth)?)?;
+                    object.insert(name, value);
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) if is_name_start(c) => Ok(match self.name()?.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // enum values are passed as their names
+                name => Value::String(name.to_string()),
+            }),
+            other => Err(format!("expected a value, found {:?}", other)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut text = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            text.push(c);
+            self.chars.next();
+        }
+        serde_json::from_str::<serde_json::Number>(&text)
+            .map(Value::Number)
+            .map_err(|_| format!("invalid number {}", text))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('u') => {
+                        let hex = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape \\u{}", hex))?;
+                        out.push(c);
+                    }
+                    Some(c) => out.push(c),
+                    None => return Err("unterminated string".to_string()),
+                },
+                Some(c) => out.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let mut name = String::new();
+        if let Some(c) = self.peek().filter(|c| is_name_start(*c)) {
+            name.push(c);
+            self.chars.next();
+        }
+        if name.is_empty() {
+            return Err(format!("expected a name, found {:?}", self.peek()));
+        }
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            name.push(c);
+            self.chars.next();
+        }
+        Ok(name)
+    }
+
+    /// Whitespace, commas and comments are insignificant
+    fn skip(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => while self.chars.next().is_some_and(|c| c != '\n') {},
+                c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {
+                    self.chars.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?}, found {:?}", c, self.peek()))
+        }
+    }
+}
+
+fn deeper(depth: usize) -> Result<usize, String> {
+    match depth < MAX_DEPTH {
+        true => Ok(depth + 1),
+        false => Err(format!("the query nests deeper than {} levels", MAX_DEPTH)),
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<Vec<Field>, String> {
+        Parser::new(query, &Map::new()).document()
+    }
+
+    #[test]
+    fn deeply_nested_queries_are_refused() {
+        let deep = 100_000;
+        let list = format!(
+            "{{ registration(id: {}1{}) {{ id }} }}",
+            "[".repeat(deep),
+            "]".repeat(deep)
+        );
+        let selection = format!("{}{}", "{ a ".repeat(deep), "}".repeat(deep));
+        let variable = format!(
+            "query Q($v: {}Int{}) {{ registrations {{ id }} }}",
+            "[".repeat(deep),
+            "]".repeat(deep)
+        );
+        for query in [list, selection, variable] {
+            assert!(parse(&query).is_err_and(|e| e.contains("nests deeper")));
+        }
+
+        let nested = format!(
+            "{{ registration(id: {}1{}) {{ id }} }}",
+            "[".repeat(10),
+            "]".repeat(10)
+        );
+        assert_eq!(parse(&nested).unwrap()[0].name, "registration");
+    }
+}
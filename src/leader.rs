@@ -0,0 +1,48 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::Path,
+    time::Duration,
+};
+
+use crate::service;
+
+/// How often a standby tries to take the lock
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Held by the leader while it runs, the lock is released when the process exits however it ends
+pub struct LeaderLock {
+    _file: File,
+}
+
+/// Waits until no other instance holds the lock file, instances sharing a storage path must use
+/// the same one. The lock is a `flock`, it needs a filesystem which supports them across the hosts
+pub async fn acquire(path: &Path) -> io::Result<LeaderLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    let mut standby = false;
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(e);
+        }
+        if !standby {
+            standby = true;
+            log::info!("standby, another instance holds the leader lock {:?}", path);
+            service::notify("STATUS=standby");
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    if standby {
+        log::info!("took over the leader lock {:?}", path);
+    }
+    service::notify("STATUS=leader");
+    Ok(LeaderLock { _file: file })
+}
@@ -0,0 +1,533 @@
+use dashmap::DashMap;
+use futures::FutureExt;
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, Mutex, RwLock},
+    task::JoinHandle,
+};
+
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex, Once,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cell_process::{CellProcess, TaskStats, SCAN_BACKOFF},
+    config::{BackfillSettings, BlockFallbackSettings, Config},
+    event_buffer::EventBuffer,
+    global_state::{Registration, ScanPanic, Subscriber},
+    metrics,
+    network::GenesisPin,
+    rpc_client::{IndexerTip, RpcClient},
+    rpc_server::RpcSearchKey,
+    scan_pool::{JobHandle, ScanPool},
+    scheduler::ScanScheduler,
+    sink::{DeliveryCursor, SinkConfig, Sinks},
+    tip_poller::IndexerTipWatch,
+    ScanTip,
+};
+
+thread_local! {
+    /// Backtrace of the last panic on this thread, taken by the task that caught it
+    static BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Keeps the backtrace of every panic for the task catching it, the default output stays
+fn capture_backtraces() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture().to_string();
+            BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            previous(info);
+        }));
+    });
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panic without a message".to_string())
+}
+
+/// Owns the registered keys and their scan tasks, registering and deleting a key moves both
+/// under one lock so they never disagree. Entries of `state` may be changed in place,
+/// keys are only added and removed here
+pub(crate) struct Lifecycle {
+    state: Arc<DashMap<RpcSearchKey, Registration>>,
+    tasks: Arc<Mutex<HashMap<RpcSearchKey, Scan>>>,
+    client: RpcClient,
+    indexer_tip: IndexerTipWatch,
+    sinks: Sinks,
+    skip_unavailable: bool,
+    backfill: BackfillSettings,
+    block_fallback: BlockFallbackSettings,
+    /// Shared by scans moving their tip, exclusive while the tips are snapshotted
+    tips: Arc<RwLock<()>>,
+    stats: TaskTable,
+    /// Runs the scans instead of a task per key when set
+    pool: Option<ScanPool>,
+    scheduler: Option<ScanScheduler>,
+    deliveries: Option<ScanScheduler>,
+    buffer: Option<EventBuffer>,
+    /// Set by `admin_maintenance`, no scan runs meanwhile
+    maintenance: Arc<AtomicBool>,
+    genesis: Arc<GenesisPin>,
+    /// Takes the keys of completed registrations, which are deleted
+    completed: mpsc::UnboundedSender<RpcSearchKey>,
+}
+
+/// A registration's scan, on a task of its own or on the pool
+enum Scan {
+    Task(JoinHandle<()>),
+    Pooled(JobHandle),
+}
+
+type TaskTable = Arc<DashMap<RpcSearchKey, Arc<StdMutex<TaskStats>>>>;
+
+/// Dumps the state of the scan tasks for `/debug/tasks` and samples the resource metrics
+#[derive(Clone)]
+pub(crate) struct DebugTasks {
+    state: Arc<DashMap<RpcSearchKey, Registration>>,
+    stats: TaskTable,
+    sinks: Sinks,
+}
+
+#[derive(Serialize)]
+struct TaskDump {
+    key: RpcSearchKey,
+    tip: IndexerTip,
+    #[serde(flatten)]
+    stats: TaskStats,
+}
+
+impl DebugTasks {
+    pub fn dump(&self) -> serde_json::Value {
+        let tasks = self
+            .stats
+            .iter()
+            .filter_map(|kv| {
+                Some(TaskDump {
+                    key: kv.key().clone(),
+                    tip: self.state.get(kv.key())?.scan_tip.load(),
+                    stats: kv.value().lock().unwrap().clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "tasks": tasks,
+            "unix_socket_queues": self.sinks.unix_socket.as_ref().map(|sink| sink.queued()),
+        })
+    }
+
+    /// The task of a single scan
+    #[cfg(test)]
+    pub fn of(process: &CellProcess) -> Self {
+        let stats = TaskTable::default();
+        stats.insert(process.key.clone(), process.stats.clone());
+        DebugTasks {
+            state: process.state.clone(),
+            stats,
+            sinks: process.sinks.clone(),
+        }
+    }
+
+    /// A registration whose running scan neither started nor had a call to the node end within
+    /// `limit`, a wedged one. The clock is paused while the scan delivers
+    pub fn stalled(&self, limit: Duration) -> Option<RpcSearchKey> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.stats
+            .iter()
+            .find(|kv| {
+                let stats = kv.value().lock().unwrap();
+                !stats.delivering
+                    && stats.scan_started.is_some_and(|started| {
+                        let active = started.max(stats.last_call.load(Ordering::Acquire));
+                        now.saturating_sub(active) > limit.as_millis() as u64
+                    })
+            })
+            .map(|kv| kv.key().clone())
+    }
+
+    /// Sets the gauges of the registrations and buffers, right before they are served
+    pub fn sample_metrics(&self) {
+        metrics::set("emitter_registered_keys", &[], self.state.len() as f64);
+        let pending = self
+            .stats
+            .iter()
+            .map(|kv| kv.value().lock().unwrap().pending_events)
+            .sum::<usize>();
+        metrics::set("emitter_pending_events", &[], pending as f64);
+        if let Some(sink) = &self.sinks.unix_socket {
+            let queued = sink.queued().into_iter().max().unwrap_or_default();
+            metrics::set("emitter_unix_socket_queued_frames", &[], queued as f64);
+            metrics::set(
+                "emitter_buffered_bytes",
+                &[("buffer", "unix_socket")],
+                sink.buffered_bytes(queued) as f64,
+            );
+        }
+    }
+}
+
+/// The indexer tip and the scan tip of every registration at one moment
+#[derive(Serialize)]
+pub struct TipSnapshot {
+    pub indexer_tip: IndexerTip,
+    pub scan_tips: Vec<(RpcSearchKey, IndexerTip)>,
+}
+
+impl Lifecycle {
+    /// Starts scanning every key of `state`, with `skip_unavailable_blocks` scans move past blocks
+    /// the node doesn't serve. With `scan_workers` the scans share that many tasks, with
+    /// `max_concurrent_scans` no more scans run at once and the waiting ones start by priority, and
+    /// likewise the deliveries with `max_concurrent_deliveries`. With `max_buffered_events` the
+    /// events beyond are delivered from the event log. Scans run only while the node is
+    /// on the chain of `genesis`
+    pub fn new(
+        state: Arc<DashMap<RpcSearchKey, Registration>>,
+        client: RpcClient,
+        indexer_tip: IndexerTipWatch,
+        sinks: Sinks,
+        genesis: Arc<GenesisPin>,
+        config: &Config,
+    ) -> Self {
+        capture_backtraces();
+        let (completed, mut completions) = mpsc::unbounded_channel();
+        let mut lifecycle = Lifecycle {
+            state,
+            tasks: Default::default(),
+            client,
+            indexer_tip,
+            sinks,
+            skip_unavailable: config.skip_unavailable_blocks,
+            backfill: config.backfill,
+            block_fallback: config.block_fallback,
+            tips: Default::default(),
+            stats: Default::default(),
+            pool: config.scan_workers.map(ScanPool::new),
+            scheduler: config.max_concurrent_scans.map(ScanScheduler::new),
+            deliveries: config.max_concurrent_deliveries.map(ScanScheduler::new),
+            buffer: config.max_buffered_events.map(EventBuffer::new),
+            maintenance: Default::default(),
+            genesis,
+            completed,
+        };
+        let tasks = lifecycle
+            .state
+            .iter()
+            .map(|kv| {
+                let task = lifecycle.spawn(kv.key(), kv.value().scan_tip.clone());
+                (kv.key().clone(), task)
+            })
+            .collect();
+        lifecycle.tasks = Arc::new(Mutex::new(tasks));
+
+        // a scan can't stop itself, its registration is deleted here once it completed
+        let (tasks, state, stats) = (
+            lifecycle.tasks.clone(),
+            lifecycle.state.clone(),
+            lifecycle.stats.clone(),
+        );
+        tokio::spawn(async move {
+            while let Some(key) = completions.recv().await {
+                if remove_scan(&mut *tasks.lock().await, &stats, &state, &key).await {
+                    log::info!("Completed registration deleted, key: {:?}", key);
+                }
+            }
+        });
+        lifecycle
+    }
+
+    pub fn state(&self) -> &Arc<DashMap<RpcSearchKey, Registration>> {
+        &self.state
+    }
+
+    pub fn stats(&self, key: &RpcSearchKey) -> Option<TaskStats> {
+        Some(self.stats.get(key)?.lock().unwrap().clone())
+    }
+
+    pub fn debug_tasks(&self) -> DebugTasks {
+        DebugTasks {
+            state: self.state.clone(),
+            stats: self.stats.clone(),
+            sinks: self.sinks.clone(),
+        }
+    }
+
+    pub fn genesis(&self) -> &GenesisPin {
+        &self.genesis
+    }
+
+    pub fn skips_unavailable(&self) -> bool {
+        self.skip_unavailable
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Acquire)
+    }
+
+    /// Pauses every scan or lets them go on, a running scan finishes first
+    pub fn set_maintenance(&self, on: bool) {
+        self.maintenance.store(on, Ordering::Release);
+    }
+
+    /// No scan tip moves and no key is added or removed while the snapshot is taken,
+    /// which includes waiting for the indexer tip
+    pub async fn tips(&self) -> io::Result<TipSnapshot> {
+        let _keys = self.tasks.lock().await;
+        let _tips = self.tips.write().await;
+        let indexer_tip = self.client.get_indexer_tip().await?;
+        Ok(TipSnapshot {
+            indexer_tip,
+            scan_tips: self
+                .state
+                .iter()
+                .map(|kv| (kv.key().clone(), kv.value().scan_tip.load()))
+                .collect(),
+        })
+    }
+
+    /// Adds the registration and starts scanning it, false if the key is registered already
+    pub async fn insert(&self, key: RpcSearchKey, registration: Registration) -> bool {
+        let mut tasks = self.tasks.lock().await;
+        if self.state.contains_key(&key) {
+            return false;
+        }
+        // the task looks its registration up, it must not start before the insert
+        let scan_tip = registration.scan_tip.clone();
+        self.state.insert(key.clone(), registration);
+        tasks.insert(key.clone(), self.spawn(&key, scan_tip));
+        true
+    }
+
+    /// Adds a client's sink to the scan of a registered key, `None` if the key isn't registered
+    /// and false if the client registered or subscribed to it already
+    pub async fn subscribe(&self, key: &RpcSearchKey, subscriber: Subscriber) -> Option<bool> {
+        let _tasks = self.tasks.lock().await;
+        let mut registration = self.state.get_mut(key)?;
+        if registration.has_client(subscriber.client.as_deref()) {
+            return Some(false);
+        }
+        registration.subscribers.push(subscriber);
+        Some(true)
+    }
+
+    /// Ends the scan of `key` and adds its client and sink and its subscribers to the scan of `into`,
+    /// they get its events from then on. Clients subscribed to both keep one subscription. False if
+    /// either key isn't registered
+    pub async fn merge(&self, key: &RpcSearchKey, into: &RpcSearchKey) -> bool {
+        let mut tasks = self.tasks.lock().await;
+        if key == into || !self.state.contains_key(into) {
+            return false;
+        }
+        let registration = match self.state.get(key) {
+            Some(registration) => registration.clone(),
+            None => return false,
+        };
+        self.remove_scan(&mut tasks, key).await;
+        let subscribers = std::iter::once(Subscriber {
+            client: registration.client,
+            sink: registration.sink,
+        })
+        .chain(registration.subscribers);
+        if let Some(mut target) = self.state.get_mut(into) {
+            for subscriber in subscribers {
+                if !target.has_client(subscriber.client.as_deref()) {
+                    target.subscribers.push(subscriber);
+                }
+            }
+        }
+        true
+    }
+
+    /// Ends the client's registration or subscription of the key, the scan stops with the last one.
+    /// A subscriber takes the place of a registering client which leaves. False if the client has neither
+    pub async fn unsubscribe(&self, key: &RpcSearchKey, client: Option<&str>) -> bool {
+        let mut tasks = self.tasks.lock().await;
+        {
+            let mut registration = match self.state.get_mut(key) {
+                Some(registration) => registration,
+                None => return false,
+            };
+            if let Some(i) = registration
+                .subscribers
+                .iter()
+                .position(|s| s.client.as_deref() == client)
+            {
+                registration.subscribers.remove(i);
+                return true;
+            }
+            if registration.client.as_deref() != client {
+                return false;
+            }
+            if !registration.subscribers.is_empty() {
+                let next = registration.subscribers.remove(0);
+                registration.client = next.client;
+                registration.sink = next.sink;
+                registration.sink_profile = None;
+                return true;
+            }
+        }
+        self.remove_scan(&mut tasks, key).await
+    }
+
+    /// Stops scanning the key and drops its registration, false if it isn't registered.
+    /// The task has ended when this returns, it can't store a scan tip of the deleted key anymore
+    pub async fn remove(&self, key: &RpcSearchKey) -> bool {
+        let mut tasks = self.tasks.lock().await;
+        self.remove_scan(&mut tasks, key).await
+    }
+
+    /// Scans the key again from `tip`, its sinks and the rest of its registration stay. The delivery
+    /// cursors are moved before the tip so that blocks delivered before are delivered again, except
+    /// for the genesis. False if the key isn't registered
+    pub async fn restart(&self, key: &RpcSearchKey, tip: IndexerTip) -> io::Result<bool> {
+        let mut tasks = self.tasks.lock().await;
+        let sinks = match self.state.get(key) {
+            Some(registration) => registration.sinks(),
+            None => return Ok(false),
+        };
+        // the running scan would move the tip it loaded
+        stop_scan(&mut tasks, key).await;
+        let storage = &self.sinks.storage;
+        let block_number = tip.block_number.value();
+        let before = DeliveryCursor {
+            block_number: block_number.saturating_sub(1),
+            index: u32::MAX,
+        };
+        for sink in sinks.iter().map(SinkConfig::id) {
+            match storage.load_delivery_cursor(key, &sink)? {
+                Some(delivered) if block_number > 0 && delivered > before => {
+                    storage.store_delivery_cursor(key, &sink, before)?
+                }
+                _ => {}
+            }
+        }
+        storage.store_scan_tip(key, &tip)?;
+        let scan_tip = ScanTip::new(tip);
+        if let Some(mut registration) = self.state.get_mut(key) {
+            registration.scan_tip = scan_tip.clone();
+        }
+        tasks.insert(key.clone(), self.spawn(key, scan_tip));
+        Ok(true)
+    }
+
+    async fn remove_scan(
+        &self,
+        tasks: &mut HashMap<RpcSearchKey, Scan>,
+        key: &RpcSearchKey,
+    ) -> bool {
+        remove_scan(tasks, &self.stats, &self.state, key).await
+    }
+
+    fn spawn(&self, key: &RpcSearchKey, scan_tip: ScanTip) -> Scan {
+        let stats = self.stats.entry(key.clone()).or_default().clone();
+        let last_call = stats.lock().unwrap().last_call.clone();
+        let mut cell_process = CellProcess {
+            key: key.clone(),
+            scan_tip,
+            client: self.client.clone().with_progress(last_call),
+            indexer_tip: self.indexer_tip.clone(),
+            sinks: self.sinks.clone(),
+            state: self.state.clone(),
+            skip_unavailable: self.skip_unavailable,
+            backfill: self.backfill,
+            block_fallback: self.block_fallback,
+            tips: self.tips.clone(),
+            stats,
+            scheduler: self.scheduler.clone(),
+            deliveries: self.deliveries.clone(),
+            buffer: self.buffer.clone(),
+            paused: self.maintenance.clone(),
+            genesis: self.genesis.clone(),
+            completed: self.completed.clone(),
+        };
+        if let Some(pool) = &self.pool {
+            return Scan::Pooled(pool.add(cell_process));
+        }
+        Scan::Task(metrics::spawn("scan", async move {
+            let mut panics = 0;
+            loop {
+                let payload = match AssertUnwindSafe(cell_process.run()).catch_unwind().await {
+                    Ok(()) => return,
+                    Err(payload) => payload,
+                };
+                let delay = scan_panicked(&cell_process, payload, &mut panics);
+                tokio::time::sleep(delay).await;
+            }
+        }))
+    }
+}
+
+/// Stops the scan and forgets the registration, false if it wasn't registered
+async fn remove_scan(
+    tasks: &mut HashMap<RpcSearchKey, Scan>,
+    stats: &TaskTable,
+    state: &DashMap<RpcSearchKey, Registration>,
+    key: &RpcSearchKey,
+) -> bool {
+    stop_scan(tasks, key).await;
+    stats.remove(key);
+    state.remove(key).is_some()
+}
+
+/// Ends the key's scan, the task has ended when this returns
+async fn stop_scan(tasks: &mut HashMap<RpcSearchKey, Scan>, key: &RpcSearchKey) {
+    match tasks.remove(key) {
+        Some(Scan::Task(task)) => {
+            task.abort();
+            if let Err(e) = task.await {
+                if e.is_panic() {
+                    log::warn!("Stopped scan task of key {:?} had panicked", key);
+                }
+            }
+        }
+        Some(Scan::Pooled(job)) => ScanPool::remove(&job).await,
+        None => {}
+    }
+}
+
+/// A panicking scan is recorded in its registration and restarted after the returned delay,
+/// it must not stop silently. `panics` counts the panics of the scan
+pub(crate) fn scan_panicked(
+    cell_process: &CellProcess,
+    payload: Box<dyn Any + Send>,
+    panics: &mut u32,
+) -> Duration {
+    let delay = SCAN_BACKOFF.delay(*panics);
+    *panics += 1;
+    cell_process.stats.lock().unwrap().scan_started = None;
+    let scan_panic = ScanPanic {
+        message: panic_message(payload.as_ref()),
+        backtrace: BACKTRACE
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_default(),
+        panics: *panics,
+    };
+    log::error!(
+        "Scan task panicked, restart in {:?}, key: {:?}, panic: {}",
+        delay,
+        cell_process.key,
+        scan_panic.message
+    );
+    if let Some(mut registration) = cell_process.state.get_mut(&cell_process.key) {
+        registration.scan_panic = Some(scan_panic);
+    }
+    delay
+}
@@ -0,0 +1,96 @@
+//! Which transactions created and consumed the cells a registration emitted, for `get_cell_history`
+
+use ckb_jsonrpc_types::{BlockNumber, OutPoint, Uint32};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{rpc_server::RpcSearchKey, schema, Submit};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    CreatedBy,
+    ConsumedBy,
+}
+
+/// A transaction creating a cell as its output or consuming it as its input
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct TxRef {
+    #[schemars(with = "schema::Hex")]
+    pub tx_hash: H256,
+    /// Of the output or the input
+    #[schemars(with = "schema::Hex")]
+    pub index: Uint32,
+    #[schemars(with = "schema::Hex")]
+    pub block_number: BlockNumber,
+    #[schemars(with = "schema::Hex")]
+    pub block_hash: H256,
+}
+
+/// A cell emitted by a registration and the transaction of the event
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CellLink {
+    pub out_point: OutPoint,
+    pub kind: LinkKind,
+    pub tx: TxRef,
+}
+
+/// What a registration emitted of a cell, unset until an event of the registration had it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct CellHistory {
+    pub key: RpcSearchKey,
+    pub created_by: Option<TxRef>,
+    pub consumed_by: Option<TxRef>,
+}
+
+/// The links of the cells a fetched event emits, none for events the cells of which aren't known
+pub(crate) fn links(submit: &Submit) -> Vec<CellLink> {
+    let tx = |tx_hash: &H256, index: Uint32| TxRef {
+        tx_hash: tx_hash.clone(),
+        index,
+        block_number: submit.header.inner.number,
+        block_hash: submit.header.hash.clone(),
+    };
+    let mut links = Vec::new();
+    if submit.spent_points.len() == submit.inputs.len() {
+        for (input, spent) in submit.inputs.iter().zip(&submit.spent_points) {
+            links.push(CellLink {
+                out_point: spent.clone(),
+                kind: LinkKind::ConsumedBy,
+                tx: tx(&input.tx_hash, input.index),
+            });
+        }
+    }
+    for output in &submit.output_points {
+        links.push(CellLink {
+            out_point: output.clone(),
+            kind: LinkKind::CreatedBy,
+            tx: tx(&output.tx_hash, output.index),
+        });
+    }
+    links
+}
+
+/// The histories of a cell by registration, from its stored links
+pub(crate) fn histories(links: Vec<(RpcSearchKey, CellLink)>) -> Vec<CellHistory> {
+    let mut histories: Vec<CellHistory> = Vec::new();
+    for (key, link) in links {
+        let history = match histories.iter_mut().position(|h| h.key == key) {
+            Some(i) => &mut histories[i],
+            None => {
+                histories.push(CellHistory {
+                    key,
+                    created_by: None,
+                    consumed_by: None,
+                });
+                histories.last_mut().unwrap()
+            }
+        };
+        match link.kind {
+            LinkKind::CreatedBy => history.created_by = Some(link.tx),
+            LinkKind::ConsumedBy => history.consumed_by = Some(link.tx),
+        }
+    }
+    histories
+}
@@ -0,0 +1,244 @@
+//! The cells a registration emitted as outputs and hasn't emitted as spent yet, for `get_live_cells`
+
+use ckb_jsonrpc_types::{BlockNumber, CellInfo, OutPoint, Uint128, Uint64};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{event_log::LoggedEvent, schema, script_info::ScriptNames, Submit};
+
+/// A live cell of a registration
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct LiveCell {
+    #[schemars(with = "schema::OutPoint")]
+    pub out_point: OutPoint,
+    #[schemars(with = "schema::Hex")]
+    pub capacity: Uint64,
+    /// Of the event creating it
+    #[schemars(with = "schema::Hex")]
+    pub block_number: BlockNumber,
+    /// Of a sUDT or xUDT cell whose data was fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udt: Option<UdtAmount>,
+}
+
+/// The token and amount of a UDT cell
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct UdtAmount {
+    /// Of the type script, naming the token
+    #[schemars(with = "schema::Hex")]
+    pub type_hash: H256,
+    #[schemars(with = "schema::Hex")]
+    pub amount: Uint128,
+}
+
+impl UdtAmount {
+    /// The amount is the first 16 bytes of the data, little endian
+    fn new(cell: &CellInfo, names: &ScriptNames) -> Option<Self> {
+        let type_ = names.cell(&cell.output).type_?;
+        if !matches!(type_.name.as_deref(), Some("sudt" | "xudt")) {
+            return None;
+        }
+        let data = cell.data.as_ref()?.content.as_bytes();
+        Some(UdtAmount {
+            type_hash: type_.hash,
+            amount: u128::from_le_bytes(data.get(..16)?.try_into().ok()?).into(),
+        })
+    }
+}
+
+/// What the live cells of a registration hold, for `get_summary`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct LiveSummary {
+    #[schemars(with = "schema::Hex")]
+    pub count: Uint64,
+    /// In shannons
+    #[schemars(with = "schema::Hex")]
+    pub capacity: Uint64,
+    /// By token, of the cells with a UDT amount
+    pub udts: Vec<UdtTotal>,
+    /// Of the latest logged event adding or removing live cells, or of the newest live cell
+    /// once that event is dropped
+    #[schemars(with = "Option<schema::Hex>")]
+    pub last_change: Option<BlockNumber>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct UdtTotal {
+    #[schemars(with = "schema::Hex")]
+    pub type_hash: H256,
+    /// Saturating at the maximum u128
+    #[schemars(with = "schema::Hex")]
+    pub amount: Uint128,
+    #[schemars(with = "schema::Hex")]
+    pub cells: Uint64,
+}
+
+impl LiveSummary {
+    pub fn new(cells: &[LiveCell], last_event_change: Option<u64>) -> Self {
+        let mut udts = BTreeMap::<H256, (u128, u64)>::new();
+        for udt in cells.iter().filter_map(|cell| cell.udt.as_ref()) {
+            let total = udts.entry(udt.type_hash.clone()).or_default();
+            total.0 = total.0.saturating_add(udt.amount.value());
+            total.1 += 1;
+        }
+        let newest = cells.iter().map(|cell| cell.block_number.value()).max();
+        LiveSummary {
+            count: (cells.len() as u64).into(),
+            capacity: cells
+                .iter()
+                .map(|cell| cell.capacity.value())
+                .sum::<u64>()
+                .into(),
+            udts: udts
+                .into_iter()
+                .map(|(type_hash, (amount, count))| UdtTotal {
+                    type_hash,
+                    amount: amount.into(),
+                    cells: count.into(),
+                })
+                .collect(),
+            last_change: newest.max(last_event_change).map(Into::into),
+        }
+    }
+}
+
+/// How an event changed the live cells of its registration, and what they are after it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LiveCellDiff {
+    #[schemars(with = "Vec<schema::OutPoint>")]
+    pub added: Vec<OutPoint>,
+    /// The live cells spent by the inputs, cells created before the registration's start aren't
+    #[schemars(with = "Vec<schema::OutPoint>")]
+    pub removed: Vec<OutPoint>,
+    /// The cells of `removed`, to rebuild the live cells as of an earlier block
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spent: Vec<LiveCell>,
+    #[schemars(with = "schema::Hex")]
+    pub count: Uint64,
+    /// Of all the live cells, in shannons
+    #[schemars(with = "schema::Hex")]
+    pub capacity: Uint64,
+}
+
+/// The stored live cells of a registration and the changes to store once its events are logged
+pub(crate) struct LiveSet {
+    cells: HashMap<OutPoint, LiveCell>,
+    capacity: u64,
+    added: HashMap<OutPoint, LiveCell>,
+    removed: HashSet<OutPoint>,
+}
+
+impl LiveSet {
+    pub fn new(cells: Vec<LiveCell>) -> Self {
+        LiveSet {
+            capacity: cells.iter().map(|cell| cell.capacity.value()).sum(),
+            cells: cells
+                .into_iter()
+                .map(|cell| (cell.out_point.clone(), cell))
+                .collect(),
+            added: HashMap::new(),
+            removed: HashSet::new(),
+        }
+    }
+
+    /// Applies the cells of a fetched event and sets its diff. Outputs are added before the inputs
+    /// are removed, a cell created and spent in the same block is in neither list
+    pub fn apply(&mut self, submit: &mut Submit, names: &ScriptNames) {
+        let mut diff = LiveCellDiff::default();
+        // out points are only known to the scan fetching the cells
+        if submit.output_points.len() == submit.outputs.len() {
+            for (output, out_point) in submit.outputs.iter().zip(&submit.output_points) {
+                let cell = LiveCell {
+                    out_point: out_point.clone(),
+                    capacity: output.output.capacity,
+                    block_number: submit.header.inner.number,
+                    udt: UdtAmount::new(output, names),
+                };
+                self.capacity += cell.capacity.value();
+                if let Some(old) = self.cells.insert(out_point.clone(), cell.clone()) {
+                    self.capacity -= old.capacity.value();
+                }
+                self.removed.remove(out_point);
+                self.added.insert(out_point.clone(), cell);
+                diff.added.push(out_point.clone());
+            }
+        }
+        for out_point in &submit.spent_points {
+            let cell = match self.cells.remove(out_point) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            self.capacity -= cell.capacity.value();
+            if self.added.remove(out_point).is_none() {
+                self.removed.insert(out_point.clone());
+            }
+            match diff.added.iter().position(|added| added == out_point) {
+                Some(i) => {
+                    diff.added.remove(i);
+                }
+                None => {
+                    diff.removed.push(out_point.clone());
+                    diff.spent.push(cell);
+                }
+            }
+        }
+        diff.count = (self.cells.len() as u64).into();
+        diff.capacity = self.capacity.into();
+        submit.live_cells = Some(diff);
+    }
+
+    /// The cells added and removed since the set was loaded
+    pub fn changes(&self) -> (Vec<LiveCell>, Vec<OutPoint>) {
+        (
+            self.added.values().cloned().collect(),
+            self.removed.iter().cloned().collect(),
+        )
+    }
+}
+
+/// The live cells as of the end of block `at` from the cells live now, by undoing the events
+/// logged after it. Fails if one of them was logged before the events had their `spent` cells.
+/// An event logged after the cells were loaded may be among them, its cells are undone only once
+pub(crate) fn rewind(
+    cells: Vec<LiveCell>,
+    later: &[LoggedEvent],
+    at: u64,
+) -> Result<Vec<LiveCell>, String> {
+    let mut live = cells
+        .into_iter()
+        .filter(|cell| cell.block_number.value() <= at)
+        .map(|cell| (cell.out_point.clone(), cell))
+        .collect::<HashMap<_, _>>();
+    for event in later {
+        if let Some(diff) = &event.submit.live_cells {
+            if diff.spent.len() != diff.removed.len() {
+                return Err(format!(
+                    "the event of block {} doesn't have its spent cells, it was logged by an older emitter",
+                    event.submit.header.inner.number.value()
+                ));
+            }
+            live.extend(
+                diff.spent
+                    .iter()
+                    .filter(|cell| cell.block_number.value() <= at)
+                    .map(|cell| (cell.out_point.clone(), cell.clone())),
+            );
+        }
+    }
+    Ok(live.into_values().collect())
+}
+
+/// Oldest first, then by out point
+pub(crate) fn sorted(mut cells: Vec<LiveCell>) -> Vec<LiveCell> {
+    cells.sort_by_key(|cell| {
+        (
+            cell.block_number.value(),
+            cell.out_point.tx_hash.clone(),
+            cell.out_point.index.value(),
+        )
+    });
+    cells
+}
@@ -0,0 +1,84 @@
+//! Where the matched cells of an event are in its block, so consumers can order and deduplicate
+//! the cells of events without looking the transactions up
+
+use ckb_jsonrpc_types::{OutPoint, Uint32};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{rpc_client::CellType, schema, Submit};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct CellLocation {
+    /// Of the transaction creating the output or spending the input
+    #[schemars(with = "schema::Hex")]
+    pub tx_hash: H256,
+    /// Of the transaction in the block
+    #[schemars(with = "schema::Hex")]
+    pub tx_index: Uint32,
+    /// Of the cell in the outputs or inputs of the transaction
+    #[schemars(with = "schema::Hex")]
+    pub index: Uint32,
+    /// The same for the cell in the same block whenever it is delivered, see `idempotency_key`
+    pub idempotency_key: String,
+}
+
+/// Sha256 of the transaction hash, the index of the cell as u32 little endian, 0 for an output or
+/// 1 for an input and the block hash, for consumers upserting cells
+pub(crate) fn idempotency_key(
+    tx_hash: &H256,
+    index: Uint32,
+    io_type: CellType,
+    block_hash: &H256,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(tx_hash.as_bytes());
+    hasher.update(index.value().to_le_bytes());
+    hasher.update([match io_type {
+        CellType::Output => 0,
+        CellType::Input => 1,
+    }]);
+    hasher.update(block_hash.as_bytes());
+    format!("0x{}", faster_hex::hex_string(&hasher.finalize()))
+}
+
+/// Locates the cells left in the submit, in the order of its outputs and inputs. Empty where the
+/// out points of the cells or the transaction indexes are unknown
+pub(crate) fn locate(submit: &mut Submit) {
+    let block_hash = &submit.header.hash;
+    let locations =
+        |out_points: &[OutPoint], cells: usize, io_type: CellType| -> Vec<CellLocation> {
+            if out_points.len() != cells {
+                return Vec::new();
+            }
+            out_points
+                .iter()
+                .map(|out_point| {
+                    submit
+                        .tx_indexes
+                        .get(&out_point.tx_hash)
+                        .map(|tx_index| CellLocation {
+                            tx_hash: out_point.tx_hash.clone(),
+                            tx_index: *tx_index,
+                            index: out_point.index,
+                            idempotency_key: idempotency_key(
+                                &out_point.tx_hash,
+                                out_point.index,
+                                io_type.clone(),
+                                block_hash,
+                            ),
+                        })
+                })
+                .collect::<Option<_>>()
+                .unwrap_or_default()
+        };
+    let output_locations = locations(
+        &submit.output_points,
+        submit.outputs.len(),
+        CellType::Output,
+    );
+    let input_locations = locations(&submit.inputs, submit.inputs.len(), CellType::Input);
+    submit.output_locations = output_locations;
+    submit.input_locations = input_locations;
+}
@@ -0,0 +1,163 @@
+use env_logger::filter::Filter;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, OnceLock, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Warnings and errors kept for `GET /status`
+const RECENT_ERRORS: usize = 50;
+
+/// A logged warning or error
+#[derive(Serialize, Clone)]
+pub struct RecentError {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// env_logger output with levels which can be changed while running
+struct Logger {
+    format: env_logger::Logger,
+    // RUST_LOG directives
+    env: Filter,
+    levels: RwLock<Levels>,
+    recent: Mutex<VecDeque<RecentError>>,
+}
+
+#[derive(Default)]
+struct Levels {
+    /// Level of the config, RUST_LOG module directives still apply under it
+    default: Option<LevelFilter>,
+    /// Set over rpc, win over RUST_LOG and the default for the target and its submodules
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn enabled(&self, env: &Filter, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let set = self
+            .targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len());
+        match set {
+            Some((_, level)) => metadata.level() <= *level,
+            None => {
+                self.default.is_none_or(|level| metadata.level() <= level) && env.enabled(metadata)
+            }
+        }
+    }
+
+    fn max(&self, env: &Filter) -> LevelFilter {
+        let base = self.default.map_or(env.filter(), |l| l.min(env.filter()));
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(base, Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.levels.read().unwrap().enabled(&self.env, metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            if record.level() <= Level::Warn {
+                let mut recent = self.recent.lock().unwrap();
+                if recent.len() == RECENT_ERRORS {
+                    recent.pop_front();
+                }
+                recent.push_back(RecentError {
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis() as u64,
+                    level: record.level().to_string(),
+                    target: record.target().to_string(),
+                    message: record.args().to_string(),
+                });
+            }
+            self.format.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.format.flush()
+    }
+}
+
+/// Installs the logger, `default` caps every target without a RUST_LOG module directive
+pub fn init(default: Option<LevelFilter>) {
+    let mut env = env_logger::filter::Builder::from_env(env_logger::DEFAULT_FILTER_ENV);
+    if default.is_some() {
+        env.filter_level(LevelFilter::Trace);
+    }
+    let mut format = env_logger::Builder::new();
+    format.filter_level(LevelFilter::Trace);
+    if let Ok(style) = std::env::var(env_logger::DEFAULT_WRITE_STYLE_ENV) {
+        format.parse_write_style(&style);
+    }
+
+    let logger = LOGGER.get_or_init(|| Logger {
+        format: format.build(),
+        env: env.build(),
+        levels: RwLock::new(Levels {
+            default,
+            targets: Vec::new(),
+        }),
+        recent: Mutex::new(VecDeque::with_capacity(RECENT_ERRORS)),
+    });
+    log::set_logger(logger).unwrap();
+    update_max_level(logger);
+}
+
+fn update_max_level(logger: &Logger) {
+    log::set_max_level(logger.levels.read().unwrap().max(&logger.env));
+}
+
+/// The last warnings and errors logged, oldest first
+pub fn recent_errors() -> Vec<RecentError> {
+    LOGGER.get().map_or_else(Vec::new, |logger| {
+        logger.recent.lock().unwrap().iter().cloned().collect()
+    })
+}
+
+/// Changes the default level, only if one was set at startup
+pub fn set_default(level: LevelFilter) {
+    if let Some(logger) = LOGGER.get() {
+        let mut levels = logger.levels.write().unwrap();
+        if levels.default.is_some() {
+            levels.default = Some(level);
+        }
+        drop(levels);
+        update_max_level(logger);
+    }
+}
+
+/// Sets the level of a target such as `emitter::cell_process` and its submodules,
+/// None goes back to the default
+pub fn set_target(target: &str, level: Option<LevelFilter>) {
+    if let Some(logger) = LOGGER.get() {
+        let mut levels = logger.levels.write().unwrap();
+        levels.targets.retain(|(prefix, _)| prefix != target);
+        if let Some(level) = level {
+            levels.targets.push((target.to_string(), level));
+        }
+        drop(levels);
+        update_max_level(logger);
+    }
+}
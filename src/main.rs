@@ -1,94 +1,719 @@
-use ckb_jsonrpc_types::{CellInfo, HeaderView, OutPoint};
+use ckb_jsonrpc_types::{CellInfo, HeaderView, OutPoint, Uint32};
 use ckb_types::H256;
-use jsonrpsee::http_server::HttpServerBuilder;
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use std::{
-    collections::HashMap,
-    sync::{
-        atomic::{AtomicPtr, Ordering},
-        Arc,
-    },
+use std::sync::{
+    atomic::{AtomicPtr, Ordering},
+    Arc,
 };
 
+use cipher::Cipher;
+use config::Config;
+use consumer::ConsumerCursors;
 use global_state::GlobalState;
+use lifecycle::Lifecycle;
+use network::{GenesisPin, Network};
+use plugin::Plugin;
+use retention::RetentionPolicy;
 use rpc_client::{IndexerTip, RpcClient};
+use rpc_http::RpcHttp;
 use rpc_server::{EmitterRpc, EmitterServer};
+use sink::{Compression, Sinks, UnixSocketSink};
+use snapshot::Snapshot;
+use tip_poller::IndexerTipWatch;
 
+mod alert;
+mod audit;
+mod bench;
+mod block_scan;
+mod capabilities;
 mod cell_process;
+mod cheque;
+mod cipher;
+mod cli;
+mod config;
+mod consumer;
+mod correlation;
+mod epoch;
+mod event_buffer;
+mod event_log;
+mod fee;
 mod global_state;
+mod graphql;
+mod leader;
+mod lifecycle;
+mod lineage;
+mod live_cells;
+mod location;
+mod logging;
+mod metrics;
+#[cfg(any(test, feature = "testkit"))]
+mod mock_node;
+mod multisig;
+mod network;
+mod overlap;
+mod pattern;
+mod plugin;
+mod recovery;
+mod rest;
+mod retention;
 mod rpc_client;
+mod rpc_error;
+mod rpc_http;
 mod rpc_server;
+mod rpc_stream;
+mod scan_pool;
+mod scheduler;
+mod schema;
+mod script_info;
+#[cfg(feature = "ckb-sdk")]
+mod sdk;
+mod sealing;
+mod service;
+mod since;
+mod sink;
+mod snapshot;
+mod stats;
+mod statsd;
+mod status;
+mod storage;
+mod summary;
+#[cfg(any(test, feature = "testkit"))]
+mod testkit;
+mod tip_poller;
+mod uncle;
+mod validate;
+mod witness;
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+fn main() {
+    let matches = clap::Command::new("emitter")
+        .version(clap::crate_version!())
+        .args(server_args())
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            clap::Command::new("run")
+                .about("Runs the emitter service, the default without a subcommand")
+                .args(server_args()),
+        )
+        .subcommands(cli::commands())
+        .get_matches();
 
-    let cmd = clap::Command::new("emitter")
-    .version(clap::crate_version!()).arg(
-        clap::Arg::new("ckb_uri")
-            .short('c')
-            .default_value("http://127.0.0.1:8114")
-            .help(
-                "CKB rpc service uri, supports http and tcp, for example: `http://127.0.0.1:8114`",
-            )
-            .action(clap::ArgAction::Set),
-    ).arg(
-        clap::Arg::new("listen_uri")
-        .short('l')
-        .default_value("127.0.0.1:8120")
-        .help("Emitter rpc http service listen address, default 127.0.0.1:8120")
-        .action(clap::ArgAction::Set),
-    ).arg(
-        clap::Arg::new("store_path")
-        .short('s')
-        .help("Sets the indexer store path to use")
-        .required(true)
-        .action(clap::ArgAction::Set),
-    );
+    match matches.subcommand() {
+        Some(("run", matches)) => serve(matches),
+        Some((name, matches)) => {
+            env_logger::init();
+            runtime().block_on(cli::execute(name, matches))
+        }
+        None => serve(&matches),
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Runtime::new().unwrap()
+}
+
+/// Daemonizes before the runtime starts any thread
+fn serve(matches: &clap::ArgMatches) {
+    if matches.get_flag("daemon") {
+        service::daemonize().unwrap();
+    }
+    if let Some(path) = matches.get_one::<String>("pid_file") {
+        service::write_pid_file(path.as_ref()).unwrap();
+    }
+    runtime().block_on(run(matches))
+}
+
+async fn run(matches: &clap::ArgMatches) {
+    let config_path = matches
+        .get_one::<String>("config")
+        .map(std::path::PathBuf::from);
+    let config = config_path
+        .as_ref()
+        .map(|path| Config::load(path).unwrap())
+        .unwrap_or_default();
+    config::init_logger(&config);
+
+    let mut client = cli::ckb_client(matches, &config.ckb_connections)
+        .unwrap()
+        .with_retry(config.retry.clone());
+    if let Some(secs) = config.ckb_timeout_secs {
+        client = client.with_timeout(std::time::Duration::from_secs(secs));
+    }
+    if let Some(millis) = config.ckb_slow_call_ms {
+        client = client.with_slow_call_threshold(std::time::Duration::from_millis(millis));
+    }
+
+    let network = matches
+        .get_one::<String>("network")
+        .map(|s| s.parse::<Network>().unwrap());
+    if let Some(network) = network {
+        match network.verify(&client).await {
+            Ok(()) => log::info!("connected to {:?}", network),
+            Err(e) if matches.get_flag("warn_network_mismatch") => log::warn!("{}", e),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    // filters the indexer doesn't take are checked by the emitter, which is also the fallback
+    match capabilities::detect(&client).await {
+        Ok(indexer) => {
+            log::info!(
+                "indexer {:?}, output data modes {:?}",
+                indexer.flavor,
+                indexer.output_data_modes
+            );
+            client = client.with_indexer(indexer);
+        }
+        Err(e) => log::warn!("detect indexer capabilities: {}", e),
+    }
 
-    let matches = cmd.get_matches();
+    let store_path: std::path::PathBuf = matches.get_one::<String>("store_path").unwrap().into();
 
-    let client = RpcClient::new(matches.get_one::<String>("ckb_uri").unwrap());
+    // a standby opens the storage only once the leader is gone, starting from the tips it persisted
+    let _leader = match matches.get_one::<String>("leader_lock") {
+        Some(path) => Some(leader::acquire(path.as_ref()).await.unwrap()),
+        None => None,
+    };
 
-    let mut global =
-        GlobalState::load_from_dir(matches.get_one::<String>("store_path").unwrap().into());
+    let cipher = Cipher::load(
+        matches
+            .get_one::<String>("encryption_key_file")
+            .map(|s| s.as_str()),
+    )
+    .unwrap();
+
+    if let Some(sealer) = sealing::Sealer::load(
+        matches
+            .get_one::<String>("signing_key_file")
+            .map(|s| s.as_str()),
+        matches.get_one::<String>("encrypt_to").map(|s| s.as_str()),
+    )
+    .unwrap()
+    {
+        sealing::init(sealer);
+    }
+
+    let storage = storage::open(
+        matches.get_one::<String>("store_backend").unwrap(),
+        &store_path,
+        cipher.clone(),
+        matches
+            .get_one::<i64>("event_log_compression_level")
+            .map(|level| *level as i32),
+    )
+    .unwrap();
+
+    if let Some(path) = matches.get_one::<String>("export_snapshot") {
+        Snapshot::take(storage.as_ref())
+            .unwrap()
+            .write(path, cipher.as_ref())
+            .unwrap();
+        log::info!("exported snapshot to {}", path);
+        return;
+    }
+    if let Some(path) = matches.get_one::<String>("import_snapshot") {
+        Snapshot::read(path, cipher.as_ref())
+            .unwrap()
+            .restore(storage.as_ref())
+            .unwrap();
+        log::info!("imported snapshot from {}", path);
+    }
+
+    recovery::recover(&store_path, storage.as_ref(), cipher.as_ref()).unwrap();
+
+    let cursors = ConsumerCursors::load(storage.clone()).unwrap();
+
+    let mut global = GlobalState::load(storage.clone()).unwrap();
 
     let state = global.state.clone();
 
-    let cell_handles = global.spawn_cells(client.clone());
+    let sinks = Sinks {
+        storage,
+        unix_socket: matches.get_one::<String>("unix_socket").map(|path| {
+            let compression = match matches
+                .get_one::<String>("unix_socket_compression")
+                .unwrap()
+                .as_str()
+            {
+                "gzip" => Compression::Gzip,
+                "zstd" => Compression::Zstd,
+                _ => Compression::None,
+            };
+            let sink = UnixSocketSink::bind(path, compression).unwrap();
+            log::info!("stream events on unix socket {}", path);
+            sink
+        }),
+        http: reqwest::Client::new(),
+        settings: Default::default(),
+        consumers: Default::default(),
+        plugin: Plugin::load(&config.plugin).unwrap().map(Arc::new),
+        failed: Default::default(),
+        breakers: Default::default(),
+    };
+    let webhook_secret = matches.get_one::<String>("webhook_secret").cloned();
+    config::apply(&config, webhook_secret.as_ref(), &sinks.settings);
+
+    let verifiers = config
+        .verify_nodes
+        .iter()
+        .map(|uri| {
+            let verifier = RpcClient::new(uri).with_retry(config.retry.clone());
+            match config.ckb_timeout_secs {
+                Some(secs) => verifier.with_timeout(std::time::Duration::from_secs(secs)),
+                None => verifier,
+            }
+        })
+        .collect::<Vec<_>>();
+    if !verifiers.is_empty() {
+        log::info!("scans advance once {} further nodes agree", verifiers.len());
+    }
+    let indexer_tip = IndexerTipWatch::spawn(client.clone(), verifiers);
+    let store_backend = matches.get_one::<String>("store_backend").unwrap();
+    let genesis = Arc::new(
+        GenesisPin::load((store_backend != "memory").then(|| store_path.clone())).unwrap(),
+    );
+    if let Err(e) = genesis
+        .check(&client)
+        .await
+        .and_then(|()| genesis.verified())
+    {
+        log::error!("scans refused: {}", e);
+    }
+    tokio::spawn(genesis.clone().run(client.clone()));
+    let registrations = Lifecycle::new(
+        state.clone(),
+        client.clone(),
+        indexer_tip.clone(),
+        sinks.clone(),
+        genesis,
+        &config,
+    );
 
     let _global_handle = tokio::spawn(async move { global.run().await });
+    tokio::spawn(sink::heartbeat::run(state.clone(), sinks.clone()));
+    tokio::spawn(sink::breaker::run(state.clone(), sinks.clone()));
+    tokio::spawn(sink::finality::run(
+        state.clone(),
+        client.clone(),
+        indexer_tip.clone(),
+        sinks.clone(),
+    ));
+    tokio::spawn(alert::run(
+        state.clone(),
+        client.clone(),
+        indexer_tip,
+        sinks.clone(),
+    ));
 
-    let rpc = EmitterRpc {
-        state,
-        cell_handles,
-        client,
+    let retention = RetentionPolicy {
+        max_age: matches
+            .get_one::<u64>("retention_max_age")
+            .map(|secs| std::time::Duration::from_secs(*secs)),
+        max_events: matches.get_one::<u64>("retention_max_events").copied(),
+        acked: matches.get_flag("retention_acked"),
+    };
+    if !retention.is_empty() {
+        let storage = sinks.storage.clone();
+        let cursors = cursors.clone();
+        tokio::spawn(retention::run(retention, storage, cursors, state.clone()));
     }
-    .into_rpc();
 
-    let listen_url = matches.get_one::<String>("listen_uri").unwrap();
-    let handle = HttpServerBuilder::new()
-        .build(listen_url)
-        .await
-        .unwrap()
-        .start(rpc)
+    let snapshot_dir = config
+        .snapshot
+        .dir
+        .as_ref()
+        .map_or_else(|| store_path.join("snapshots"), Into::into);
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+    let _signal_handle = tokio::spawn(recovery::handle_signals(
+        store_path,
+        state.clone(),
+        cursors.clone(),
+        sinks.storage.clone(),
+        cipher.clone(),
+        {
+            let settings = sinks.settings.clone();
+            Box::new(move || {
+                if let Some(path) = &config_path {
+                    config::reload(path, webhook_secret.as_ref(), &settings);
+                }
+            })
+        },
+        shutdown.clone(),
+    ));
+
+    let audit = matches
+        .get_one::<String>("audit_log")
+        .map(audit::AuditLog::open)
+        .transpose()
         .unwrap();
+    let debug_tasks = registrations.debug_tasks();
+    let statsd = statsd::run(config.statsd.clone(), debug_tasks.clone());
+    tokio::spawn(async move {
+        if let Err(e) = statsd.await {
+            log::warn!("Statsd metrics stopped: {:?}", e);
+        }
+    });
+    let listen_url = match matches.value_source("listen_uri") {
+        Some(clap::parser::ValueSource::DefaultValue) => config.rpc.listen_address.clone(),
+        _ => None,
+    }
+    .unwrap_or_else(|| matches.get_one::<String>("listen_uri").unwrap().clone());
+    let mut running = config.clone();
+    // no tcp listener is bound serving the unix socket only
+    running.rpc.listen_address = (!matches.get_flag("rpc_unix_only")).then(|| listen_url.clone());
+    let emitter = EmitterRpc {
+        registrations,
+        client,
+        sinks,
+        cursors,
+        network,
+        read_only: matches.get_flag("read_only"),
+        quotas: config.quotas.clone(),
+        audit: audit.clone(),
+        started: std::time::Instant::now(),
+        merge_duplicates: config.merge_duplicates,
+        shutdown,
+        snapshot_dir,
+        cipher,
+        config: running,
+    };
+    emitter
+        .reconcile(&config.registrations, config.prune_registrations)
+        .await;
+    if config.merge_duplicates {
+        emitter.merge_overlaps(false).await;
+    }
+    let mut rpc = RpcHttp::new(
+        emitter.into_rpc().into(),
+        &config.rpc,
+        audit,
+        debug_tasks.clone(),
+    )
+    .unwrap();
+    let scan_stall = std::time::Duration::from_secs(config.scan_stall_secs());
+    if let Some(token) = matches.get_one::<String>("debug_token") {
+        rpc = rpc.with_debug(token.clone());
+    }
+    if let Some(token) = matches.get_one::<String>("status_token") {
+        rpc = rpc.with_status(token.clone());
+    }
+    if let Some(token) = matches.get_one::<String>("admin_token") {
+        rpc = rpc.with_admin(token.clone());
+    }
+    if !config.namespaces.is_empty() {
+        rpc = rpc.with_namespaces(&config.namespaces);
+    }
+
+    if let Some(path) = matches.get_one::<String>("rpc_unix_socket") {
+        let listener = rpc_http::bind_unix(path).unwrap();
+        log::info!("listen on unix socket {}", path);
+        tokio::spawn(rpc.clone().serve_unix(listener));
+    }
+    if matches.get_flag("rpc_unix_only") {
+        let watchdog_tasks = debug_tasks.clone();
+        service::notify("READY=1");
+        tokio::spawn(service::watchdog(watchdog_tasks, scan_stall));
+        std::future::pending::<()>().await;
+    }
+
+    let listener = tokio::net::TcpListener::bind(&listen_url).await.unwrap();
 
     log::info!("listen on {}", listen_url);
-    handle.await;
+    service::notify("READY=1");
+    tokio::spawn(service::watchdog(debug_tasks, scan_stall));
+    rpc.serve_tcp(listener).await;
+}
+
+/// Arguments of the emitter service, accepted with and without the `run` subcommand,
+/// each one is also read from the `EMITTER_*` env var of the same name
+fn server_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("config")
+            .env("EMITTER_CONFIG")
+            .long("config")
+            .help("Toml config file, reloaded on SIGHUP")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("daemon")
+            .env("EMITTER_DAEMON")
+            .long("daemon")
+            .help("Detaches into the background, stdout and stderr stay where they were redirected to")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("pid_file")
+            .env("EMITTER_PID_FILE")
+            .long("pid-file")
+            .help("Writes the pid to this file, removed on shutdown, refuses to start while the pid in it is running")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("leader_lock")
+            .env("EMITTER_LEADER_LOCK")
+            .long("leader-lock")
+            .help("Lock file of instances sharing the storage, only the one holding it scans and serves, the others wait as standby")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("read_only")
+            .env("EMITTER_READ_ONLY")
+            .long("read-only")
+            .help("Rejects the rpc calls changing registrations, sinks, log levels or writing files, for replicas exposed to a wider audience")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("ckb_uri")
+            .env("EMITTER_CKB_URI")
+            .short('c')
+            .default_value("http://127.0.0.1:8114")
+            .help(
+                "CKB rpc service uri, http, ws(s) or tcp, for example: `http://127.0.0.1:8114`",
+            )
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("listen_uri")
+            .env("EMITTER_LISTEN_URI")
+            .short('l')
+            .default_value("127.0.0.1:8120")
+            .help("Emitter rpc http service listen address, default 127.0.0.1:8120 or the `[rpc]` listen_address of the config")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("network")
+            .env("EMITTER_NETWORK")
+            .long("network")
+            .value_parser(["mainnet", "testnet", "devnet"])
+            .help("Refuses to start if the genesis of the ckb node isn't the one of this network, devnets aren't checked")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("warn_network_mismatch")
+            .env("EMITTER_WARN_NETWORK_MISMATCH")
+            .long("warn-network-mismatch")
+            .help("Only warns if the ckb node is on another network than `--network`")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("rpc_unix_socket")
+            .env("EMITTER_RPC_UNIX_SOCKET")
+            .long("rpc-unix-socket")
+            .help("Also serves the rpc on this unix socket path, the permissions of the socket file control the access")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("rpc_unix_only")
+            .env("EMITTER_RPC_UNIX_ONLY")
+            .long("rpc-unix-only")
+            .requires("rpc_unix_socket")
+            .help("Serves the rpc on the unix socket only, no tcp listener")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("audit_log")
+            .env("EMITTER_AUDIT_LOG")
+            .long("audit-log")
+            .help("Appends every mutating rpc call with its caller and params to this ndjson file, served by the `audit_log` rpc")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("status_token")
+            .env("EMITTER_STATUS_TOKEN")
+            .long("status-token")
+            .help("Serves a json overview for dashboards at `/status` to requests with this bearer token")
+            .hide_env_values(true)
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("debug_token")
+            .env("EMITTER_DEBUG_TOKEN")
+            .long("debug-token")
+            .help("Serves the scan task state at `/debug/tasks` to requests with this bearer token")
+            .hide_env_values(true)
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("admin_token")
+            .env("EMITTER_ADMIN_TOKEN")
+            .long("admin-token")
+            .help("Serves the `admin_` rpc methods to requests with this bearer token, they are refused without it")
+            .hide_env_values(true)
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("store_path")
+            .env("EMITTER_STORE_PATH")
+            .short('s')
+            .help("Sets the indexer store path to use")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("store_backend")
+            .env("EMITTER_STORE_BACKEND")
+            .short('b')
+            .default_value(storage::DEFAULT_BACKEND)
+            .help("Storage backend of the registrations and the event log, `file`, `memory`, or `rocksdb`/`sled`/`sqlite` if compiled with the feature of the same name")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("encryption_key_file")
+            .env("EMITTER_ENCRYPTION_KEY_FILE")
+            .long("encryption-key-file")
+            .help("Encrypts the store with the hex encoded 32 byte AES-256-GCM key in this file, `EMITTER_ENCRYPTION_KEY` holds the key otherwise, file backend only")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("signing_key_file")
+            .env("EMITTER_SIGNING_KEY_FILE")
+            .long("signing-key-file")
+            .help("Signs every json event with the Ed25519 key in this file, PEM or the hex encoded 32 byte seed, `EMITTER_SIGNING_KEY` holds the seed otherwise")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("encrypt_to")
+            .env("EMITTER_ENCRYPT_TO")
+            .long("encrypt-to")
+            .help("Encrypts the payload of every json event to this hex encoded X25519 public key")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("webhook_secret")
+            .env("EMITTER_WEBHOOK_SECRET")
+            .hide_env_values(true)
+            .long("webhook-secret")
+            .help("Signs the requests of webhooks registered without their own secret")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("unix_socket")
+            .env("EMITTER_UNIX_SOCKET")
+            .short('u')
+            .help("Streams length-prefixed json events to clients connected to this unix socket path")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("unix_socket_compression")
+            .env("EMITTER_UNIX_SOCKET_COMPRESSION")
+            .long("unix-socket-compression")
+            .default_value("none")
+            .value_parser(["none", "gzip", "zstd"])
+            .help("Compresses every event streamed on the unix socket")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("event_log_compression_level")
+            .env("EMITTER_EVENT_LOG_COMPRESSION_LEVEL")
+            .long("event-log-compression-level")
+            .value_parser(clap::value_parser!(i64).range(1..=22))
+            .help("Compresses new event log records with zstd at this level, 1 to 22, records are read whatever their compression, file backend only")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("retention_max_age")
+            .env("EMITTER_RETENTION_MAX_AGE")
+            .long("retention-max-age")
+            .value_parser(clap::value_parser!(u64))
+            .help("Drops logged events of blocks older than this many seconds")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("retention_max_events")
+            .env("EMITTER_RETENTION_MAX_EVENTS")
+            .long("retention-max-events")
+            .value_parser(clap::value_parser!(u64))
+            .help("Keeps at most this many of the newest logged events")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("retention_acked")
+            .env("EMITTER_RETENTION_ACKED")
+            .long("retention-acked")
+            .help("Drops logged events once every consumer acked them")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("export_snapshot")
+            .env("EMITTER_EXPORT_SNAPSHOT")
+            .long("export-snapshot")
+            .help("Writes a snapshot of the registrations, scan tips and consumer cursors in the store to this file and exits")
+            .conflicts_with("import_snapshot")
+            .action(clap::ArgAction::Set),
+        clap::Arg::new("import_snapshot")
+            .env("EMITTER_IMPORT_SNAPSHOT")
+            .long("import-snapshot")
+            .help("Restores a snapshot file into the empty store before starting")
+            .action(clap::ArgAction::Set),
+    ]
+    .into_iter()
+    .chain(cli::ckb_auth_args())
+    .collect()
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 struct Submit {
+    #[schemars(with = "schema::HeaderView")]
     header: HeaderView,
+    #[schemars(with = "Vec<schema::OutPoint>")]
     inputs: Vec<OutPoint>,
+    #[schemars(with = "Vec<schema::CellInfo>")]
     outputs: Vec<CellInfo>,
+    /// Of the lock and type scripts of `outputs`, in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    output_scripts: Vec<script_info::CellScripts>,
+    /// Of `outputs` in the block, in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    output_locations: Vec<location::CellLocation>,
+    /// Of `inputs` in the block, in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    input_locations: Vec<location::CellLocation>,
+    /// The cells `inputs` spend, in the same order, for keys asking for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Vec<schema::CellInfo>")]
+    input_cells: Vec<CellInfo>,
+    /// Of the lock and type scripts of `input_cells`, in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    input_scripts: Vec<script_info::CellScripts>,
+    /// Of the matched cells locked by the cheque lock
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cheques: Vec<cheque::Cheque>,
+    /// Of the matched cells locked by the secp256k1 multisig lock
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    multisigs: Vec<multisig::Multisig>,
+    /// Of the inputs whose `since` is set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    since: Vec<since::InputSince>,
+    /// Of the transactions with matched cells, only computed for keys filtering by fee
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fees: Vec<fee::TxFee>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    capacity_change: Option<fee::CapacityChange>,
+    /// Indexes in `outputs` of the cells created by the cellbase, for keys tagging them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Vec<schema::Hex>")]
+    cellbase_outputs: Vec<Uint32>,
+    /// Of the block, for keys asking for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    uncles: Vec<uncle::Uncle>,
+    /// Starting at the block, for keys asking for epochs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    epoch: Option<epoch::EpochStart>,
+    /// Of the transactions with matched cells, for keys asking for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    witnesses: Vec<witness::TxWitnesses>,
+    /// Of the transactions consuming matched cells, for keys with a correlation rule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    correlations: Vec<correlation::Correlation>,
+    /// Of the transactions with matched cells, for keys asking for them only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Vec<schema::Hex>")]
+    transactions: Vec<H256>,
+    /// For keys summarizing blocks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    summary: Option<summary::BlockSummary>,
+    /// For keys tracking live cells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    live_cells: Option<live_cells::LiveCellDiff>,
+    /// Added by the wasm plugin, delivered as is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extra: Option<serde_json::Value>,
+    /// `x-request-id` the key was registered with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// Of the cells whose data was left out to keep a webhook's body under its `size_limit`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    truncated_data: Vec<sink::TruncatedData>,
+    /// Out points of `outputs`, only known to the scan fetching them
+    #[serde(skip)]
+    output_points: Vec<OutPoint>,
+    /// Out points of the cells `inputs` spend, only known to the scan fetching them
+    #[serde(skip)]
+    spent_points: Vec<OutPoint>,
+    /// Indexes in the block of the transactions with matched cells, only known to the scan fetching them
+    #[serde(skip)]
+    tx_indexes: std::collections::HashMap<H256, Uint32>,
 }
 
-async fn submit_to_realyer(submits: HashMap<H256, Submit>) {
-    for (_, sub) in submits {
-        println!("{}", serde_json::to_string_pretty(&sub).unwrap())
+impl Submit {
+    /// Of the block, without cells yet
+    fn new(header: HeaderView) -> Self {
+        Submit {
+            header,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            output_scripts: Vec::new(),
+            output_locations: Vec::new(),
+            input_locations: Vec::new(),
+            input_cells: Vec::new(),
+            input_scripts: Vec::new(),
+            since: Vec::new(),
+            cheques: Vec::new(),
+            multisigs: Vec::new(),
+            fees: Vec::new(),
+            capacity_change: None,
+            cellbase_outputs: Vec::new(),
+            uncles: Vec::new(),
+            epoch: None,
+            witnesses: Vec::new(),
+            correlations: Vec::new(),
+            transactions: Vec::new(),
+            summary: None,
+            live_cells: None,
+            extra: None,
+            request_id: None,
+            truncated_data: Vec::new(),
+            output_points: Vec::new(),
+            spent_points: Vec::new(),
+            tx_indexes: Default::default(),
+        }
     }
 }
 
@@ -102,6 +727,18 @@ impl Drop for ScanTipInner {
     }
 }
 
+impl ScanTip {
+    pub fn new(tip: IndexerTip) -> Self {
+        ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(Box::into_raw(
+            Box::new(tip),
+        )))))
+    }
+
+    pub fn load(&self) -> IndexerTip {
+        unsafe { &*self.0 .0.load(Ordering::Acquire) }.clone()
+    }
+}
+
 impl Clone for ScanTip {
     fn clone(&self) -> Self {
         ScanTip(self.0.clone())
@@ -126,8 +763,6 @@ impl<'a> Deserialize<'a> for ScanTip {
     {
         let inner = IndexerTip::deserialize(deserializer)?;
 
-        Ok(ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(
-            Box::into_raw(Box::new(inner)),
-        )))))
+        Ok(ScanTip::new(inner))
     }
 }
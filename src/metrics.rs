@@ -0,0 +1,360 @@
+use tokio::task::JoinHandle;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    io,
+    sync::{Mutex, OnceLock},
+};
+
+/// Upper bounds in seconds of the histogram buckets
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// Every metric family with its help text, the names are unique
+const FAMILIES: &[(&str, Kind, &str)] = &[
+    (
+        "emitter_delivery_duration_seconds",
+        Kind::Histogram,
+        "Time to deliver an event to a sink",
+    ),
+    (
+        "emitter_delivery_failures_total",
+        Kind::Counter,
+        "Deliveries a sink failed",
+    ),
+    (
+        "emitter_delivery_retries_total",
+        Kind::Counter,
+        "Deliveries of an event which failed before",
+    ),
+    (
+        "emitter_spilled_events_total",
+        Kind::Counter,
+        "Events logged without delivery while the event buffers were full, delivered from the log later",
+    ),
+    (
+        "emitter_ckb_call_duration_seconds",
+        Kind::Histogram,
+        "Time of a ckb rpc call including its retries",
+    ),
+    (
+        "emitter_ckb_connections_opened_total",
+        Kind::Counter,
+        "Connections to the ckb node which answered a call, high against the calls means churn",
+    ),
+    (
+        "emitter_tip_mismatches_total",
+        Kind::Counter,
+        "Polled indexer tips a verifying node has another block at the height of",
+    ),
+    (
+        "emitter_rpc_requests_total",
+        Kind::Counter,
+        "Calls of an rpc method, batches count each call",
+    ),
+    (
+        "emitter_rpc_request_duration_seconds",
+        Kind::Histogram,
+        "Time to answer a call of an rpc method",
+    ),
+    (
+        "emitter_rpc_errors_total",
+        Kind::Counter,
+        "Calls of an rpc method answered with an error, by its code",
+    ),
+    (
+        "emitter_sink_circuit_open",
+        Kind::Gauge,
+        "Whether the circuit breaker of a sink is open",
+    ),
+    (
+        "emitter_scan_run_events",
+        Kind::Gauge,
+        "Events written by the one-shot scan run",
+    ),
+    (
+        "emitter_scan_run_seconds",
+        Kind::Gauge,
+        "Time the one-shot scan run took",
+    ),
+    (
+        "emitter_registered_keys",
+        Kind::Gauge,
+        "Registered search keys",
+    ),
+    (
+        "emitter_tasks",
+        Kind::Gauge,
+        "Running tasks of the kinds which grow with the registrations and clients",
+    ),
+    (
+        "emitter_pending_events",
+        Kind::Gauge,
+        "Events of all registrations waiting for their delivery",
+    ),
+    (
+        "emitter_unix_socket_queued_frames",
+        Kind::Gauge,
+        "Frames buffered for the slowest unix socket client",
+    ),
+    (
+        "emitter_buffered_bytes",
+        Kind::Gauge,
+        "Approximate memory held by event buffers",
+    ),
+];
+
+/// Label names and values, in the order given
+pub(crate) type Labels = Vec<(&'static str, String)>;
+
+/// The value of a metric at one moment, histograms by their sum and count
+pub(crate) enum Sample {
+    Counter(u64),
+    Gauge(f64),
+    Histogram { sum: f64, count: u64 },
+}
+
+struct Histogram {
+    /// Per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    gauges: BTreeMap<(&'static str, Labels), f64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+fn labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+pub(crate) fn inc(name: &'static str, label_values: &[(&'static str, &str)]) {
+    *registry()
+        .lock()
+        .unwrap()
+        .counters
+        .entry((name, labels(label_values)))
+        .or_default() += 1;
+}
+
+pub(crate) fn inc_by(name: &'static str, label_values: &[(&'static str, &str)], n: u64) {
+    *registry()
+        .lock()
+        .unwrap()
+        .counters
+        .entry((name, labels(label_values)))
+        .or_default() += n;
+}
+
+pub(crate) fn set(name: &'static str, label_values: &[(&'static str, &str)], value: f64) {
+    registry()
+        .lock()
+        .unwrap()
+        .gauges
+        .insert((name, labels(label_values)), value);
+}
+
+fn add(name: &'static str, label_values: &[(&'static str, &str)], delta: f64) {
+    *registry()
+        .lock()
+        .unwrap()
+        .gauges
+        .entry((name, labels(label_values)))
+        .or_default() += delta;
+}
+
+/// Spawns the task counted in `emitter_tasks` under `kind` until it ends or is aborted
+pub(crate) fn spawn<F>(kind: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    struct Running(&'static str);
+    impl Drop for Running {
+        fn drop(&mut self) {
+            add("emitter_tasks", &[("kind", self.0)], -1.0);
+        }
+    }
+
+    add("emitter_tasks", &[("kind", kind)], 1.0);
+    let running = Running(kind);
+    tokio::spawn(async move {
+        let _running = running;
+        future.await
+    })
+}
+
+pub(crate) fn observe(name: &'static str, label_values: &[(&'static str, &str)], seconds: f64) {
+    let mut registry = registry().lock().unwrap();
+    let histogram = registry
+        .histograms
+        .entry((name, labels(label_values)))
+        .or_insert_with(|| Histogram {
+            counts: vec![0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+    if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+        histogram.counts[bucket] += 1;
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+/// All metrics in the prometheus text exposition format
+pub(crate) fn render() -> String {
+    let registry = registry().lock().unwrap();
+    let mut out = String::new();
+    for (family, kind, help) in FAMILIES {
+        let kind_name = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", family, help);
+        let _ = writeln!(out, "# TYPE {} {}", family, kind_name);
+        match kind {
+            Kind::Counter => {
+                for ((_, labels), value) in
+                    registry.counters.iter().filter(|((n, _), _)| n == family)
+                {
+                    let _ = writeln!(out, "{}{} {}", family, format_labels(labels, None), value);
+                }
+            }
+            Kind::Gauge => {
+                for ((_, labels), value) in registry.gauges.iter().filter(|((n, _), _)| n == family)
+                {
+                    let _ = writeln!(out, "{}{} {}", family, format_labels(labels, None), value);
+                }
+            }
+            Kind::Histogram => {
+                for ((_, labels), histogram) in
+                    registry.histograms.iter().filter(|((n, _), _)| n == family)
+                {
+                    let mut cumulative = 0;
+                    for (bound, count) in BUCKETS.iter().zip(&histogram.counts) {
+                        cumulative += count;
+                        let le = bound.to_string();
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            family,
+                            format_labels(labels, Some(&le)),
+                            cumulative
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{} {}",
+                        family,
+                        format_labels(labels, Some("+Inf")),
+                        histogram.count
+                    );
+                    let _ = writeln!(
+                        out,
+                        "{}_sum{} {}",
+                        family,
+                        format_labels(labels, None),
+                        histogram.sum
+                    );
+                    let _ = writeln!(
+                        out,
+                        "{}_count{} {}",
+                        family,
+                        format_labels(labels, None),
+                        histogram.count
+                    );
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Every metric with its labels, as `render` would serve them
+pub(crate) fn snapshot() -> Vec<(&'static str, Labels, Sample)> {
+    let registry = registry().lock().unwrap();
+    let counters = registry
+        .counters
+        .iter()
+        .map(|((name, labels), value)| (*name, labels.clone(), Sample::Counter(*value)));
+    let gauges = registry
+        .gauges
+        .iter()
+        .map(|((name, labels), value)| (*name, labels.clone(), Sample::Gauge(*value)));
+    let histograms = registry
+        .histograms
+        .iter()
+        .map(|((name, labels), histogram)| {
+            let sample = Sample::Histogram {
+                sum: histogram.sum,
+                count: histogram.count,
+            };
+            (*name, labels.clone(), sample)
+        });
+    counters.chain(gauges).chain(histograms).collect()
+}
+
+/// Replaces the metrics of `job` on a Pushgateway with all metrics, for runs which end before they
+/// could be scraped
+pub(crate) async fn push(url: &str, job: &str) -> io::Result<()> {
+    let url = format!("{}/metrics/job/{}", url.trim_end_matches('/'), job);
+    let resp = reqwest::Client::new()
+        .put(&url)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(render())
+        .send()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, format!("{:?}", e)))?;
+    if !resp.status().is_success() {
+        return Err(io::Error::other(format!(
+            "pushgateway {} answered {}",
+            url,
+            resp.status()
+        )));
+    }
+    Ok(())
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
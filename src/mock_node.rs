@@ -0,0 +1,2795 @@
+//! A scripted ckb node serving the calls of the scans from memory, with reorgs, delays and failures,
+//! and a harness driving a single registration's scan against it one step at a time
+#![cfg_attr(not(test), allow(dead_code))]
+
+use ckb_jsonrpc_types::{JsonBytes, Script, TxStatus, Uint32};
+use ckb_types::{
+    bytes::Bytes,
+    core::{self, Capacity, EpochNumberWithFraction, HeaderBuilder, TransactionBuilder},
+    packed,
+    prelude::*,
+    H256,
+};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::rpc_client::{
+    CellType, IndexerTip, Pagination, RpcClient, ScriptType, SearchKey, Transport, TxWithCells,
+};
+#[cfg(test)]
+use crate::{
+    cell_process::CellProcess,
+    event_log::LoggedEvent,
+    global_state::{Registration, Status},
+    network::GenesisPin,
+    rpc_server::RpcSearchKey,
+    scheduler::Priority,
+    sink::{SinkConfig, Sinks},
+    storage,
+    tip_poller::{IndexerTipWatch, Polled},
+    ScanTip,
+};
+#[cfg(test)]
+use dashmap::DashMap;
+#[cfg(test)]
+use tokio::sync::watch;
+
+struct Block {
+    header: core::HeaderView,
+    txs: Vec<core::TransactionView>,
+    uncles: Vec<core::BlockView>,
+}
+
+impl Block {
+    fn view(&self) -> ckb_jsonrpc_types::BlockView {
+        core::BlockBuilder::default()
+            .header(self.header.clone())
+            .transactions(self.txs.clone())
+            .uncles(self.uncles.iter().map(core::BlockView::as_uncle))
+            .build_unchecked()
+            .into()
+    }
+}
+
+#[derive(Default)]
+struct Chain {
+    blocks: Vec<Block>,
+    /// Tells the headers of blocks replacing forked ones apart
+    branch: u64,
+    delay: Duration,
+    /// Calls failing to reach the node before it answers again
+    failures: u32,
+    /// Fields of the search key filter `get_transactions` refuses
+    refused_filters: Vec<&'static str>,
+}
+
+impl Chain {
+    fn block(&self, number: u64) -> Option<&Block> {
+        self.blocks.get(number as usize)
+    }
+
+    fn output(&self, out_point: &packed::OutPoint) -> Option<packed::CellOutput> {
+        let tx_hash: H256 = out_point.tx_hash().unpack();
+        let index: u32 = out_point.index().unpack();
+        self.blocks
+            .iter()
+            .flat_map(|block| &block.txs)
+            .find(|tx| tx.hash().unpack() == tx_hash)
+            .and_then(|tx| tx.output(index as usize))
+    }
+}
+
+/// The chain starts with an empty genesis block, the indexer tip is always the last block. Epochs
+/// are 10 blocks long
+#[derive(Clone)]
+pub(crate) struct MockNode {
+    chain: Arc<Mutex<Chain>>,
+}
+
+impl MockNode {
+    pub fn new() -> Self {
+        let node = MockNode {
+            chain: Default::default(),
+        };
+        node.push_block(Vec::new());
+        node
+    }
+
+    /// Calls aren't retried, a failure fails the scan at once
+    pub fn client(&self) -> RpcClient {
+        RpcClient::with_transport("http://mock-node".parse().unwrap(), self.clone()).with_retry(
+            crate::rpc_client::RetryPolicy {
+                retries: 0,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn tip(&self) -> IndexerTip {
+        let chain = self.chain.lock().unwrap();
+        let header = &chain.blocks.last().unwrap().header;
+        IndexerTip {
+            block_hash: header.hash().unpack(),
+            block_number: header.number().into(),
+        }
+    }
+
+    /// The block on the current branch, as a scan tip
+    pub fn block(&self, number: u64) -> IndexerTip {
+        let chain = self.chain.lock().unwrap();
+        let header = &chain.block(number).expect("block beyond the tip").header;
+        IndexerTip {
+            block_hash: header.hash().unpack(),
+            block_number: header.number().into(),
+        }
+    }
+
+    /// Appends a block with the transactions, returns its number
+    pub fn push_block(&self, txs: Vec<core::TransactionView>) -> u64 {
+        self.push_block_with_uncles(txs, Vec::new())
+    }
+
+    /// Appends a block with the transactions and uncles, the node serves the uncles by hash too
+    pub fn push_block_with_uncles(
+        &self,
+        txs: Vec<core::TransactionView>,
+        uncles: Vec<core::BlockView>,
+    ) -> u64 {
+        let mut chain = self.chain.lock().unwrap();
+        let number = chain.blocks.len() as u64;
+        let parent_hash = chain
+            .blocks
+            .last()
+            .map(|block| block.header.hash())
+            .unwrap_or_default();
+        let header = HeaderBuilder::default()
+            .number(number.pack())
+            .parent_hash(parent_hash)
+            .nonce((chain.branch as u128).pack())
+            .compact_target(1u32.pack())
+            .epoch(EpochNumberWithFraction::new(number / 10, number % 10, 10).pack())
+            .timestamp((1_600_000_000_000 + number * 10_000).pack())
+            .extra_hash(
+                packed::UncleBlockVec::new_builder()
+                    .set(uncles.iter().map(|uncle| uncle.as_uncle().data()).collect())
+                    .build()
+                    .calc_uncles_hash(),
+            )
+            .build();
+        chain.blocks.push(Block {
+            header,
+            txs,
+            uncles,
+        });
+        number
+    }
+
+    /// Appends `count` blocks without transactions
+    pub fn push_empty(&self, count: u64) {
+        for _ in 0..count {
+            self.push_block(Vec::new());
+        }
+    }
+
+    /// Appends a block with a transaction creating a cell locked by `lock`, returns its out point
+    pub fn push_cell(&self, lock: &Script) -> packed::OutPoint {
+        let tx = cell_tx(lock, Vec::new());
+        self.push_block(vec![tx.clone()]);
+        packed::OutPoint::new(tx.hash(), 0)
+    }
+
+    /// Drops the last `depth` blocks, the blocks pushed next form the new branch
+    pub fn fork(&self, depth: u64) {
+        let mut chain = self.chain.lock().unwrap();
+        let keep = chain.blocks.len().saturating_sub(depth as usize).max(1);
+        chain.blocks.truncate(keep);
+        chain.branch += 1;
+    }
+
+    /// Every answer is sent this much later
+    pub fn set_delay(&self, delay: Duration) {
+        self.chain.lock().unwrap().delay = delay;
+    }
+
+    /// The next `count` calls fail like an unreachable node
+    pub fn fail_next(&self, count: u32) {
+        self.chain.lock().unwrap().failures = count;
+    }
+
+    /// The indexer refuses search keys filtering by these fields from now on, as some do
+    /// combinations
+    pub fn refuse_filters(&self, fields: &[&'static str]) {
+        self.chain.lock().unwrap().refused_filters = fields.to_vec();
+    }
+
+    fn answer(&self, request: &Value) -> io::Result<Value> {
+        let mut chain = self.chain.lock().unwrap();
+        if chain.failures > 0 {
+            chain.failures -= 1;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "mock node unreachable",
+            ));
+        }
+        let params = &request["params"];
+        let result = match request["method"].as_str().unwrap_or_default() {
+            "get_indexer_tip" => {
+                let header = &chain.blocks.last().unwrap().header;
+                serde_json::to_value(IndexerTip {
+                    block_hash: header.hash().unpack(),
+                    block_number: header.number().into(),
+                })?
+            }
+            "get_tip_header" => {
+                let header = &chain.blocks.last().unwrap().header;
+                serde_json::to_value(ckb_jsonrpc_types::HeaderView::from(header.clone()))?
+            }
+            "get_header_by_number" => {
+                let number =
+                    serde_json::from_value::<ckb_jsonrpc_types::BlockNumber>(params[0].clone())?;
+                let header = chain
+                    .block(number.value())
+                    .map(|block| ckb_jsonrpc_types::HeaderView::from(block.header.clone()));
+                serde_json::to_value(header)?
+            }
+            "get_header" => {
+                let hash = serde_json::from_value::<H256>(params[0].clone())?;
+                let header = chain
+                    .blocks
+                    .iter()
+                    .find(|block| block.header.hash().unpack() == hash)
+                    .map(|block| ckb_jsonrpc_types::HeaderView::from(block.header.clone()));
+                serde_json::to_value(header)?
+            }
+            "get_transaction" => {
+                let hash = serde_json::from_value::<H256>(params[0].clone())?;
+                let found = chain.blocks.iter().find_map(|block| {
+                    let tx = block.txs.iter().find(|tx| tx.hash().unpack() == hash)?;
+                    Some((tx.clone(), block.header.hash().unpack()))
+                });
+                match found {
+                    Some((tx, block_hash)) => json!({
+                        "transaction": ckb_jsonrpc_types::TransactionView::from(tx),
+                        "tx_status": TxStatus::committed(block_hash),
+                    }),
+                    None => json!({ "transaction": null, "tx_status": TxStatus::unknown() }),
+                }
+            }
+            "get_transactions" => {
+                let search_key = serde_json::from_value::<SearchKey>(params[0].clone())?;
+                let filter = serde_json::to_value(&search_key.filter)?;
+                if chain
+                    .refused_filters
+                    .iter()
+                    .any(|field| !filter[field].is_null())
+                {
+                    return Ok(json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "error": { "code": -32602, "message": "unsupported filter" },
+                    }));
+                }
+                let limit = serde_json::from_value::<Uint32>(params[2].clone())?.value() as usize;
+                let after = serde_json::from_value::<Option<JsonBytes>>(params[3].clone())?
+                    .map(|cursor| {
+                        let mut bytes = [0; 8];
+                        bytes.copy_from_slice(&cursor.as_bytes()[..8]);
+                        u64::from_be_bytes(bytes) as usize
+                    })
+                    .unwrap_or_default();
+                let txs = transactions(&chain, &search_key);
+                let objects = txs.into_iter().skip(after).take(limit).collect::<Vec<_>>();
+                let last_cursor = (after + objects.len()) as u64;
+                serde_json::to_value(Pagination {
+                    objects,
+                    last_cursor: JsonBytes::from_vec(last_cursor.to_be_bytes().to_vec()),
+                })?
+            }
+            "get_block" => {
+                let hash = serde_json::from_value::<H256>(params[0].clone())?;
+                let block = chain.blocks.iter().find_map(|block| {
+                    if block.header.hash().unpack() == hash {
+                        return Some(block.view());
+                    }
+                    let uncle = block
+                        .uncles
+                        .iter()
+                        .find(|uncle| uncle.hash().unpack() == hash)?;
+                    Some(uncle.clone().into())
+                });
+                serde_json::to_value(block)?
+            }
+            "get_block_by_number" => {
+                let number =
+                    serde_json::from_value::<ckb_jsonrpc_types::BlockNumber>(params[0].clone())?;
+                serde_json::to_value(chain.block(number.value()).map(Block::view))?
+            }
+            "get_block_hash" => {
+                let number =
+                    serde_json::from_value::<ckb_jsonrpc_types::BlockNumber>(params[0].clone())?;
+                let hash = chain
+                    .block(number.value())
+                    .map(|block| -> H256 { block.header.hash().unpack() });
+                serde_json::to_value(hash)?
+            }
+            method => {
+                return Ok(json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": { "code": -32601, "message": format!("mock node has no {}", method) },
+                }))
+            }
+        };
+        Ok(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+    }
+}
+
+impl Transport for MockNode {
+    fn send(&self, request: &Value) -> BoxFuture<'static, io::Result<hyper::body::Bytes>> {
+        let delay = self.chain.lock().unwrap().delay;
+        let answer = self.answer(request);
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            Ok(serde_json::to_vec(&answer?)?.into())
+        })
+    }
+}
+
+/// A transaction creating a cell locked by `lock`, spending `inputs`
+pub(crate) fn cell_tx(lock: &Script, inputs: Vec<packed::OutPoint>) -> core::TransactionView {
+    let output = packed::CellOutput::new_builder()
+        .lock(lock.clone().into())
+        .build_exact_capacity(Capacity::bytes(2).unwrap())
+        .unwrap();
+    let mut tx = TransactionBuilder::default()
+        .output(output)
+        .output_data(Bytes::from_static(b"\x12\x34").pack());
+    for input in inputs {
+        tx = tx.input(packed::CellInput::new(input, 0));
+    }
+    tx.build()
+}
+
+/// The indexer matches a script by code hash, hash type and args prefix
+fn matches(search: &Script, script: &packed::Script) -> bool {
+    let script = Script::from(script.clone());
+    script.code_hash == search.code_hash
+        && script.hash_type == search.hash_type
+        && script.args.as_bytes().starts_with(search.args.as_bytes())
+}
+
+/// Transactions with the key's cells in the block range, grouped, in block order.
+/// The other filters of the key aren't applied
+fn transactions(chain: &Chain, search_key: &SearchKey) -> Vec<TxWithCells> {
+    let [from, to] = search_key
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.block_range)
+        .map(|[from, to]| [from.value(), to.value()])
+        .unwrap_or([0, u64::MAX]);
+    let of_key = |output: &packed::CellOutput| match search_key.script_type {
+        ScriptType::Lock => matches(&search_key.script, &output.lock()),
+        ScriptType::Type => output
+            .type_()
+            .to_opt()
+            .is_some_and(|script| matches(&search_key.script, &script)),
+    };
+    let mut txs = Vec::new();
+    for block in chain.blocks.iter().filter(|block| {
+        let number = block.header.number();
+        from <= number && number < to
+    }) {
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let mut cells = Vec::new();
+            for (index, input) in tx.inputs().into_iter().enumerate() {
+                if chain
+                    .output(&input.previous_output())
+                    .is_some_and(|output| of_key(&output))
+                {
+                    cells.push((CellType::Input, (index as u32).into()));
+                }
+            }
+            for (index, output) in tx.outputs().into_iter().enumerate() {
+                if of_key(&output) {
+                    cells.push((CellType::Output, (index as u32).into()));
+                }
+            }
+            if !cells.is_empty() {
+                txs.push(TxWithCells {
+                    tx_hash: tx.hash().unpack(),
+                    block_number: block.header.number().into(),
+                    tx_index: (tx_index as u32).into(),
+                    cells,
+                });
+            }
+        }
+    }
+    txs
+}
+
+/// A registration of `key` scanned against a mock node, every `scan` sees the node's current tip
+#[cfg(test)]
+pub(crate) struct Harness {
+    pub node: MockNode,
+    pub process: CellProcess,
+    tips: watch::Sender<Polled>,
+    failures: u32,
+    /// Keys the scan completed
+    pub completed: tokio::sync::mpsc::UnboundedReceiver<RpcSearchKey>,
+}
+
+#[cfg(test)]
+impl Harness {
+    /// Registers the key at block `start` with a stdout sink and an in-memory store
+    pub fn new(node: MockNode, key: RpcSearchKey, start: IndexerTip) -> Self {
+        let client = node.client();
+        let storage = storage::open("memory", "", None, None).unwrap();
+        let (tips, indexer_tip) = IndexerTipWatch::channel();
+        let state = Arc::new(DashMap::new());
+        let scan_tip = ScanTip::new(start);
+        let (completed_tx, completed) = tokio::sync::mpsc::unbounded_channel();
+        state.insert(
+            key.clone(),
+            Registration {
+                scan_tip: scan_tip.clone(),
+                sink: SinkConfig::default(),
+                sink_profile: None,
+                label: None,
+                client: None,
+                scan_failure: None,
+                scan_panic: None,
+                lag_alert: None,
+                status: Status::Ok,
+                priority: Priority::Normal,
+                scan_parallelism: None,
+                max_blocks_per_second: None,
+                activate_at: None,
+                complete_after: None,
+                end_block: None,
+                registered_at: None,
+                request_id: None,
+                completed_at: None,
+                spilled_from: None,
+                subscribers: Vec::new(),
+            },
+        );
+        let process = CellProcess {
+            key: key.clone(),
+            scan_tip,
+            client: client.clone(),
+            indexer_tip,
+            sinks: Sinks {
+                storage,
+                unix_socket: None,
+                http: reqwest::Client::new(),
+                settings: Default::default(),
+                consumers: Default::default(),
+                plugin: None,
+                failed: Default::default(),
+                breakers: Default::default(),
+            },
+            state,
+            skip_unavailable: false,
+            backfill: Default::default(),
+            block_fallback: Default::default(),
+            tips: Default::default(),
+            stats: Default::default(),
+            scheduler: None,
+            deliveries: None,
+            buffer: None,
+            paused: Default::default(),
+            genesis: Arc::new(GenesisPin::load(None).unwrap()),
+            completed: completed_tx,
+        };
+        let genesis = node.block(0).block_hash;
+        process.genesis.record(genesis).unwrap();
+        Harness {
+            node,
+            process,
+            tips,
+            failures: 0,
+            completed,
+        }
+    }
+
+    /// Scans once against the node's tip, the backoff a failing scan would wait
+    pub async fn scan(&mut self) -> Option<Duration> {
+        self.tips.send_replace(Polled::Tip(self.node.tip()));
+        self.process.step(&mut self.failures).await
+    }
+
+    /// Scans once with the indexer at block `number`, behind the node's tip
+    pub async fn scan_lagging(&mut self, number: u64) -> Option<Duration> {
+        self.tips.send_replace(Polled::Tip(self.node.block(number)));
+        self.process.step(&mut self.failures).await
+    }
+
+    pub fn scan_tip(&self) -> IndexerTip {
+        self.process.scan_tip.load()
+    }
+
+    /// Block numbers of the logged events, in seq order
+    pub fn event_blocks(&self) -> Vec<u64> {
+        self.events()
+            .iter()
+            .map(|event| event.submit.header.inner.number.value())
+            .collect()
+    }
+
+    pub fn events(&self) -> Vec<LoggedEvent> {
+        self.process
+            .sinks
+            .storage
+            .read_events(0, u64::MAX, usize::MAX, &|_| true)
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        consumer::ConsumerCursors,
+        lifecycle::Lifecycle,
+        rpc_server::{EmitterRpc, EmitterServer},
+        sink::{CellConsumer, Cells},
+    };
+
+    fn lock(args: u8) -> Script {
+        Script {
+            code_hash: H256::default(),
+            hash_type: ckb_jsonrpc_types::ScriptHashType::Type,
+            args: JsonBytes::from_vec(vec![args, 7]),
+        }
+    }
+
+    fn key(args: u8) -> RpcSearchKey {
+        RpcSearchKey {
+            script: Script {
+                args: JsonBytes::from_vec(vec![args]),
+                ..lock(args)
+            },
+            script_type: ScriptType::Lock,
+            filter: None,
+            with_data: None,
+            with_uncles: None,
+            with_epochs: None,
+            with_witnesses: None,
+            with_input_cells: None,
+            track_lineage: None,
+            track_live_cells: None,
+            discover: None,
+            correlate: None,
+            summary: None,
+            transactions_only: None,
+            namespace: None,
+        }
+    }
+
+    /// The rpc methods over the registrations of a fresh lifecycle
+    fn rpc(node: &MockNode, config: Config) -> EmitterRpc {
+        let sinks = harness(node).process.sinks.clone();
+        rpc_with(node, Arc::new(DashMap::new()), sinks, config)
+    }
+
+    /// The rpc methods over the registration and the store of the harness, which alone scans it
+    fn rpc_over(harness: &Harness, config: Config) -> EmitterRpc {
+        let (state, sinks) = (harness.process.state.clone(), harness.process.sinks.clone());
+        rpc_with(&harness.node, state, sinks, config)
+    }
+
+    fn rpc_with(
+        node: &MockNode,
+        state: Arc<DashMap<RpcSearchKey, Registration>>,
+        sinks: Sinks,
+        config: Config,
+    ) -> EmitterRpc {
+        let (_tips, indexer_tip) = IndexerTipWatch::channel();
+        let registrations = Lifecycle::new(
+            state,
+            node.client(),
+            indexer_tip,
+            sinks.clone(),
+            Arc::new(GenesisPin::load(None).unwrap()),
+            &config,
+        );
+        EmitterRpc {
+            registrations,
+            client: node.client(),
+            cursors: ConsumerCursors::load(sinks.storage.clone()).unwrap(),
+            sinks,
+            network: None,
+            read_only: false,
+            quotas: config.quotas.clone(),
+            audit: None,
+            started: std::time::Instant::now(),
+            merge_duplicates: false,
+            shutdown: Default::default(),
+            snapshot_dir: std::env::temp_dir(),
+            cipher: None,
+            config,
+        }
+    }
+
+    fn harness(node: &MockNode) -> Harness {
+        Harness::new(node.clone(), key(1), node.block(0))
+    }
+
+    #[tokio::test]
+    async fn scan_emits_cells_in_block_order() {
+        let node = MockNode::new();
+        let spent = node.push_cell(&lock(1));
+        node.push_cell(&lock(2));
+        node.push_block(vec![cell_tx(&lock(3), vec![spent])]);
+        node.push_empty(30);
+        let mut harness = harness(&node);
+
+        assert_eq!(harness.scan().await, None);
+        // 24 blocks stay unscanned for reorgs, the input spending block 1's cell is emitted
+        assert_eq!(harness.event_blocks(), vec![1, 3]);
+        assert_eq!(harness.scan_tip().block_number.value(), 33 - 24);
+        let events = harness.events();
+        assert_eq!(events[0].submit.outputs.len(), 1);
+        assert_eq!(events[1].submit.inputs.len(), 1);
+        // the mock chain's genesis is no public network's
+        let envelope = serde_json::to_value(events[0].as_event()).unwrap();
+        let genesis = format!("{:#x}", node.block(0).block_hash);
+        assert_eq!(envelope["chain"], format!("devnet:{}", &genesis[..10]));
+    }
+
+    #[tokio::test]
+    async fn forked_scan_tip_rescans_the_new_branch() {
+        let node = MockNode::new();
+        node.push_empty(40);
+        let mut harness = harness(&node);
+        harness.scan().await;
+        let tip = harness.scan_tip();
+        assert_eq!(tip.block_number.value(), 40 - 24);
+
+        // the blocks from 10 on are replaced, the new branch has a cell at block 12
+        node.fork(31);
+        node.push_empty(2);
+        node.push_cell(&lock(1));
+        node.push_empty(40);
+        harness.scan().await;
+        assert_eq!(
+            harness.scan_tip().block_number.value(),
+            tip.block_number.value().saturating_sub(24)
+        );
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![12]);
+        let header = &harness.events()[0].submit.header;
+        assert_eq!(header.hash, node.block(12).block_hash);
+    }
+
+    #[tokio::test]
+    async fn unreachable_or_slow_node_fails_the_scan() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+
+        node.fail_next(1);
+        assert!(harness.scan().await.is_some());
+        assert!(harness.event_blocks().is_empty());
+
+        node.set_delay(Duration::from_millis(50));
+        harness.process.client = node.client().with_timeout(Duration::from_millis(10));
+        assert!(harness.scan().await.is_some());
+        assert_eq!(harness.scan_tip().block_number.value(), 0);
+
+        harness.process.client = node.client();
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn scans_refuse_a_node_of_another_chain() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let dir = tempfile::tempdir().unwrap();
+        let pin = GenesisPin::load(Some(dir.path().into())).unwrap();
+        pin.check(&node.client()).await.unwrap();
+        // a restart reads the pin, the node behind the uri was replaced by another chain's
+        let pin = Arc::new(GenesisPin::load(Some(dir.path().into())).unwrap());
+        assert_eq!(pin.pinned(), Some(node.block(0).block_hash));
+        pin.record(H256::from([1; 32])).unwrap();
+        harness.process.genesis = pin.clone();
+
+        assert!(harness.scan().await.is_some());
+        let failure = harness
+            .process
+            .state
+            .get(&key(1))
+            .unwrap()
+            .scan_failure
+            .clone();
+        assert!(failure.unwrap().error.contains("another chain"));
+        assert!(harness.event_blocks().is_empty());
+
+        pin.check(&node.client()).await.unwrap();
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn paused_scan_resumes_where_it_stopped() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+
+        harness
+            .process
+            .paused
+            .store(true, std::sync::atomic::Ordering::Release);
+        assert_eq!(harness.scan().await, None);
+        assert!(harness.event_blocks().is_empty());
+        assert_eq!(harness.scan_tip().block_number.value(), 0);
+
+        harness
+            .process
+            .paused
+            .store(false, std::sync::atomic::Ordering::Release);
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1]);
+    }
+
+    /// Records the blocks it got, fails while `fail` is set, on the block in `fail_block` and the
+    /// next `fail_calls` times it is called
+    #[derive(Clone, Default)]
+    struct Recorder {
+        blocks: Arc<Mutex<Vec<u64>>>,
+        fail: Arc<std::sync::atomic::AtomicBool>,
+        fail_block: Arc<Mutex<Option<u64>>>,
+        fail_calls: Arc<Mutex<u32>>,
+        delay: Arc<Mutex<Duration>>,
+    }
+
+    impl CellConsumer for Recorder {
+        fn on_cells<'a>(
+            &'a self,
+            _key: &'a RpcSearchKey,
+            block: &'a ckb_jsonrpc_types::HeaderView,
+            cells: Cells<'a>,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                let delay = *self.delay.lock().unwrap();
+                tokio::time::sleep(delay).await;
+                let failing = {
+                    let mut fail_calls = self.fail_calls.lock().unwrap();
+                    let failing = *fail_calls > 0;
+                    *fail_calls = fail_calls.saturating_sub(1);
+                    failing
+                };
+                if self.fail.load(std::sync::atomic::Ordering::Relaxed)
+                    || *self.fail_block.lock().unwrap() == Some(block.inner.number.value())
+                    || failing
+                {
+                    return Err(io::Error::other("consumer busy"));
+                }
+                assert_eq!(cells.outputs.len(), 1);
+                self.blocks.lock().unwrap().push(block.inner.number.value());
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn cell_dep_filter_matches_direct_and_dep_group_deps() {
+        let node = MockNode::new();
+        let binary = node.push_cell(&lock(9));
+        let group = {
+            let data = packed::OutPointVec::new_builder()
+                .push(binary.clone())
+                .build();
+            let tx = TransactionBuilder::default()
+                .output(
+                    packed::CellOutput::new_builder()
+                        .lock(lock(9).into())
+                        .build(),
+                )
+                .output_data(data.as_bytes().pack())
+                .build();
+            node.push_block(vec![tx.clone()]);
+            packed::OutPoint::new(tx.hash(), 0)
+        };
+        let using = |dep: &packed::OutPoint, dep_type: core::DepType| {
+            let tx = cell_tx(&lock(1), Vec::new());
+            tx.as_advanced_builder()
+                .cell_dep(
+                    packed::CellDep::new_builder()
+                        .out_point(dep.clone())
+                        .dep_type(dep_type.into())
+                        .build(),
+                )
+                .build()
+        };
+        node.push_block(vec![using(&binary, core::DepType::Code)]);
+        node.push_cell(&lock(1));
+        node.push_block(vec![using(&group, core::DepType::DepGroup)]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            cell_dep: Some(binary.into()),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![3, 5]);
+    }
+
+    #[tokio::test]
+    async fn header_dep_filter_matches_anchored_transactions() {
+        let node = MockNode::new();
+        node.push_empty(1);
+        let anchor = node.block(1).block_hash;
+        let tx = cell_tx(&lock(1), Vec::new())
+            .as_advanced_builder()
+            .header_dep(anchor.pack())
+            .build();
+        node.push_block(vec![tx]);
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            header_dep: Some(anchor),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn since_filter_matches_time_locked_spends() {
+        let node = MockNode::new();
+        let locked = node.push_cell(&lock(1));
+        let unlocked = node.push_cell(&lock(1));
+        // relative, 10 epochs
+        let since = (1 << 63) | (1 << 61) | 10;
+        let spend = TransactionBuilder::default()
+            .input(packed::CellInput::new(locked, since))
+            .output(
+                packed::CellOutput::new_builder()
+                    .lock(lock(2).into())
+                    .build(),
+            )
+            .output_data(Bytes::new().pack())
+            .build();
+        node.push_block(vec![spend]);
+        node.push_block(vec![cell_tx(&lock(2), vec![unlocked])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            since: Some(crate::since::SinceFilter {
+                relative: Some(true),
+                metric: None,
+            }),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![3]);
+        let since = &harness.events()[0].submit.since;
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].metric, crate::since::SinceMetric::Epoch);
+        assert_eq!(since[0].value.value(), 10);
+    }
+
+    #[tokio::test]
+    async fn fee_range_filter_resolves_spent_capacities() {
+        let tx = |inputs: Vec<packed::OutPoint>, capacity: u64| {
+            let output = packed::CellOutput::new_builder()
+                .lock(lock(1).into())
+                .capacity(capacity.pack())
+                .build();
+            let mut tx = TransactionBuilder::default()
+                .output(output.clone())
+                .output(output)
+                .outputs_data(vec![Bytes::new().pack(), Bytes::new().pack()]);
+            for input in inputs {
+                tx = tx.input(packed::CellInput::new(input, 0));
+            }
+            tx.build()
+        };
+        let node = MockNode::new();
+        let created = tx(Vec::new(), 1000_0000_0000);
+        node.push_block(vec![created.clone()]);
+        let cheap = tx(
+            vec![packed::OutPoint::new(created.hash(), 0)],
+            500_0000_0000,
+        );
+        let paying = tx(
+            vec![packed::OutPoint::new(created.hash(), 1)],
+            499_9000_0000,
+        );
+        node.push_block(vec![cheap]);
+        node.push_block(vec![paying.clone()]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            fee_range: Some([1000_0000.into(), 1_0000_0000.into()]),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![3]);
+        let fees = &harness.events()[0].submit.fees;
+        assert_eq!(fees[0].tx_hash, paying.hash().unpack());
+        assert_eq!(fees[0].fee.value(), 2000_0000);
+    }
+
+    #[tokio::test]
+    async fn data_pattern_filter_drops_other_outputs() {
+        let node = MockNode::new();
+        let with_data = |data: &'static [u8]| {
+            TransactionBuilder::default()
+                .output(
+                    packed::CellOutput::new_builder()
+                        .lock(lock(1).into())
+                        .build(),
+                )
+                .output_data(Bytes::from_static(data).pack())
+                .build()
+        };
+        let matching = with_data(b"\x00\x01\xaa\x02");
+        node.push_block(vec![matching.clone(), with_data(b"\x02")]);
+        node.push_block(vec![with_data(b"\x01\xbb\x03")]);
+        node.push_block(vec![with_data(b"\x01")]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            data_pattern: Some("0x01??02".parse().unwrap()),
+            ..Default::default()
+        });
+        key.with_witnesses = Some(crate::witness::WitnessSelection::ScriptGroup);
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1]);
+        // the dropped transaction leaves nothing on the event
+        let submit = &harness.events()[0].submit;
+        assert_eq!(submit.outputs.len(), 1);
+        let tx_hashes = submit
+            .witnesses
+            .iter()
+            .map(|witnesses| witnesses.tx_hash.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(tx_hashes, vec![matching.hash().unpack()]);
+        for invalid in ["01", "0x0", "0x0g", "0x"] {
+            assert!(invalid.parse::<crate::pattern::DataPattern>().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn output_data_filter_is_checked_without_indexer_support() {
+        let node = MockNode::new();
+        let with_data = |data: &'static [u8]| {
+            TransactionBuilder::default()
+                .output(
+                    packed::CellOutput::new_builder()
+                        .lock(lock(1).into())
+                        .build(),
+                )
+                .output_data(Bytes::from_static(data).pack())
+                .build()
+        };
+        node.push_block(vec![with_data(b"\x00\x01\xaa\x02")]);
+        node.push_block(vec![with_data(b"\x01\xbb\x03")]);
+        node.push_block(vec![with_data(b"\x01")]);
+        node.push_empty(30);
+
+        use crate::rpc_client::OutputDataMode;
+        for (data, mode, blocks) in [
+            (vec![1], None, vec![2, 3]),
+            (vec![1], Some(OutputDataMode::Exact), vec![3]),
+            (vec![0xbb], Some(OutputDataMode::Partial), vec![2]),
+        ] {
+            let mut key = key(1);
+            key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+                output_data: Some(JsonBytes::from_vec(data)),
+                output_data_filter_mode: mode,
+                ..Default::default()
+            });
+            let mut harness = Harness::new(node.clone(), key, node.block(0));
+            assert_eq!(harness.scan().await, None);
+            assert_eq!(harness.event_blocks(), blocks);
+        }
+    }
+
+    #[tokio::test]
+    async fn args_pattern_filter_checks_created_and_spent_cells() {
+        let script = |args: &[u8]| Script {
+            args: JsonBytes::from_vec(args.to_vec()),
+            ..lock(1)
+        };
+        let node = MockNode::new();
+        let matching = node.push_cell(&script(&[1, 9, 5, 0]));
+        let other = node.push_cell(&script(&[1, 9, 6]));
+        node.push_block(vec![cell_tx(&lock(2), vec![other])]);
+        node.push_block(vec![cell_tx(&lock(2), vec![matching])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            args_pattern: Some("0x01??05".parse().unwrap()),
+            ..Default::default()
+        });
+        assert_eq!(key.check(), Ok(()));
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1, 4]);
+
+        key.script.args = JsonBytes::from_vec(vec![2]);
+        assert!(key.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn min_capacity_change_filter_drops_dust_moves() {
+        let tx = |lock: Script, input: Option<packed::OutPoint>, capacity: u64| {
+            let output = packed::CellOutput::new_builder()
+                .lock(lock.into())
+                .capacity(capacity.pack())
+                .build();
+            let tx = TransactionBuilder::default()
+                .output(output)
+                .output_data(Bytes::new().pack());
+            match input {
+                Some(input) => tx.input(packed::CellInput::new(input, 0)).build(),
+                None => tx.build(),
+            }
+        };
+        let node = MockNode::new();
+        let created = tx(lock(1), None, 1000_0000_0000);
+        let kept = tx(
+            lock(1),
+            Some(packed::OutPoint::new(created.hash(), 0)),
+            999_9990_0000,
+        );
+        let sent = tx(
+            lock(2),
+            Some(packed::OutPoint::new(kept.hash(), 0)),
+            999_9980_0000,
+        );
+        node.push_block(vec![created]);
+        node.push_block(vec![kept]);
+        node.push_block(vec![sent]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            min_capacity_change: Some(1_0000_0000.into()),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1, 3]);
+        let change = harness.events()[1].submit.capacity_change.clone().unwrap();
+        assert_eq!(change.created.value(), 0);
+        assert_eq!(change.spent.value(), 999_9990_0000);
+    }
+
+    #[tokio::test]
+    async fn min_transactions_filter_keeps_busy_blocks() {
+        let other = Script {
+            args: JsonBytes::from_vec(vec![1, 8]),
+            ..lock(1)
+        };
+        let node = MockNode::new();
+        let spent = node.push_cell(&lock(1));
+        node.push_block(vec![
+            cell_tx(&lock(1), vec![spent]),
+            cell_tx(&other, Vec::new()),
+        ]);
+        node.push_cell(&other);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            min_transactions: Some(2.into()),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn cellbase_outputs_are_excluded_or_tagged() {
+        let node = MockNode::new();
+        let reward = TransactionBuilder::default()
+            .input(packed::CellInput::new_cellbase_input(1))
+            .output(
+                packed::CellOutput::new_builder()
+                    .lock(lock(1).into())
+                    .build(),
+            )
+            .output_data(Bytes::new().pack())
+            .build();
+        node.push_block(vec![reward]);
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+
+        let with_cellbase = |mode| {
+            let mut key = key(1);
+            key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+                cellbase: Some(mode),
+                ..Default::default()
+            });
+            Harness::new(node.clone(), key, node.block(0))
+        };
+        let mut excluded = with_cellbase(crate::rpc_server::CellbaseMode::Exclude);
+        assert_eq!(excluded.scan().await, None);
+        assert_eq!(excluded.event_blocks(), vec![2]);
+
+        let mut tagged = with_cellbase(crate::rpc_server::CellbaseMode::Tag);
+        assert_eq!(tagged.scan().await, None);
+        assert_eq!(tagged.event_blocks(), vec![1, 2]);
+        let events = tagged.events();
+        assert_eq!(events[0].submit.cellbase_outputs, vec![0.into()]);
+        assert!(events[1].submit.cellbase_outputs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn uncles_are_events_of_keys_asking_for_them() {
+        let witness = packed::CellbaseWitness::new_builder()
+            .lock(lock(9).into())
+            .build();
+        let uncle = core::BlockBuilder::default()
+            .number(1u64.pack())
+            .nonce(99u128.pack())
+            .compact_target(1u32.pack())
+            .epoch(EpochNumberWithFraction::new(0, 1, 2).pack())
+            .transaction(
+                TransactionBuilder::default()
+                    .input(packed::CellInput::new_cellbase_input(1))
+                    .witness(witness.as_bytes().pack())
+                    .build(),
+            )
+            .build();
+        let node = MockNode::new();
+        node.push_block_with_uncles(Vec::new(), vec![uncle.clone()]);
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.with_uncles = Some(true);
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1, 2]);
+        let uncles = &harness.events()[0].submit.uncles;
+        assert_eq!(uncles.len(), 1);
+        assert_eq!(uncles[0].hash, uncle.hash().unpack());
+        assert_eq!(uncles[0].miner_lock, Some(lock(9)));
+        assert!(harness.events()[1].submit.uncles.is_empty());
+    }
+
+    #[tokio::test]
+    async fn epoch_starts_are_events_of_keys_asking_for_them() {
+        let node = MockNode::new();
+        node.push_empty(4);
+        node.push_cell(&lock(1));
+        node.push_empty(40);
+
+        let mut key = key(1);
+        key.with_epochs = Some(true);
+        let mut harness = Harness::new(node.clone(), key, node.block(3));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![5, 10, 20]);
+        let events = harness.events();
+        assert_eq!(events[0].submit.epoch, None);
+        let epoch = events[2].submit.epoch.clone().unwrap();
+        assert_eq!(epoch.number.value(), 2);
+        assert_eq!(epoch.start_number.value(), 20);
+        assert_eq!(epoch.length.value(), 10);
+    }
+
+    #[tokio::test]
+    async fn witnesses_of_the_script_group_or_all() {
+        let node = MockNode::new();
+        let other = node.push_cell(&lock(2));
+        let matched = node.push_cell(&lock(1));
+        let spending = cell_tx(&lock(2), vec![other, matched])
+            .as_advanced_builder()
+            .witnesses(
+                [&b"\x00"[..], b"\x01", b"\x02"].map(|witness| Bytes::from_static(witness).pack()),
+            )
+            .build();
+        node.push_block(vec![spending]);
+        node.push_empty(30);
+
+        let witnesses = |selection| {
+            let node = node.clone();
+            async move {
+                let mut key = key(1);
+                key.with_witnesses = Some(selection);
+                let mut harness = Harness::new(node.clone(), key, node.block(3));
+                assert_eq!(harness.scan().await, None);
+                let events = harness.events();
+                assert_eq!(events.len(), 1);
+                events[0].submit.witnesses[0]
+                    .witnesses
+                    .iter()
+                    .map(|witness| (witness.index.value(), witness.data.as_bytes().to_vec()))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            witnesses(crate::witness::WitnessSelection::ScriptGroup).await,
+            vec![(1, vec![1])]
+        );
+        assert_eq!(
+            witnesses(crate::witness::WitnessSelection::All).await.len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn input_cells_are_resolved_in_the_order_of_the_inputs() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        let second = node.push_cell(&Script {
+            args: JsonBytes::from_vec(vec![1, 8]),
+            ..lock(1)
+        });
+        node.push_block(vec![cell_tx(&lock(2), vec![second, first])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.with_input_cells = Some(true);
+        let mut harness = Harness::new(node.clone(), key, node.block(3));
+        assert_eq!(harness.scan().await, None);
+        let submit = &harness.events()[0].submit;
+        assert_eq!(submit.inputs.len(), 2);
+        let args = submit
+            .input_cells
+            .iter()
+            .map(|cell| cell.output.lock.args.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(args, vec![vec![1, 8], vec![1, 7]]);
+        let data = submit.input_cells[0].data.as_ref().unwrap();
+        assert_eq!(data.content.as_bytes(), b"\x12\x34");
+    }
+
+    #[tokio::test]
+    async fn cells_are_located_in_their_block() {
+        let node = MockNode::new();
+        let created = cell_tx(&lock(1), Vec::new());
+        node.push_block(vec![cell_tx(&lock(2), Vec::new()), created.clone()]);
+        let spending = cell_tx(&lock(2), vec![packed::OutPoint::new(created.hash(), 0)]);
+        node.push_block(vec![spending.clone()]);
+        node.push_empty(30);
+
+        let mut harness = harness(&node);
+        assert_eq!(harness.scan().await, None);
+        let events = harness.events();
+        let located = |tx: &core::TransactionView, tx_index: u32, io_type, block: u64| {
+            let tx_hash = tx.hash().unpack();
+            vec![crate::location::CellLocation {
+                idempotency_key: crate::location::idempotency_key(
+                    &tx_hash,
+                    0.into(),
+                    io_type,
+                    &node.block(block).block_hash,
+                ),
+                tx_hash,
+                tx_index: tx_index.into(),
+                index: 0.into(),
+            }]
+        };
+        assert_eq!(
+            events[0].submit.output_locations,
+            located(&created, 1, CellType::Output, 1)
+        );
+        assert_eq!(
+            events[1].submit.input_locations,
+            located(&spending, 0, CellType::Input, 2)
+        );
+        // a cell in another block after a reorg gets another key
+        assert_ne!(
+            located(&created, 1, CellType::Output, 1),
+            located(&created, 1, CellType::Output, 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn lineage_links_the_creating_and_consuming_transactions() {
+        let node = MockNode::new();
+        let created = node.push_cell(&lock(1));
+        let consuming = cell_tx(&lock(2), vec![created.clone()]);
+        node.push_block(vec![consuming.clone()]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.track_lineage = Some(true);
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let links = harness
+            .process
+            .sinks
+            .storage
+            .load_cell_links(&created.clone().into())
+            .unwrap();
+        let histories = crate::lineage::histories(links);
+        assert_eq!(histories.len(), 1);
+        assert_eq!(histories[0].key, key);
+        let created_by = histories[0].created_by.as_ref().unwrap();
+        assert_eq!(created_by.tx_hash, created.tx_hash().unpack());
+        assert_eq!(created_by.block_number.value(), 1);
+        let consumed_by = histories[0].consumed_by.as_ref().unwrap();
+        assert_eq!(consumed_by.tx_hash, consuming.hash().unpack());
+        assert_eq!(consumed_by.block_number.value(), 2);
+    }
+
+    #[tokio::test]
+    async fn live_cells_follow_the_emitted_outputs_and_inputs() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        let second = node.push_cell(&Script {
+            args: JsonBytes::from_vec(vec![1, 8]),
+            ..lock(1)
+        });
+        node.push_block(vec![cell_tx(&lock(2), vec![first.clone()])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.track_live_cells = Some(true);
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let diffs = harness
+            .events()
+            .iter()
+            .map(|event| {
+                let diff = event.submit.live_cells.clone().unwrap();
+                (diff.added.len(), diff.removed, diff.count.value())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            diffs,
+            vec![
+                (1, vec![], 1),
+                (1, vec![], 2),
+                (0, vec![first.clone().into()], 1)
+            ]
+        );
+        let live = harness.process.sinks.storage.load_live_cells(&key).unwrap();
+        assert_eq!(
+            live.iter().map(|cell| &cell.out_point).collect::<Vec<_>>(),
+            vec![&second.clone().into()]
+        );
+        let summary = crate::live_cells::LiveSummary::new(&live, Some(3));
+        assert_eq!(
+            (summary.count.value(), summary.capacity, summary.udts.len()),
+            (1, live[0].capacity, 0)
+        );
+        // undoing the spend of the last block
+        let later = harness.events().into_iter().skip(2).collect::<Vec<_>>();
+        let at_second =
+            crate::live_cells::sorted(crate::live_cells::rewind(live, &later, 2).unwrap());
+        assert_eq!(
+            at_second
+                .iter()
+                .map(|cell| &cell.out_point)
+                .collect::<Vec<_>>(),
+            vec![&first.into(), &second.into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn live_cells_are_paged_as_of_a_block_before_a_spend() {
+        let node = MockNode::new();
+        // args extending the key's, the transactions differ
+        let cells = (0..3)
+            .map(|i| {
+                node.push_cell(&Script {
+                    args: JsonBytes::from_vec(vec![1, i]),
+                    ..lock(1)
+                })
+            })
+            .collect::<Vec<_>>();
+        node.push_block(vec![cell_tx(&lock(2), vec![cells[0].clone()])]);
+        node.push_empty(30);
+        let mut key = key(1);
+        key.track_live_cells = Some(true);
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        harness.scan().await;
+        let rpc = rpc_over(&harness, Config::default());
+
+        let pages = |at_block: Option<u64>, limit: u32| {
+            let (rpc, key) = (&rpc, key.clone());
+            async move {
+                let (mut pages, mut cursor) = (Vec::new(), None);
+                loop {
+                    let page = rpc
+                        .get_live_cells(
+                            key.clone(),
+                            at_block.map(Into::into),
+                            cursor,
+                            Some(limit.into()),
+                        )
+                        .await
+                        .unwrap();
+                    let out_points = page
+                        .cells
+                        .into_iter()
+                        .map(|cell| packed::OutPoint::from(cell.out_point))
+                        .collect::<Vec<_>>();
+                    pages.push((page.block_number.value(), out_points));
+                    match page.next_cursor {
+                        Some(next) => cursor = Some(next),
+                        None => return pages,
+                    }
+                }
+            }
+        };
+        // the scan tip is at block 10, the spend was at block 4
+        assert_eq!(
+            pages(None, 1).await,
+            vec![(9, vec![cells[1].clone()]), (9, vec![cells[2].clone()])]
+        );
+        assert_eq!(
+            pages(Some(3), 2).await,
+            vec![
+                (3, vec![cells[0].clone(), cells[1].clone()]),
+                (3, vec![cells[2].clone()])
+            ]
+        );
+        assert_eq!(pages(Some(1), 2).await, vec![(1, vec![cells[0].clone()])]);
+        assert!(rpc
+            .get_live_cells(key.clone(), Some(10.into()), None, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn summary_totals_the_live_udt_cells_by_token() {
+        let node = MockNode::new();
+        let sudt = |owner: u8| Script {
+            code_hash: ckb_types::h256!(
+                "0x5e7a36a77e68eecc013dfa2fe6a23f3b6c344b04005808694ae6dd45eea4cfd5"
+            ),
+            hash_type: ckb_jsonrpc_types::ScriptHashType::Type,
+            args: JsonBytes::from_vec(vec![owner]),
+        };
+        let udt_tx = |type_: &Script, amount: u128| {
+            let tx = TransactionBuilder::default()
+                .output(
+                    packed::CellOutput::new_builder()
+                        .lock(lock(1).into())
+                        .type_(Some(packed::Script::from(type_.clone())).pack())
+                        .capacity(Capacity::shannons(100).pack())
+                        .build(),
+                )
+                .output_data(Bytes::from(amount.to_le_bytes().to_vec()).pack())
+                .build();
+            node.push_block(vec![tx.clone()]);
+            packed::OutPoint::new(tx.hash(), 0)
+        };
+        let (first, second) = (sudt(1), sudt(2));
+        udt_tx(&first, 100);
+        let spent = udt_tx(&first, 50);
+        udt_tx(&second, 7);
+        node.push_cell(&lock(1));
+        let spending = node.push_block(vec![cell_tx(&lock(2), vec![spent])]);
+        node.push_empty(30);
+        let mut key = key(1);
+        key.track_live_cells = Some(true);
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        harness.scan().await;
+        let rpc = rpc_over(&harness, Config::default());
+
+        let summary = rpc.get_summary(key).await.unwrap();
+        assert_eq!(summary.count.value(), 3);
+        let plain: u64 = cell_tx(&lock(1), vec![])
+            .output(0)
+            .unwrap()
+            .capacity()
+            .unpack();
+        assert_eq!(summary.capacity.value(), 100 + 100 + plain);
+        let mut udts = [(first, 100), (second, 7)]
+            .map(|(type_, amount)| {
+                let type_hash: H256 = packed::Script::from(type_).calc_script_hash().unpack();
+                (type_hash, amount, 1)
+            })
+            .to_vec();
+        udts.sort();
+        let totals = summary
+            .udts
+            .iter()
+            .map(|udt| (udt.type_hash.clone(), udt.amount.value(), udt.cells.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(totals, udts);
+        assert_eq!(summary.last_change.map(|at| at.value()), Some(spending));
+    }
+
+    #[tokio::test]
+    async fn stats_count_the_emitted_cells_once() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        node.push_block(vec![cell_tx(&lock(2), vec![first])]);
+        node.push_empty(30);
+
+        let key = key(1);
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let stats = harness.process.sinks.storage.load_stats(&key).unwrap();
+        assert_eq!((stats.events, stats.cells, stats.transactions), (2, 2, 2));
+        let bytes = harness
+            .events()
+            .iter()
+            .map(|logged| serde_json::to_vec(&logged.as_event()).unwrap().len() as u64)
+            .sum::<u64>();
+        assert_eq!(stats.bytes_delivered, bytes);
+        assert_eq!(stats.days.keys().collect::<Vec<_>>(), vec!["2020-09-13"]);
+    }
+
+    #[tokio::test]
+    async fn discovery_emits_the_first_cell_of_each_lock() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        node.push_cell(&lock(1));
+        node.push_cell(&Script {
+            args: JsonBytes::from_vec(vec![1, 8]),
+            ..lock(1)
+        });
+        node.push_block(vec![cell_tx(&lock(2), vec![first])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.discover = Some(true);
+        let mut harness = Harness::new(node.clone(), key.clone(), node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1, 3]);
+        assert_eq!(
+            harness
+                .process
+                .sinks
+                .storage
+                .load_discovered_scripts(&key)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn correlations_pair_consumed_and_created_cells() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        let swap = cell_tx(&lock(2), vec![first]);
+        node.push_block(vec![swap.clone()]);
+        node.push_empty(30);
+
+        let correlations = |args: u8| {
+            let node = node.clone();
+            async move {
+                let mut key = key(1);
+                key.correlate = Some(crate::correlation::CorrelationRule {
+                    script: lock(args),
+                    script_type: ScriptType::Lock,
+                });
+                let mut harness = Harness::new(node.clone(), key, node.block(0));
+                assert_eq!(harness.scan().await, None);
+                harness
+                    .events()
+                    .iter()
+                    .flat_map(|event| event.submit.correlations.clone())
+                    .collect::<Vec<_>>()
+            }
+        };
+        let found = correlations(2).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].tx_hash, swap.hash().unpack());
+        assert_eq!(found[0].consumed, vec![0.into()]);
+        assert_eq!(found[0].created[0].index.value(), 0);
+        assert!(correlations(3).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn summaries_replace_the_cells() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        node.push_block(vec![cell_tx(&lock(2), vec![first])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.summary = Some(crate::summary::SummaryMode::Instead);
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let events = harness.events();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.submit.outputs.is_empty()
+            && event.submit.inputs.is_empty()
+            && event.submit.capacity_change.is_none()));
+        let created = events[0].submit.summary.clone().unwrap();
+        let spent = events[1].submit.summary.clone().unwrap();
+        assert_eq!((created.created.value(), created.spent.value()), (1, 0));
+        assert_eq!((spent.created.value(), spent.spent.value()), (0, 1));
+        assert_eq!(spent.capacity_in, created.capacity_out);
+        assert_eq!(spent.transactions.value(), 1);
+    }
+
+    #[tokio::test]
+    async fn lagging_registration_takes_its_action() {
+        use crate::global_state::{LagAction, LagThreshold, Status};
+
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let lagging = |harness: &Harness, action, status| {
+            let mut registration = harness.process.state.get_mut(&key(1)).unwrap();
+            registration.lag_alert = Some(LagThreshold {
+                max_blocks: Some(1),
+                max_secs: None,
+                action,
+            });
+            registration.status = status;
+        };
+
+        lagging(&harness, LagAction::Pause, Status::Paused);
+        assert_eq!(harness.scan().await, None);
+        assert!(harness.event_blocks().is_empty());
+
+        lagging(&harness, LagAction::Summary, Status::Degraded);
+        assert_eq!(harness.scan().await, None);
+        let events = harness.events();
+        assert_eq!(harness.event_blocks(), vec![1]);
+        assert!(events[0].submit.outputs.is_empty());
+        assert_eq!(events[0].submit.summary.clone().unwrap().created.value(), 1);
+    }
+
+    #[tokio::test]
+    async fn transactions_only_lists_the_hashes() {
+        let node = MockNode::new();
+        let created = node.push_cell(&lock(1));
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.transactions_only = Some(true);
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let submit = &harness.events()[0].submit;
+        assert!(submit.outputs.is_empty() && submit.output_scripts.is_empty());
+        let hash: H256 = created.tx_hash().unpack();
+        assert_eq!(submit.transactions, vec![hash]);
+    }
+
+    #[tokio::test]
+    async fn cheques_are_decoded_when_created_and_claimed() {
+        let cheque = Script {
+            code_hash: ckb_types::h256!(
+                "0x60d5f39efce409c587cb9ea359cefdead650ca128f0bd9cb3855348f98c70d5b"
+            ),
+            hash_type: ckb_jsonrpc_types::ScriptHashType::Type,
+            args: JsonBytes::from_vec([[1; 20], [2; 20]].concat()),
+        };
+        let node = MockNode::new();
+        let created = node.push_cell(&cheque);
+        node.push_block(vec![cell_tx(&lock(2), vec![created])]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.script = Script {
+            args: JsonBytes::from_vec(vec![1; 20]),
+            ..cheque
+        };
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let cheques = harness
+            .events()
+            .iter()
+            .flat_map(|event| event.submit.cheques.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(cheques.len(), 2);
+        assert_eq!(cheques[0].phase, crate::cheque::ChequePhase::Created);
+        assert_eq!(cheques[1].phase, crate::cheque::ChequePhase::Claimed);
+        assert_eq!(cheques[1].io_type, CellType::Input);
+        assert_eq!(cheques[1].sender_lock_hash.as_bytes(), [2; 20]);
+    }
+
+    #[tokio::test]
+    async fn multisig_config_is_decoded_from_the_spending_witness() {
+        let script = [&[0, 0, 1, 1][..], &[9; 20]].concat();
+        let multisig = Script {
+            code_hash: crate::network::SECP256K1_BLAKE160_MULTISIG_ALL,
+            hash_type: ckb_jsonrpc_types::ScriptHashType::Type,
+            args: JsonBytes::from_vec(ckb_hash::blake2b_256(&script)[..20].to_vec()),
+        };
+        let node = MockNode::new();
+        let created = node.push_cell(&multisig);
+        let witness = packed::WitnessArgs::new_builder()
+            .lock(Some(Bytes::from([&script[..], &[0; 65]].concat())).pack())
+            .build();
+        let spending = cell_tx(&lock(2), vec![created])
+            .as_advanced_builder()
+            .witness(witness.as_bytes().pack())
+            .build();
+        node.push_block(vec![spending]);
+        node.push_empty(30);
+
+        let mut key = key(1);
+        key.script = multisig;
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let multisigs = harness
+            .events()
+            .iter()
+            .flat_map(|event| event.submit.multisigs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(multisigs.len(), 2);
+        assert_eq!(multisigs[0].config, None);
+        let config = multisigs[1].config.clone().unwrap();
+        assert_eq!((config.require_first_n, config.threshold), (0, 1));
+        assert_eq!(config.pubkey_hashes[0].as_bytes(), [9; 20]);
+    }
+
+    #[tokio::test]
+    async fn cells_carry_their_script_hashes_and_names() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        harness
+            .process
+            .sinks
+            .settings
+            .write()
+            .unwrap()
+            .scripts
+            .insert("test_lock".to_string(), H256::default());
+        assert_eq!(harness.scan().await, None);
+        let scripts = &harness.events()[0].submit.output_scripts;
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(scripts[0].lock.name.as_deref(), Some("test_lock"));
+        let hash: H256 = packed::Script::from(lock(1)).calc_script_hash().unpack();
+        assert_eq!(scripts[0].lock.hash, hash);
+        assert!(scripts[0].type_.is_none());
+    }
+
+    #[tokio::test]
+    async fn tip_is_verified_once_every_node_has_its_block() {
+        use crate::tip_poller::verified;
+        let (node, verifier) = (MockNode::new(), MockNode::new());
+        node.push_empty(5);
+        verifier.push_empty(4);
+        let verifiers = [verifier.client()];
+        assert!(!verified(&verifiers, &node.tip()).await.unwrap());
+
+        verifier.push_empty(1);
+        assert!(verified(&verifiers, &node.tip()).await.unwrap());
+
+        verifier.fork(1);
+        verifier.push_empty(1);
+        assert!(!verified(&verifiers, &node.tip()).await.unwrap());
+        verifier.fail_next(1);
+        assert!(verified(&verifiers, &node.tip()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn callback_sink_gets_cells_until_it_fails() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let recorder = Recorder::default();
+        harness
+            .process
+            .sinks
+            .consumers
+            .add("recorder", recorder.clone());
+        harness
+            .process
+            .state
+            .get_mut(&harness.process.key)
+            .unwrap()
+            .sink = SinkConfig::Callback {
+            name: "recorder".to_string(),
+            retry: None,
+        };
+
+        recorder
+            .fail
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        // the scan backs off and reports the failure, the logged events wait in the event log
+        assert!(harness.scan().await.is_some());
+        let registration = harness
+            .process
+            .state
+            .get(&harness.process.key)
+            .unwrap()
+            .clone();
+        assert!(registration.scan_failure.is_some());
+        assert_eq!(harness.scan_tip().block_number.value(), 32 - 24);
+        let retry = (harness.process.key.clone(), "callback:recorder".to_string());
+        assert_eq!(harness.process.sinks.failed.get(&retry).unwrap().queued, 2);
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1, 2]);
+
+        recorder
+            .fail
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        node.push_empty(1);
+        harness.scan().await;
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1, 2]);
+        assert!(harness.process.sinks.failed.is_empty());
+        assert_eq!(harness.event_blocks(), vec![1, 2]);
+        let stats = harness
+            .process
+            .sinks
+            .storage
+            .load_stats(&harness.process.key)
+            .unwrap();
+        assert_eq!(stats.events, 2);
+        assert_eq!(harness.scan_tip().block_number.value(), 33 - 24);
+    }
+
+    #[tokio::test]
+    async fn events_beyond_the_buffer_are_delivered_from_the_log() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        harness.process.buffer = Some(crate::event_buffer::EventBuffer::new(1));
+        let recorder = Recorder::default();
+        harness
+            .process
+            .sinks
+            .consumers
+            .add("recorder", recorder.clone());
+        harness
+            .process
+            .state
+            .get_mut(&harness.process.key)
+            .unwrap()
+            .sink = SinkConfig::Callback {
+            name: "recorder".to_string(),
+            retry: None,
+        };
+        let spilled_from = |harness: &Harness| {
+            harness
+                .process
+                .state
+                .get(&harness.process.key)
+                .unwrap()
+                .spilled_from
+        };
+
+        // the two events don't fit, the tip moves past them while the sink is down
+        recorder
+            .fail
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        harness.scan().await;
+        assert_eq!(harness.scan_tip().block_number.value(), 32 - 24);
+        assert_eq!(harness.event_blocks(), vec![1, 2]);
+        assert_eq!(spilled_from(&harness), Some(0));
+        let persisted = harness.process.sinks.storage.load_registrations().unwrap();
+        assert_eq!(persisted[0].1.spilled_from, Some(0));
+
+        // a newer event waits behind the spilled ones
+        node.push_cell(&lock(1));
+        node.push_empty(25);
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1, 2, 33]);
+        assert!(recorder.blocks.lock().unwrap().is_empty());
+
+        recorder
+            .fail
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        node.push_empty(1);
+        harness.scan().await;
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1, 2, 33]);
+        assert_eq!(spilled_from(&harness), None);
+    }
+
+    #[tokio::test]
+    async fn scans_follow_a_simulated_chain_through_its_reorgs() {
+        use crate::testkit::{SimChain, SimSettings};
+
+        let settings = SimSettings {
+            cells_per_block: 3,
+            locks: 2,
+            reorg_every: Some(20),
+            reorg_depth: 30,
+            seed: 7,
+            ..Default::default()
+        };
+        let mut chain = SimChain::new(settings);
+        // a history to backfill, the reorgs then replace scanned blocks
+        for _ in 0..100 {
+            chain.advance();
+        }
+        let node = chain.node().clone();
+        let key = RpcSearchKey {
+            script: crate::bench::synthetic_lock(0),
+            ..key(0)
+        };
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        for _ in 0..120 {
+            chain.advance();
+            harness.scan().await;
+        }
+
+        // the rescanned blocks are logged again, every block of the final branch with cells of
+        // the key is
+        let events = harness.events();
+        let logged = events
+            .iter()
+            .map(|event| event.submit.header.hash.clone())
+            .collect::<std::collections::HashSet<_>>();
+        assert!(logged.len() < events.len());
+        let scanned = harness.scan_tip().block_number.value();
+        assert!(scanned > 160);
+        let lock = packed::Script::from(crate::bench::synthetic_lock(0));
+        let chain = node.chain.lock().unwrap();
+        for number in 1..scanned {
+            let block = chain.block(number).unwrap();
+            let has_cells = block.txs.iter().any(|tx| {
+                tx.outputs().into_iter().any(|output| output.lock() == lock)
+                    || tx.inputs().into_iter().any(|input| {
+                        chain
+                            .output(&input.previous_output())
+                            .is_some_and(|output| output.lock() == lock)
+                    })
+            });
+            assert_eq!(logged.contains(&block.header.hash().unpack()), has_cells);
+        }
+        drop(chain);
+
+        let mut again = SimChain::new(settings);
+        for _ in 0..220 {
+            again.advance();
+        }
+        assert_eq!(again.node().tip().block_hash, node.tip().block_hash);
+    }
+
+    #[tokio::test]
+    async fn sink_retry_policy_retries_within_the_scan() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let recorder = Recorder::default();
+        harness
+            .process
+            .sinks
+            .consumers
+            .add("recorder", recorder.clone());
+        harness
+            .process
+            .state
+            .get_mut(&harness.process.key)
+            .unwrap()
+            .sink = SinkConfig::Callback {
+            name: "recorder".to_string(),
+            retry: Some(crate::sink::SinkRetry {
+                max_attempts: Some(3),
+                base_delay_ms: Some(1),
+                ..Default::default()
+            }),
+        };
+
+        *recorder.fail_calls.lock().unwrap() = 2;
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1, 2]);
+        assert_eq!(harness.scan_tip().block_number.value(), 32 - 24);
+
+        // out of attempts the delivery waits for the next scan
+        node.push_cell(&lock(1));
+        node.push_empty(25);
+        *recorder.fail_calls.lock().unwrap() = 3;
+        harness.scan().await;
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1, 2]);
+        assert_eq!(harness.scan_tip().block_number.value(), 58 - 24);
+        harness.scan().await;
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1, 2, 33]);
+    }
+
+    #[tokio::test]
+    async fn scan_parallelism_of_a_registration_shards_its_backfill() {
+        for (parallelism, tip) in [(None, 42 - 24), (Some(1), 15)] {
+            let node = MockNode::new();
+            node.push_cell(&lock(1));
+            node.push_empty(10);
+            node.push_cell(&lock(1));
+            node.push_empty(30);
+            let mut harness = harness(&node);
+            harness.process.backfill.shard_blocks = Some(5);
+            let recorder = Recorder::default();
+            *recorder.fail_block.lock().unwrap() = Some(12);
+            harness
+                .process
+                .sinks
+                .consumers
+                .add("recorder", recorder.clone());
+            {
+                let mut registration = harness.process.state.get_mut(&harness.process.key).unwrap();
+                registration.sink = SinkConfig::Callback {
+                    name: "recorder".to_string(),
+                    retry: None,
+                };
+                registration.scan_parallelism = parallelism;
+            }
+
+            // a sharded scan stops after the failing shard, its events wait in the event log
+            harness.scan().await;
+            assert_eq!(harness.scan_tip().block_number.value(), tip);
+            assert_eq!(*recorder.blocks.lock().unwrap(), vec![1]);
+            assert_eq!(harness.event_blocks(), vec![1, 12]);
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_advances_by_the_blocks_per_second_quota() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(40);
+        let mut harness = harness(&node);
+        harness
+            .process
+            .state
+            .get_mut(&harness.process.key)
+            .unwrap()
+            .max_blocks_per_second = Some(1);
+
+        // a scan per 8 seconds advances 8 blocks
+        harness.scan().await;
+        assert_eq!(harness.scan_tip().block_number.value(), 8);
+        harness.scan().await;
+        assert_eq!(harness.scan_tip().block_number.value(), 16);
+        assert_eq!(harness.event_blocks(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn scan_waits_for_the_activation_time() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let key = harness.process.key.clone();
+        harness.process.state.get_mut(&key).unwrap().activate_at =
+            Some(crate::cell_process::now_ms() + 60_000);
+        harness.scan().await;
+        assert_eq!(harness.scan_tip().block_number.value(), 0);
+
+        harness.process.state.get_mut(&key).unwrap().activate_at =
+            Some(crate::cell_process::now_ms() - 1);
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1]);
+        assert!(harness
+            .process
+            .state
+            .get(&key)
+            .unwrap()
+            .activate_at
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn registration_completes_after_its_events() {
+        let node = MockNode::new();
+        for _ in 0..3 {
+            node.push_cell(&lock(1));
+        }
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let key = harness.process.key.clone();
+        harness.process.state.get_mut(&key).unwrap().complete_after = Some(2);
+
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1, 2]);
+        assert_eq!(harness.scan_tip().block_number.value(), 3);
+        assert_eq!(harness.completed.try_recv().unwrap(), key);
+        // nothing more is scanned until the registration is deleted
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn events_carry_the_request_id_of_the_registration() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let key = harness.process.key.clone();
+        let request_id =
+            crate::rpc_http::with_request_id("deploy-42", async { crate::rpc_http::request_id() })
+                .await;
+        harness.process.state.get_mut(&key).unwrap().request_id = request_id;
+
+        harness.scan().await;
+        let events = harness.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].submit.request_id.as_deref(), Some("deploy-42"));
+    }
+
+    #[tokio::test]
+    async fn namespaces_see_and_delete_only_their_registrations() {
+        use crate::{config::NamespaceSettings, rpc_http::with_namespace};
+
+        let node = MockNode::new();
+        node.push_empty(10);
+        let mut config = Config::default();
+        for (name, token, max_registrations) in [("a", "token-a", Some(1)), ("b", "token-b", None)]
+        {
+            let namespace = NamespaceSettings {
+                token: token.to_string(),
+                max_registrations,
+                ..Default::default()
+            };
+            config.namespaces.insert(name.to_string(), namespace);
+        }
+        let rpc = rpc(&node, config);
+        let register = |namespace, key| {
+            with_namespace(
+                namespace,
+                rpc.register(key, 0.into(), None, None, None, None),
+            )
+        };
+
+        // the id is of the key in the caller's namespace
+        let id = register("a", key(1)).await.unwrap();
+        let mut scoped = key(1);
+        assert_ne!(id, scoped.id());
+        scoped.namespace = Some("a".to_string());
+        assert_eq!(id, scoped.id());
+        // the same key is a registration of its own in another namespace
+        register("b", key(1)).await.unwrap();
+        assert!(register("a", key(2)).await.is_err());
+        assert!(with_namespace("b", rpc.get_config()).await.is_err());
+
+        let info = with_namespace("a", rpc.info()).await.unwrap();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].0.namespace.as_deref(), Some("a"));
+        assert_eq!(
+            with_namespace("b", rpc.delete_all()).await.unwrap().value(),
+            1
+        );
+        // callers without a namespace see every namespace
+        let info = rpc.info().await.unwrap();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].0.metric_label().split('/').next(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn total_quota_holds_whatever_client_is_named() {
+        let node = MockNode::new();
+        node.push_empty(10);
+        let mut config = Config::default();
+        config.quotas.max_registrations = Some(1);
+        config.quotas.max_total = Some(2);
+        let rpc = rpc(&node, config);
+        let register = |args, client: &str| {
+            rpc.register(
+                key(args),
+                0.into(),
+                None,
+                Some(client.to_string()),
+                None,
+                None,
+            )
+        };
+        register(1, "a").await.unwrap();
+        assert!(register(2, "a").await.is_err());
+        register(2, "b").await.unwrap();
+        assert!(register(3, "c").await.is_err());
+        // subscribing to a registered key scans nothing more
+        register(1, "c").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_only_written_into_the_snapshot_dir() {
+        let node = MockNode::new();
+        node.push_empty(10);
+        let dir = tempfile::tempdir().unwrap();
+        let mut rpc = rpc(&node, Config::default());
+        rpc.snapshot_dir = dir.path().join("snapshots");
+        let cipher = crate::cipher::Cipher::from_hex(&"11".repeat(32)).unwrap();
+        rpc.cipher = Some(cipher.clone());
+        rpc.register(key(1), 0.into(), None, None, None, None)
+            .await
+            .unwrap();
+
+        for file in ["../escaped.json", "/tmp/escaped.json", "a/b.json", ""] {
+            assert!(rpc.admin_export_snapshot(file.to_string()).await.is_err());
+        }
+        assert!(!dir.path().join("escaped.json").exists());
+        rpc.admin_export_snapshot("backup.json".to_string())
+            .await
+            .unwrap();
+        let path = rpc.snapshot_dir.join("backup.json");
+        // sealed with the store's key
+        assert!(crate::snapshot::Snapshot::read(&path, None).is_err());
+        let snapshot = crate::snapshot::Snapshot::read(&path, Some(&cipher)).unwrap();
+        assert_eq!(snapshot.registrations.len(), 1);
+        // a plain snapshot still imports into an encrypted store
+        let plain = dir.path().join("plain.json");
+        snapshot.write(&plain, None).unwrap();
+        assert!(crate::snapshot::Snapshot::read(&plain, Some(&cipher)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn delete_without_a_client_keeps_the_other_subscribers() {
+        let node = MockNode::new();
+        node.push_empty(10);
+        let rpc = rpc(&node, Config::default());
+        let register = |client: Option<&str>| {
+            rpc.register(
+                key(1),
+                0.into(),
+                None,
+                client.map(str::to_string),
+                None,
+                None,
+            )
+        };
+        register(None).await.unwrap();
+        register(Some("b")).await.unwrap();
+
+        rpc.delete(key(1), None).await.unwrap();
+        let info = rpc.info().await.unwrap();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].1.registration.client.as_deref(), Some("b"));
+        assert!(rpc.delete(key(1), None).await.is_err());
+
+        rpc.delete(key(1), Some("b".to_string())).await.unwrap();
+        assert!(rpc.info().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn info_for_finds_the_keys_in_the_callers_namespace() {
+        use crate::{rpc_http::with_namespace, rpc_server::KeyRef};
+        let node = MockNode::new();
+        node.push_empty(10);
+        let rpc = rpc(&node, Config::default());
+        let register = |namespace, key| {
+            with_namespace(
+                namespace,
+                rpc.register(key, 0.into(), None, None, None, None),
+            )
+        };
+        let first = register("a", key(1)).await.unwrap();
+        let second = register("a", key(2)).await.unwrap();
+        let other = register("b", key(1)).await.unwrap();
+        let info_for = |namespace: Option<&str>, keys: Vec<KeyRef>| {
+            let rpc = &rpc;
+            let namespace = namespace.map(str::to_string);
+            async move {
+                let info = match namespace {
+                    Some(namespace) => with_namespace(&namespace, rpc.info_for(keys)).await,
+                    None => rpc.info_for(keys).await,
+                };
+                let mut ids = info
+                    .unwrap()
+                    .iter()
+                    .map(|(key, _)| key.id())
+                    .collect::<Vec<_>>();
+                ids.sort();
+                ids
+            }
+        };
+        let by_key = |args| KeyRef::Key(Box::new(key(args)));
+
+        let a = Some("a");
+        assert_eq!(
+            info_for(a, vec![KeyRef::Id(first.clone())]).await,
+            [&*first]
+        );
+        // a search key is taken in the caller's namespace
+        assert_eq!(info_for(a, vec![by_key(2)]).await, [&*second]);
+        let mut both = vec![first.clone(), second.clone()];
+        both.sort();
+        let ids = info_for(a, vec![KeyRef::Id(first.clone()), by_key(2)]).await;
+        assert_eq!(ids, both);
+        // unknown ids, the ones of another namespace and of the key outside of any aren't found
+        for id in ["0x01".to_string(), other.clone(), key(1).id()] {
+            assert!(info_for(a, vec![KeyRef::Id(id)]).await.is_empty());
+        }
+        assert!(info_for(a, vec![]).await.is_empty());
+        // callers without a namespace name the registrations of any by id
+        assert_eq!(
+            info_for(None, vec![KeyRef::Id(other.clone())]).await,
+            [&*other]
+        );
+        assert!(info_for(None, vec![by_key(1)]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn delete_returns_what_the_registration_produced() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        harness.scan().await;
+        let tip = harness.scan_tip();
+        let rpc = rpc_over(&harness, Config::default());
+        rpc.register(key(1), 0.into(), None, Some("b".to_string()), None, None)
+            .await
+            .unwrap();
+
+        // a subscriber gets the stats of the whole shared registration
+        for client in [Some("b".to_string()), None] {
+            let deleted = rpc.delete(key(1), client).await.unwrap();
+            assert_eq!(deleted.scan_tip.block_hash, tip.block_hash);
+            assert_eq!(deleted.scan_tip.block_number.value(), 32 - 24);
+            assert_eq!(
+                (deleted.events, deleted.cells, deleted.transactions),
+                (2, 2, 2)
+            );
+            // the harness doesn't stamp `registered_at`
+            assert_eq!(deleted.duration_secs, None);
+        }
+        assert!(rpc.info().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn registration_completes_past_its_end_block_and_stays_listed() {
+        let node = MockNode::new();
+        for _ in 0..5 {
+            node.push_cell(&lock(1));
+        }
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let key = harness.process.key.clone();
+        harness.process.state.get_mut(&key).unwrap().end_block = Some(3);
+
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1, 2, 3]);
+        assert_eq!(harness.scan_tip().block_number.value(), 4);
+        assert!(harness
+            .process
+            .state
+            .get(&key)
+            .unwrap()
+            .completed_at
+            .is_some());
+        assert!(harness.completed.try_recv().is_err());
+        harness.scan().await;
+        assert_eq!(harness.event_blocks(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn slow_delivery_doesnt_stall_the_scan() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let recorder = Recorder::default();
+        *recorder.delay.lock().unwrap() = Duration::from_millis(500);
+        harness
+            .process
+            .sinks
+            .consumers
+            .add("recorder", recorder.clone());
+        harness
+            .process
+            .state
+            .get_mut(&harness.process.key)
+            .unwrap()
+            .sink = SinkConfig::Callback {
+            name: "recorder".to_string(),
+            retry: None,
+        };
+        let tasks = crate::lifecycle::DebugTasks::of(&harness.process);
+
+        let (_, stalled) = tokio::join!(harness.scan(), async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            tasks.stalled(Duration::from_millis(100))
+        });
+        assert_eq!(stalled, None);
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1]);
+        assert!(!harness.process.stats.lock().unwrap().delivering);
+    }
+
+    #[tokio::test]
+    async fn open_circuit_holds_deliveries_until_the_sink_is_healthy() {
+        let node = MockNode::new();
+        node.push_cell(&lock(1));
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        harness
+            .process
+            .sinks
+            .settings
+            .write()
+            .unwrap()
+            .circuit_breaker_failures = Some(2);
+        let recorder = Recorder::default();
+        harness
+            .process
+            .sinks
+            .consumers
+            .add("recorder", recorder.clone());
+        let sink = SinkConfig::Callback {
+            name: "recorder".to_string(),
+            retry: None,
+        };
+        harness
+            .process
+            .state
+            .get_mut(&harness.process.key)
+            .unwrap()
+            .sink = sink.clone();
+
+        *recorder.fail_calls.lock().unwrap() = 2;
+        harness.scan().await;
+        assert!(!harness.process.sinks.breakers.is_open(&sink.id()));
+        harness.scan().await;
+        assert!(harness.process.sinks.breakers.is_open(&sink.id()));
+
+        // the consumer recovered, but no delivery is tried while the circuit is open
+        harness.scan().await;
+        assert!(recorder.blocks.lock().unwrap().is_empty());
+        let sinks = harness.process.sinks.clone();
+        let ok = sinks.check(&sink).await.unwrap();
+        sinks.record_health(&sink.id(), ok).await;
+        assert!(!sinks.breakers.is_open(&sink.id()));
+        harness.scan().await;
+        assert_eq!(*recorder.blocks.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn sink_gets_events_in_block_order_unless_relaxed() {
+        use crate::sink::DeliveryOrdering;
+        for (ordering, delivered, redelivered) in [
+            (DeliveryOrdering::Strict, vec![1, 2], vec![1, 2, 3, 4]),
+            (
+                DeliveryOrdering::Relaxed,
+                vec![1, 2, 4],
+                vec![1, 2, 4, 3, 4],
+            ),
+        ] {
+            let node = MockNode::new();
+            for _ in 0..4 {
+                node.push_cell(&lock(1));
+            }
+            node.push_empty(30);
+            let mut harness = harness(&node);
+            harness.process.sinks.settings.write().unwrap().ordering = ordering;
+            let recorder = Recorder::default();
+            harness
+                .process
+                .sinks
+                .consumers
+                .add("recorder", recorder.clone());
+            harness
+                .process
+                .state
+                .get_mut(&harness.process.key)
+                .unwrap()
+                .sink = SinkConfig::Callback {
+                name: "recorder".to_string(),
+                retry: None,
+            };
+
+            *recorder.fail_block.lock().unwrap() = Some(3);
+            harness.scan().await;
+            assert_eq!(*recorder.blocks.lock().unwrap(), delivered);
+            assert_eq!(harness.scan_tip().block_number.value(), 34 - 24);
+
+            // a restart only keeps the delivery cursors
+            harness.process.sinks.failed = Default::default();
+            *recorder.fail_block.lock().unwrap() = None;
+            harness.scan().await;
+            assert_eq!(*recorder.blocks.lock().unwrap(), redelivered);
+            assert_eq!(harness.scan_tip().block_number.value(), 34 - 24);
+        }
+    }
+
+    #[tokio::test]
+    async fn sinks_get_every_block_once_across_a_restart() {
+        let node = MockNode::new();
+        for _ in 0..4 {
+            node.push_cell(&lock(1));
+        }
+        node.push_empty(30);
+        let mut harness = harness(&node);
+        let (first, second) = (Recorder::default(), Recorder::default());
+        let callback = |name: &str| SinkConfig::Callback {
+            name: name.to_string(),
+            retry: None,
+        };
+        let add = |sinks: &Sinks| {
+            sinks.consumers.add("first", first.clone());
+            sinks.consumers.add("second", second.clone());
+        };
+        add(&harness.process.sinks);
+        {
+            let mut registration = harness.process.state.get_mut(&harness.process.key).unwrap();
+            registration.sink = callback("first");
+            registration
+                .subscribers
+                .push(crate::global_state::Subscriber {
+                    client: Some("b".to_string()),
+                    sink: callback("second"),
+                });
+        }
+
+        *second.fail_block.lock().unwrap() = Some(3);
+        assert!(harness.scan().await.is_some());
+        assert_eq!(*first.blocks.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(*second.blocks.lock().unwrap(), vec![1, 2]);
+
+        // the restarted sinks only share the store
+        let storage = harness.process.sinks.storage.clone();
+        harness.process.sinks = Sinks {
+            storage,
+            unix_socket: None,
+            http: reqwest::Client::new(),
+            settings: Default::default(),
+            consumers: Default::default(),
+            plugin: None,
+            failed: Default::default(),
+            breakers: Default::default(),
+        };
+        add(&harness.process.sinks);
+        *second.fail_block.lock().unwrap() = None;
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(*first.blocks.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(*second.blocks.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(harness.event_blocks(), vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn oversized_events_are_truncated_or_split() {
+        use crate::sink::{
+            size_limit::{fit, Oversize, SizeLimit},
+            Event,
+        };
+
+        let node = MockNode::new();
+        let with_data = |size: usize| {
+            TransactionBuilder::default()
+                .output(
+                    packed::CellOutput::new_builder()
+                        .lock(lock(1).into())
+                        .build(),
+                )
+                .output_data(Bytes::from(vec![7; size]).pack())
+                .build()
+        };
+        node.push_block(vec![
+            with_data(300),
+            with_data(200),
+            with_data(200),
+            with_data(200),
+        ]);
+        node.push_empty(30);
+        let mut key = key(1);
+        key.with_data = Some(true);
+        let mut harness = Harness::new(node.clone(), key, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        let events = harness.events();
+        let event = events[0].as_event();
+        let size = |event: &Event<'_>| Ok(serde_json::to_vec(event)?.len());
+        let whole = size(&event).unwrap();
+        let limit = |max_bytes, oversize| SizeLimit {
+            max_bytes,
+            oversize,
+        };
+        assert!(fit(&event, &limit(whole, Oversize::Split), size)
+            .unwrap()
+            .is_none());
+
+        let truncated = fit(&event, &limit(whole - 1, Oversize::Truncate), size)
+            .unwrap()
+            .unwrap();
+        assert_eq!(truncated.len(), 1);
+        let (part, submit) = &truncated[0];
+        assert_eq!(part, &None);
+        assert_eq!(submit.truncated_data.len(), 1);
+        let out_point = submit.truncated_data[0].out_point.clone().unwrap();
+        assert_eq!(out_point.tx_hash, submit.output_locations[0].tx_hash);
+        assert!(submit.outputs[0].data.is_none() && submit.outputs[1].data.is_some());
+
+        let parts = fit(&event, &limit(whole / 2, Oversize::Split), size)
+            .unwrap()
+            .unwrap();
+        assert!(parts.len() > 1);
+        let mut outputs = 0;
+        for (index, (part, submit)) in parts.iter().enumerate() {
+            let part = part.unwrap();
+            assert_eq!((part.index, part.count), (index as u32, parts.len() as u32));
+            assert_eq!(part.first_output, outputs);
+            assert_eq!(submit.outputs.len(), submit.output_locations.len());
+            outputs += submit.outputs.len() as u32;
+            assert!(
+                size(&Event {
+                    part: Some(part),
+                    ..event
+                })
+                .unwrap()
+                    > 0
+            );
+        }
+        assert_eq!(outputs, 4);
+    }
+
+    #[tokio::test]
+    async fn lagging_indexer_falls_back_to_the_blocks() {
+        let node = MockNode::new();
+        let first = node.push_cell(&lock(1));
+        node.push_cell(&Script {
+            args: JsonBytes::from_vec(vec![1, 8]),
+            ..lock(1)
+        });
+        node.push_block(vec![cell_tx(&lock(2), vec![first])]);
+        node.push_empty(40);
+        let mut indexed = Harness::new(node.clone(), key(1), node.block(0));
+        assert_eq!(indexed.scan().await, None);
+
+        let mut fallback = Harness::new(node.clone(), key(1), node.block(0));
+        // off by default, the scan waits for the indexer
+        assert_eq!(fallback.scan_lagging(2).await, None);
+        assert_eq!(fallback.scan_tip().block_number.value(), 0);
+
+        fallback.process.block_fallback.lag_blocks = Some(5);
+        assert_eq!(fallback.scan_lagging(2).await, None);
+        assert_eq!(
+            fallback.scan_tip().block_hash,
+            indexed.scan_tip().block_hash
+        );
+        assert_eq!(fallback.event_blocks(), vec![1, 2, 3]);
+        for (by_blocks, by_indexer) in fallback.events().iter().zip(indexed.events()) {
+            assert_eq!(
+                serde_json::to_value(&by_blocks.submit).unwrap(),
+                serde_json::to_value(&by_indexer.submit).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_filters_are_checked_by_the_emitter() {
+        let node = MockNode::new();
+        let short = node.push_cell(&lock(1));
+        let long = TransactionBuilder::default()
+            .output(
+                packed::CellOutput::new_builder()
+                    .lock(lock(1).into())
+                    .build(),
+            )
+            .output_data(Bytes::from_static(b"long data").pack())
+            .build();
+        node.push_block(vec![long]);
+        node.push_block(vec![cell_tx(&lock(2), vec![short])]);
+        node.push_empty(30);
+        node.refuse_filters(&["output_data_len_range"]);
+
+        let mut filtered = key(1);
+        filtered.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            output_data_len_range: Some([3.into(), 100.into()]),
+            ..Default::default()
+        });
+        let mut harness = Harness::new(node.clone(), filtered, node.block(0));
+        assert_eq!(harness.scan().await, None);
+        // neither the cell with short data nor its spend
+        assert_eq!(harness.event_blocks(), vec![2]);
+
+        // the block range alone is never refused
+        let mut harness = Harness::new(node.clone(), key(1), node.block(0));
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn refused_filter_fields_are_named() {
+        let node = MockNode::new();
+        node.push_empty(1);
+        let client = node.client();
+        let mut key = key(1);
+        key.filter = Some(crate::rpc_server::RpcSearchKeyFilter {
+            output_capacity_range: Some([0.into(), 100.into()]),
+            output_data_len_range: Some([3.into(), 100.into()]),
+            ..Default::default()
+        });
+        let refused = |fields| {
+            node.refuse_filters(fields);
+            crate::validate::refused_filter(&client, &key)
+        };
+        assert_eq!(refused(&[]).await.unwrap(), None);
+        let refused_one = refused(&["output_data_len_range"]).await.unwrap().unwrap();
+        assert!(
+            refused_one.starts_with("the indexer doesn't support output_data_len_range:"),
+            "{}",
+            refused_one
+        );
+        let refused_both = refused(&["output_data_len_range", "output_capacity_range"])
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(refused_both.starts_with(
+            "the indexer doesn't support output_data_len_range, output_capacity_range:"
+        ));
+    }
+
+    /// Serves the node over websockets or tcp lines, closing each connection after `calls`
+    /// answers
+    async fn serve_stream(node: MockNode, websocket: bool, calls: usize) -> String {
+        use crate::rpc_stream::{accept_key, read_frame, write_frame};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let node = node.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = socket.into_split();
+                    let mut read = tokio::io::BufReader::new(read);
+                    if websocket {
+                        let mut key = String::new();
+                        loop {
+                            let mut line = String::new();
+                            read.read_line(&mut line).await?;
+                            if let Some(value) = line.strip_prefix("Sec-WebSocket-Key:") {
+                                key = value.trim().to_string();
+                            }
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        let upgrade = format!(
+                            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                            accept_key(&key)
+                        );
+                        write.write_all(upgrade.as_bytes()).await?;
+                    }
+                    for _ in 0..calls {
+                        let request = match websocket {
+                            true => read_frame(&mut read).await?.2,
+                            false => {
+                                let mut line = Vec::new();
+                                read.read_until(b'\n', &mut line).await?;
+                                line
+                            }
+                        };
+                        let answer = node.answer(&serde_json::from_slice(&request)?)?;
+                        let answer = serde_json::to_vec(&answer)?;
+                        if websocket {
+                            write_frame(&mut write, 1, &answer, false).await?;
+                        } else {
+                            write.write_all(&answer).await?;
+                            write.write_all(b"\n").await?;
+                        }
+                    }
+                    io::Result::Ok(())
+                });
+            }
+        });
+        let scheme = if websocket { "ws" } else { "tcp" };
+        format!("{}://{}", scheme, address)
+    }
+
+    #[tokio::test]
+    async fn websocket_and_tcp_nodes_are_reconnected() {
+        let node = MockNode::new();
+        node.push_empty(3);
+        for websocket in [true, false] {
+            let client = RpcClient::new(&serve_stream(node.clone(), websocket, 1).await);
+            // each connection is closed after an answer, the next call opens a new one
+            for _ in 0..3 {
+                let tip = client.get_indexer_tip().await.unwrap();
+                assert_eq!(tip.block_number.value(), 3);
+            }
+        }
+    }
+}
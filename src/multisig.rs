@@ -0,0 +1,93 @@
+use ckb_jsonrpc_types::{JsonBytes, OutPoint, Script, ScriptHashType, Transaction, Uint64};
+use ckb_types::{packed, prelude::*};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{network::SECP256K1_BLAKE160_MULTISIG_ALL, rpc_client::CellType, schema};
+
+/// A matched cell locked by the secp256k1 multisig lock
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Multisig {
+    pub io_type: CellType,
+    /// The input as in the event's `inputs`, or the created cell
+    #[schemars(with = "schema::OutPoint")]
+    pub out_point: OutPoint,
+    /// Blake160 of the multisig script, the first 20 bytes of the args
+    #[schemars(with = "schema::Hex")]
+    pub script_hash: JsonBytes,
+    /// The `since` the args may carry after the hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub since: Option<Uint64>,
+    /// Unset unless a witness of the transaction has the multisig script, as when it spends such a cell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<MultisigConfig>,
+}
+
+/// The signers of a multisig script, any `threshold` of them of which the first `require_first_n`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct MultisigConfig {
+    pub require_first_n: u8,
+    pub threshold: u8,
+    /// Blake160 of each signer's pubkey
+    #[schemars(with = "Vec<schema::Hex>")]
+    pub pubkey_hashes: Vec<JsonBytes>,
+}
+
+pub(crate) fn is_multisig(lock: &Script) -> bool {
+    lock.hash_type == ScriptHashType::Type && lock.code_hash == SECP256K1_BLAKE160_MULTISIG_ALL
+}
+
+impl Multisig {
+    /// None for a lock with args other than the script hash and an optional `since`
+    pub fn decode(
+        io_type: CellType,
+        out_point: OutPoint,
+        lock: &Script,
+        tx: &Transaction,
+    ) -> Option<Self> {
+        let args = lock.args.as_bytes();
+        if !is_multisig(lock) || !(args.len() == 20 || args.len() == 28) {
+            return None;
+        }
+        let script_hash = &args[..20];
+        Some(Multisig {
+            io_type,
+            out_point,
+            script_hash: JsonBytes::from_vec(script_hash.to_vec()),
+            since: args
+                .get(20..)
+                .filter(|since| !since.is_empty())
+                .map(|since| u64::from_le_bytes(since.try_into().unwrap()).into()),
+            config: tx
+                .witnesses
+                .iter()
+                .find_map(|witness| config(witness.as_bytes(), script_hash)),
+        })
+    }
+}
+
+/// The config of the multisig script at the start of the witness's lock, if it hashes to `script_hash`
+fn config(witness: &[u8], script_hash: &[u8]) -> Option<MultisigConfig> {
+    let lock = packed::WitnessArgs::from_slice(witness)
+        .ok()?
+        .lock()
+        .to_opt()?
+        .raw_data();
+    // reserved, require_first_n, threshold, pubkey count
+    let (&[0, require_first_n, threshold, count], rest) = lock.split_first_chunk::<4>()? else {
+        return None;
+    };
+    let script = lock.get(..4 + 20 * count as usize)?;
+    if ckb_hash::blake2b_256(script)[..20] != *script_hash {
+        return None;
+    }
+    Some(MultisigConfig {
+        require_first_n,
+        threshold,
+        pubkey_hashes: rest[..20 * count as usize]
+            .chunks(20)
+            .map(|hash| JsonBytes::from_vec(hash.to_vec()))
+            .collect(),
+    })
+}
@@ -0,0 +1,257 @@
+use ckb_jsonrpc_types::{Script, ScriptHashType};
+use ckb_types::{h256, H256};
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
+
+use crate::rpc_client::RpcClient;
+
+/// Well known type script hashes shared by mainnet and testnet
+pub(crate) const SECP256K1_BLAKE160_SIGHASH_ALL: H256 =
+    h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8");
+pub(crate) const SECP256K1_BLAKE160_MULTISIG_ALL: H256 =
+    h256!("0x5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8");
+pub(crate) const DAO: H256 =
+    h256!("0x82d76d1b75fe2fd9a27dfbaa65a039221a380d76c926f378d3f81cf3e7e13f2e");
+
+/// The ckb chain the emitter is meant to watch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Devnet,
+}
+
+impl FromStr for Network {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "devnet" => Ok(Network::Devnet),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown network: {}", s),
+            )),
+        }
+    }
+}
+
+impl Network {
+    /// None for devnets, every one has its own genesis
+    pub fn genesis_hash(self) -> Option<H256> {
+        match self {
+            Network::Mainnet => Some(h256!(
+                "0x92b197aa1fba0f63633922c61c92375c9c074a93e85963554f5499fe1450d0e5"
+            )),
+            Network::Testnet => Some(h256!(
+                "0x10639e0895502b5688a6be8cf69460d76541bfa4821629d86d62ba0aae3f9606"
+            )),
+            Network::Devnet => None,
+        }
+    }
+
+    /// Type script hashes of the system and common scripts deployed on the network
+    pub fn code_hashes(self) -> Vec<H256> {
+        let deployed = match self {
+            Network::Mainnet => vec![
+                // anyone can pay
+                h256!("0xd369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354"),
+                // sudt
+                h256!("0x5e7a36a77e68eecc013dfa2fe6a23f3b6c344b04005808694ae6dd45eea4cfd5"),
+            ],
+            Network::Testnet => vec![
+                h256!("0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356"),
+                h256!("0xc5e5dcf215925f7ef4dfaf5f4b4f105bc321c02776d6e7d52a1db3fcd9d011a4"),
+            ],
+            Network::Devnet => Vec::new(),
+        };
+        [
+            SECP256K1_BLAKE160_SIGHASH_ALL,
+            SECP256K1_BLAKE160_MULTISIG_ALL,
+            DAO,
+        ]
+        .into_iter()
+        .chain(deployed)
+        .collect()
+    }
+
+    /// The other public network if `script` uses one of its scripts which isn't deployed on this one,
+    /// likely a key meant for that network
+    pub fn foreign(self, script: &Script) -> Option<Network> {
+        if script.hash_type != ScriptHashType::Type || self == Network::Devnet {
+            return None;
+        }
+        [Network::Mainnet, Network::Testnet]
+            .into_iter()
+            .filter(|other| *other != self)
+            .find(|other| {
+                other.code_hashes().contains(&script.code_hash)
+                    && !self.code_hashes().contains(&script.code_hash)
+            })
+    }
+
+    /// Compares the genesis hash of the connected node with the network's
+    pub async fn verify(self, client: &RpcClient) -> io::Result<()> {
+        let expected = match self.genesis_hash() {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        let genesis = client.get_header_by_number(0.into()).await?;
+        if genesis.hash != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "ckb node is not on {:?}, genesis hash {:#x} instead of {:#x}",
+                    self, genesis.hash, expected
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `<network>:<genesis hash prefix>` of the chain the events come from, see `name_chain`
+static CHAIN: OnceLock<String> = OnceLock::new();
+
+/// Names the chain of `genesis` in the envelope of every event, the first chain named stays
+pub(crate) fn name_chain(genesis: &H256) {
+    let network = [Network::Mainnet, Network::Testnet]
+        .into_iter()
+        .find(|network| network.genesis_hash().as_ref() == Some(genesis))
+        .unwrap_or(Network::Devnet);
+    let hash = format!("{:#x}", genesis);
+    let _ = CHAIN.set(format!(
+        "{}:{}",
+        format!("{:?}", network).to_lowercase(),
+        &hash[..10]
+    ));
+}
+
+/// Unset until the first chain is named
+pub(crate) fn chain() -> Option<&'static str> {
+    CHAIN.get().map(String::as_str)
+}
+
+/// Kept in the store path by every backend but memory, the genesis hash the registrations were
+/// made on. Deleting it pins the next node's genesis
+const GENESIS_FILE: &str = "GENESIS";
+
+/// The genesis hash of the chain a store's registrations were made on, scans refuse to run
+/// until the node's genesis is checked against it and while it differs
+pub(crate) struct GenesisPin {
+    /// `None` for the memory store, which is pinned until the emitter exits
+    file: Option<PathBuf>,
+    pinned: RwLock<Option<H256>>,
+    /// Last seen of the connected node
+    node: RwLock<Option<H256>>,
+}
+
+impl GenesisPin {
+    /// Reads the pinned hash of the store at `store_path`, nothing is pinned in a new store
+    pub fn load(store_path: Option<PathBuf>) -> io::Result<Self> {
+        let file = store_path.map(|path| path.join(GENESIS_FILE));
+        let pinned = match file.as_ref().map(fs::read_to_string) {
+            Some(Ok(hash)) => Some(
+                H256::from_str(hash.trim().trim_start_matches("0x")).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid genesis hash in {:?}", file),
+                    )
+                })?,
+            ),
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => None,
+        };
+        if let Some(pinned) = &pinned {
+            name_chain(pinned);
+        }
+        Ok(GenesisPin {
+            file,
+            pinned: RwLock::new(pinned),
+            node: Default::default(),
+        })
+    }
+
+    pub fn pinned(&self) -> Option<H256> {
+        self.pinned.read().unwrap().clone()
+    }
+
+    /// Takes the node's genesis hash, pinning it if nothing is pinned yet
+    pub fn record(&self, genesis: H256) -> io::Result<()> {
+        let mut pinned = self.pinned.write().unwrap();
+        if pinned.is_none() {
+            if let Some(file) = &self.file {
+                fs::write(file, format!("{:#x}\n", genesis))?;
+            }
+            log::info!(
+                "registrations pinned to the chain of genesis {:#x}",
+                genesis
+            );
+            name_chain(&genesis);
+            *pinned = Some(genesis.clone());
+        }
+        *self.node.write().unwrap() = Some(genesis);
+        Ok(())
+    }
+
+    /// Fetches and records the node's genesis hash
+    pub async fn check(&self, client: &RpcClient) -> io::Result<()> {
+        let genesis = client.get_header_by_number(0.into()).await?;
+        self.record(genesis.hash)
+    }
+
+    /// Why scanning is refused, if it is
+    pub fn mismatch(&self) -> Option<String> {
+        self.verified().err().map(|e| e.to_string())
+    }
+
+    /// Errs unless the node was seen on the pinned chain
+    pub fn verified(&self) -> io::Result<()> {
+        let (pinned, node) = (self.pinned(), self.node.read().unwrap().clone());
+        match (pinned, node) {
+            (Some(pinned), Some(node)) if pinned == node => Ok(()),
+            (Some(pinned), Some(node)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "ckb node is on another chain, genesis hash {:#x} instead of {:#x} the \
+                     registrations were made on",
+                    node, pinned
+                ),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the genesis of the ckb node isn't checked yet",
+            )),
+        }
+    }
+
+    /// Checks the node's genesis every minute, it may be replaced behind the same uri. Retries
+    /// sooner until a check succeeded
+    pub async fn run(self: Arc<Self>, client: RpcClient) {
+        loop {
+            let was = self.mismatch();
+            let delay = match self.check(&client).await {
+                Ok(()) => Duration::from_secs(60),
+                Err(e) => {
+                    log::warn!("check the genesis of the ckb node: {}", e);
+                    Duration::from_secs(5)
+                }
+            };
+            let mismatch = self.mismatch();
+            if mismatch != was {
+                match &mismatch {
+                    Some(mismatch) => log::error!("scans refused: {}", mismatch),
+                    None => log::info!("ckb node on the pinned chain, scans go on"),
+                }
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use ckb_jsonrpc_types::Uint64;
+use serde::Serialize;
+
+use crate::rpc_server::{CellbaseMode, RpcSearchKey, RpcSearchKeyFilter};
+
+/// A registration whose events are the same as those of another one, `merge_registrations`
+/// makes it a subscriber of `into`
+#[derive(Serialize, Clone, Debug)]
+pub struct Duplicate {
+    pub key: RpcSearchKey,
+    pub into: RpcSearchKey,
+}
+
+/// A registration whose cells all match another one too, it has a narrower filter. Reported only,
+/// its events differ
+#[derive(Serialize, Clone, Debug)]
+pub struct Subset {
+    pub key: RpcSearchKey,
+    pub covered_by: RpcSearchKey,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Overlaps {
+    pub duplicates: Vec<Duplicate>,
+    pub subsets: Vec<Subset>,
+}
+
+/// The key with its options set to their defaults left out, keys which are the same this way
+/// have the same events
+pub(crate) fn normalized(key: &RpcSearchKey) -> RpcSearchKey {
+    let unset_false = |flag: Option<bool>| flag.filter(|flag| *flag);
+    let mut key = key.clone();
+    if let Some(filter) = &mut key.filter {
+        filter.cellbase = filter
+            .cellbase
+            .filter(|cellbase| *cellbase != CellbaseMode::Include);
+    }
+    key.filter = key.filter.filter(|filter| *filter != Default::default());
+    key.with_data = key.with_data.filter(|with_data| !with_data);
+    key.with_uncles = unset_false(key.with_uncles);
+    key.with_epochs = unset_false(key.with_epochs);
+    key.with_input_cells = unset_false(key.with_input_cells);
+    key.track_lineage = unset_false(key.track_lineage);
+    key.track_live_cells = unset_false(key.track_live_cells);
+    key.discover = unset_false(key.discover);
+    key.transactions_only = unset_false(key.transactions_only);
+    key
+}
+
+/// Whether every cell matching `narrower` matches `wider` and both emit the same parts of them,
+/// only the filters may differ
+fn covers(wider: &RpcSearchKey, narrower: &RpcSearchKey) -> bool {
+    let (wider, narrower) = (normalized(wider), normalized(narrower));
+    let filters = |key: &RpcSearchKey| RpcSearchKey {
+        filter: None,
+        ..key.clone()
+    };
+    if filters(&wider) != filters(&narrower) {
+        return false;
+    }
+    let (wider, narrower) = (
+        wider.filter.unwrap_or_default(),
+        narrower.filter.unwrap_or_default(),
+    );
+    // a constraint of the wider filter has to be in the narrower one, or be looser for ranges
+    fn same<T: PartialEq>(wider: &Option<T>, narrower: &Option<T>) -> bool {
+        wider.is_none() || wider == narrower
+    }
+    fn within(wider: &Option<[Uint64; 2]>, narrower: &Option<[Uint64; 2]>) -> bool {
+        match (wider, narrower) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some([start, end]), Some([inner_start, inner_end])) => {
+                start.value() <= inner_start.value() && inner_end.value() <= end.value()
+            }
+        }
+    }
+    let RpcSearchKeyFilter {
+        script,
+        script_len_range,
+        output_data_len_range,
+        output_capacity_range,
+        // compared with the default mode filled in
+        output_data: _,
+        output_data_filter_mode: _,
+        cell_dep,
+        header_dep,
+        since,
+        fee_range,
+        has_type_script,
+        data_pattern,
+        args_pattern,
+        min_capacity_change,
+        min_transactions,
+        cellbase,
+    } = &wider;
+    same(script, &narrower.script)
+        && within(script_len_range, &narrower.script_len_range)
+        && within(output_data_len_range, &narrower.output_data_len_range)
+        && within(output_capacity_range, &narrower.output_capacity_range)
+        && same(&wider.output_data_filter(), &narrower.output_data_filter())
+        && same(cell_dep, &narrower.cell_dep)
+        && same(header_dep, &narrower.header_dep)
+        && same(since, &narrower.since)
+        && within(fee_range, &narrower.fee_range)
+        && same(has_type_script, &narrower.has_type_script)
+        && same(data_pattern, &narrower.data_pattern)
+        && same(args_pattern, &narrower.args_pattern)
+        && min_capacity_change.is_none_or(|min| {
+            narrower
+                .min_capacity_change
+                .is_some_and(|inner| inner.value() >= min.value())
+        })
+        && min_transactions.is_none_or(|min| {
+            narrower
+                .min_transactions
+                .is_some_and(|inner| inner.value() >= min.value())
+        })
+        && same(cellbase, &narrower.cellbase)
+}
+
+/// The duplicates and subsets among the registered keys, each with the scan tip of its registration.
+/// Duplicates are merged into the one scanning furthest behind, whose subscribers may get events
+/// again but miss none
+pub(crate) fn find(keys: &[(RpcSearchKey, u64)]) -> Overlaps {
+    let mut overlaps = Overlaps::default();
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|(_, tip)| *tip);
+    let mut kept: Vec<&RpcSearchKey> = Vec::new();
+    for (key, _) in &keys {
+        let normal = normalized(key);
+        match kept.iter().find(|into| normalized(into) == normal) {
+            Some(into) => overlaps.duplicates.push(Duplicate {
+                key: key.clone(),
+                into: (*into).clone(),
+            }),
+            None => kept.push(key),
+        }
+    }
+    for key in &kept {
+        if let Some(covered_by) = kept
+            .iter()
+            .find(|wider| *wider != key && covers(wider, key))
+        {
+            overlaps.subsets.push(Subset {
+                key: (*key).clone(),
+                covered_by: (*covered_by).clone(),
+            });
+        }
+    }
+    overlaps
+}
@@ -0,0 +1,82 @@
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use std::fmt;
+
+/// Hex bytes with `??` for any byte, e.g. `0x12??34`
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DataPattern(Vec<Option<u8>>);
+
+impl DataPattern {
+    /// Found anywhere in the data
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.windows(self.0.len())
+            .any(|window| self.matches_start(window))
+    }
+
+    /// At the start of the data, which may have more bytes
+    pub fn matches_start(&self, data: &[u8]) -> bool {
+        self.0.len() <= data.len() && self.allows_prefix(data)
+    }
+
+    /// Whether some data matching at the start can begin with `prefix`
+    pub fn allows_prefix(&self, prefix: &[u8]) -> bool {
+        prefix
+            .iter()
+            .zip(&self.0)
+            .all(|(byte, pattern)| pattern.is_none_or(|pattern| pattern == *byte))
+    }
+
+    /// The bytes before the first wildcard
+    pub fn fixed_prefix(&self) -> Vec<u8> {
+        self.0.iter().map_while(|byte| *byte).collect()
+    }
+}
+
+impl std::str::FromStr for DataPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid data pattern {:?}, expected hex bytes and ??", s);
+        let hex = s.strip_prefix("0x").ok_or_else(invalid)?;
+        if hex.is_empty() || hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| match &hex[i..i + 2] {
+                "??" => Ok(None),
+                byte => u8::from_str_radix(byte, 16)
+                    .map(Some)
+                    .map_err(|_| invalid()),
+            })
+            .collect::<Result<_, _>>()
+            .map(DataPattern)
+    }
+}
+
+impl fmt::Display for DataPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x")?;
+        for byte in &self.0 {
+            match byte {
+                Some(byte) => write!(f, "{:02x}", byte)?,
+                None => write!(f, "??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for DataPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DataPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
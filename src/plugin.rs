@@ -0,0 +1,209 @@
+//! A wasm module transforming, enriching or dropping each event before it is logged and delivered.
+//!
+//! The module exports its `memory`, `alloc(len: i32) -> i32` returning where the emitter writes the
+//! `len` bytes of the input, and `transform(ptr: i32, len: i32) -> i64`. The input is the json of the
+//! event's payload, `{key, header, inputs, outputs}`. `transform` returns 0 to drop the event, else where
+//! the json of the event to deliver is, pointer in the high 32 bits and length in the low ones. It has
+//! the fields of the input but `key`, which can't change, and may add an `extra` one delivered as is
+
+use std::io;
+
+use crate::{config::PluginSettings, rpc_server::RpcSearchKey, Submit};
+
+pub(crate) struct Plugin {
+    #[cfg(feature = "wasm-plugin")]
+    wasm: std::sync::Mutex<wasm::Wasm>,
+    #[cfg(not(feature = "wasm-plugin"))]
+    never: std::convert::Infallible,
+}
+
+impl Plugin {
+    /// The configured plugin, none without a path. Fails if the emitter was built without `wasm-plugin`
+    pub fn load(settings: &PluginSettings) -> io::Result<Option<Self>> {
+        let path = match &settings.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        #[cfg(feature = "wasm-plugin")]
+        {
+            let wasm = wasm::Wasm::load(path, settings.fuel)?;
+            log::info!("loaded plugin {}", path);
+            Ok(Some(Plugin {
+                wasm: std::sync::Mutex::new(wasm),
+            }))
+        }
+        #[cfg(not(feature = "wasm-plugin"))]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "plugin {} needs the emitter built with the wasm-plugin feature",
+                path
+            ),
+        ))
+    }
+
+    /// The event to deliver instead, none if the plugin drops it. A trapping plugin or one
+    /// returning something else than an event fails the delivery, it is retried on the next scan
+    pub fn transform(&self, key: &RpcSearchKey, submit: Submit) -> io::Result<Option<Submit>> {
+        #[cfg(feature = "wasm-plugin")]
+        {
+            self.wasm.lock().unwrap().transform(key, submit)
+        }
+        #[cfg(not(feature = "wasm-plugin"))]
+        {
+            let _ = (key, submit);
+            match self.never {}
+        }
+    }
+}
+
+#[cfg(feature = "wasm-plugin")]
+mod wasm {
+    use serde::Serialize;
+    use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+    use std::io;
+
+    use crate::{rpc_server::RpcSearchKey, Submit};
+
+    fn error(e: impl std::fmt::Display) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("plugin: {}", e))
+    }
+
+    #[derive(Serialize)]
+    struct Input<'a> {
+        key: &'a RpcSearchKey,
+        #[serde(flatten)]
+        submit: &'a Submit,
+    }
+
+    pub(super) struct Wasm {
+        store: Store<()>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        transform: TypedFunc<(i32, i32), i64>,
+        fuel: Option<u64>,
+    }
+
+    impl Wasm {
+        pub fn load(path: &str, fuel: Option<u64>) -> io::Result<Self> {
+            let mut config = wasmtime::Config::new();
+            config.consume_fuel(fuel.is_some());
+            let engine = Engine::new(&config).map_err(error)?;
+            let module = Module::from_file(&engine, path).map_err(error)?;
+            let mut store = Store::new(&engine, ());
+            if fuel.is_some() {
+                store.set_fuel(u64::MAX).map_err(error)?;
+            }
+            // the plugin imports nothing, it sees only the events
+            let instance = Instance::new(&mut store, &module, &[]).map_err(error)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| error("no exported memory"))?;
+            Ok(Wasm {
+                alloc: instance
+                    .get_typed_func(&mut store, "alloc")
+                    .map_err(error)?,
+                transform: instance
+                    .get_typed_func(&mut store, "transform")
+                    .map_err(error)?,
+                store,
+                memory,
+                fuel,
+            })
+        }
+
+        pub fn transform(
+            &mut self,
+            key: &RpcSearchKey,
+            submit: Submit,
+        ) -> io::Result<Option<Submit>> {
+            if let Some(fuel) = self.fuel {
+                self.store.set_fuel(fuel).map_err(error)?;
+            }
+            let input = serde_json::to_vec(&Input {
+                key,
+                submit: &submit,
+            })?;
+            let len = i32::try_from(input.len()).map_err(error)?;
+            let ptr = self.alloc.call(&mut self.store, len).map_err(error)?;
+            self.memory
+                .write(&mut self.store, ptr as u32 as usize, &input)
+                .map_err(error)?;
+            let output = self
+                .transform
+                .call(&mut self.store, (ptr, len))
+                .map_err(error)? as u64;
+            if output == 0 {
+                return Ok(None);
+            }
+            let (ptr, len) = ((output >> 32) as usize, (output as u32) as usize);
+            let bytes = self
+                .memory
+                .data(&self.store)
+                .get(ptr..ptr + len)
+                .ok_or_else(|| error("event out of the memory"))?;
+            Ok(Some(serde_json::from_slice(bytes).map_err(error)?))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm-plugin"))]
+mod tests {
+    use ckb_jsonrpc_types::{JsonBytes, Script};
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        mock_node::{Harness, MockNode},
+        storage::conformance::key,
+    };
+
+    /// Passes every other event as is and drops the rest, loops forever from the fifth
+    const EVERY_OTHER: &str = r#"(module
+        (memory (export "memory") 2)
+        (global $calls (mut i32) (i32.const 0))
+        (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+        (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+            (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+            (if (i32.ge_u (global.get $calls) (i32.const 5)) (then (loop $forever (br $forever))))
+            (if (result i64) (i32.and (global.get $calls) (i32.const 1))
+                (then (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len))))
+                (else (i64.const 0)))))"#;
+
+    #[tokio::test]
+    async fn plugin_drops_events_and_runs_out_of_fuel() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plugin.wat");
+        std::fs::write(&path, EVERY_OTHER).unwrap();
+        let plugin = Plugin::load(&PluginSettings {
+            path: Some(path.to_str().unwrap().to_string()),
+            fuel: Some(100_000),
+        })
+        .unwrap();
+
+        let node = MockNode::new();
+        let lock = Script {
+            args: JsonBytes::from_vec(vec![1, 7]),
+            ..key(1).script
+        };
+        for _ in 0..4 {
+            node.push_cell(&lock);
+        }
+        node.push_empty(30);
+        let mut harness = Harness::new(node.clone(), key(1), node.block(0));
+        harness.process.sinks.plugin = plugin.map(Arc::new);
+
+        assert_eq!(harness.scan().await, None);
+        assert_eq!(harness.event_blocks(), vec![1, 3]);
+
+        node.push_cell(&lock);
+        node.push_empty(30);
+        harness.scan().await;
+        assert_eq!(harness.scan_tip().block_number.value(), 34 - 24);
+        assert_eq!(harness.event_blocks(), vec![1, 3]);
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fs::{create_dir_all, remove_file},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+};
+
+use crate::{
+    cipher::Cipher, consumer::ConsumerCursors, global_state::Registration,
+    rpc_server::RpcSearchKey, service, snapshot::Snapshot, storage::Storage,
+};
+
+/// Written next to the store on termination signals, in the snapshot format,
+/// sealed like the store when it is encrypted
+const RECOVERY_FILE: &str = "recovery.json";
+
+fn recovery_path(store_path: &Path) -> PathBuf {
+    store_path.join(RECOVERY_FILE)
+}
+
+/// Restores the state left by a signaled shutdown into the store, then removes the recovery file
+pub(crate) fn recover(
+    store_path: &Path,
+    storage: &dyn Storage,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let path = recovery_path(store_path);
+    if !path.exists() {
+        return Ok(());
+    }
+    let snapshot = Snapshot::read(&path, cipher)?;
+    let cursors = snapshot
+        .cursors
+        .into_iter()
+        .map(|c| (c.consumer_id, c.key, c.seq))
+        .collect::<Vec<_>>();
+    storage.save_registrations(&snapshot.registrations)?;
+    storage.save_cursors(&cursors)?;
+    remove_file(&path)?;
+    log::info!(
+        "recovered {} registrations from {:?}",
+        snapshot.registrations.len(),
+        path
+    );
+    Ok(())
+}
+
+/// Flushes the live state to the store, on SIGTERM and SIGINT or when `shutdown` is notified
+/// also serializes it to the recovery file and exits. On SIGHUP calls `on_hangup` and keeps
+/// running, no recovery file is left to override the later state after a crash
+pub(crate) async fn handle_signals(
+    store_path: PathBuf,
+    state: Arc<dashmap::DashMap<RpcSearchKey, Registration>>,
+    cursors: ConsumerCursors,
+    storage: Arc<dyn Storage>,
+    cipher: Option<Cipher>,
+    on_hangup: Box<dyn Fn() + Send>,
+    shutdown: Arc<Notify>,
+) -> io::Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut hup = signal(SignalKind::hangup())?;
+
+    loop {
+        let exit = tokio::select! {
+            _ = term.recv() => true,
+            _ = int.recv() => true,
+            _ = hup.recv() => false,
+            _ = shutdown.notified() => true,
+        };
+        if exit {
+            service::notify("STOPPING=1");
+        }
+
+        let registrations = state
+            .iter()
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect::<Vec<_>>();
+        if exit {
+            let path = recovery_path(&store_path);
+            let written = create_dir_all(&store_path).and_then(|_| {
+                Snapshot::new(registrations.clone(), cursors.all()).write(&path, cipher.as_ref())
+            });
+            match written {
+                Ok(()) => log::info!("wrote recovery file {:?}", path),
+                Err(e) => log::error!("failed to write recovery file {:?}: {}", path, e),
+            }
+        }
+        if let Err(e) = storage.save_registrations(&registrations) {
+            log::error!("failed to flush registrations: {}", e);
+        }
+
+        if exit {
+            log::info!("shutting down");
+            service::remove_pid_file();
+            std::process::exit(0);
+        }
+        on_hangup();
+    }
+}
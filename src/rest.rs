@@ -0,0 +1,162 @@
+//! REST routes in front of the rpc methods, for tools which speak http but not json-rpc. Each route
+//! is answered by calling its method, so quotas, the audit log and the metrics apply as to json-rpc
+
+use hyper::{Body, Method, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{rpc_error::RpcError, rpc_server::RpcSearchKey};
+
+pub(crate) enum Route {
+    /// `GET /registrations`
+    List,
+    /// `POST /registrations`
+    Create,
+    /// `DELETE /registrations/{id}`, `?client=` ends only that client's subscription
+    Delete { id: String, client: Option<String> },
+}
+
+impl Route {
+    pub fn parse(method: &Method, uri: &hyper::Uri) -> Option<Route> {
+        let path = uri.path().trim_end_matches('/');
+        match (method, path) {
+            (&Method::GET, "/registrations") => Some(Route::List),
+            (&Method::POST, "/registrations") => Some(Route::Create),
+            (&Method::DELETE, path) => {
+                let id = path
+                    .strip_prefix("/registrations/")
+                    .filter(|id| !id.is_empty() && !id.contains('/'))?;
+                let client = reqwest::Url::parse(&format!("http://localhost{}", uri))
+                    .ok()?
+                    .query_pairs()
+                    .find(|(name, _)| name == "client")
+                    .map(|(_, client)| client.into_owned());
+                Some(Route::Delete {
+                    id: id.to_string(),
+                    client,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The json-rpc request answering a route, `POST` takes the named params of `register` as its body,
+/// `DELETE` the search key whose id it names. A bad request is answered at once
+pub(crate) fn request(
+    route: &Route,
+    body: &[u8],
+    key: Option<RpcSearchKey>,
+) -> Result<Value, Box<Response<Body>>> {
+    let (method, params) = match route {
+        Route::List => ("info", json!([])),
+        Route::Create => match serde_json::from_slice::<Value>(body) {
+            Ok(params @ Value::Object(_)) => ("register", params),
+            _ => {
+                let e = RpcError::InvalidParams("the body must be a json object".to_string());
+                return Err(Box::new(error(&e.to_json())));
+            }
+        },
+        Route::Delete { client, .. } => match key {
+            Some(key) => ("delete", json!({ "search_key": key, "client": client })),
+            None => return Err(Box::new(error(&RpcError::NotRegistered.to_json()))),
+        },
+    };
+    Ok(json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+}
+
+/// Turns the json-rpc response into the http one, errors get the status of their code
+pub(crate) fn respond(route: &Route, response: &str) -> Response<Body> {
+    let response = match serde_json::from_str::<Value>(response) {
+        Ok(response) => response,
+        Err(_) => return reply(StatusCode::INTERNAL_SERVER_ERROR, None),
+    };
+    if !response["error"].is_null() {
+        return error(&response["error"]);
+    }
+    match route {
+        Route::List => {
+            let registrations = response["result"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| {
+                    let key = serde_json::from_value::<RpcSearchKey>(entry[0].clone()).ok()?;
+                    let mut registration = json!({ "id": key.id(), "search_key": entry[0] });
+                    registration
+                        .as_object_mut()?
+                        .extend(entry[1].as_object()?.clone());
+                    Some(registration)
+                })
+                .collect();
+            reply(StatusCode::OK, Some(&Value::Array(registrations)))
+        }
+        Route::Create => reply(
+            StatusCode::CREATED,
+            Some(&json!({ "id": response["result"] })),
+        ),
+        Route::Delete { .. } => reply(StatusCode::OK, Some(&response["result"])),
+    }
+}
+
+/// `{"error": ...}` with the status of the error's code
+fn error(error: &Value) -> Response<Body> {
+    let status = RpcError::http_status(error["code"].as_i64().unwrap_or_default());
+    reply(status, Some(&json!({ "error": error })))
+}
+
+fn reply(status: StatusCode, body: Option<&Value>) -> Response<Body> {
+    let builder = Response::builder().status(status);
+    match body {
+        Some(body) => builder
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => builder.body(Body::empty()),
+    }
+    .unwrap_or_else(|_| jsonrpsee::http_server::response::internal_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_are_the_registrations_collection() {
+        let parse = |method, uri: &str| Route::parse(&method, &uri.parse().unwrap());
+        assert!(matches!(
+            parse(Method::GET, "/registrations/"),
+            Some(Route::List)
+        ));
+        assert!(matches!(
+            parse(Method::POST, "/registrations"),
+            Some(Route::Create)
+        ));
+        assert!(matches!(
+            parse(Method::DELETE, "/registrations/0x01?client=a"),
+            Some(Route::Delete { id, client }) if id == "0x01" && client.as_deref() == Some("a")
+        ));
+        for (method, uri) in [
+            (Method::GET, "/registrationsXYZ"),
+            (Method::POST, "/registrations/0x01"),
+            (Method::DELETE, "/registrationsXYZ"),
+            (Method::DELETE, "/registrations/"),
+            (Method::DELETE, "/registrations/0x01/0x02"),
+        ] {
+            assert!(parse(method, uri).is_none(), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn namespace_errors_have_their_status() {
+        let status = |e: RpcError| error(&e.to_json()).status();
+        let quota = RpcError::NamespaceQuotaExceeded {
+            namespace: "team".to_string(),
+            max: 1,
+        };
+        assert_eq!(status(quota), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            status(RpcError::Namespaced("admin_shutdown")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(RpcError::NotRegistered), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,271 @@
+use dashmap::DashMap;
+
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    consumer::ConsumerCursors, global_state::Registration, rpc_server::RpcSearchKey,
+    storage::Storage,
+};
+
+/// How often the event log is compacted
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Events are dropped once any of the configured limits is exceeded
+#[derive(Clone, Default)]
+pub(crate) struct RetentionPolicy {
+    /// Drops events of blocks older than this
+    pub max_age: Option<Duration>,
+    /// Keeps at most this many of the newest events
+    pub max_events: Option<u64>,
+    /// Drops events acked by every consumer
+    pub acked: bool,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.max_events.is_none() && !self.acked
+    }
+
+    /// Seq before which the log may be truncated, spilled events are kept until they are delivered
+    fn cut(
+        &self,
+        storage: &dyn Storage,
+        cursors: &ConsumerCursors,
+        state: &DashMap<RpcSearchKey, Registration>,
+    ) -> io::Result<Option<u64>> {
+        let (first, last) = match storage.event_bounds()? {
+            Some(bounds) => bounds,
+            None => return Ok(None),
+        };
+        let mut cut = None;
+
+        if let Some(max_age) = self.max_age {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let oldest = now.saturating_sub(max_age).as_millis() as u64;
+            // events are logged in block order per registration, the first young one bounds the cut
+            let young = storage.read_events(first, u64::MAX, 1, &|event| {
+                event.submit.header.inner.timestamp.value() >= oldest
+            })?;
+            cut = cut.max(Some(young.first().map(|e| e.seq).unwrap_or(last + 1)));
+        }
+
+        if let Some(max_events) = self.max_events {
+            cut = cut.max(Some((last + 1).saturating_sub(max_events)));
+        }
+
+        if self.acked {
+            if let Some(min_acked) = cursors.all().iter().map(|(_, _, seq)| *seq).min() {
+                cut = cut.max(Some(min_acked + 1));
+            }
+        }
+
+        if let Some(spilled) = state.iter().filter_map(|kv| kv.value().spilled_from).min() {
+            cut = cut.map(|cut| cut.min(spilled));
+        }
+
+        Ok(cut.filter(|cut| *cut > first))
+    }
+}
+
+/// Periodically truncates the event log according to the policy
+pub(crate) async fn run(
+    policy: RetentionPolicy,
+    storage: Arc<dyn Storage>,
+    cursors: ConsumerCursors,
+    state: Arc<DashMap<RpcSearchKey, Registration>>,
+) {
+    let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        let compacted = policy
+            .cut(storage.as_ref(), &cursors, &state)
+            .and_then(|cut| match cut {
+                Some(cut) => storage.truncate_events(cut).map(|_| Some(cut)),
+                None => Ok(None),
+            });
+        match compacted {
+            Ok(Some(cut)) => log::info!("Compacted event log before seq {}", cut),
+            Ok(None) => (),
+            Err(e) => log::warn!("Failed to compact event log, error: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ckb_jsonrpc_types::{JsonBytes, Script};
+    use ckb_types::{
+        core::{EpochNumberWithFraction, HeaderBuilder},
+        prelude::*,
+    };
+
+    use super::*;
+    use crate::{
+        event_buffer::EventBuffer,
+        mock_node::{Harness, MockNode},
+        storage::{
+            self,
+            conformance::{key, submit},
+        },
+        Submit,
+    };
+
+    fn stamped(timestamp: u64) -> Submit {
+        Submit::new(
+            HeaderBuilder::default()
+                .timestamp(timestamp.pack())
+                .compact_target(1u32.pack())
+                .epoch(EpochNumberWithFraction::new(0, 0, 1).pack())
+                .build()
+                .into(),
+        )
+    }
+
+    fn memory(submits: Vec<Submit>) -> (Arc<dyn Storage>, ConsumerCursors) {
+        let storage = storage::open("memory", "", None, None).unwrap();
+        storage.append_events(&key(1), submits).unwrap();
+        let cursors = ConsumerCursors::load(storage.clone()).unwrap();
+        (storage, cursors)
+    }
+
+    fn cut(
+        policy: &RetentionPolicy,
+        storage: &dyn Storage,
+        cursors: &ConsumerCursors,
+    ) -> Option<u64> {
+        policy.cut(storage, cursors, &DashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn events_older_than_the_max_age_are_cut() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let hour = 3_600_000;
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+
+        let (storage, cursors) = memory(vec![
+            stamped(now - 3 * hour),
+            stamped(now - 2 * hour),
+            stamped(now),
+        ]);
+        assert_eq!(cut(&policy, storage.as_ref(), &cursors), Some(2));
+        let (storage, cursors) = memory(vec![stamped(now - 2 * hour), stamped(now - hour - 1000)]);
+        assert_eq!(cut(&policy, storage.as_ref(), &cursors), Some(2));
+        let (storage, cursors) = memory(vec![stamped(now), stamped(now)]);
+        assert_eq!(cut(&policy, storage.as_ref(), &cursors), None);
+    }
+
+    #[test]
+    fn events_beyond_the_max_count_are_cut() {
+        let (storage, cursors) = memory((0..5).map(submit).collect());
+        let policy = |max_events| RetentionPolicy {
+            max_events: Some(max_events),
+            ..Default::default()
+        };
+        assert_eq!(cut(&policy(2), storage.as_ref(), &cursors), Some(3));
+        assert_eq!(cut(&policy(5), storage.as_ref(), &cursors), None);
+        assert_eq!(cut(&policy(10), storage.as_ref(), &cursors), None);
+        // the smaller cut of another limit doesn't hold it back
+        let both = RetentionPolicy {
+            acked: true,
+            ..policy(2)
+        };
+        cursors.ack("c", 0).unwrap();
+        assert_eq!(cut(&both, storage.as_ref(), &cursors), Some(3));
+    }
+
+    #[test]
+    fn events_acked_by_every_consumer_are_cut() {
+        let (storage, cursors) = memory((0..5).map(submit).collect());
+        let policy = RetentionPolicy {
+            acked: true,
+            ..Default::default()
+        };
+        // nothing is acked without consumers
+        assert_eq!(cut(&policy, storage.as_ref(), &cursors), None);
+        cursors.ack("a", 2).unwrap();
+        assert_eq!(cut(&policy, storage.as_ref(), &cursors), Some(3));
+        cursors.ack("b", 0).unwrap();
+        assert_eq!(cut(&policy, storage.as_ref(), &cursors), Some(1));
+    }
+
+    #[test]
+    fn the_current_segment_is_never_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage::open("file", dir.path(), None, None).unwrap();
+        storage
+            .append_events(&key(1), (0..3).map(submit).collect())
+            .unwrap();
+        let cursors = ConsumerCursors::load(storage.clone()).unwrap();
+        let policy = RetentionPolicy {
+            max_events: Some(0),
+            ..Default::default()
+        };
+        let before = cut(&policy, storage.as_ref(), &cursors).unwrap();
+        assert_eq!(before, 3);
+        storage.truncate_events(before).unwrap();
+        assert_eq!(storage.event_bounds().unwrap(), Some((0, 2)));
+        let kept = storage
+            .read_events(0, u64::MAX, usize::MAX, &|_| true)
+            .unwrap();
+        assert_eq!(kept.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn spilled_events_are_kept_until_delivered() {
+        let node = MockNode::new();
+        let lock = Script {
+            args: JsonBytes::from_vec(vec![1, 7]),
+            ..key(1).script
+        };
+        node.push_cell(&lock);
+        node.push_cell(&lock);
+        node.push_empty(30);
+        let mut harness = Harness::new(node.clone(), key(1), node.block(0));
+        harness.process.buffer = Some(EventBuffer::new(1));
+        harness.scan().await;
+        let storage = harness.process.sinks.storage.clone();
+        assert_eq!(storage.event_bounds().unwrap(), Some((0, 1)));
+
+        let cursors = ConsumerCursors::load(storage.clone()).unwrap();
+        let policy = RetentionPolicy {
+            max_events: Some(0),
+            ..Default::default()
+        };
+        let state = harness.process.state.clone();
+        assert_eq!(state.get(&key(1)).unwrap().spilled_from, Some(0));
+        assert_eq!(
+            policy.cut(storage.as_ref(), &cursors, &state).unwrap(),
+            None
+        );
+
+        state.get_mut(&key(1)).unwrap().spilled_from = Some(1);
+        assert_eq!(
+            policy.cut(storage.as_ref(), &cursors, &state).unwrap(),
+            Some(1)
+        );
+
+        state.get_mut(&key(1)).unwrap().spilled_from = Some(0);
+        harness.process.buffer = None;
+        node.push_empty(1);
+        harness.scan().await;
+        assert_eq!(state.get(&key(1)).unwrap().spilled_from, None);
+        assert_eq!(
+            policy.cut(storage.as_ref(), &cursors, &state).unwrap(),
+            Some(2)
+        );
+    }
+}
@@ -1,22 +1,33 @@
 #![allow(dead_code)]
 
 use ckb_jsonrpc_types::{
-    BlockNumber, Capacity, CellOutput, HeaderView, JsonBytes, OutPoint, Script, TransactionView,
-    TxStatus, Uint32, Uint64,
+    BlockNumber, BlockView, Capacity, CellOutput, HeaderView, JsonBytes, OutPoint, Script,
+    TransactionView, TxStatus, Uint32, Uint64,
 };
 use ckb_types::H256;
-use reqwest::{Client, Url};
+use rand::Rng;
+use reqwest::{Certificate, Client, Identity, RequestBuilder, Url};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use hyper::{body::Bytes, client::connect::HttpInfo};
+
+use futures::future::BoxFuture;
+
 use std::{
+    collections::HashSet,
     future::Future,
     io,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use crate::{capabilities::IndexerCapabilities, metrics, rpc_stream::Stream, schema};
+
 macro_rules! jsonrpc {
     ($method:expr, $self:ident, $return:ty$(, $params:ident$(,)?)*) => {{
         let old = $self.id.fetch_add(1, Ordering::AcqRel);
@@ -29,20 +40,16 @@ macro_rules! jsonrpc {
 
         let req_json: serde_json::Value = serde_json::from_str(&data).unwrap();
 
-        let c = $self.raw.post($self.ckb_uri.clone()).json(&req_json);
+        let task = $self.request(req_json);
         async {
-            let resp = c
-                .send()
-                .await
-                .map_err::<io::Error, _>(|e| io::Error::new(io::ErrorKind::ConnectionAborted, format!("{:?}", e)))?;
-            let output = resp
-                .json::<jsonrpc_core::response::Output>()
-                .await
-                .map_err::<io::Error, _>(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
-
-            match output {
+            match task.await? {
                 jsonrpc_core::response::Output::Success(success) => {
-                    Ok(serde_json::from_value::<$return>(success.result).unwrap())
+                    serde_json::from_value::<$return>(success.result).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("unexpected result of {}: {}", $method, e),
+                        )
+                    })
                 }
                 jsonrpc_core::response::Output::Failure(e) => {
                     Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
@@ -52,12 +59,94 @@ macro_rules! jsonrpc {
     }}
 }
 
+/// Backoff of ckb rpc calls failing on the transport, e.g. a restarting node,
+/// errors answered by the node are returned at once
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 disables retrying
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every further one
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Waits a random delay between half and all of the backoff, so scans don't retry in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            base_delay_ms: 200,
+            max_delay_ms: 5000,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1 << attempt.min(32))
+            .min(self.max_delay_ms);
+        let millis = if self.jitter && backoff > 0 {
+            rand::thread_rng().gen_range(backoff / 2..=backoff)
+        } else {
+            backoff
+        };
+        Duration::from_millis(millis)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct IndexerTip {
+    #[schemars(with = "schema::Hex")]
     pub block_hash: H256,
+    #[schemars(with = "schema::Hex")]
     pub block_number: BlockNumber,
 }
 
+impl IndexerTip {
+    /// Scan tip of a registration starting beyond the indexer tip, the block hash is zero until
+    /// the start block is indexed
+    pub fn parked(block_number: BlockNumber) -> Self {
+        IndexerTip {
+            block_hash: H256::default(),
+            block_number,
+        }
+    }
+
+    pub fn is_parked(&self) -> bool {
+        self.block_hash == H256::default()
+    }
+}
+
+/// A block the node doesn't serve, it was pruned or the node hasn't synced that far
+#[derive(Debug)]
+pub struct BlockUnavailable(pub u64);
+
+impl BlockUnavailable {
+    /// The unavailable block `e` was returned for
+    pub fn of(e: &io::Error) -> Option<u64> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<BlockUnavailable>())
+            .map(|unavailable| unavailable.0)
+    }
+}
+
+impl std::fmt::Display for BlockUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "block {} is not available on the ckb node, it was pruned or isn't synced yet",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for BlockUnavailable {}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum Order {
@@ -71,7 +160,7 @@ pub struct Pagination<T> {
     pub last_cursor: JsonBytes,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum CellType {
     Input,
@@ -120,6 +209,25 @@ pub struct SearchKey {
     pub group_by_transaction: Option<bool>,
 }
 
+impl SearchKey {
+    /// The key with only the block range left of its filter, none if it filters nothing else
+    pub fn without_filter(&self) -> Option<SearchKey> {
+        let filter = self.filter.as_ref()?;
+        let filters = filter.script.is_some()
+            || filter.script_len_range.is_some()
+            || filter.output_data_len_range.is_some()
+            || filter.output_capacity_range.is_some()
+            || filter.output_data.is_some();
+        filters.then(|| SearchKey {
+            filter: Some(SearchKeyFilter {
+                block_range: filter.block_range,
+                ..Default::default()
+            }),
+            ..self.clone()
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct SearchKeyFilter {
     pub script: Option<Script>,
@@ -127,9 +235,45 @@ pub struct SearchKeyFilter {
     pub output_data_len_range: Option<[Uint64; 2]>,
     pub output_capacity_range: Option<[Uint64; 2]>,
     pub block_range: Option<[BlockNumber; 2]>,
+    /// Only sent to indexers taking it, older ones refuse unknown fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_data: Option<JsonBytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_data_filter_mode: Option<OutputDataMode>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+/// How the data of a cell is compared to the `output_data` of a filter
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputDataMode {
+    /// The data starts with it
+    #[default]
+    Prefix,
+    /// The data is it
+    Exact,
+    /// The data contains it
+    Partial,
+}
+
+impl OutputDataMode {
+    pub fn matches(self, data: &[u8], filter: &[u8]) -> bool {
+        match self {
+            OutputDataMode::Prefix => data.starts_with(filter),
+            OutputDataMode::Exact => data == filter,
+            OutputDataMode::Partial => {
+                filter.is_empty() || data.windows(filter.len()).any(|window| window == filter)
+            }
+        }
+    }
+}
+
+/// `local_node_info` as far as the emitter reads it
+#[derive(Deserialize, Clone, Debug)]
+pub struct LocalNode {
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum ScriptType {
     Lock,
@@ -152,21 +296,333 @@ pub struct Cell {
     pub tx_index: Uint32,
 }
 
+/// Connections kept to the ckb node, calls reuse them instead of reconnecting
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ConnectionPool {
+    /// Idle connections kept open, unlimited when unset
+    pub max_idle: Option<usize>,
+    /// Seconds an idle connection is kept open, 90 when unset
+    pub idle_timeout_secs: Option<u64>,
+    /// Speaks http/2 to a node or proxy on plain http, https nodes negotiate it on their own
+    pub http2_prior_knowledge: bool,
+}
+
+impl ConnectionPool {
+    pub fn idle_timeout_secs(&self) -> u64 {
+        self.idle_timeout_secs.unwrap_or(90)
+    }
+}
+
 #[derive(Clone)]
 pub struct RpcClient {
-    raw: Client,
+    transport: Arc<dyn Transport>,
     ckb_uri: Url,
     id: Arc<AtomicU64>,
+    retry: RetryPolicy,
+    timeout: Duration,
+    slow_call: Option<Duration>,
+    indexer: Arc<IndexerCapabilities>,
+    /// Unix time in milliseconds the last call of the client ended at, see `with_progress`
+    progress: Option<Arc<AtomicU64>>,
+}
+
+/// Sends one attempt of a json-rpc request to the ckb node and returns the undecoded response.
+/// The client retries, times and observes the attempts, failing to reach the node is `ConnectionAborted`
+pub(crate) trait Transport: Send + Sync {
+    fn send(&self, request: &serde_json::Value) -> BoxFuture<'static, io::Result<Bytes>>;
+}
+
+/// The ckb node over http
+struct Http {
+    raw: Client,
+    ckb_uri: Url,
+    credentials: Option<Credentials>,
+    /// Connections which answered a call so far, by local address
+    connections: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl Transport for Http {
+    fn send(&self, request: &serde_json::Value) -> BoxFuture<'static, io::Result<Bytes>> {
+        let mut req = self.raw.post(self.ckb_uri.clone()).json(request);
+        if let Some(credentials) = &self.credentials {
+            req = credentials.apply(req);
+        }
+        let connections = self.connections.clone();
+        Box::pin(async move {
+            let resp = req
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map_err(|e| {
+                    io::Error::new(io::ErrorKind::ConnectionAborted, format!("{:?}", e))
+                })?;
+            count_connection(&connections, &resp);
+            resp.bytes()
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+        })
+    }
+}
+
+/// Counts the response's connection in `emitter_ckb_connections_opened_total` if it is a new one,
+/// a connection is told apart by its local address
+fn count_connection(connections: &Mutex<HashSet<SocketAddr>>, resp: &reqwest::Response) {
+    let local = match resp.extensions().get::<HttpInfo>() {
+        Some(info) => info.local_addr(),
+        None => return,
+    };
+    let mut connections = connections.lock().unwrap();
+    // a closed connection's address may be taken again, the set only has to outnumber the pool
+    if connections.len() > 10_000 {
+        connections.clear();
+    }
+    if connections.insert(local) {
+        metrics::inc("emitter_ckb_connections_opened_total", &[]);
+    }
+}
+
+/// A hung connection to the node must not stall a scan or an rpc handler for good
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Credentials presented to the ckb node, managed node providers often require them
+#[derive(Default)]
+pub struct UpstreamAuth {
+    /// PEM client certificate chain and its PKCS#8 PEM key, for mTLS
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// PEM root certificate trusted besides the system ones, for nodes behind a private CA
+    pub ca_cert: Option<Vec<u8>>,
+    pub credentials: Option<Credentials>,
+}
+
+/// Sent in the authorization header of every request
+#[derive(Clone)]
+pub enum Credentials {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+impl Credentials {
+    fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credentials::Basic { username, password } => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Credentials::Bearer(token) => request.bearer_auth(token),
+        }
+    }
+
+    /// The authorization header, for transports sending their own requests
+    pub(crate) fn header_value(&self) -> String {
+        match self {
+            Credentials::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!(
+                    "{}:{}",
+                    username,
+                    password.as_deref().unwrap_or_default()
+                ))
+            ),
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+        }
+    }
 }
 
 impl RpcClient {
     pub fn new(ckb_uri: &str) -> Self {
         let ckb_uri = Url::parse(ckb_uri).expect("ckb uri, e.g. \"http://127.0.0.1:8114\"");
 
-        RpcClient {
+        if Stream::is_stream(&ckb_uri) {
+            let stream =
+                Stream::new(ckb_uri.clone(), &UpstreamAuth::default()).expect("tls connector");
+            return RpcClient::with_transport(ckb_uri, stream);
+        }
+        let http = Http {
             raw: Client::new(),
+            ckb_uri: ckb_uri.clone(),
+            credentials: None,
+            connections: Default::default(),
+        };
+        RpcClient::with_transport(ckb_uri, http)
+    }
+
+    /// Sends the calls through `transport`, e.g. a scripted node in tests
+    pub(crate) fn with_transport(ckb_uri: Url, transport: impl Transport + 'static) -> Self {
+        RpcClient {
+            transport: Arc::new(transport),
             ckb_uri,
             id: Arc::new(AtomicU64::new(0)),
+            retry: RetryPolicy::default(),
+            timeout: DEFAULT_TIMEOUT,
+            slow_call: None,
+            indexer: Default::default(),
+            progress: None,
+        }
+    }
+
+    /// Builds the connections with the credentials and the pool settings, the pool is http's
+    pub fn with_auth(mut self, auth: UpstreamAuth, pool: &ConnectionPool) -> io::Result<Self> {
+        if Stream::is_stream(&self.ckb_uri) {
+            self.transport = Arc::new(Stream::new(self.ckb_uri.clone(), &auth)?);
+            return Ok(self);
+        }
+        let invalid =
+            |e: reqwest::Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+        let mut builder = Client::builder()
+            .pool_idle_timeout(Duration::from_secs(pool.idle_timeout_secs()))
+            .tcp_keepalive(Duration::from_secs(60));
+        if let Some(max_idle) = pool.max_idle {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if pool.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some((cert, key)) = &auth.identity {
+            builder = builder.identity(Identity::from_pkcs8_pem(cert, key).map_err(invalid)?);
+        }
+        if let Some(ca_cert) = &auth.ca_cert {
+            builder =
+                builder.add_root_certificate(Certificate::from_pem(ca_cert).map_err(invalid)?);
+        }
+        self.transport = Arc::new(Http {
+            raw: builder.build().map_err(invalid)?,
+            ckb_uri: self.ckb_uri.clone(),
+            credentials: auth.credentials,
+            connections: Default::default(),
+        });
+        Ok(self)
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Caps every attempt of a call, a timed out attempt is retried like a failed connection
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Stamps the end of every call of this client into `progress`, a scan waiting on the node
+    /// makes progress as long as they end
+    pub fn with_progress(mut self, progress: Arc<AtomicU64>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Names the node in logs, without the credentials a uri may carry
+    pub fn origin(&self) -> String {
+        match self.ckb_uri.origin() {
+            origin if origin.is_tuple() => origin.ascii_serialization(),
+            // of schemes without a known origin, e.g. tcp
+            _ => format!(
+                "{}://{}:{}",
+                self.ckb_uri.scheme(),
+                self.ckb_uri.host_str().unwrap_or_default(),
+                self.ckb_uri.port().unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Sends the search key filters the indexer takes, see `capabilities::detect`
+    pub fn with_indexer(mut self, indexer: IndexerCapabilities) -> Self {
+        self.indexer = Arc::new(indexer);
+        self
+    }
+
+    pub fn indexer(&self) -> &IndexerCapabilities {
+        &self.indexer
+    }
+
+    /// Calls taking longer, retries included, are logged as a warning
+    pub fn with_slow_call_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_call = Some(threshold);
+        self
+    }
+
+    fn request(
+        &self,
+        req_json: serde_json::Value,
+    ) -> impl Future<Output = Result<jsonrpc_core::response::Output, io::Error>> {
+        let body = self.request_body(req_json);
+        async {
+            serde_json::from_slice(&body.await?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+        }
+    }
+
+    /// The undecoded response, every call is observed in `emitter_ckb_call_duration_seconds` by method
+    fn request_body(
+        &self,
+        req_json: serde_json::Value,
+    ) -> impl Future<Output = Result<Bytes, io::Error>> {
+        let (transport, retry, timeout, slow_call, progress) = (
+            self.transport.clone(),
+            self.retry.clone(),
+            self.timeout,
+            self.slow_call,
+            self.progress.clone(),
+        );
+        async move {
+            let started = Instant::now();
+            let mut attempt = 0;
+            let res = loop {
+                // the timeout also covers reading the body, retried like a failed send
+                let res = tokio::time::timeout(timeout, transport.send(&req_json))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            format!("no response within {:?}", timeout),
+                        ))
+                    });
+                match res {
+                    Err(e)
+                        if e.kind() == io::ErrorKind::ConnectionAborted
+                            && attempt < retry.retries =>
+                    {
+                        let delay = retry.delay(attempt);
+                        log::warn!(
+                            "ckb rpc {} failed, retry in {:?}: {}",
+                            req_json["method"],
+                            delay,
+                            e
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    res => break res,
+                }
+            };
+            let elapsed = started.elapsed();
+            if let Some(progress) = progress {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                progress.store(now.as_millis() as u64, Ordering::Release);
+            }
+            let method = req_json["method"].as_str().unwrap_or_default();
+            metrics::observe(
+                "emitter_ckb_call_duration_seconds",
+                &[("method", method)],
+                elapsed.as_secs_f64(),
+            );
+            if let Some(threshold) = slow_call.filter(|threshold| elapsed > *threshold) {
+                // the node is the bottleneck when its calls are slow, else the scans wait on the emitter
+                log::warn!(
+                    "Slow ckb rpc call, method: {}, elapsed_ms: {}, attempts: {}, threshold_ms: {}, ok: {}",
+                    method,
+                    elapsed.as_millis(),
+                    attempt + 1,
+                    threshold.as_millis(),
+                    res.is_ok()
+                );
+            }
+            res
         }
     }
 
@@ -188,11 +644,36 @@ impl RpcClient {
         }
     }
 
+    /// A block the node doesn't serve is a `BlockUnavailable` error
     pub fn get_header_by_number(
         &self,
         number: BlockNumber,
     ) -> impl Future<Output = Result<HeaderView, io::Error>> {
-        jsonrpc!("get_header_by_number", self, HeaderView, number)
+        let block_number = number.value();
+        let task = jsonrpc!("get_header_by_number", self, Option<HeaderView>, number);
+        async move {
+            task.await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, BlockUnavailable(block_number))
+            })
+        }
+    }
+
+    /// Header of the lowest block of `low..=high` the node serves, nodes keep a contiguous range
+    /// of the chain so the blocks below it are unavailable
+    pub async fn earliest_header(&self, mut low: u64, mut high: u64) -> io::Result<HeaderView> {
+        let mut earliest = self.get_header_by_number(high.into()).await?;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.get_header_by_number(mid.into()).await {
+                Ok(header) => {
+                    earliest = header;
+                    high = mid;
+                }
+                Err(e) if BlockUnavailable::of(&e).is_some() => low = mid + 1,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(earliest)
     }
 
     pub fn get_header(
@@ -202,10 +683,41 @@ impl RpcClient {
         jsonrpc!("get_header", self, Option<HeaderView>, hash)
     }
 
+    /// Also serves the uncles and other blocks the node keeps off the main chain
+    pub fn get_block(
+        &self,
+        hash: H256,
+    ) -> impl Future<Output = Result<Option<BlockView>, io::Error>> {
+        jsonrpc!("get_block", self, Option<BlockView>, hash)
+    }
+
+    /// A block the node doesn't serve is a `BlockUnavailable` error
+    pub fn get_block_by_number(
+        &self,
+        number: BlockNumber,
+    ) -> impl Future<Output = Result<BlockView, io::Error>> {
+        let block_number = number.value();
+        let task = jsonrpc!("get_block_by_number", self, Option<BlockView>, number);
+        async move {
+            task.await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, BlockUnavailable(block_number))
+            })
+        }
+    }
+
+    pub fn local_node_info(&self) -> impl Future<Output = Result<LocalNode, io::Error>> {
+        jsonrpc!("local_node_info", self, LocalNode)
+    }
+
     pub fn get_indexer_tip(&self) -> impl Future<Output = Result<IndexerTip, io::Error>> {
         jsonrpc!("get_indexer_tip", self, IndexerTip)
     }
 
+    /// Of the node's chain, ahead of the indexer tip while the indexer catches up
+    pub fn get_tip_header(&self) -> impl Future<Output = Result<HeaderView, io::Error>> {
+        jsonrpc!("get_tip_header", self, HeaderView)
+    }
+
     pub fn get_transactions(
         &self,
         search_key: SearchKey,
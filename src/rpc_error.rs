@@ -0,0 +1,208 @@
+use hyper::StatusCode;
+use jsonrpsee::{
+    core::Error,
+    types::error::{
+        CallError, ErrorObject, INVALID_PARAMS_CODE, INVALID_REQUEST_CODE, PARSE_ERROR_CODE,
+        SERVER_IS_BUSY_CODE,
+    },
+};
+use serde_json::json;
+
+use std::io;
+
+const CALL_QUOTA_EXCEEDED_CODE: i32 = -32005;
+const UNAUTHORIZED_CODE: i32 = -32006;
+
+const ALREADY_REGISTERED_CODE: i32 = -32010;
+const NOT_REGISTERED_CODE: i32 = -32011;
+const EVENT_NOT_FOUND_CODE: i32 = -32012;
+const READ_ONLY_CODE: i32 = -32013;
+const CLIENT_QUOTA_EXCEEDED_CODE: i32 = -32014;
+const DISABLED_CODE: i32 = -32015;
+const MAINTENANCE_CODE: i32 = -32016;
+const NAMESPACE_QUOTA_EXCEEDED_CODE: i32 = -32017;
+const NAMESPACED_CODE: i32 = -32018;
+const TOTAL_QUOTA_EXCEEDED_CODE: i32 = -32019;
+const NODE_CODE: i32 = -32020;
+const STORAGE_CODE: i32 = -32021;
+const DELIVERY_CODE: i32 = -32022;
+
+/// Why an rpc call failed, every variant has its own error code and a `reason` in the error data
+/// so clients needn't parse messages
+pub(crate) enum RpcError {
+    AlreadyRegistered,
+    NotRegistered,
+    /// The logged event to ack doesn't exist or was dropped by retention
+    EventNotFound(u64),
+    ReadOnly(&'static str),
+    /// An `admin_` method called without the admin token
+    Unauthorized(String),
+    /// The caller went over the calls per second of the method's `rpc.method_quotas`
+    CallQuotaExceeded {
+        method: String,
+        max: u32,
+    },
+    QuotaExceeded {
+        client: Option<String>,
+        max: usize,
+    },
+    NamespaceQuotaExceeded {
+        namespace: String,
+        max: usize,
+    },
+    /// The registrations of all clients reached `quotas.max_total`
+    TotalQuotaExceeded(usize),
+    /// A method of the whole host, refused to the callers of a namespace
+    Namespaced(&'static str),
+    /// A feature the emitter wasn't started with
+    Disabled(&'static str),
+    /// Registrations are refused while the scans are paused by `admin_maintenance`
+    Maintenance,
+    InvalidParams(String),
+    /// The ckb node failed or is unreachable
+    Node(io::Error),
+    Storage(io::Error),
+    /// The sink refused or couldn't take the events
+    Delivery(io::Error),
+}
+
+impl RpcError {
+    fn code(&self) -> i32 {
+        match self {
+            RpcError::AlreadyRegistered => ALREADY_REGISTERED_CODE,
+            RpcError::NotRegistered => NOT_REGISTERED_CODE,
+            RpcError::EventNotFound(_) => EVENT_NOT_FOUND_CODE,
+            RpcError::ReadOnly(_) => READ_ONLY_CODE,
+            RpcError::Unauthorized(_) => UNAUTHORIZED_CODE,
+            RpcError::CallQuotaExceeded { .. } => CALL_QUOTA_EXCEEDED_CODE,
+            RpcError::QuotaExceeded { .. } => CLIENT_QUOTA_EXCEEDED_CODE,
+            RpcError::Disabled(_) => DISABLED_CODE,
+            RpcError::Maintenance => MAINTENANCE_CODE,
+            RpcError::NamespaceQuotaExceeded { .. } => NAMESPACE_QUOTA_EXCEEDED_CODE,
+            RpcError::Namespaced(_) => NAMESPACED_CODE,
+            RpcError::TotalQuotaExceeded(_) => TOTAL_QUOTA_EXCEEDED_CODE,
+            RpcError::InvalidParams(_) => INVALID_PARAMS_CODE,
+            RpcError::Node(_) => NODE_CODE,
+            RpcError::Storage(_) => STORAGE_CODE,
+            RpcError::Delivery(_) => DELIVERY_CODE,
+        }
+    }
+
+    /// The http status of the rest api answering an error with `code`, of this enum or of the
+    /// json-rpc server
+    pub(crate) fn http_status(code: i64) -> StatusCode {
+        match i32::try_from(code).unwrap_or_default() {
+            PARSE_ERROR_CODE | INVALID_REQUEST_CODE | INVALID_PARAMS_CODE => {
+                StatusCode::BAD_REQUEST
+            }
+            UNAUTHORIZED_CODE => StatusCode::UNAUTHORIZED,
+            READ_ONLY_CODE | DISABLED_CODE | NAMESPACED_CODE => StatusCode::FORBIDDEN,
+            NOT_REGISTERED_CODE | EVENT_NOT_FOUND_CODE => StatusCode::NOT_FOUND,
+            ALREADY_REGISTERED_CODE => StatusCode::CONFLICT,
+            CALL_QUOTA_EXCEEDED_CODE
+            | CLIENT_QUOTA_EXCEEDED_CODE
+            | NAMESPACE_QUOTA_EXCEEDED_CODE
+            | TOTAL_QUOTA_EXCEEDED_CODE => StatusCode::TOO_MANY_REQUESTS,
+            // maintenance and a busy server
+            MAINTENANCE_CODE | SERVER_IS_BUSY_CODE => StatusCode::SERVICE_UNAVAILABLE,
+            NODE_CODE => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The error object of a json-rpc response, as it answers the call
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({ "code": self.code(), "message": self.message(), "data": self.data() })
+    }
+
+    fn data(&self) -> serde_json::Value {
+        let mut data = json!({ "reason": self.reason() });
+        match self {
+            RpcError::EventNotFound(seq) => data["seq"] = json!(seq),
+            RpcError::QuotaExceeded { client, max } => {
+                data["client"] = json!(client);
+                data["max"] = json!(max);
+            }
+            RpcError::NamespaceQuotaExceeded { namespace, max } => {
+                data["namespace"] = json!(namespace);
+                data["max"] = json!(max);
+            }
+            RpcError::TotalQuotaExceeded(max) => data["max"] = json!(max),
+            RpcError::CallQuotaExceeded { max, .. } => data["max"] = json!(max),
+            _ => {}
+        }
+        data
+    }
+
+    fn reason(&self) -> &'static str {
+        match self {
+            RpcError::AlreadyRegistered => "already_registered",
+            RpcError::NotRegistered => "not_registered",
+            RpcError::EventNotFound(_) => "event_not_found",
+            RpcError::ReadOnly(_) => "read_only",
+            RpcError::Unauthorized(_) => "unauthorized",
+            RpcError::CallQuotaExceeded { .. } => "call_quota_exceeded",
+            RpcError::QuotaExceeded { .. } => "quota_exceeded",
+            RpcError::Disabled(_) => "disabled",
+            RpcError::Maintenance => "maintenance",
+            RpcError::NamespaceQuotaExceeded { .. } => "namespace_quota_exceeded",
+            RpcError::Namespaced(_) => "namespaced",
+            RpcError::TotalQuotaExceeded(_) => "total_quota_exceeded",
+            RpcError::InvalidParams(_) => "invalid_params",
+            RpcError::Node(_) => "node",
+            RpcError::Storage(_) => "storage",
+            RpcError::Delivery(_) => "delivery",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            RpcError::AlreadyRegistered => "search key is registered already".to_string(),
+            RpcError::NotRegistered => "search key is not registered".to_string(),
+            RpcError::EventNotFound(seq) => format!("no logged event with seq {}", seq),
+            RpcError::ReadOnly(method) => {
+                format!("{} is disabled, the emitter runs in read-only mode", method)
+            }
+            RpcError::Unauthorized(method) => {
+                format!("{} needs the admin token as bearer token", method)
+            }
+            RpcError::CallQuotaExceeded { method, max } => {
+                format!("quota of {} calls per second to {} exceeded", max, method)
+            }
+            RpcError::QuotaExceeded { client, max } => format!(
+                "client {} reached its quota of {} registrations",
+                client.as_deref().unwrap_or("without a name"),
+                max
+            ),
+            RpcError::NamespaceQuotaExceeded { namespace, max } => format!(
+                "namespace {} reached its quota of {} registrations",
+                namespace, max
+            ),
+            RpcError::TotalQuotaExceeded(max) => {
+                format!(
+                    "the clients together reached the quota of {} registrations",
+                    max
+                )
+            }
+            RpcError::Namespaced(method) => {
+                format!("{} is refused to the callers of a namespace", method)
+            }
+            RpcError::Disabled(hint) => hint.to_string(),
+            RpcError::Maintenance => "the emitter is in maintenance mode".to_string(),
+            RpcError::InvalidParams(e) => e.clone(),
+            RpcError::Node(e) => format!("ckb node: {}", e),
+            RpcError::Storage(e) => format!("storage: {}", e),
+            RpcError::Delivery(e) => format!("delivery: {}", e),
+        }
+    }
+}
+
+impl From<RpcError> for Error {
+    fn from(e: RpcError) -> Self {
+        Error::Call(CallError::Custom(ErrorObject::owned(
+            e.code(),
+            e.message(),
+            Some(e.data()),
+        )))
+    }
+}
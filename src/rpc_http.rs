@@ -0,0 +1,703 @@
+use hyper::{
+    body::HttpBody, server::conn::Http, service::service_fn, Body, HeaderMap, Method, Request,
+    Response,
+};
+use jsonrpsee::{
+    core::{
+        http_helpers::{get_cors_request_headers, read_header_value},
+        server::rpc_module::Methods,
+    },
+    http_server::{response, AccessControl, AccessControlBuilder},
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::Semaphore,
+};
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs::{remove_file, symlink_metadata},
+    io,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    audit::AuditLog,
+    config::{NamespaceSettings, RpcSettings},
+    graphql,
+    lifecycle::DebugTasks,
+    metrics, rest,
+    rpc_error::RpcError,
+    rpc_server::{RpcSearchKey, MUTATING_METHODS},
+    status,
+};
+
+/// Default of `max_request_body_size`, the one of jsonrpsee
+pub(crate) const MAX_REQUEST_BODY_SIZE: u32 = 10 * 1024 * 1024;
+
+/// Longest `x-request-id` kept, longer ones are ignored
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// `x-request-id` of the http request the running rpc method answers
+    static REQUEST_ID: Option<String>;
+}
+
+tokio::task_local! {
+    /// Namespace of the token of the http request the running rpc method answers
+    static NAMESPACE: Option<String>;
+}
+
+/// The client's id of the request being answered when called from an rpc method
+pub(crate) fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok().flatten()
+}
+
+/// The namespace the request being answered is scoped to, none for the admin token and without
+/// namespaces
+pub(crate) fn namespace() -> Option<String> {
+    NAMESPACE.try_with(Clone::clone).ok().flatten()
+}
+
+/// Runs `f` as if answering a request with the id, for tests calling the rpc methods directly
+#[cfg(test)]
+pub(crate) async fn with_request_id<F: std::future::Future>(id: &str, f: F) -> F::Output {
+    REQUEST_ID.scope(Some(id.to_string()), f).await
+}
+
+/// Runs `f` as if answering a request with the namespace's token, for tests calling the rpc
+/// methods directly
+#[cfg(test)]
+pub(crate) async fn with_namespace<F: std::future::Future>(namespace: &str, f: F) -> F::Output {
+    NAMESPACE.scope(Some(namespace.to_string()), f).await
+}
+
+/// Http front of the rpc module on tcp and unix sockets, checks CORS like jsonrpsee does
+/// and rejects requests over the limits before they reach a method. `GET /metrics` serves the metrics
+#[derive(Clone)]
+pub(crate) struct RpcHttp {
+    methods: Methods,
+    acl: AccessControl,
+    max_request_body_size: u32,
+    in_flight: Option<Arc<Semaphore>>,
+    quotas: Arc<MethodQuotas>,
+    audit: Option<AuditLog>,
+    tasks: DebugTasks,
+    graphql: bool,
+    /// Bearer token of `/debug/tasks`, served only when set
+    debug_token: Option<String>,
+    /// Bearer token of `/status`, served only when set
+    status_token: Option<String>,
+    /// Bearer token of the `admin_` methods, refused to every request when unset
+    admin_token: Option<String>,
+    /// Namespaces by their bearer token, with any every request needs one of them or the admin token
+    namespaces: HashMap<String, String>,
+}
+
+/// Calls per second of a method allowed to every client, refilled continuously
+struct MethodQuotas {
+    limits: HashMap<String, u32>,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl MethodQuotas {
+    /// Takes a call from the client's bucket, the quota if none is left
+    fn take(&self, client: &str, method: &str) -> Result<(), u32> {
+        let limit = match self.limits.get(method) {
+            Some(limit) => *limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // forget idle clients once in a while, a full bucket is the same as none
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, bucket| now.duration_since(bucket.refilled).as_secs() < 1);
+        }
+        let bucket = buckets
+            .entry((client.to_string(), method.to_string()))
+            .or_insert(Bucket {
+                tokens: limit as f64,
+                refilled: now,
+            });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit as f64).min(limit as f64);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            return Err(limit);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+impl RpcHttp {
+    pub fn new(
+        methods: Methods,
+        settings: &RpcSettings,
+        audit: Option<AuditLog>,
+        tasks: DebugTasks,
+    ) -> io::Result<Self> {
+        let mut acl = AccessControlBuilder::new();
+        if let Some(origins) = &settings.cors_allowed_origins {
+            acl = acl
+                .set_allowed_origins(origins.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        }
+        Ok(RpcHttp {
+            methods,
+            acl: acl.build(),
+            max_request_body_size: settings
+                .max_request_body_size
+                .unwrap_or(MAX_REQUEST_BODY_SIZE),
+            in_flight: settings
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max as usize))),
+            quotas: Arc::new(MethodQuotas {
+                limits: settings.method_quotas.clone(),
+                buckets: Default::default(),
+            }),
+            audit,
+            tasks,
+            graphql: settings.graphql,
+            debug_token: None,
+            status_token: None,
+            admin_token: None,
+            namespaces: HashMap::new(),
+        })
+    }
+
+    pub fn with_debug(mut self, token: String) -> Self {
+        self.debug_token = Some(token);
+        self
+    }
+
+    pub fn with_status(mut self, token: String) -> Self {
+        self.status_token = Some(token);
+        self
+    }
+
+    pub fn with_admin(mut self, token: String) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    pub fn with_namespaces(mut self, namespaces: &HashMap<String, NamespaceSettings>) -> Self {
+        self.namespaces = namespaces
+            .iter()
+            .map(|(name, namespace)| (namespace.token.clone(), name.clone()))
+            .collect();
+        self
+    }
+
+    /// Every remote ip is a client of its own
+    pub async fn serve_tcp(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => self.serve_connection(stream, addr.ip().to_string()),
+                Err(e) => log::warn!("Failed to accept rpc client, error: {:?}", e),
+            }
+        }
+    }
+
+    /// Access is controlled by the permissions of the socket file, all callers share the quotas
+    pub async fn serve_unix(self, listener: UnixListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => self.serve_connection(stream, "unix".to_string()),
+                Err(e) => log::warn!("Failed to accept rpc unix socket client, error: {:?}", e),
+            }
+        }
+    }
+
+    fn serve_connection<S>(&self, stream: S, client: String)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let front = self.clone();
+        metrics::spawn("rpc_connection", async move {
+            let service = service_fn(move |request| {
+                let (front, client) = (front.clone(), client.clone());
+                async move { Ok::<_, Infallible>(front.handle(&client, request).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                log::debug!("rpc connection closed, error: {:?}", e);
+            }
+        });
+    }
+
+    async fn handle(&self, client: &str, request: Request<Body>) -> Response<Body> {
+        let headers = request.headers();
+        let host = match read_header_value(headers, "host") {
+            Some(host) => host,
+            None => return response::malformed(),
+        };
+        let origin = read_header_value(headers, "origin").map(|s| s.to_string());
+        if self.acl.verify_host(host).is_err() {
+            return response::host_not_allowed();
+        }
+        if self.acl.verify_origin(origin.as_deref(), host).is_err() {
+            return response::invalid_allow_origin();
+        }
+        let keys = headers.keys().map(|k| k.as_str());
+        if self
+            .acl
+            .verify_headers(keys, get_cors_request_headers(headers))
+            .is_err()
+        {
+            return response::invalid_allow_headers();
+        }
+
+        let route = rest::Route::parse(request.method(), request.uri());
+        let mut res = match *request.method() {
+            Method::OPTIONS => {
+                let origin = match &origin {
+                    Some(origin) => origin.clone(),
+                    None => return response::malformed(),
+                };
+                return Response::builder()
+                    .header("access-control-allow-origin", origin)
+                    .header("access-control-allow-methods", "POST")
+                    .header(
+                        "access-control-allow-headers",
+                        self.acl.allowed_headers().to_cors_header_value().as_ref(),
+                    )
+                    .body(Body::empty())
+                    .unwrap_or_else(|_| response::internal_error());
+            }
+            Method::GET if request.uri().path() == "/debug/tasks" => self.debug_tasks(headers),
+            Method::GET if request.uri().path() == "/status" => self.status(headers).await,
+            Method::GET if request.uri().path() == "/metrics" => {
+                self.tasks.sample_metrics();
+                Response::builder()
+                    .header("content-type", "text/plain; version=0.0.4")
+                    .body(Body::from(metrics::render()))
+                    .unwrap_or_else(|_| response::internal_error())
+            }
+            Method::POST if route.is_some() && !is_json(request.headers()) => {
+                response::unsupported_content_type()
+            }
+            _ if route.is_some() => {
+                let target = Target::Rest(route.unwrap());
+                self.process(client, target, request).await
+            }
+            Method::POST if self.graphql && request.uri().path() == "/graphql" => {
+                match is_json(request.headers()) {
+                    true => self.process(client, Target::GraphQl, request).await,
+                    false => response::unsupported_content_type(),
+                }
+            }
+            Method::POST if is_json(request.headers()) => {
+                self.process(client, Target::JsonRpc, request).await
+            }
+            Method::POST => response::unsupported_content_type(),
+            _ => response::method_not_allowed(),
+        };
+        if let Some(origin) = origin.and_then(|origin| origin.parse().ok()) {
+            res.headers_mut()
+                .insert("access-control-allow-origin", origin);
+        }
+        res
+    }
+
+    fn debug_tasks(&self, headers: &HeaderMap) -> Response<Body> {
+        let token = match &self.debug_token {
+            Some(token) => token,
+            None => return response::method_not_allowed(),
+        };
+        if !bearer(headers, token) {
+            return unauthorized();
+        }
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(self.tasks.dump().to_string()))
+            .unwrap_or_else(|_| response::internal_error())
+    }
+
+    async fn status(&self, headers: &HeaderMap) -> Response<Body> {
+        let token = match &self.status_token {
+            Some(token) => token,
+            None => return response::method_not_allowed(),
+        };
+        if !bearer(headers, token) {
+            return unauthorized();
+        }
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(
+                status::overview(&self.methods).await.to_string(),
+            ))
+            .unwrap_or_else(|_| response::internal_error())
+    }
+
+    async fn process(
+        &self,
+        client: &str,
+        target: Target,
+        request: Request<Body>,
+    ) -> Response<Body> {
+        let _permit = match &self.in_flight {
+            Some(in_flight) => match in_flight.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    return response::ok_response(error(
+                        &serde_json::Value::Null,
+                        jsonrpsee::types::error::SERVER_IS_BUSY_CODE,
+                        jsonrpsee::types::error::SERVER_IS_BUSY_MSG,
+                        None,
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        let admin = self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| bearer(request.headers(), token));
+        let namespace = self
+            .namespaces
+            .iter()
+            .filter(|_| !admin)
+            .find(|(token, _)| bearer(request.headers(), token))
+            .map(|(_, name)| name.clone());
+        if !self.namespaces.is_empty() && !admin && namespace.is_none() {
+            return unauthorized();
+        }
+        let request_id = read_header_value(request.headers(), "x-request-id")
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.bytes().all(|b| b.is_ascii_graphic())
+            })
+            .map(str::to_string);
+        let limit = self.max_request_body_size;
+        let mut body = request.into_body();
+        let mut bytes = Vec::new();
+        if Some(limit as u64) < body.size_hint().upper() {
+            return response::too_large(limit);
+        }
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) if bytes.len() + chunk.len() <= limit as usize => {
+                    bytes.extend_from_slice(&chunk)
+                }
+                Ok(_) => return response::too_large(limit),
+                Err(_) => return response::malformed(),
+            }
+        }
+        let request_id = request_id.as_deref();
+        // the methods, the graphql queries and the key lookups of rest all see the namespace
+        let answer = async {
+            match target {
+                Target::JsonRpc => {
+                    response::ok_response(self.call(client, admin, request_id, &bytes).await)
+                }
+                Target::Rest(route) => self.rest(client, admin, request_id, route, &bytes).await,
+                Target::GraphQl => self.graphql(client, &bytes).await,
+            }
+        };
+        let mut response = NAMESPACE.scope(namespace, answer).await;
+        if let Some(id) = request_id.and_then(|id| id.parse().ok()) {
+            response.headers_mut().insert("x-request-id", id);
+        }
+        response
+    }
+
+    async fn rest(
+        &self,
+        client: &str,
+        admin: bool,
+        request_id: Option<&str>,
+        route: rest::Route,
+        body: &[u8],
+    ) -> Response<Body> {
+        let key = match &route {
+            rest::Route::Delete { id, .. } => self.find_key(id).await,
+            _ => None,
+        };
+        let request = match rest::request(&route, body, key) {
+            Ok(request) => request,
+            Err(response) => return *response,
+        };
+        let response = self
+            .call(client, admin, request_id, request.to_string().as_bytes())
+            .await;
+        rest::respond(&route, &response)
+    }
+
+    /// Counted as calls of a `graphql` method, its quota applies to each query
+    async fn graphql(&self, client: &str, body: &[u8]) -> Response<Body> {
+        let started = Instant::now();
+        let response = match self.quotas.take(client, "graphql") {
+            Ok(()) => graphql::execute(&self.methods, body).await,
+            Err(limit) => serde_json::json!({
+                "data": null,
+                "errors": [{ "message": format!("quota of {} calls per second to graphql exceeded", limit) }],
+            }),
+        };
+        metrics::inc("emitter_rpc_requests_total", &[("method", "graphql")]);
+        metrics::observe(
+            "emitter_rpc_request_duration_seconds",
+            &[("method", "graphql")],
+            started.elapsed().as_secs_f64(),
+        );
+        if response["errors"].is_array() {
+            metrics::inc(
+                "emitter_rpc_errors_total",
+                &[("method", "graphql"), ("code", "graphql")],
+            );
+        }
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(response.to_string()))
+            .unwrap_or_else(|_| response::internal_error())
+    }
+
+    /// The registered search key with the id, looked up without counting as a call of `info`
+    async fn find_key(&self, id: &str) -> Option<RpcSearchKey> {
+        local_call(&self.methods, "info", serde_json::json!([]))
+            .await
+            .ok()?
+            .as_array()?
+            .iter()
+            .filter_map(|entry| serde_json::from_value::<RpcSearchKey>(entry[0].clone()).ok())
+            .find(|key| key.id() == id)
+    }
+
+    /// Answers a single or a batch request, `admin` if it has the admin token. The calls of a
+    /// batch share its `request_id`
+    async fn call(
+        &self,
+        client: &str,
+        admin: bool,
+        request_id: Option<&str>,
+        body: &[u8],
+    ) -> String {
+        const PARSE_ERROR: &str =
+            r#"{"jsonrpc":"2.0","error":{"code":-32700,"message":"Parse error"},"id":null}"#;
+
+        let dispatch = |request: serde_json::Value| async move {
+            let method = request["method"].as_str().unwrap_or_default();
+            if method.starts_with("admin_") && !admin {
+                return refused(&request["id"], RpcError::Unauthorized(method.to_string()));
+            }
+            if let Err(max) = self.quotas.take(client, method) {
+                let method = method.to_string();
+                return refused(&request["id"], RpcError::CallQuotaExceeded { method, max });
+            }
+            let raw = request.to_string();
+            let answer = self.methods.raw_json_request(&raw);
+            let response = match REQUEST_ID
+                .scope(request_id.map(str::to_string), answer)
+                .await
+            {
+                Ok((response, _)) => response.result,
+                Err(_) => return PARSE_ERROR.to_string(),
+            };
+            if let Some(audit) = self
+                .audit
+                .as_ref()
+                .filter(|_| MUTATING_METHODS.contains(&method))
+            {
+                let error = serde_json::from_str::<serde_json::Value>(&response)
+                    .ok()
+                    .and_then(|response| Some(response["error"]["message"].as_str()?.to_string()));
+                let params = request["params"].clone();
+                if let Err(e) = audit.record(client, request_id, method, params, error) {
+                    log::error!(
+                        "Failed to record {} call in the audit log, error: {:?}",
+                        method,
+                        e
+                    );
+                }
+            }
+            response
+        };
+        // times every call and labels it by method, unknown methods by `unknown` so a client
+        // can't add labels
+        let call = |request: serde_json::Value| async move {
+            let started = Instant::now();
+            let method = match request["method"].as_str() {
+                Some(method) if self.methods.method(method).is_some() => method.to_string(),
+                _ => "unknown".to_string(),
+            };
+            let response = dispatch(request).await;
+            let elapsed = started.elapsed();
+            let code = serde_json::from_str::<serde_json::Value>(&response)
+                .ok()
+                .and_then(|response| response["error"]["code"].as_i64());
+            metrics::inc("emitter_rpc_requests_total", &[("method", &method)]);
+            metrics::observe(
+                "emitter_rpc_request_duration_seconds",
+                &[("method", &method)],
+                elapsed.as_secs_f64(),
+            );
+            match code {
+                Some(code) => {
+                    metrics::inc(
+                        "emitter_rpc_errors_total",
+                        &[("method", &method), ("code", &code.to_string())],
+                    );
+                    log::info!(
+                        "rpc method={} client={} request_id={} duration_ms={} error={}",
+                        method,
+                        client,
+                        request_id.unwrap_or("-"),
+                        elapsed.as_millis(),
+                        code
+                    );
+                }
+                None => log::debug!(
+                    "rpc method={} client={} request_id={} duration_ms={}",
+                    method,
+                    client,
+                    request_id.unwrap_or("-"),
+                    elapsed.as_millis()
+                ),
+            }
+            response
+        };
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(serde_json::Value::Array(batch)) => {
+                let mut responses = Vec::with_capacity(batch.len());
+                for request in batch {
+                    responses.push(call(request).await);
+                }
+                format!("[{}]", responses.join(","))
+            }
+            Ok(request) => call(request).await,
+            Err(_) => PARSE_ERROR.to_string(),
+        }
+    }
+}
+
+/// Calls a method bypassing the quotas, audit log and metrics, for the answers made of other methods.
+/// The result, or the error message
+pub(crate) async fn local_call(
+    methods: &Methods,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let request =
+        serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let (response, _) = methods
+        .raw_json_request(&request.to_string())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut response =
+        serde_json::from_str::<serde_json::Value>(&response.result).map_err(|e| e.to_string())?;
+    match response["error"]["message"].as_str() {
+        Some(message) => Err(format!("{}: {}", method, message)),
+        None => Ok(response["result"].take()),
+    }
+}
+
+/// What a request is answered by, once its body is read
+enum Target {
+    JsonRpc,
+    Rest(rest::Route),
+    GraphQl,
+}
+
+/// Binds the unix socket path, a stale socket file from a previous run would make bind fail and is
+/// removed, any other file at the path is refused
+pub(crate) fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    match symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Whether the request has the token in its `Authorization: Bearer` header
+fn bearer(headers: &HeaderMap, token: &str) -> bool {
+    read_header_value(headers, "authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// 401 asking for a bearer token
+fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(hyper::StatusCode::UNAUTHORIZED)
+        .header("www-authenticate", "Bearer")
+        .body(Body::empty())
+        .unwrap_or_else(|_| response::internal_error())
+}
+
+/// Doesn't reveal how much of a token matched through its timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    read_header_value(headers, "content-type").is_some_and(|content_type| {
+        content_type.eq_ignore_ascii_case("application/json")
+            || content_type.starts_with("application/json;")
+    })
+}
+
+/// The response refusing the call with `id` before its method runs
+fn refused(id: &serde_json::Value, error: RpcError) -> String {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "error": error.to_json(),
+        "id": id,
+    })
+    .to_string()
+}
+
+fn error(
+    id: &serde_json::Value,
+    code: i32,
+    message: &str,
+    data: Option<serde_json::Value>,
+) -> String {
+    let mut error = serde_json::json!({ "code": code, "message": message });
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "error": error,
+        "id": id,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_a_stale_socket_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("emitter.sock");
+        drop(bind_unix(&path).unwrap());
+        // left behind by the dropped listener
+        assert!(path.exists());
+        drop(bind_unix(&path).unwrap());
+
+        let file = dir.path().join("config.toml");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind_unix(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
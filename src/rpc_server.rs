@@ -1,141 +1,1683 @@
-use ckb_jsonrpc_types::{BlockNumber, Script, Uint64};
+use ckb_jsonrpc_types::{BlockNumber, JsonBytes, OutPoint, Script, Uint32, Uint64};
+use ckb_types::{packed, prelude::Unpack, H256};
 use jsonrpsee::{
     core::{async_trait, Error},
     proc_macros::rpc,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
 
-use std::sync::{atomic::AtomicPtr, Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    cell_process::CellProcess,
-    rpc_client::{IndexerTip, RpcClient, ScriptType, SearchKey, SearchKeyFilter},
-    ScanTip, ScanTipInner,
+    audit::{AuditEntry, AuditLog},
+    capabilities::IndexerCapabilities,
+    cell_process::now_ms,
+    cipher::Cipher,
+    config::{Config, DeclaredRegistration, QuotaSettings},
+    consumer::ConsumerCursors,
+    correlation::CorrelationRule,
+    global_state::{LagAction, LagThreshold, Registration, Status, Subscriber},
+    lifecycle::{Lifecycle, TipSnapshot},
+    lineage::{self, CellHistory},
+    live_cells::{self, LiveCell, LiveSummary},
+    logging,
+    network::Network,
+    overlap::{self, Overlaps},
+    pattern::DataPattern,
+    rpc_client::{
+        BlockUnavailable, IndexerTip, Order, OutputDataMode, RpcClient, ScriptType, SearchKey,
+        SearchKeyFilter,
+    },
+    rpc_error::RpcError,
+    rpc_http,
+    scheduler::Priority,
+    schema, sealing,
+    since::SinceFilter,
+    sink::{Compression, SinkConfig, Sinks},
+    snapshot::Snapshot,
+    stats::Stats,
+    summary::SummaryMode,
+    validate::{self, Validation},
+    witness::WitnessSelection,
+    ScanTip,
 };
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Hash, PartialEq, Eq)]
 pub struct RpcSearchKey {
+    #[schemars(with = "schema::Script")]
     pub script: Script,
     pub script_type: ScriptType,
     pub filter: Option<RpcSearchKeyFilter>,
+    /// Output data is left out of the events when false, included by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub with_data: Option<bool>,
+    /// Blocks with uncles are events listing them when true, with or without matched cells.
+    /// Every scanned block's header is fetched for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub with_uncles: Option<bool>,
+    /// The first block of each epoch is an event carrying the epoch when true, with or without
+    /// matched cells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub with_epochs: Option<bool>,
+    /// Events list these witnesses of the transactions with matched cells, none by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub with_witnesses: Option<WitnessSelection>,
+    /// Events carry the cells the matched inputs spend when true, looked up in the transactions
+    /// creating them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub with_input_cells: Option<bool>,
+    /// The transactions creating and consuming the emitted cells are stored for `get_cell_history`
+    /// when true
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_lineage: Option<bool>,
+    /// The live cells of the registration are stored for `get_live_cells` when true, every event
+    /// carries how it changed them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_live_cells: Option<bool>,
+    /// Events only have the first output of each distinct script under the key when true, the
+    /// scripts emitted before and the inputs are left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discover: Option<bool>,
+    /// Events list the transactions consuming cells of the key and creating cells of the rule's key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlate: Option<CorrelationRule>,
+    /// Events carry a summary of the block's matched cells, alongside or instead of the cells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<SummaryMode>,
+    /// Events only list the hashes of the transactions with matched cells when true, not the cells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transactions_only: Option<bool>,
+    /// The namespace the key is registered in, the one of the caller's token. Callers without a
+    /// namespace token may name one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
 }
 
 impl RpcSearchKey {
-    pub fn into_key(self, block_range: Option<[Uint64; 2]>) -> SearchKey {
+    /// Names the registration in metric labels, script type and script hash after the namespace,
+    /// the filter is left out
+    pub fn metric_label(&self) -> String {
+        let hash: H256 = packed::Script::from(self.script.clone())
+            .calc_script_hash()
+            .unpack();
+        let label = format!("{:?}:{:#x}", self.script_type, hash).to_lowercase();
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, label),
+            None => label,
+        }
+    }
+
+    /// Names the key in events, the sha256 of its json, so it stays the same across restarts
+    pub fn id(&self) -> String {
+        let digest = Sha256::digest(serde_json::to_vec(self).unwrap());
+        format!("0x{}", faster_hex::hex_string(&digest))
+    }
+
+    /// Refuses filters which contradict themselves or the key
+    pub fn check(&self) -> Result<(), String> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return Ok(()),
+        };
+        if filter.has_type_script.is_some() {
+            if self.script_type == ScriptType::Type {
+                return Err("has_type_script filters the cells of lock scripts".to_string());
+            }
+            if filter.script_len_range.is_some() {
+                return Err(
+                    "has_type_script and script_len_range both filter by the type script"
+                        .to_string(),
+                );
+            }
+        }
+        if let Some(pattern) = &filter.args_pattern {
+            if !pattern.allows_prefix(self.script.args.as_bytes()) {
+                return Err(format!(
+                    "args_pattern {} doesn't agree with the script args",
+                    pattern
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_key(
+        self,
+        block_range: Option<[Uint64; 2]>,
+        indexer: &IndexerCapabilities,
+    ) -> SearchKey {
+        let mut script = self.script;
+        // a longer fixed prefix narrows the indexer's search, `check` made sure it agrees with the args
+        if let Some(pattern) = self.filter.as_ref().and_then(|f| f.args_pattern.as_ref()) {
+            let prefix = pattern.fixed_prefix();
+            if prefix.len() > script.args.len() {
+                script.args = JsonBytes::from_vec(prefix);
+            }
+        }
         SearchKey {
-            script: self.script,
+            script,
             script_type: self.script_type,
             filter: if self.filter.is_some() {
-                self.filter.map(|f| f.into_filter(block_range))
+                self.filter.map(|f| f.into_filter(block_range, indexer))
             } else {
-                Some(RpcSearchKeyFilter::default().into_filter(block_range))
+                Some(RpcSearchKeyFilter::default().into_filter(block_range, indexer))
             },
-            with_data: None,
+            with_data: self.with_data,
             group_by_transaction: Some(true),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, Hash, PartialEq, Eq)]
 pub struct RpcSearchKeyFilter {
+    #[schemars(with = "Option<schema::Script>")]
     pub script: Option<Script>,
+    #[schemars(with = "Option<[schema::Hex; 2]>")]
     pub script_len_range: Option<[Uint64; 2]>,
+    #[schemars(with = "Option<[schema::Hex; 2]>")]
     pub output_data_len_range: Option<[Uint64; 2]>,
+    #[schemars(with = "Option<[schema::Hex; 2]>")]
     pub output_capacity_range: Option<[Uint64; 2]>,
+    /// Only cells whose data matches these bytes as `output_data_filter_mode` says. Sent to
+    /// indexers supporting the mode, checked by the emitter on the fetched transactions otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub output_data: Option<JsonBytes>,
+    /// `prefix` unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_data_filter_mode: Option<OutputDataMode>,
+    /// Only transactions with this cell dep, directly or in a dep group, checked by the emitter
+    /// on the fetched transactions, the indexer doesn't know it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::OutPoint>")]
+    pub cell_dep: Option<OutPoint>,
+    /// Only transactions with this block hash in their header deps, checked like `cell_dep`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub header_dep: Option<H256>,
+    /// Only transactions spending a matched input with a `since` of this kind, checked like `cell_dep`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<SinceFilter>,
+    /// Only transactions paying a fee in this range of shannons, [inclusive, exclusive], checked like
+    /// `cell_dep`. The spent cells of every matched transaction are looked up, events list the fees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<[schema::Hex; 2]>")]
+    pub fee_range: Option<[Uint64; 2]>,
+    /// Only cells with a type script when true, only plain capacity cells when false. Keys of lock
+    /// scripts only, the indexer gets it as the `script_len_range` it stands for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_type_script: Option<bool>,
+    /// Only output cells whose data contains this pattern, checked by the emitter on the fetched
+    /// transactions. Matched inputs are emitted whatever their data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub data_pattern: Option<DataPattern>,
+    /// Only cells whose key script args start with this pattern, for args of several fields. The
+    /// indexer is queried with the bytes before the first wildcard, the emitter checks the rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub args_pattern: Option<DataPattern>,
+    /// Only blocks whose emitted cells grow or shrink the key's capacity by at least this many
+    /// shannons, checked like `cell_dep`. The spent cells of matched inputs are looked up, events
+    /// carry the change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub min_capacity_change: Option<Uint64>,
+    /// Only blocks with at least this many transactions with emitted cells, for consumers of
+    /// summaries. Checked by the emitter after the other filters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub min_transactions: Option<Uint32>,
+    /// What to do with the cells created by cellbases, the miner rewards, included by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cellbase: Option<CellbaseMode>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CellbaseMode {
+    Include,
+    Exclude,
+    /// Included, events list which outputs are cellbase ones
+    Tag,
 }
 
 impl RpcSearchKeyFilter {
-    fn into_filter(self, block_range: Option<[Uint64; 2]>) -> SearchKeyFilter {
+    /// `script_len_range`, or the one `has_type_script` stands for
+    pub fn script_len_range(&self) -> Option<[Uint64; 2]> {
+        self.script_len_range.or(match self.has_type_script {
+            Some(true) => Some([1.into(), u64::MAX.into()]),
+            Some(false) => Some([0.into(), 1.into()]),
+            None => None,
+        })
+    }
+
+    /// The `output_data` filter with its mode
+    pub fn output_data_filter(&self) -> Option<(&JsonBytes, OutputDataMode)> {
+        self.output_data
+            .as_ref()
+            .map(|data| (data, self.output_data_filter_mode.unwrap_or_default()))
+    }
+
+    fn into_filter(
+        self,
+        block_range: Option<[Uint64; 2]>,
+        indexer: &IndexerCapabilities,
+    ) -> SearchKeyFilter {
+        let (output_data, output_data_filter_mode) = match self.output_data_filter() {
+            Some((data, mode)) if indexer.filters_output_data(mode) => {
+                (Some(data.clone()), Some(mode))
+            }
+            _ => (None, None),
+        };
         SearchKeyFilter {
+            script_len_range: self.script_len_range(),
             script: self.script,
-            script_len_range: self.script_len_range,
             output_data_len_range: self.output_data_len_range,
             output_capacity_range: self.output_capacity_range,
             block_range,
+            output_data,
+            output_data_filter_mode,
         }
     }
 }
 
+/// When a registration starts scanning and when it completes, the `lifetime` of `register`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default)]
+pub struct Lifetime {
+    /// Unix time in milliseconds the scan starts at, at once when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub activate_at: Option<Uint64>,
+    /// Events after which the registration completes and is deleted, never when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub complete_after: Option<Uint64>,
+    /// Last block scanned, then the registration completes and stays listed, never when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Hex>")]
+    pub end_block: Option<BlockNumber>,
+}
+
+/// A registration as listed by `info`
+#[derive(Serialize)]
+pub struct InfoEntry {
+    #[serde(flatten)]
+    pub registration: Registration,
+    /// Waiting for `activate_at` or for the start block to be indexed
+    pub pending: bool,
+    /// Scan tip over indexer tip in percent, unset while the start is waited for or the node is unreachable
+    pub sync_percentage: Option<f64>,
+    /// Unset until the scan tip moves after startup
+    pub secs_since_advance: Option<u64>,
+    /// Of the emitter process
+    pub uptime_secs: u64,
+}
+
+/// A registration named by its key id or by its search key
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum KeyRef {
+    Id(String),
+    Key(Box<RpcSearchKey>),
+}
+
+/// Returned by `server_info`
+#[derive(Serialize)]
+pub struct ServerInfo {
+    /// Of the emitter
+    pub version: &'static str,
+    /// As detected at startup
+    pub indexer: IndexerCapabilities,
+    /// Hex Ed25519 public key verifying the signatures of the events, unset when they aren't signed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<&'static str>,
+    /// The registrations were made on the chain of this genesis, unset until the first check
+    pub genesis_hash: Option<H256>,
+    /// Why the scans are refused, a node of another chain or one not checked yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genesis_mismatch: Option<String>,
+}
+
+/// Events as returned by `query_events`
+#[derive(Serialize)]
+pub struct EventPage {
+    pub events: Vec<serde_json::Value>,
+    /// Seq to pass as the cursor for the next page, unset after the last one
+    pub next_cursor: Option<Uint64>,
+}
+
+/// What a registration produced, returned by `delete`. Ending one subscription of a shared
+/// registration returns the whole registration's
+#[derive(Serialize, Deserialize)]
+pub struct Deleted {
+    /// At deletion, the blocks below it were scanned
+    pub scan_tip: IndexerTip,
+    /// Logged events, of registrations older than the count only those since
+    pub events: u64,
+    pub cells: u64,
+    pub transactions: u64,
+    /// Since the `register` call, unset for registrations older than `registered_at`
+    pub duration_secs: Option<u64>,
+}
+
+/// Live cells as returned by `get_live_cells`
+#[derive(Serialize)]
+pub struct LiveCellPage {
+    /// The cells are live as of the end of this block
+    pub block_number: BlockNumber,
+    pub cells: Vec<LiveCell>,
+    /// Position to pass as the cursor for the next page, unset after the last one
+    pub next_cursor: Option<Uint64>,
+}
+
+/// Events of a registration waiting on the way to its sinks and consumers, as returned by `backlog`
+#[derive(Serialize)]
+pub struct Backlog {
+    pub key: RpcSearchKey,
+    /// Fetched by the scan, not yet delivered
+    pub pending_events: usize,
+    /// Logged events after the cursor of each consumer which acked one
+    pub unacked_events: BTreeMap<String, usize>,
+    /// Events waiting for each sink whose delivery failed, retried on the next scan
+    pub retry_queues: BTreeMap<String, usize>,
+}
+
+/// What `clone_registration` changes of the cloned registration, the rest is copied
+#[derive(Deserialize, Default)]
+pub struct CloneOverrides {
+    /// The clone starts at the scan tip of the original unless set
+    pub start: Option<BlockNumber>,
+    /// Replaces the filter of the search key, `{}` for none
+    pub filter: Option<RpcSearchKeyFilter>,
+    pub sink: Option<SinkConfig>,
+}
+
+/// Events per `query_events` page unless a limit is given
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+
 #[rpc(server)]
 pub trait Emitter {
     #[method(name = "register")]
-    async fn register(&self, search_key: RpcSearchKey, start: BlockNumber) -> Result<bool, Error>;
+    async fn register(
+        &self,
+        search_key: RpcSearchKey,
+        start: BlockNumber,
+        sink: Option<SinkConfig>,
+        client: Option<String>,
+        sink_profile: Option<String>,
+        lifetime: Option<Lifetime>,
+    ) -> Result<String, Error>;
 
     #[method(name = "delete")]
-    async fn delete(&self, search_key: RpcSearchKey) -> Result<bool, Error>;
+    async fn delete(
+        &self,
+        search_key: RpcSearchKey,
+        client: Option<String>,
+    ) -> Result<Deleted, Error>;
+
+    #[method(name = "delete_all")]
+    async fn delete_all(&self) -> Result<Uint64, Error>;
+
+    #[method(name = "set_webhook")]
+    async fn set_webhook(
+        &self,
+        search_key: RpcSearchKey,
+        url: String,
+        secret: Option<String>,
+        compression: Option<Compression>,
+    ) -> Result<bool, Error>;
+
+    #[method(name = "set_lag_alert")]
+    async fn set_lag_alert(
+        &self,
+        search_key: RpcSearchKey,
+        max_blocks: Option<Uint64>,
+        max_secs: Option<Uint64>,
+        action: Option<LagAction>,
+    ) -> Result<bool, Error>;
+
+    #[method(name = "set_priority")]
+    async fn set_priority(
+        &self,
+        search_key: RpcSearchKey,
+        priority: Priority,
+    ) -> Result<bool, Error>;
+
+    #[method(name = "set_scan_parallelism")]
+    async fn set_scan_parallelism(
+        &self,
+        search_key: RpcSearchKey,
+        parallelism: Option<Uint32>,
+    ) -> Result<bool, Error>;
+
+    #[method(name = "set_max_blocks_per_second")]
+    async fn set_max_blocks_per_second(
+        &self,
+        search_key: RpcSearchKey,
+        max_blocks_per_second: Option<Uint64>,
+    ) -> Result<bool, Error>;
+
+    #[method(name = "clone_registration")]
+    async fn clone_registration(
+        &self,
+        key_id: String,
+        overrides: Option<CloneOverrides>,
+    ) -> Result<RpcSearchKey, Error>;
+
+    #[method(name = "merge_registrations")]
+    async fn merge_registrations(&self, dry_run: Option<bool>) -> Result<Overlaps, Error>;
+
+    #[method(name = "set_start")]
+    async fn set_start(&self, search_key: RpcSearchKey, start: BlockNumber) -> Result<bool, Error>;
+
+    #[method(name = "replay")]
+    async fn replay(&self, from: Uint64, to: Uint64, sink: SinkConfig) -> Result<Uint64, Error>;
+
+    #[method(name = "pull")]
+    async fn pull(
+        &self,
+        consumer_id: String,
+        search_key: RpcSearchKey,
+        max: Uint32,
+    ) -> Result<Vec<serde_json::Value>, Error>;
+
+    #[method(name = "ack")]
+    async fn ack(&self, consumer_id: String, seq: Uint64) -> Result<bool, Error>;
+
+    #[method(name = "query_events")]
+    async fn query_events(
+        &self,
+        search_key: RpcSearchKey,
+        from_block: BlockNumber,
+        to_block: BlockNumber,
+        cursor: Option<Uint64>,
+        limit: Option<Uint32>,
+    ) -> Result<EventPage, Error>;
 
     #[method(name = "info")]
-    async fn info(&self) -> Result<Vec<(RpcSearchKey, ScanTip)>, Error>;
+    async fn info(&self) -> Result<Vec<(RpcSearchKey, InfoEntry)>, Error>;
+
+    #[method(name = "info_for")]
+    async fn info_for(&self, keys: Vec<KeyRef>) -> Result<Vec<(RpcSearchKey, InfoEntry)>, Error>;
+
+    #[method(name = "schemas")]
+    async fn schemas(&self) -> Result<serde_json::Value, Error>;
+
+    #[method(name = "tips")]
+    async fn tips(&self) -> Result<TipSnapshot, Error>;
+
+    #[method(name = "get_tip")]
+    async fn get_tip(&self) -> Result<IndexerTip, Error>;
+
+    #[method(name = "validate")]
+    async fn validate(&self, search_key: RpcSearchKey) -> Result<Validation, Error>;
+
+    #[method(name = "admin_export_snapshot")]
+    async fn admin_export_snapshot(&self, file: String) -> Result<bool, Error>;
+
+    #[method(name = "set_log_level")]
+    async fn set_log_level(&self, target: String, level: Option<String>) -> Result<bool, Error>;
+
+    #[method(name = "audit_log")]
+    async fn audit_log(&self, limit: Option<Uint32>) -> Result<Vec<AuditEntry>, Error>;
+
+    #[method(name = "get_cell_history")]
+    async fn get_cell_history(&self, out_point: OutPoint) -> Result<Vec<CellHistory>, Error>;
+
+    #[method(name = "get_live_cells")]
+    async fn get_live_cells(
+        &self,
+        search_key: RpcSearchKey,
+        at_block: Option<BlockNumber>,
+        cursor: Option<Uint64>,
+        limit: Option<Uint32>,
+    ) -> Result<LiveCellPage, Error>;
+
+    #[method(name = "get_summary")]
+    async fn get_summary(&self, search_key: RpcSearchKey) -> Result<LiveSummary, Error>;
+
+    #[method(name = "stats")]
+    async fn stats(&self, search_key: RpcSearchKey) -> Result<Stats, Error>;
+
+    #[method(name = "backlog")]
+    async fn backlog(&self) -> Result<Vec<Backlog>, Error>;
+
+    #[method(name = "get_config")]
+    async fn get_config(&self) -> Result<Config, Error>;
+
+    #[method(name = "server_info")]
+    async fn server_info(&self) -> Result<ServerInfo, Error>;
+
+    #[method(name = "admin_shutdown")]
+    async fn admin_shutdown(&self) -> Result<bool, Error>;
+
+    #[method(name = "admin_maintenance")]
+    async fn admin_maintenance(&self, on: bool) -> Result<bool, Error>;
 }
 
+/// Methods rejected in read-only mode and recorded in the audit log
+pub(crate) const MUTATING_METHODS: &[&str] = &[
+    "register",
+    "delete",
+    "delete_all",
+    "set_webhook",
+    "set_lag_alert",
+    "set_priority",
+    "set_scan_parallelism",
+    "set_max_blocks_per_second",
+    "set_start",
+    "clone_registration",
+    "merge_registrations",
+    "replay",
+    "admin_export_snapshot",
+    "set_log_level",
+    "admin_shutdown",
+    "admin_maintenance",
+];
+
 pub(crate) struct EmitterRpc {
-    pub state: Arc<dashmap::DashMap<RpcSearchKey, ScanTip>>,
-    pub cell_handles: dashmap::DashMap<RpcSearchKey, tokio::task::JoinHandle<()>>,
+    pub registrations: Lifecycle,
     pub client: RpcClient,
+    pub sinks: Sinks,
+    pub cursors: ConsumerCursors,
+    pub network: Option<Network>,
+    /// Rejects the calls changing the watch list, the sinks or the host, `info`, `pull` and `ack` keep working
+    pub read_only: bool,
+    pub quotas: QuotaSettings,
+    pub audit: Option<AuditLog>,
+    /// When the emitter started, for the uptime of `info`
+    pub started: Instant,
+    /// Registering a duplicate of a registered key subscribes to its scan
+    pub merge_duplicates: bool,
+    /// Notified by `admin_shutdown` to exit like on SIGTERM
+    pub shutdown: Arc<Notify>,
+    /// The only directory `admin_export_snapshot` writes into
+    pub snapshot_dir: PathBuf,
+    /// The store's encryption key, sealing the exported snapshots
+    pub cipher: Option<Cipher>,
+    /// As loaded at startup with the listen address in use, for `get_config`
+    pub config: Config,
+}
+
+/// The key in the caller's namespace, the key as given to callers without one
+fn scoped(mut search_key: RpcSearchKey) -> RpcSearchKey {
+    if let Some(namespace) = rpc_http::namespace() {
+        search_key.namespace = Some(namespace);
+    }
+    search_key
+}
+
+/// Whether the key is in the caller's namespace, callers without one see every key
+fn visible(search_key: &RpcSearchKey) -> bool {
+    rpc_http::namespace().is_none_or(|namespace| search_key.namespace.as_ref() == Some(&namespace))
+}
+
+impl EmitterRpc {
+    fn writable(&self, method: &'static str) -> Result<(), Error> {
+        if self.read_only {
+            return Err(RpcError::ReadOnly(method).into());
+        }
+        Ok(())
+    }
+
+    /// Refuses the methods reaching past a namespace to the callers of one
+    fn unscoped(&self, method: &'static str) -> Result<(), Error> {
+        if rpc_http::namespace().is_some() {
+            return Err(RpcError::Namespaced(method).into());
+        }
+        Ok(())
+    }
+
+    /// The sink of the profile, the profiles of the key's namespace shadow the shared ones
+    fn sink_profile(&self, search_key: &RpcSearchKey, name: &str) -> Result<SinkConfig, RpcError> {
+        search_key
+            .namespace
+            .as_ref()
+            .and_then(|namespace| self.config.namespaces.get(namespace))
+            .and_then(|namespace| namespace.profiles.get(name).cloned())
+            .or_else(|| {
+                self.sinks
+                    .settings
+                    .read()
+                    .unwrap()
+                    .profiles
+                    .get(name)
+                    .cloned()
+            })
+            .ok_or_else(|| RpcError::InvalidParams(format!("unknown sink profile: {}", name)))
+    }
+
+    /// Starts scanning for `search_key` from `start`, false if it is registered already.
+    /// A `start` beyond the indexer tip is parked until the indexer reaches it
+    #[allow(clippy::too_many_arguments)]
+    pub async fn register_key(
+        &self,
+        search_key: RpcSearchKey,
+        start: BlockNumber,
+        sink: SinkConfig,
+        sink_profile: Option<String>,
+        label: Option<String>,
+        client: Option<String>,
+        lifetime: Lifetime,
+    ) -> std::io::Result<bool> {
+        if self.registrations.state().contains_key(&search_key) {
+            return Ok(false);
+        }
+        if let Some(network) = self.network {
+            if let Some(other) = network.foreign(&search_key.script) {
+                log::warn!(
+                    "code hash {:#x} is a {:?} script not deployed on {:?}",
+                    search_key.script.code_hash,
+                    other,
+                    network
+                );
+            }
+        }
+        let scan_tip = ScanTip::new(self.start_tip(start).await?);
+
+        Ok(self
+            .registrations
+            .insert(
+                search_key,
+                Registration {
+                    scan_tip,
+                    sink,
+                    sink_profile,
+                    label,
+                    client,
+                    scan_failure: None,
+                    scan_panic: None,
+                    lag_alert: None,
+                    status: Status::Ok,
+                    priority: Priority::Normal,
+                    scan_parallelism: None,
+                    max_blocks_per_second: None,
+                    activate_at: lifetime.activate_at.map(|at| at.value()),
+                    complete_after: lifetime.complete_after.map(|events| events.value().max(1)),
+                    end_block: lifetime.end_block.map(|end| end.value()),
+                    registered_at: Some(now_ms()),
+                    request_id: crate::rpc_http::request_id(),
+                    completed_at: None,
+                    spilled_from: None,
+                    subscribers: Vec::new(),
+                },
+            )
+            .await)
+    }
+
+    /// The `info` entries of the registered keys `wanted` picks
+    async fn info_entries(
+        &self,
+        wanted: impl Fn(&RpcSearchKey) -> bool,
+    ) -> Vec<(RpcSearchKey, InfoEntry)> {
+        // progress is left out while the node is unreachable, the registrations are still listed
+        let indexer_tip = self.client.get_indexer_tip().await.ok();
+        let uptime_secs = self.started.elapsed().as_secs();
+        let now = now_ms();
+        self.registrations
+            .state()
+            .iter()
+            .filter(|kv| wanted(kv.key()))
+            .map(|kv| {
+                let mut registration = kv.value().clone();
+                registration.sink = registration.sink.redacted();
+                for subscriber in &mut registration.subscribers {
+                    subscriber.sink = subscriber.sink.redacted();
+                }
+                let tip = registration.scan_tip.load();
+                let pending =
+                    tip.is_parked() || registration.activate_at.is_some_and(|at| at > now);
+                let sync_percentage = indexer_tip
+                    .as_ref()
+                    .filter(|indexer_tip| !tip.is_parked() && indexer_tip.block_number.value() > 0)
+                    .map(|indexer_tip| {
+                        let percentage = tip.block_number.value() as f64 * 100.0
+                            / indexer_tip.block_number.value() as f64;
+                        percentage.min(100.0)
+                    });
+                let secs_since_advance = self
+                    .registrations
+                    .stats(kv.key())
+                    .and_then(|stats| stats.last_advance)
+                    .map(|at| now.saturating_sub(at) / 1000);
+                let entry = InfoEntry {
+                    registration,
+                    pending,
+                    sync_percentage,
+                    secs_since_advance,
+                    uptime_secs,
+                };
+                (kv.key().clone(), entry)
+            })
+            .collect::<Vec<_>>()
+    }
+
+    /// Refuses a key whose filter the indexer doesn't take, the scan would fail on it. Taken
+    /// unchecked when the node can't be asked
+    async fn check_filter(&self, search_key: &RpcSearchKey) -> Result<(), RpcError> {
+        if search_key.filter.is_none() {
+            return Ok(());
+        }
+        match validate::refused_filter(&self.client, search_key).await {
+            Ok(Some(refused)) => Err(RpcError::InvalidParams(refused)),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                log::warn!(
+                    "Filter not checked against the indexer, key: {:?}, error: {}",
+                    search_key,
+                    e
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Refuses a registration of the key in a namespace or by a client which has as many as its
+    /// quota allows, clients are counted within the namespace. The client is named by the caller,
+    /// the total quota holds whatever clients it names
+    fn check_quota(
+        &self,
+        search_key: &RpcSearchKey,
+        client: &Option<String>,
+    ) -> Result<(), RpcError> {
+        let namespace = search_key.namespace.as_ref();
+        let in_namespace = |key: &RpcSearchKey| key.namespace.as_ref() == namespace;
+        let registered = self.registrations.state().contains_key(search_key);
+        if let Some(max) = self.quotas.max_total {
+            if self.registrations.state().len() >= max && !registered {
+                return Err(RpcError::TotalQuotaExceeded(max));
+            }
+        }
+        if let Some((namespace, max)) = namespace.and_then(|namespace| {
+            let max = self.config.namespaces.get(namespace)?.max_registrations?;
+            Some((namespace, max))
+        }) {
+            let active = self
+                .registrations
+                .state()
+                .iter()
+                .filter(|kv| in_namespace(kv.key()))
+                .count();
+            if active >= max && !registered {
+                return Err(RpcError::NamespaceQuotaExceeded {
+                    namespace: namespace.clone(),
+                    max,
+                });
+            }
+        }
+        let max = match self.quotas.max_registrations(client.as_deref()) {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let active = self
+            .registrations
+            .state()
+            .iter()
+            .filter(|kv| in_namespace(kv.key()) && kv.value().has_client(client.as_deref()))
+            .count();
+        let has = self
+            .registrations
+            .state()
+            .get(search_key)
+            .is_some_and(|registration| registration.has_client(client.as_deref()));
+        if active >= max && !has {
+            return Err(RpcError::QuotaExceeded {
+                client: client.clone(),
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    /// The scan tip of a registration starting at `start`
+    async fn start_tip(&self, start: BlockNumber) -> std::io::Result<IndexerTip> {
+        let indexer_tip = self.client.get_indexer_tip().await?;
+        if indexer_tip.block_number < start {
+            return Ok(IndexerTip::parked(start));
+        }
+        match self.client.get_header_by_number(start).await {
+            Ok(header) => Ok(IndexerTip {
+                block_hash: header.hash,
+                block_number: header.inner.number,
+            }),
+            // the scan moves it to the earliest available block
+            Err(e)
+                if self.registrations.skips_unavailable() && BlockUnavailable::of(&e).is_some() =>
+            {
+                Ok(IndexerTip::parked(start))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Merges every duplicate registration into the one it duplicates unless `dry_run`, the subsets
+    /// are reported only
+    pub async fn merge_overlaps(&self, dry_run: bool) -> Overlaps {
+        let keys = self
+            .registrations
+            .state()
+            .iter()
+            .map(|kv| {
+                (
+                    kv.key().clone(),
+                    kv.value().scan_tip.load().block_number.value(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut overlaps = overlap::find(&keys);
+        if !dry_run {
+            let mut merged = Vec::new();
+            for duplicate in overlaps.duplicates {
+                if self
+                    .registrations
+                    .merge(&duplicate.key, &duplicate.into)
+                    .await
+                {
+                    log::info!(
+                        "merged registration {:?} into {:?}",
+                        duplicate.key,
+                        duplicate.into
+                    );
+                    merged.push(duplicate);
+                }
+            }
+            overlaps.duplicates = merged;
+        }
+        overlaps
+    }
+
+    pub async fn delete_key(&self, search_key: &RpcSearchKey) -> bool {
+        self.registrations.remove(search_key).await
+    }
+
+    /// Registers the declared keys which are missing and updates the sink and label of the others,
+    /// with `prune` registrations which aren't declared are deleted
+    pub async fn reconcile(&self, declared: &[DeclaredRegistration], prune: bool) {
+        for declaration in declared {
+            let name = declaration.label.as_deref().unwrap_or("unlabeled");
+            if let Some(mut registration) = self.registrations.state().get_mut(&declaration.key) {
+                registration.sink = declaration.sink.clone();
+                registration.sink_profile = None;
+                registration.label = declaration.label.clone();
+                continue;
+            }
+            match self
+                .register_key(
+                    declaration.key.clone(),
+                    declaration.start.into(),
+                    declaration.sink.clone(),
+                    None,
+                    declaration.label.clone(),
+                    None,
+                    Lifetime::default(),
+                )
+                .await
+            {
+                Ok(true) => log::info!("registered declared key {}", name),
+                Ok(false) => log::warn!("declared key {} was registered meanwhile", name),
+                Err(e) => log::error!("failed to register declared key {}: {}", name, e),
+            }
+        }
+
+        if prune {
+            let undeclared = self
+                .registrations
+                .state()
+                .iter()
+                // the keys of namespaces are registered over rpc only
+                .filter(|kv| {
+                    kv.key().namespace.is_none() && !declared.iter().any(|d| &d.key == kv.key())
+                })
+                .map(|kv| (kv.key().clone(), kv.value().label.clone()))
+                .collect::<Vec<_>>();
+            for (key, label) in undeclared {
+                self.delete_key(&key).await;
+                log::info!(
+                    "deleted undeclared key {}",
+                    label.unwrap_or_else(|| serde_json::to_string(&key).unwrap())
+                );
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl EmitterServer for EmitterRpc {
-    async fn register(&self, search_key: RpcSearchKey, start: BlockNumber) -> Result<bool, Error> {
-        if self.state.contains_key(&search_key) {
-            return Ok(false);
+    async fn register(
+        &self,
+        search_key: RpcSearchKey,
+        start: BlockNumber,
+        sink: Option<SinkConfig>,
+        client: Option<String>,
+        sink_profile: Option<String>,
+        lifetime: Option<Lifetime>,
+    ) -> Result<String, Error> {
+        self.writable("register")?;
+        if self.registrations.in_maintenance() {
+            return Err(RpcError::Maintenance.into());
         }
-        let indexer_tip = self
-            .client
-            .get_indexer_tip()
+        let search_key = scoped(search_key);
+        search_key.check().map_err(RpcError::InvalidParams)?;
+        let sink = match (sink, &sink_profile) {
+            (Some(_), Some(_)) => {
+                return Err(RpcError::InvalidParams(
+                    "sink and sink_profile exclude each other".to_string(),
+                )
+                .into())
+            }
+            (None, Some(name)) => Some(self.sink_profile(&search_key, name)?),
+            (sink, None) => sink,
+        };
+        self.check_quota(&search_key, &client)?;
+        self.check_filter(&search_key).await?;
+        let search_key = if self.merge_duplicates {
+            let normal = overlap::normalized(&search_key);
+            self.registrations
+                .state()
+                .iter()
+                .map(|kv| kv.key().clone())
+                .find(|key| overlap::normalized(key) == normal)
+                .unwrap_or(search_key)
+        } else {
+            search_key
+        };
+        // the scan of a key registered by another client is shared, the start doesn't apply
+        let subscriber = Subscriber {
+            client: client.clone(),
+            sink: sink.clone().unwrap_or_default(),
+        };
+        match self.registrations.subscribe(&search_key, subscriber).await {
+            Some(true) => return Ok(search_key.id()),
+            Some(false) => return Err(RpcError::AlreadyRegistered.into()),
+            None => {}
+        }
+        let id = search_key.id();
+        match self
+            .register_key(
+                search_key,
+                start,
+                sink.unwrap_or_default(),
+                sink_profile,
+                None,
+                client,
+                lifetime.unwrap_or_default(),
+            )
             .await
-            .map_err(|e| Error::Custom(e.to_string()))?;
+        {
+            Ok(true) => Ok(id),
+            Ok(false) => Err(RpcError::AlreadyRegistered.into()),
+            Err(e) => Err(RpcError::Node(e).into()),
+        }
+    }
 
-        if indexer_tip.block_number > start {
-            let header = self
-                .client
-                .get_header_by_number(start)
-                .await
-                .map_err(|e| Error::Custom(e.to_string()))?;
+    async fn delete(
+        &self,
+        search_key: RpcSearchKey,
+        client: Option<String>,
+    ) -> Result<Deleted, Error> {
+        self.writable("delete")?;
+        let search_key = scoped(search_key);
+        let (scan_tip, registered_at) = match self.registrations.state().get(&search_key) {
+            Some(registration) => (registration.scan_tip.load(), registration.registered_at),
+            None => return Err(RpcError::NotRegistered.into()),
+        };
+        let stats = self
+            .sinks
+            .storage
+            .load_stats(&search_key)
+            .map_err(RpcError::Storage)?;
+        // without a client only the subscription made without one ends, the others keep theirs
+        if !self
+            .registrations
+            .unsubscribe(&search_key, client.as_deref())
+            .await
+        {
+            return Err(RpcError::NotRegistered.into());
+        }
+        Ok(Deleted {
+            scan_tip,
+            events: stats.events,
+            cells: stats.cells,
+            transactions: stats.transactions,
+            duration_secs: registered_at.map(|at| now_ms().saturating_sub(at) / 1000),
+        })
+    }
 
-            let scan_tip = {
-                let tip = IndexerTip {
-                    block_hash: header.hash,
-                    block_number: header.inner.number,
+    async fn delete_all(&self) -> Result<Uint64, Error> {
+        self.writable("delete_all")?;
+        let keys = self
+            .registrations
+            .state()
+            .iter()
+            .filter(|kv| visible(kv.key()))
+            .map(|kv| kv.key().clone())
+            .collect::<Vec<_>>();
+        let mut deleted = 0u64;
+        for key in keys {
+            if self.delete_key(&key).await {
+                deleted += 1;
+            }
+        }
+        Ok(deleted.into())
+    }
+
+    async fn set_webhook(
+        &self,
+        search_key: RpcSearchKey,
+        url: String,
+        secret: Option<String>,
+        compression: Option<Compression>,
+    ) -> Result<bool, Error> {
+        self.writable("set_webhook")?;
+        let search_key = scoped(search_key);
+        reqwest::Url::parse(&url)
+            .map_err(|e| RpcError::InvalidParams(format!("invalid webhook url: {}", e)))?;
+
+        match self.registrations.state().get_mut(&search_key) {
+            Some(mut registration) => {
+                registration.sink = SinkConfig::Webhook {
+                    url,
+                    secret,
+                    compression: compression.unwrap_or_default(),
+                    encoding: registration.sink.encoding(),
+                    format: registration.sink.body_format(),
+                    transform: registration.sink.transform().cloned(),
+                    retry: registration.sink.retry().cloned(),
+                    size_limit: registration.sink.size_limit().cloned(),
                 };
-                ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(Box::into_raw(
-                    Box::new(tip),
-                )))))
-            };
+                registration.sink_profile = None;
+                Ok(true)
+            }
+            None => Err(RpcError::NotRegistered.into()),
+        }
+    }
 
-            self.state.insert(search_key.clone(), scan_tip.clone());
+    async fn set_lag_alert(
+        &self,
+        search_key: RpcSearchKey,
+        max_blocks: Option<Uint64>,
+        max_secs: Option<Uint64>,
+        action: Option<LagAction>,
+    ) -> Result<bool, Error> {
+        self.writable("set_lag_alert")?;
+        let search_key = scoped(search_key);
+        let (max_blocks, max_secs) = (max_blocks.map(|v| v.value()), max_secs.map(|v| v.value()));
+        match self.registrations.state().get_mut(&search_key) {
+            Some(mut registration) => {
+                // without a threshold the registration can't be degraded, no alert resolves it
+                if max_blocks.is_none() && max_secs.is_none() {
+                    registration.lag_alert = None;
+                    registration.status = Status::Ok;
+                } else {
+                    registration.lag_alert = Some(LagThreshold {
+                        max_blocks,
+                        max_secs,
+                        action: action.unwrap_or_default(),
+                    });
+                }
+                Ok(true)
+            }
+            None => Err(RpcError::NotRegistered.into()),
+        }
+    }
 
-            let mut cell_process = CellProcess {
-                key: search_key.clone(),
-                client: self.client.clone(),
-                scan_tip,
-            };
+    async fn set_priority(
+        &self,
+        search_key: RpcSearchKey,
+        priority: Priority,
+    ) -> Result<bool, Error> {
+        self.writable("set_priority")?;
+        let search_key = scoped(search_key);
+        match self.registrations.state().get_mut(&search_key) {
+            Some(mut registration) => {
+                registration.priority = priority;
+                Ok(true)
+            }
+            None => Err(RpcError::NotRegistered.into()),
+        }
+    }
 
-            let handle = tokio::spawn(async move {
-                cell_process.run().await;
-            });
+    async fn set_scan_parallelism(
+        &self,
+        search_key: RpcSearchKey,
+        parallelism: Option<Uint32>,
+    ) -> Result<bool, Error> {
+        self.writable("set_scan_parallelism")?;
+        let search_key = scoped(search_key);
+        match self.registrations.state().get_mut(&search_key) {
+            Some(mut registration) => {
+                registration.scan_parallelism =
+                    parallelism.map(|shards| shards.value().max(1) as usize);
+                Ok(true)
+            }
+            None => Err(RpcError::NotRegistered.into()),
+        }
+    }
 
-            self.cell_handles.insert(search_key, handle);
-            return Ok(true);
+    async fn set_max_blocks_per_second(
+        &self,
+        search_key: RpcSearchKey,
+        max_blocks_per_second: Option<Uint64>,
+    ) -> Result<bool, Error> {
+        self.writable("set_max_blocks_per_second")?;
+        let search_key = scoped(search_key);
+        match self.registrations.state().get_mut(&search_key) {
+            Some(mut registration) => {
+                registration.max_blocks_per_second =
+                    max_blocks_per_second.map(|rate| rate.value().max(1));
+                Ok(true)
+            }
+            None => Err(RpcError::NotRegistered.into()),
         }
+    }
 
-        Ok(false)
+    async fn clone_registration(
+        &self,
+        key_id: String,
+        overrides: Option<CloneOverrides>,
+    ) -> Result<RpcSearchKey, Error> {
+        self.writable("clone_registration")?;
+        if self.registrations.in_maintenance() {
+            return Err(RpcError::Maintenance.into());
+        }
+        let overrides = overrides.unwrap_or_default();
+        let (mut search_key, original) = self
+            .registrations
+            .state()
+            .iter()
+            .find(|kv| visible(kv.key()) && kv.key().id() == key_id)
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .ok_or(RpcError::NotRegistered)?;
+        if let Some(filter) = overrides.filter {
+            search_key.filter = Some(filter).filter(|filter| *filter != Default::default());
+        }
+        search_key.check().map_err(RpcError::InvalidParams)?;
+        self.check_filter(&search_key).await?;
+        self.check_quota(&search_key, &original.client)?;
+        let overridden_sink = overrides.sink.is_some();
+        let start = overrides
+            .start
+            .unwrap_or_else(|| original.scan_tip.load().block_number);
+        let registered = self
+            .register_key(
+                search_key.clone(),
+                start,
+                overrides.sink.unwrap_or(original.sink),
+                original.sink_profile.filter(|_| !overridden_sink),
+                original.label,
+                original.client,
+                Lifetime {
+                    activate_at: original.activate_at.map(Into::into),
+                    complete_after: original.complete_after.map(Into::into),
+                    end_block: original.end_block.map(Into::into),
+                },
+            )
+            .await
+            .map_err(RpcError::Node)?;
+        if !registered {
+            return Err(RpcError::AlreadyRegistered.into());
+        }
+        if let Some(mut registration) = self.registrations.state().get_mut(&search_key) {
+            registration.lag_alert = original.lag_alert;
+            registration.priority = original.priority;
+            registration.scan_parallelism = original.scan_parallelism;
+            registration.max_blocks_per_second = original.max_blocks_per_second;
+        }
+        Ok(search_key)
     }
 
-    async fn delete(&self, search_key: RpcSearchKey) -> Result<bool, Error> {
-        if self.state.remove(&search_key).is_some() {
-            let handle = self.cell_handles.remove(&search_key).unwrap();
-            handle.1.abort();
-            return Ok(true);
+    async fn merge_registrations(&self, dry_run: Option<bool>) -> Result<Overlaps, Error> {
+        self.unscoped("merge_registrations")?;
+        let dry_run = dry_run == Some(true);
+        if !dry_run {
+            self.writable("merge_registrations")?;
+        }
+        Ok(self.merge_overlaps(dry_run).await)
+    }
+
+    async fn set_start(&self, search_key: RpcSearchKey, start: BlockNumber) -> Result<bool, Error> {
+        self.writable("set_start")?;
+        let search_key = scoped(search_key);
+        if !self.registrations.state().contains_key(&search_key) {
+            return Err(RpcError::NotRegistered.into());
+        }
+        let tip = self.start_tip(start).await.map_err(RpcError::Node)?;
+        match self.registrations.restart(&search_key, tip).await {
+            Ok(true) => Ok(true),
+            Ok(false) => Err(RpcError::NotRegistered.into()),
+            Err(e) => Err(RpcError::Storage(e).into()),
         }
-        Ok(false)
     }
 
-    async fn info(&self) -> Result<Vec<(RpcSearchKey, ScanTip)>, Error> {
+    async fn replay(&self, from: Uint64, to: Uint64, sink: SinkConfig) -> Result<Uint64, Error> {
+        self.writable("replay")?;
+        let events = self
+            .sinks
+            .storage
+            .read_events(from.value(), to.value(), usize::MAX, &|event| {
+                visible(&event.key)
+            })
+            .map_err(RpcError::Storage)?;
+
+        for event in &events {
+            self.sinks
+                .deliver(&sink, &event.as_event())
+                .await
+                .map_err(RpcError::Delivery)?;
+        }
+        Ok((events.len() as u64).into())
+    }
+
+    async fn pull(
+        &self,
+        consumer_id: String,
+        search_key: RpcSearchKey,
+        max: Uint32,
+    ) -> Result<Vec<serde_json::Value>, Error> {
+        let search_key = scoped(search_key);
+        let events = self
+            .cursors
+            .pull(&consumer_id, &search_key, max.value() as usize)
+            .map_err(RpcError::Storage)?;
+        Ok(events
+            .iter()
+            .map(|event| serde_json::to_value(event.as_event()).unwrap())
+            .collect())
+    }
+
+    async fn ack(&self, consumer_id: String, seq: Uint64) -> Result<bool, Error> {
+        // an event of another namespace is as unknown as a dropped one
+        if rpc_http::namespace().is_some()
+            && !self
+                .sinks
+                .storage
+                .read_events(seq.value(), seq.value(), 1, &|_| true)
+                .map_err(RpcError::Storage)?
+                .first()
+                .is_some_and(|event| visible(&event.key))
+        {
+            return Err(RpcError::EventNotFound(seq.value()).into());
+        }
+        if !self
+            .cursors
+            .ack(&consumer_id, seq.value())
+            .map_err(RpcError::Storage)?
+        {
+            return Err(RpcError::EventNotFound(seq.value()).into());
+        }
+        Ok(true)
+    }
+
+    async fn query_events(
+        &self,
+        search_key: RpcSearchKey,
+        from_block: BlockNumber,
+        to_block: BlockNumber,
+        cursor: Option<Uint64>,
+        limit: Option<Uint32>,
+    ) -> Result<EventPage, Error> {
+        let search_key = scoped(search_key);
+        let limit = limit.map_or(DEFAULT_PAGE_LIMIT, |limit| limit.value()) as usize;
+        if limit == 0 {
+            return Err(RpcError::InvalidParams("limit must be positive".to_string()).into());
+        }
+        let (from_block, to_block) = (from_block.value(), to_block.value());
+        // one more than the page to know whether another follows
+        let mut events = self
+            .sinks
+            .storage
+            .read_events(
+                cursor.map_or(0, |cursor| cursor.value()),
+                u64::MAX,
+                limit + 1,
+                &|event| {
+                    let number = event.submit.header.inner.number.value();
+                    event.key == search_key && from_block <= number && number <= to_block
+                },
+            )
+            .map_err(RpcError::Storage)?;
+        let next_cursor = if events.len() > limit {
+            events.pop().map(|event| event.seq.into())
+        } else {
+            None
+        };
+        Ok(EventPage {
+            events: events
+                .iter()
+                .map(|event| serde_json::to_value(event.as_event()).unwrap())
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    async fn schemas(&self) -> Result<serde_json::Value, Error> {
+        Ok(schema::schemas())
+    }
+
+    async fn info(&self) -> Result<Vec<(RpcSearchKey, InfoEntry)>, Error> {
+        Ok(self.info_entries(visible).await)
+    }
+
+    async fn info_for(&self, keys: Vec<KeyRef>) -> Result<Vec<(RpcSearchKey, InfoEntry)>, Error> {
+        let (mut ids, mut search_keys) = (HashSet::new(), HashSet::new());
+        for key in keys {
+            match key {
+                KeyRef::Id(id) => ids.insert(id),
+                KeyRef::Key(key) => search_keys.insert(scoped(*key)),
+            };
+        }
         Ok(self
-            .state
+            .info_entries(|key| {
+                visible(key)
+                    && (search_keys.contains(key) || (!ids.is_empty() && ids.contains(&key.id())))
+            })
+            .await)
+    }
+
+    async fn tips(&self) -> Result<TipSnapshot, Error> {
+        let mut tips = self.registrations.tips().await.map_err(RpcError::Node)?;
+        tips.scan_tips.retain(|(key, _)| visible(key));
+        Ok(tips)
+    }
+
+    async fn validate(&self, search_key: RpcSearchKey) -> Result<Validation, Error> {
+        let mut validation = Validation::default();
+        if let Err(e) = search_key.check() {
+            validation.errors.push(e);
+        }
+        if let Some(other) = self
+            .network
+            .and_then(|network| network.foreign(&search_key.script))
+        {
+            validation.warnings.push(format!(
+                "code hash {:#x} is a {:?} script not deployed on this network",
+                search_key.script.code_hash, other
+            ));
+        }
+        validation.warnings.extend(validate::filter_warnings(
+            &search_key,
+            self.client.indexer(),
+        ));
+        // the node refuses filters it doesn't support
+        match self
+            .client
+            .get_transactions(
+                search_key.clone().into_key(None, self.client.indexer()),
+                Order::Asc,
+                1.into(),
+                None,
+            )
+            .await
+        {
+            Ok(txs) if txs.objects.is_empty() => validation
+                .warnings
+                .push("the indexer has no transaction matching the key so far".to_string()),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => validation.errors.push(
+                validate::refused_filter(&self.client, &search_key)
+                    .await
+                    .map_err(RpcError::Node)?
+                    .unwrap_or_else(|| format!("the indexer refused the key: {}", e)),
+            ),
+            Err(e) => return Err(RpcError::Node(e).into()),
+        }
+        Ok(validation)
+    }
+
+    async fn get_tip(&self) -> Result<IndexerTip, Error> {
+        Ok(self
+            .client
+            .get_indexer_tip()
+            .await
+            .map_err(RpcError::Node)?)
+    }
+
+    async fn admin_export_snapshot(&self, file: String) -> Result<bool, Error> {
+        self.writable("admin_export_snapshot")?;
+        self.unscoped("admin_export_snapshot")?;
+        // a plain file name, the snapshot holds the webhook secrets and mustn't land elsewhere
+        let mut components = Path::new(&file).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(RpcError::InvalidParams(
+                "file must be a file name inside the snapshot directory".to_string(),
+            )
+            .into());
+        }
+        std::fs::create_dir_all(&self.snapshot_dir).map_err(RpcError::Storage)?;
+        let path = self.snapshot_dir.join(&file);
+        if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            return Err(RpcError::InvalidParams(format!("{} is a symlink", file)).into());
+        }
+        let registrations = self
+            .registrations
+            .state()
             .iter()
             .map(|kv| (kv.key().clone(), kv.value().clone()))
-            .collect::<Vec<_>>())
+            .collect();
+        Snapshot::new(registrations, self.cursors.all())
+            .write(path, self.cipher.as_ref())
+            .map_err(RpcError::Storage)?;
+        Ok(true)
+    }
+
+    async fn set_log_level(&self, target: String, level: Option<String>) -> Result<bool, Error> {
+        self.writable("set_log_level")?;
+        self.unscoped("set_log_level")?;
+        let level = level
+            .map(|level| {
+                level
+                    .parse::<log::LevelFilter>()
+                    .map_err(|_| RpcError::InvalidParams(format!("invalid log level: {}", level)))
+            })
+            .transpose()?;
+        logging::set_target(&target, level);
+        log::info!("set log level of {} to {:?}", target, level);
+        Ok(true)
+    }
+
+    async fn audit_log(&self, limit: Option<Uint32>) -> Result<Vec<AuditEntry>, Error> {
+        self.unscoped("audit_log")?;
+        let audit = self
+            .audit
+            .as_ref()
+            .ok_or(RpcError::Disabled("audit log is disabled, see --audit-log"))?;
+        audit
+            .recent(limit.map_or(100, |limit| limit.value() as usize))
+            .map_err(|e| RpcError::Storage(e).into())
+    }
+
+    async fn get_cell_history(&self, out_point: OutPoint) -> Result<Vec<CellHistory>, Error> {
+        self.unscoped("get_cell_history")?;
+        let links = self
+            .sinks
+            .storage
+            .load_cell_links(&out_point)
+            .map_err(RpcError::Storage)?;
+        Ok(lineage::histories(links))
+    }
+
+    async fn get_live_cells(
+        &self,
+        search_key: RpcSearchKey,
+        at_block: Option<BlockNumber>,
+        cursor: Option<Uint64>,
+        limit: Option<Uint32>,
+    ) -> Result<LiveCellPage, Error> {
+        let search_key = scoped(search_key);
+        let limit = limit.map_or(DEFAULT_PAGE_LIMIT, |limit| limit.value()) as usize;
+        if limit == 0 {
+            return Err(RpcError::InvalidParams("limit must be positive".to_string()).into());
+        }
+        let tip = match self.registrations.state().get(&search_key) {
+            Some(registration) => registration.scan_tip.load(),
+            None => return Err(RpcError::NotRegistered.into()),
+        };
+        // the blocks below the scan tip are scanned
+        let scanned = tip
+            .block_number
+            .value()
+            .checked_sub(1)
+            .ok_or_else(|| RpcError::InvalidParams("no block is scanned yet".to_string()))?;
+        let at = at_block.map_or(scanned, |at| at.value());
+        if at > scanned {
+            return Err(RpcError::InvalidParams(format!(
+                "block {} isn't scanned yet, the last scanned one is {}",
+                at, scanned
+            ))
+            .into());
+        }
+        let storage = &self.sinks.storage;
+        let mut cells = storage
+            .load_live_cells(&search_key)
+            .map_err(RpcError::Storage)?;
+        if at < scanned {
+            // loaded after the cells, see `rewind`
+            let later = storage
+                .read_events(0, u64::MAX, usize::MAX, &|event| {
+                    event.key == search_key && event.submit.header.inner.number.value() > at
+                })
+                .map_err(RpcError::Storage)?;
+            cells = live_cells::rewind(cells, &later, at).map_err(RpcError::InvalidParams)?;
+        }
+        let from = cursor.map_or(0, |cursor| cursor.value()) as usize;
+        let cells = live_cells::sorted(cells);
+        let next = from.saturating_add(limit);
+        Ok(LiveCellPage {
+            block_number: at.into(),
+            next_cursor: (next < cells.len()).then(|| (next as u64).into()),
+            cells: cells.into_iter().skip(from).take(limit).collect(),
+        })
+    }
+
+    async fn get_summary(&self, search_key: RpcSearchKey) -> Result<LiveSummary, Error> {
+        let search_key = scoped(search_key);
+        if !self.registrations.state().contains_key(&search_key) {
+            return Err(RpcError::NotRegistered.into());
+        }
+        let storage = &self.sinks.storage;
+        let cells = storage
+            .load_live_cells(&search_key)
+            .map_err(RpcError::Storage)?;
+        let last_change = storage
+            .read_events(0, u64::MAX, usize::MAX, &|event| {
+                event.key == search_key
+                    && event
+                        .submit
+                        .live_cells
+                        .as_ref()
+                        .is_some_and(|diff| !diff.added.is_empty() || !diff.removed.is_empty())
+            })
+            .map_err(RpcError::Storage)?
+            .last()
+            .map(|event| event.submit.header.inner.number.value());
+        Ok(LiveSummary::new(&cells, last_change))
+    }
+
+    async fn stats(&self, search_key: RpcSearchKey) -> Result<Stats, Error> {
+        let search_key = scoped(search_key);
+        if !self.registrations.state().contains_key(&search_key) {
+            return Err(RpcError::NotRegistered.into());
+        }
+        Ok(self
+            .sinks
+            .storage
+            .load_stats(&search_key)
+            .map_err(RpcError::Storage)?)
+    }
+
+    async fn backlog(&self) -> Result<Vec<Backlog>, Error> {
+        let keys = self
+            .registrations
+            .state()
+            .iter()
+            .filter(|kv| visible(kv.key()))
+            .map(|kv| {
+                let sinks = kv.value().sinks().iter().map(SinkConfig::id).collect();
+                (kv.key().clone(), sinks)
+            })
+            .collect::<Vec<(_, HashSet<_>)>>();
+        let mut retry_queues = HashMap::<_, BTreeMap<_, _>>::new();
+        for kv in self.sinks.failed.iter() {
+            let (key, sink) = kv.key();
+            retry_queues
+                .entry(key.clone())
+                .or_default()
+                .insert(sink.clone(), kv.value().queued);
+        }
+        keys.into_iter()
+            .map(|(key, sinks)| {
+                let unacked_events = self
+                    .cursors
+                    .unacked(&key)
+                    .map_err(RpcError::Storage)?
+                    .into_iter()
+                    .collect();
+                Ok(Backlog {
+                    pending_events: self
+                        .registrations
+                        .stats(&key)
+                        .map_or(0, |stats| stats.pending_events),
+                    unacked_events,
+                    // a failed sink replaced meanwhile has nothing waiting for it
+                    retry_queues: retry_queues
+                        .remove(&key)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|(sink, _)| sinks.contains(sink))
+                        .collect(),
+                    key,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_config(&self) -> Result<Config, Error> {
+        self.unscoped("get_config")?;
+        let sinks = self.sinks.settings.read().unwrap().clone();
+        Ok(self.config.effective(&sinks))
+    }
+
+    async fn server_info(&self) -> Result<ServerInfo, Error> {
+        Ok(ServerInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            indexer: self.client.indexer().clone(),
+            signing_key: sealing::sealer().and_then(|sealer| sealer.public_key()),
+            genesis_hash: self.registrations.genesis().pinned(),
+            genesis_mismatch: self.registrations.genesis().mismatch(),
+        })
+    }
+
+    async fn admin_shutdown(&self) -> Result<bool, Error> {
+        self.writable("admin_shutdown")?;
+        log::info!("shutdown requested over rpc");
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            // lets the response go out before the process exits
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            shutdown.notify_one();
+        });
+        Ok(true)
+    }
+
+    async fn admin_maintenance(&self, on: bool) -> Result<bool, Error> {
+        self.writable("admin_maintenance")?;
+        let was = self.registrations.in_maintenance();
+        self.registrations.set_maintenance(on);
+        if was != on {
+            log::info!("maintenance mode {}", if on { "on" } else { "off" });
+        }
+        Ok(was != on)
     }
 }
@@ -0,0 +1,456 @@
+//! The ckb node over a connection kept open, a websocket (`ws://`, `wss://`) or ckb's tcp
+//! json-rpc (`tcp://`) of newline separated messages. Calls share the connection and are told
+//! apart by their id, a broken connection fails the calls waiting on it like an unreachable node
+//! and the next call opens a new one
+
+use futures::future::BoxFuture;
+use hyper::body::Bytes;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+        WriteHalf,
+    },
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex as AsyncMutex},
+    task::JoinHandle,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    metrics,
+    rpc_client::{Transport, UpstreamAuth},
+};
+
+/// Largest message taken from the node, a block with large cells fits many times
+const MAX_MESSAGE: u64 = 256 << 20;
+
+/// Appended to the key of a websocket upgrade before hashing it into the accept key, RFC 6455
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    WebSocket,
+    Lines,
+}
+
+pub(crate) struct Stream(Arc<Inner>);
+
+struct Inner {
+    url: Url,
+    framing: Framing,
+    tls: Option<TlsConnector>,
+    /// Of the websocket upgrade request
+    authorization: Option<String>,
+    /// Takes the calls to the open connection, closed once it broke
+    connection: AsyncMutex<Option<mpsc::UnboundedSender<Outgoing>>>,
+}
+
+enum Outgoing {
+    Call {
+        id: u64,
+        body: Vec<u8>,
+        reply: oneshot::Sender<io::Result<Bytes>>,
+    },
+    /// Answers a ping of the node
+    Pong(Vec<u8>),
+}
+
+/// Calls waiting for their answer by id, `None` once the connection broke
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<io::Result<Bytes>>>>>>;
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+enum Message {
+    Data(Vec<u8>),
+    Ping(Vec<u8>),
+}
+
+impl Stream {
+    pub fn is_stream(url: &Url) -> bool {
+        matches!(url.scheme(), "ws" | "wss" | "tcp")
+    }
+
+    /// The client certificate and the CA of `auth` secure `wss`, its credentials go into the
+    /// upgrade request
+    pub fn new(url: Url, auth: &UpstreamAuth) -> io::Result<Self> {
+        let config = |e: native_tls::Error| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let tls = match url.scheme() {
+            "wss" => {
+                let mut builder = native_tls::TlsConnector::builder();
+                if let Some((cert, key)) = &auth.identity {
+                    builder.identity(native_tls::Identity::from_pkcs8(cert, key).map_err(config)?);
+                }
+                if let Some(ca_cert) = &auth.ca_cert {
+                    builder.add_root_certificate(
+                        native_tls::Certificate::from_pem(ca_cert).map_err(config)?,
+                    );
+                }
+                Some(builder.build().map_err(config)?.into())
+            }
+            _ => None,
+        };
+        Ok(Stream(Arc::new(Inner {
+            framing: match url.scheme() {
+                "tcp" => Framing::Lines,
+                _ => Framing::WebSocket,
+            },
+            url,
+            tls,
+            authorization: auth.credentials.as_ref().map(|c| c.header_value()),
+            connection: AsyncMutex::new(None),
+        })))
+    }
+}
+
+impl Transport for Stream {
+    fn send(&self, request: &serde_json::Value) -> BoxFuture<'static, io::Result<Bytes>> {
+        let inner = self.0.clone();
+        let id = request["id"].as_u64();
+        let body = serde_json::to_vec(request);
+        Box::pin(async move {
+            let id = id.ok_or_else(|| invalid("json-rpc request without a numeric id"))?;
+            let body = body?;
+            let (reply, answer) = oneshot::channel();
+            inner
+                .connection()
+                .await?
+                .send(Outgoing::Call { id, body, reply })
+                .map_err(|_| broken())?;
+            answer.await.map_err(|_| broken())?
+        })
+    }
+}
+
+impl Inner {
+    /// The open connection, or a new one
+    async fn connection(&self) -> io::Result<mpsc::UnboundedSender<Outgoing>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(calls) = connection.as_ref().filter(|calls| !calls.is_closed()) {
+            return Ok(calls.clone());
+        }
+        let calls = self.connect().await?;
+        *connection = Some(calls.clone());
+        Ok(calls)
+    }
+
+    async fn connect(&self) -> io::Result<mpsc::UnboundedSender<Outgoing>> {
+        let host = self
+            .url
+            .host_str()
+            .ok_or_else(|| invalid("ckb uri without a host"))?;
+        let port = self
+            .url
+            .port_or_known_default()
+            .ok_or_else(|| invalid("ckb uri without a port"))?;
+        let tcp = TcpStream::connect((host, port)).await.map_err(aborted)?;
+        tcp.set_nodelay(true)?;
+        let io: Box<dyn Io> = match &self.tls {
+            Some(tls) => Box::new(tls.connect(host, tcp).await.map_err(aborted)?),
+            None => Box::new(tcp),
+        };
+        let (read, mut write) = tokio::io::split(io);
+        let mut read = BufReader::new(read);
+        if self.framing == Framing::WebSocket {
+            self.handshake(host, port, &mut read, &mut write).await?;
+        }
+        metrics::inc("emitter_ckb_connections_opened_total", &[]);
+
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (calls, outgoing) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_calls(self.framing, write, outgoing, pending.clone()));
+        tokio::spawn(read_answers(
+            self.framing,
+            read,
+            calls.clone(),
+            pending,
+            writer,
+            format!("{}://{}:{}", self.url.scheme(), host, port),
+        ));
+        Ok(calls)
+    }
+
+    async fn handshake(
+        &self,
+        host: &str,
+        port: u16,
+        read: &mut BufReader<ReadHalf<Box<dyn Io>>>,
+        write: &mut WriteHalf<Box<dyn Io>>,
+    ) -> io::Result<()> {
+        let key = base64::encode(rand::random::<[u8; 16]>());
+        let target = match self.url.query() {
+            Some(query) => format!("{}?{}", self.url.path(), query),
+            None => self.url.path().to_string(),
+        };
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            target, host, port, key
+        );
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        write.write_all(request.as_bytes()).await.map_err(aborted)?;
+        write.flush().await.map_err(aborted)?;
+
+        let mut status = String::new();
+        let mut accept = None;
+        loop {
+            let mut line = String::new();
+            if read.read_line(&mut line).await.map_err(aborted)? == 0 {
+                return Err(broken());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if status.is_empty() {
+                status = line.to_string();
+            } else if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("sec-websocket-accept") {
+                    accept = Some(value.trim().to_string());
+                }
+            }
+        }
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(invalid(format!("websocket upgrade refused: {}", status)));
+        }
+        if accept != Some(accept_key(&key)) {
+            return Err(invalid(
+                "websocket upgrade answered with a wrong accept key",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Writes the calls until writing fails, registering each as pending first
+async fn write_calls(
+    framing: Framing,
+    mut write: WriteHalf<Box<dyn Io>>,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    pending: Pending,
+) -> io::Result<()> {
+    while let Some(message) = outgoing.recv().await {
+        let (opcode, body) = match message {
+            Outgoing::Call { id, body, reply } => {
+                match pending.lock().unwrap().as_mut() {
+                    Some(pending) => {
+                        // of calls which timed out before their answer
+                        if pending.len() > 1024 {
+                            pending.retain(|_, reply| !reply.is_closed());
+                        }
+                        pending.insert(id, reply);
+                    }
+                    None => {
+                        let _ = reply.send(Err(broken()));
+                        continue;
+                    }
+                }
+                (1, body)
+            }
+            Outgoing::Pong(payload) => (10, payload),
+        };
+        match framing {
+            Framing::WebSocket => write_frame(&mut write, opcode, &body, true).await?,
+            Framing::Lines => {
+                write.write_all(&body).await?;
+                write.write_all(b"\n").await?;
+                write.flush().await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands the answers to their calls until the connection breaks, then fails the pending ones
+async fn read_answers(
+    framing: Framing,
+    mut read: BufReader<ReadHalf<Box<dyn Io>>>,
+    pongs: mpsc::UnboundedSender<Outgoing>,
+    pending: Pending,
+    mut writer: JoinHandle<io::Result<()>>,
+    origin: String,
+) {
+    #[derive(Deserialize)]
+    struct Answer {
+        id: Option<serde_json::Value>,
+    }
+
+    let error = loop {
+        // reading is cancelled only when the connection is given up anyway
+        let message = tokio::select! {
+            message = read_message(framing, &mut read) => message,
+            written = &mut writer => break match written {
+                Ok(Err(e)) => e,
+                _ => broken(),
+            },
+        };
+        match message {
+            Ok(Message::Data(data)) => {
+                // notifications have no id
+                let id = serde_json::from_slice::<Answer>(&data)
+                    .ok()
+                    .and_then(|answer| answer.id?.as_u64());
+                let reply = id.and_then(|id| pending.lock().unwrap().as_mut()?.remove(&id));
+                if let Some(reply) = reply {
+                    let _ = reply.send(Ok(Bytes::from(data)));
+                }
+            }
+            Ok(Message::Ping(payload)) => {
+                let _ = pongs.send(Outgoing::Pong(payload));
+            }
+            Err(e) => break e,
+        }
+    };
+    writer.abort();
+    log::warn!("Connection to the ckb node {} lost: {}", origin, error);
+    let pending = pending.lock().unwrap().take().unwrap_or_default();
+    for (_, reply) in pending {
+        let _ = reply.send(Err(aborted(&error)));
+    }
+}
+
+async fn read_message(
+    framing: Framing,
+    read: &mut BufReader<ReadHalf<Box<dyn Io>>>,
+) -> io::Result<Message> {
+    match framing {
+        Framing::Lines => loop {
+            let mut line = Vec::new();
+            if (&mut *read)
+                .take(MAX_MESSAGE)
+                .read_until(b'\n', &mut line)
+                .await?
+                == 0
+            {
+                return Err(broken());
+            }
+            if line.last() != Some(&b'\n') && line.len() as u64 == MAX_MESSAGE {
+                return Err(invalid("message of the ckb node too large"));
+            }
+            if !line.trim_ascii().is_empty() {
+                return Ok(Message::Data(line));
+            }
+        },
+        Framing::WebSocket => {
+            let mut data = Vec::new();
+            loop {
+                let (fin, opcode, payload) = read_frame(read).await?;
+                match opcode {
+                    0..=2 => {
+                        data.extend(payload);
+                        if data.len() as u64 > MAX_MESSAGE {
+                            return Err(invalid("message of the ckb node too large"));
+                        }
+                        if fin {
+                            return Ok(Message::Data(data));
+                        }
+                    }
+                    8 => return Err(aborted("the ckb node closed the websocket")),
+                    9 => return Ok(Message::Ping(payload)),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// A final frame, masked as a client's must be
+pub(crate) async fn write_frame(
+    write: &mut (impl AsyncWrite + Unpin),
+    opcode: u8,
+    payload: &[u8],
+    mask: bool,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    let masked = if mask { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(masked | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(masked | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(masked | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    if mask {
+        let key = rand::random::<[u8; 4]>();
+        frame.extend(key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        frame.extend(payload);
+    }
+    write.write_all(&frame).await?;
+    write.flush().await
+}
+
+/// Whether the frame is final, its opcode and its unmasked payload
+pub(crate) async fn read_frame(
+    read: &mut (impl AsyncRead + Unpin),
+) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    read.read_exact(&mut head).await?;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            read.read_exact(&mut len).await?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            read.read_exact(&mut len).await?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE {
+        return Err(invalid("websocket frame too large"));
+    }
+    let key = match head[1] & 0x80 {
+        0 => None,
+        _ => {
+            let mut key = [0; 4];
+            read.read_exact(&mut key).await?;
+            Some(key)
+        }
+    };
+    let mut payload = vec![0; len as usize];
+    read.read_exact(&mut payload).await?;
+    if let Some(key) = key {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= key[i % 4];
+        }
+    }
+    Ok((head[0] & 0x80 != 0, head[0] & 0x0f, payload))
+}
+
+/// `Sec-WebSocket-Accept` of an upgrade with `key`
+pub(crate) fn accept_key(key: &str) -> String {
+    base64::encode(openssl::sha::sha1(format!("{}{}", key, WS_GUID).as_bytes()))
+}
+
+fn broken() -> io::Error {
+    aborted("connection to the ckb node closed")
+}
+
+fn aborted(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string())
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
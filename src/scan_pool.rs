@@ -0,0 +1,166 @@
+use futures::FutureExt;
+use tokio::{
+    sync::{Mutex, Notify},
+    time::Instant,
+};
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex as StdMutex,
+    },
+};
+
+use crate::{
+    cell_process::{CellProcess, SCAN_INTERVAL},
+    lifecycle, metrics,
+};
+
+/// Runs the scans of all registrations on a fixed number of workers, each idle worker takes
+/// the scan which is due first so a slow key holds up one worker only
+#[derive(Clone)]
+pub(crate) struct ScanPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    queue: StdMutex<BinaryHeap<Due>>,
+    /// A job was queued, waiting workers look at the queue again
+    queued: Notify,
+    seq: AtomicU64,
+}
+
+/// The scan of one registration, locked by the worker running it
+pub(crate) struct ScanJob {
+    process: CellProcess,
+    failures: u32,
+    panics: u32,
+    /// Set by `remove`, the job is dropped instead of queued again
+    removed: bool,
+}
+
+pub(crate) type JobHandle = Arc<Mutex<ScanJob>>;
+
+/// A job in the queue, the earliest first and in queueing order at the same time
+struct Due {
+    at: Instant,
+    seq: u64,
+    job: JobHandle,
+}
+
+impl PartialEq for Due {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Due {}
+
+impl PartialOrd for Due {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Due {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, the heap pops its greatest
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl ScanPool {
+    pub fn new(workers: usize) -> Self {
+        let pool = ScanPool {
+            inner: Arc::new(Inner {
+                queue: Default::default(),
+                queued: Notify::new(),
+                seq: AtomicU64::new(0),
+            }),
+        };
+        for _ in 0..workers.max(1) {
+            metrics::spawn("scan_worker", pool.clone().work());
+        }
+        pool
+    }
+
+    /// Queues the scan to run at once
+    pub fn add(&self, process: CellProcess) -> JobHandle {
+        let job = Arc::new(Mutex::new(ScanJob {
+            process,
+            failures: 0,
+            panics: 0,
+            removed: false,
+        }));
+        self.push(Instant::now(), job.clone());
+        job
+    }
+
+    /// Waits for a running scan of the job to finish, it isn't run again
+    pub async fn remove(job: &JobHandle) {
+        job.lock().await.removed = true;
+    }
+
+    fn push(&self, at: Instant, job: JobHandle) {
+        let seq = self.inner.seq.fetch_add(1, AtomicOrdering::Relaxed);
+        self.inner.queue.lock().unwrap().push(Due { at, seq, job });
+        self.inner.queued.notify_one();
+    }
+
+    async fn work(self) {
+        loop {
+            let job = self.next().await;
+            let mut locked = job.lock().await;
+            if locked.removed {
+                continue;
+            }
+            let started = Instant::now();
+            let ScanJob {
+                process,
+                failures,
+                panics,
+                ..
+            } = &mut *locked;
+            let backoff = match AssertUnwindSafe(process.step(failures))
+                .catch_unwind()
+                .await
+            {
+                Ok(backoff) => backoff,
+                Err(payload) => Some(lifecycle::scan_panicked(process, payload, panics)),
+            };
+            let mut at = started + SCAN_INTERVAL;
+            if let Some(backoff) = backoff {
+                at = at.max(Instant::now() + backoff);
+            }
+            self.push(at, job.clone());
+        }
+    }
+
+    /// Waits until the earliest job is due and takes it
+    async fn next(&self) -> JobHandle {
+        loop {
+            let due = {
+                let mut queue = self.inner.queue.lock().unwrap();
+                match queue.peek() {
+                    Some(due) if due.at <= Instant::now() => {
+                        return queue.pop().unwrap().job;
+                    }
+                    Some(due) => Some(due.at),
+                    None => None,
+                }
+            };
+            match due {
+                Some(at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(at) => {}
+                        _ = self.inner.queued.notified() => {}
+                    }
+                }
+                None => self.inner.queued.notified().await,
+            }
+        }
+    }
+}
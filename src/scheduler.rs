@@ -0,0 +1,116 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Which waiting scan, or delivery, runs first when they are limited, in ascending order
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Limits the scans running at once, or the deliveries, a freed slot goes to the waiting one of
+/// the highest priority, the one waiting longest among equals
+#[derive(Clone)]
+pub(crate) struct ScanScheduler {
+    inner: Arc<Mutex<Slots>>,
+}
+
+struct Slots {
+    max: usize,
+    running: usize,
+    seq: u64,
+    /// Highest priority first, then oldest
+    waiting: BTreeMap<(std::cmp::Reverse<Priority>, u64), oneshot::Sender<()>>,
+}
+
+/// A running scan's slot, handed to the next waiting scan when dropped
+pub(crate) struct Slot {
+    inner: Arc<Mutex<Slots>>,
+}
+
+impl ScanScheduler {
+    pub fn new(max: usize) -> Self {
+        ScanScheduler {
+            inner: Arc::new(Mutex::new(Slots {
+                max: max.max(1),
+                running: 0,
+                seq: 0,
+                waiting: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub async fn acquire(&self, priority: Priority) -> Slot {
+        let rx = {
+            let mut slots = self.inner.lock().unwrap();
+            if slots.running < slots.max {
+                slots.running += 1;
+                return self.slot();
+            }
+            let (tx, rx) = oneshot::channel();
+            slots.seq += 1;
+            let seq = slots.seq;
+            slots.waiting.insert((std::cmp::Reverse(priority), seq), tx);
+            rx
+        };
+        let mut waiting = Waiting {
+            rx: Some(rx),
+            inner: self.inner.clone(),
+        };
+        // the sender is only dropped with the scheduler, which outlives every scan
+        let _ = waiting.rx.as_mut().unwrap().await;
+        waiting.rx = None;
+        self.slot()
+    }
+
+    fn slot(&self) -> Slot {
+        Slot {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        release(&self.inner)
+    }
+}
+
+/// Hands the slot to a waiting scan, waiters which gave up are skipped
+fn release(inner: &Mutex<Slots>) {
+    let mut slots = inner.lock().unwrap();
+    while let Some((_, tx)) = slots.waiting.pop_first() {
+        if tx.send(()).is_ok() {
+            return;
+        }
+    }
+    slots.running -= 1;
+}
+
+/// A scan dropped while waiting, e.g. when its key is deleted, must not keep a slot handed to it
+struct Waiting {
+    rx: Option<oneshot::Receiver<()>>,
+    inner: Arc<Mutex<Slots>>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                release(&self.inner);
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde_json::{json, Value};
+
+use crate::{
+    alert::LagAlert,
+    global_state::LagThreshold,
+    rpc_server::{Lifetime, RpcSearchKey},
+    scheduler::Priority,
+    sink::{breaker::CircuitAlert, Completion, Compression, Envelope, Heartbeat, SinkConfig},
+};
+
+/// Json schemas of the events sent to sinks and of the rpc parameters, for consumers in other languages
+pub(crate) fn schemas() -> Value {
+    json!({
+        "events": {
+            "event": schema::<Envelope>(),
+            "heartbeat": schema::<Heartbeat>(),
+            "completion": schema::<Completion>(),
+            "lag_alert": schema::<LagAlert>(),
+            "circuit_breaker": schema::<CircuitAlert>(),
+        },
+        "params": {
+            "search_key": schema::<RpcSearchKey>(),
+            "sink": schema::<SinkConfig>(),
+            "compression": schema::<Compression>(),
+            "lag_threshold": schema::<LagThreshold>(),
+            "priority": schema::<Priority>(),
+            "lifetime": schema::<Lifetime>(),
+        },
+    })
+}
+
+fn schema<T: JsonSchema>() -> Value {
+    let root = SchemaSettings::draft07()
+        .into_generator()
+        .into_root_schema_for::<T>();
+    serde_json::to_value(root).unwrap()
+}
+
+// The ckb rpc types don't implement `JsonSchema`, these mirror how they are serialized
+
+/// 0x prefixed hex of bytes or of a number
+#[derive(JsonSchema)]
+pub(crate) struct Hex(#[allow(dead_code)] String);
+
+#[derive(JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[allow(dead_code)]
+pub(crate) enum ScriptHashType {
+    Data,
+    Type,
+    Data1,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct Script {
+    code_hash: Hex,
+    hash_type: ScriptHashType,
+    args: Hex,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct OutPoint {
+    tx_hash: Hex,
+    index: Hex,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct HeaderView {
+    version: Hex,
+    compact_target: Hex,
+    timestamp: Hex,
+    number: Hex,
+    epoch: Hex,
+    parent_hash: Hex,
+    transactions_root: Hex,
+    proposals_hash: Hex,
+    extra_hash: Hex,
+    dao: Hex,
+    nonce: Hex,
+    hash: Hex,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct CellOutput {
+    capacity: Hex,
+    lock: Script,
+    #[serde(rename = "type")]
+    type_: Option<Script>,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct CellData {
+    content: Hex,
+    hash: Hex,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct CellInfo {
+    output: CellOutput,
+    data: Option<CellData>,
+}
@@ -0,0 +1,110 @@
+use ckb_jsonrpc_types::{CellOutput, Script};
+use ckb_types::{h256, packed, prelude::Unpack, H256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+use crate::{
+    network::{DAO, SECP256K1_BLAKE160_MULTISIG_ALL, SECP256K1_BLAKE160_SIGHASH_ALL},
+    schema,
+};
+
+/// Type script hashes of the common scripts on mainnet and testnet
+const KNOWN: &[(&str, H256)] = &[
+    ("secp256k1_blake160", SECP256K1_BLAKE160_SIGHASH_ALL),
+    ("secp256k1_multisig", SECP256K1_BLAKE160_MULTISIG_ALL),
+    ("dao", DAO),
+    (
+        "anyone_can_pay",
+        h256!("0xd369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354"),
+    ),
+    (
+        "anyone_can_pay",
+        h256!("0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356"),
+    ),
+    (
+        "sudt",
+        h256!("0x5e7a36a77e68eecc013dfa2fe6a23f3b6c344b04005808694ae6dd45eea4cfd5"),
+    ),
+    (
+        "sudt",
+        h256!("0xc5e5dcf215925f7ef4dfaf5f4b4f105bc321c02776d6e7d52a1db3fcd9d011a4"),
+    ),
+    (
+        "cheque",
+        h256!("0xe4d4ecc6e5f9a059bf2f7a82cca292083aebc0c421566a52484fe2ec51a9fb0c"),
+    ),
+    (
+        "cheque",
+        h256!("0x60d5f39efce409c587cb9ea359cefdead650ca128f0bd9cb3855348f98c70d5b"),
+    ),
+    (
+        "omnilock",
+        h256!("0x9b819793a64463aed77c615d6cb226eea5487ccfc0783043a587254cda2b6f26"),
+    ),
+    (
+        "omnilock",
+        h256!("0xf329effd1c475a2978453c8600e1eaf0bc2087ee093c3ee64cc96ec6847752cb"),
+    ),
+    (
+        "xudt",
+        h256!("0x50bd8d6680b8b9cf98b73f3c08faf8b2a21914311954118ad6609be6e78a1b95"),
+    ),
+    (
+        "xudt",
+        h256!("0x25c29dc317811a6f6f3985a7a9ebc4838bd388d19d0feeecf0bcd60f6c0975bb"),
+    ),
+];
+
+/// The hash and, for a known code hash, the name of a script
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ScriptInfo {
+    #[schemars(with = "schema::Hex")]
+    pub hash: H256,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Of the lock and type script of a cell
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct CellScripts {
+    pub lock: ScriptInfo,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub type_: Option<ScriptInfo>,
+}
+
+/// Names by code hash, the configured ones added to and overriding the known ones
+pub(crate) struct ScriptNames(HashMap<H256, String>);
+
+impl ScriptNames {
+    pub fn new(configured: &HashMap<String, H256>) -> Self {
+        ScriptNames(
+            KNOWN
+                .iter()
+                .map(|(name, code_hash)| (code_hash.clone(), name.to_string()))
+                .chain(
+                    configured
+                        .iter()
+                        .map(|(name, code_hash)| (code_hash.clone(), name.clone())),
+                )
+                .collect(),
+        )
+    }
+
+    fn info(&self, script: &Script) -> ScriptInfo {
+        ScriptInfo {
+            hash: packed::Script::from(script.clone())
+                .calc_script_hash()
+                .unpack(),
+            name: self.0.get(&script.code_hash).cloned(),
+        }
+    }
+
+    pub fn cell(&self, output: &CellOutput) -> CellScripts {
+        CellScripts {
+            lock: self.info(&output.lock),
+            type_: output.type_.as_ref().map(|script| self.info(script)),
+        }
+    }
+}
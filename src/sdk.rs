@@ -0,0 +1,128 @@
+//! Conversions between the emitter's search keys and cells and the types of ckb-sdk-rust,
+//! so code built on the sdk's cell collectors can register its queries and take the emitted cells
+
+use ckb_jsonrpc_types::{JsonBytes, Uint64};
+use ckb_sdk::traits::{CellQueryOptions, LiveCell, PrimaryScriptType, ValueRangeOption};
+use ckb_types::packed;
+
+use std::{convert::TryFrom, io};
+
+use crate::{
+    rpc_client::{Cell, ScriptType},
+    rpc_server::{RpcSearchKey, RpcSearchKeyFilter},
+    sink::EmittedCell,
+};
+
+impl From<ScriptType> for PrimaryScriptType {
+    fn from(script_type: ScriptType) -> Self {
+        match script_type {
+            ScriptType::Lock => PrimaryScriptType::Lock,
+            ScriptType::Type => PrimaryScriptType::Type,
+        }
+    }
+}
+
+impl From<PrimaryScriptType> for ScriptType {
+    fn from(script_type: PrimaryScriptType) -> Self {
+        match script_type {
+            PrimaryScriptType::Lock => ScriptType::Lock,
+            PrimaryScriptType::Type => ScriptType::Type,
+        }
+    }
+}
+
+fn range_option(range: Option<[Uint64; 2]>) -> Option<ValueRangeOption> {
+    range.map(|[start, end]| ValueRangeOption::new(start.value(), end.value()))
+}
+
+fn range(option: Option<&ValueRangeOption>) -> Option<[Uint64; 2]> {
+    option.map(|option| [option.start.into(), option.end.into()])
+}
+
+/// The query of the key's cells, in the sdk's default order, limit and maturity. The sdk has none of
+/// the filters the emitter checks on the fetched transactions, the query matches more cells without them
+impl From<&RpcSearchKey> for CellQueryOptions {
+    fn from(key: &RpcSearchKey) -> Self {
+        let mut query =
+            CellQueryOptions::new(key.script.clone().into(), key.script_type.clone().into());
+        query.with_data = key.with_data;
+        if let Some(filter) = &key.filter {
+            query.secondary_script = filter.script.clone().map(Into::into);
+            query.secondary_script_len_range = range_option(filter.script_len_range());
+            query.data_len_range = range_option(filter.output_data_len_range);
+            query.capacity_range = range_option(filter.output_capacity_range);
+        }
+        query
+    }
+}
+
+/// Fails for queries of a block range, a registration scans from its start block on
+impl TryFrom<&CellQueryOptions> for RpcSearchKey {
+    type Error = io::Error;
+
+    fn try_from(query: &CellQueryOptions) -> io::Result<Self> {
+        if query.block_range.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a search key has no block range, registrations take a start block",
+            ));
+        }
+        let filter = RpcSearchKeyFilter {
+            script: query.secondary_script.clone().map(Into::into),
+            script_len_range: range(query.secondary_script_len_range.as_ref()),
+            output_data_len_range: range(query.data_len_range.as_ref()),
+            output_capacity_range: range(query.capacity_range.as_ref()),
+            output_data: None,
+            output_data_filter_mode: None,
+            cell_dep: None,
+            header_dep: None,
+            since: None,
+            fee_range: None,
+            has_type_script: None,
+            data_pattern: None,
+            args_pattern: None,
+            min_capacity_change: None,
+            min_transactions: None,
+            cellbase: None,
+        };
+        Ok(RpcSearchKey {
+            script: query.primary_script.clone().into(),
+            script_type: query.primary_type.clone().into(),
+            filter: Some(filter).filter(|filter| *filter != RpcSearchKeyFilter::default()),
+            with_data: query.with_data,
+            with_uncles: None,
+            with_epochs: None,
+            with_witnesses: None,
+            with_input_cells: None,
+            track_lineage: None,
+            track_live_cells: None,
+            discover: None,
+            correlate: None,
+            summary: None,
+            transactions_only: None,
+            namespace: None,
+        })
+    }
+}
+
+/// A cell of the indexer, without data if it was fetched without
+impl From<Cell> for LiveCell {
+    fn from(cell: Cell) -> Self {
+        LiveCell {
+            output: cell.output.into(),
+            output_data: cell
+                .output_data
+                .map(JsonBytes::into_bytes)
+                .unwrap_or_default(),
+            out_point: cell.out_point.into(),
+            block_number: cell.block_number.value(),
+            tx_index: cell.tx_index.value(),
+        }
+    }
+}
+
+impl From<&EmittedCell> for packed::OutPoint {
+    fn from(cell: &EmittedCell) -> Self {
+        cell.out_point.clone().into()
+    }
+}
@@ -0,0 +1,253 @@
+//! Ed25519 signatures and X25519 encryption of the json events, for consumers on untrusted
+//! transports. Set once at startup, the envelope of every event is sealed with it
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm,
+};
+use hmac::{Hmac, Mac};
+use openssl::{
+    derive::Deriver,
+    pkey::{Id, PKey, Private, Public},
+    sign::Signer,
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::Sha256;
+
+use std::{fs::read_to_string, io, sync::OnceLock};
+
+/// Env var holding the hex encoded signing key when no key file is given
+pub const SIGNING_KEY_ENV: &str = "EMITTER_SIGNING_KEY";
+
+/// Info of the HKDF deriving the AES-256-GCM key from the X25519 shared secret
+const HKDF_INFO: &[u8] = b"ckb-emitter event";
+
+static SEALER: OnceLock<Sealer> = OnceLock::new();
+
+pub(crate) struct Sealer {
+    signing: Option<(PKey<Private>, String)>,
+    recipient: Option<PKey<Public>>,
+}
+
+/// The payload encrypted to the recipient's X25519 key
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Encrypted {
+    /// Hex X25519 public key of the sender, new for every event
+    ephemeral_key: String,
+    /// Hex AES-256-GCM nonce
+    nonce: String,
+    /// Base64 of the payload json, tag included
+    ciphertext: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Signature {
+    /// Hex Ed25519 public key of the emitter
+    public_key: String,
+    /// Hex Ed25519 signature of `version.key_id.seq.block_hash.` followed by the payload string, or
+    /// by the ciphertext of an encrypted one
+    value: String,
+}
+
+impl Sealer {
+    /// The signing key from `key_file`, else from the env, and the recipient's public key,
+    /// `None` if neither is set
+    pub fn load(key_file: Option<&str>, encrypt_to: Option<&str>) -> io::Result<Option<Self>> {
+        let signing = match key_file {
+            Some(path) => Some(signing_key(&read_to_string(path)?)?),
+            None => match std::env::var(SIGNING_KEY_ENV) {
+                Ok(key) => Some(signing_key(&key)?),
+                Err(_) => None,
+            },
+        };
+        let signing = match signing {
+            Some(key) => {
+                let public = key.raw_public_key().map_err(invalid)?;
+                Some((key, format!("0x{}", faster_hex::hex_string(&public))))
+            }
+            None => None,
+        };
+        let recipient = match encrypt_to {
+            Some(hex) => Some(
+                PKey::public_key_from_raw_bytes(&decode_key(hex, "recipient key")?, Id::X25519)
+                    .map_err(invalid)?,
+            ),
+            None => None,
+        };
+        if signing.is_none() && recipient.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Sealer { signing, recipient }))
+    }
+
+    pub fn public_key(&self) -> Option<&str> {
+        self.signing.as_ref().map(|(_, public)| public.as_str())
+    }
+
+    /// Encrypts the payload if a recipient is set, then signs it if a key is set. The plain
+    /// payload is returned as a string when signed, so the signed bytes reach the consumer
+    pub fn seal(
+        &self,
+        prefix: &str,
+        payload: String,
+    ) -> io::Result<(Option<String>, Option<Encrypted>, Option<Signature>)> {
+        let (payload, encrypted) = match &self.recipient {
+            Some(recipient) => (None, Some(encrypt(recipient, payload.as_bytes())?)),
+            None => (Some(payload), None),
+        };
+        let signature = match &self.signing {
+            Some((key, public_key)) => {
+                let signed = encrypted
+                    .as_ref()
+                    .map_or(payload.as_deref().unwrap_or_default(), |e| &e.ciphertext);
+                let mut signer = Signer::new_without_digest(key).map_err(invalid)?;
+                let value = signer
+                    .sign_oneshot_to_vec(format!("{}{}", prefix, signed).as_bytes())
+                    .map_err(invalid)?;
+                Some(Signature {
+                    public_key: public_key.clone(),
+                    value: format!("0x{}", faster_hex::hex_string(&value)),
+                })
+            }
+            None => None,
+        };
+        Ok((payload, encrypted, signature))
+    }
+}
+
+/// Seals every event from now on, only the first call has an effect
+pub(crate) fn init(sealer: Sealer) {
+    let _ = SEALER.set(sealer);
+}
+
+pub(crate) fn sealer() -> Option<&'static Sealer> {
+    SEALER.get()
+}
+
+/// A PEM private key as `openssl genpkey -algorithm ed25519` writes it, or the hex 32 byte seed
+fn signing_key(key: &str) -> io::Result<PKey<Private>> {
+    if key.contains("-----BEGIN") {
+        let key = PKey::private_key_from_pem(key.as_bytes()).map_err(invalid)?;
+        if key.id() != Id::ED25519 {
+            return Err(invalid("signing key must be an Ed25519 key"));
+        }
+        return Ok(key);
+    }
+    PKey::private_key_from_raw_bytes(&decode_key(key, "signing key")?, Id::ED25519).map_err(invalid)
+}
+
+fn decode_key(hex: &str, name: &str) -> io::Result<[u8; 32]> {
+    let hex = hex.trim().trim_start_matches("0x");
+    let mut key = [0u8; 32];
+    if hex.len() != key.len() * 2 {
+        return Err(invalid(format!("{} must be 32 bytes, hex encoded", name)));
+    }
+    faster_hex::hex_decode(hex.as_bytes(), &mut key)
+        .map_err(|e| invalid(format!("invalid {}: {}", name, e)))?;
+    Ok(key)
+}
+
+/// ECDH with an ephemeral key, the AES key is HKDF-SHA256 of the shared secret salted with
+/// the ephemeral and the recipient's public key
+fn encrypt(recipient: &PKey<Public>, plain: &[u8]) -> io::Result<Encrypted> {
+    let ephemeral = PKey::generate_x25519().map_err(invalid)?;
+    let mut deriver = Deriver::new(&ephemeral).map_err(invalid)?;
+    deriver.set_peer(recipient).map_err(invalid)?;
+    let shared = deriver.derive_to_vec().map_err(invalid)?;
+    let ephemeral_key = ephemeral.raw_public_key().map_err(invalid)?;
+    let salt = [
+        ephemeral_key.clone(),
+        recipient.raw_public_key().map_err(invalid)?,
+    ]
+    .concat();
+
+    let prk = hmac(&salt, &shared);
+    let key = hmac(&prk, &[HKDF_INFO, &[1]].concat());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new_from_slice(&key)
+        .unwrap()
+        .encrypt(&nonce, plain)
+        .map_err(|_| io::Error::other("failed to encrypt"))?;
+    Ok(Encrypted {
+        ephemeral_key: format!("0x{}", faster_hex::hex_string(&ephemeral_key)),
+        nonce: format!("0x{}", faster_hex::hex_string(&nonce)),
+        ciphertext: base64::encode(ciphertext),
+    })
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::Nonce;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn sealed_payloads_verify_and_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing.key");
+        std::fs::write(&path, format!("0x{}", "11".repeat(32))).unwrap();
+        let recipient = PKey::generate_x25519().unwrap();
+        let recipient_key = faster_hex::hex_string(&recipient.raw_public_key().unwrap());
+        let sealer = Sealer::load(path.to_str(), Some(&recipient_key))
+            .unwrap()
+            .unwrap();
+
+        let (payload, encrypted, signature) = sealer.seal("1.id.0.0x00.", "{}".into()).unwrap();
+        assert_eq!(payload, None);
+        let (encrypted, signature) = (json!(encrypted), json!(signature));
+        let hex = |value: &serde_json::Value| {
+            let hex = value.as_str().unwrap().trim_start_matches("0x");
+            let mut bytes = vec![0; hex.len() / 2];
+            faster_hex::hex_decode(hex.as_bytes(), &mut bytes).unwrap();
+            bytes
+        };
+        let ciphertext = encrypted["ciphertext"].as_str().unwrap();
+
+        let public_key = hex(&signature["public_key"]);
+        assert_eq!(
+            Some(format!("0x{}", faster_hex::hex_string(&public_key)).as_str()),
+            sealer.public_key()
+        );
+        let public_key = PKey::public_key_from_raw_bytes(&public_key, Id::ED25519).unwrap();
+        let mut verifier = openssl::sign::Verifier::new_without_digest(&public_key).unwrap();
+        assert!(verifier
+            .verify_oneshot(
+                &hex(&signature["value"]),
+                format!("1.id.0.0x00.{}", ciphertext).as_bytes()
+            )
+            .unwrap());
+
+        let ephemeral_key = hex(&encrypted["ephemeral_key"]);
+        let ephemeral = PKey::public_key_from_raw_bytes(&ephemeral_key, Id::X25519).unwrap();
+        let mut deriver = openssl::derive::Deriver::new(&recipient).unwrap();
+        deriver.set_peer(&ephemeral).unwrap();
+        let shared = deriver.derive_to_vec().unwrap();
+        let hmac = |key: &[u8], data: &[u8]| {
+            let mut mac = <hmac::Hmac<sha2::Sha256> as Mac>::new_from_slice(key).unwrap();
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        };
+        let salt = [ephemeral_key, recipient.raw_public_key().unwrap()].concat();
+        let key = hmac(&hmac(&salt, &shared), b"ckb-emitter event\x01");
+        let plain = Aes256Gcm::new_from_slice(&key)
+            .unwrap()
+            .decrypt(
+                Nonce::from_slice(&hex(&encrypted["nonce"])),
+                base64::decode(ciphertext).unwrap().as_slice(),
+            )
+            .unwrap();
+        assert_eq!(plain, b"{}");
+    }
+}
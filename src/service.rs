@@ -0,0 +1,126 @@
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt, io::AsRawFd, net::UnixDatagram},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use crate::lifecycle::DebugTasks;
+
+static PID_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Detaches from the terminal into a new session, must run before any thread is spawned,
+/// stdout and stderr are kept for the stdout sink and the logs
+pub fn daemonize() -> io::Result<()> {
+    unsafe {
+        // the second fork keeps the daemon from ever acquiring a controlling terminal again
+        for i in 0..2 {
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {}
+                _ => libc::_exit(0),
+            }
+            if i == 0 && libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let null = File::open("/dev/null")?;
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Writes the pid to `path`, refuses to if it holds the pid of a running process
+pub fn write_pid_file(path: &Path) -> io::Result<()> {
+    if let Some(pid) = fs::read_to_string(path)
+        .ok()
+        .and_then(|pid| pid.trim().parse::<libc::pid_t>().ok())
+    {
+        let alive = unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        if alive {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("pid file {:?} is held by running process {}", path, pid),
+            ));
+        }
+    }
+    fs::write(path, format!("{}\n", std::process::id()))?;
+    let _ = PID_FILE.set(path.to_path_buf());
+    Ok(())
+}
+
+pub fn remove_pid_file() {
+    if let Some(path) = PID_FILE.get() {
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!("failed to remove pid file {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Sends a state such as `READY=1` to systemd, a no-op unless started by a `Type=notify` unit
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let sent =
+        UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &path),
+        });
+    if let Err(e) = sent {
+        log::warn!("failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+/// Pings the systemd watchdog at half its timeout while no scan ran longer than `stall`, a wedged
+/// scan stops the pings and gets the emitter restarted. Returns at once if it isn't enabled
+pub(crate) async fn watchdog(tasks: DebugTasks, stall: Duration) {
+    let usec = match std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+    {
+        Some(usec) if usec > 0 => usec,
+        _ => return,
+    };
+    if let Some(pid) = std::env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+    {
+        if pid != std::process::id() {
+            return;
+        }
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    let mut stalled = false;
+    loop {
+        interval.tick().await;
+        match tasks.stalled(stall) {
+            None => {
+                stalled = false;
+                notify("WATCHDOG=1");
+            }
+            Some(key) if !stalled => {
+                stalled = true;
+                log::error!(
+                    "Scan running for over {:?}, the systemd watchdog is no longer pinged, key: {:?}",
+                    stall,
+                    key
+                );
+                notify("STATUS=scan stalled");
+            }
+            Some(_) => {}
+        }
+    }
+}
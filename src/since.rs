@@ -0,0 +1,76 @@
+use ckb_jsonrpc_types::{OutPoint, Uint64};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::schema;
+
+const RELATIVE_FLAG: u64 = 1 << 63;
+const METRIC_MASK: u64 = 0b11 << 61;
+const RESERVED_MASK: u64 = 0x1f << 56;
+const VALUE_MASK: u64 = (1 << 56) - 1;
+
+/// What the value of an input's `since` counts
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SinceMetric {
+    BlockNumber,
+    Epoch,
+    /// Seconds, of the median time of the previous blocks
+    Timestamp,
+}
+
+/// Matches transactions with a matched input whose `since` is set, and is of this kind if given
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct SinceFilter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metric: Option<SinceMetric>,
+}
+
+impl SinceFilter {
+    pub fn matches(&self, since: &InputSince) -> bool {
+        self.relative
+            .is_none_or(|relative| relative == since.relative)
+            && self.metric.is_none_or(|metric| metric == since.metric)
+    }
+}
+
+/// The decoded `since` of a matched input, events list the inputs whose `since` is set
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct InputSince {
+    /// As in the event's `inputs`
+    #[schemars(with = "schema::OutPoint")]
+    pub input: OutPoint,
+    #[schemars(with = "schema::Hex")]
+    pub since: Uint64,
+    /// Counted from the block of the spent cell, else from the genesis
+    pub relative: bool,
+    pub metric: SinceMetric,
+    /// The block number or timestamp, for epochs the number, index and length packed as in headers
+    #[schemars(with = "schema::Hex")]
+    pub value: Uint64,
+}
+
+impl InputSince {
+    /// None for a zero `since`, which locks nothing, and for one with reserved bits set
+    pub fn decode(input: OutPoint, since: Uint64) -> Option<Self> {
+        let raw = since.value();
+        if raw == 0 || raw & RESERVED_MASK != 0 {
+            return None;
+        }
+        let metric = match (raw & METRIC_MASK) >> 61 {
+            0 => SinceMetric::BlockNumber,
+            1 => SinceMetric::Epoch,
+            2 => SinceMetric::Timestamp,
+            _ => return None,
+        };
+        Some(InputSince {
+            input,
+            since,
+            relative: raw & RELATIVE_FLAG != 0,
+            metric,
+            value: (raw & VALUE_MASK).into(),
+        })
+    }
+}
@@ -0,0 +1,192 @@
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::{BodyFormat, Compression, SinkConfig, Sinks};
+use crate::{global_state::Registration, metrics, rpc_server::RpcSearchKey};
+
+/// Seconds between health checks of the sinks when `health_check_interval_secs` is unset
+const HEALTH_CHECK_INTERVAL_SECS: u64 = 30;
+
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Deliveries to the sink fail at once, its events wait in the event log
+    Open,
+    Closed,
+}
+
+#[derive(Default)]
+struct Breaker {
+    /// Consecutive failed deliveries and health checks
+    failures: u32,
+    open: bool,
+}
+
+/// Circuit breaker per sink id, shared by the registrations delivering to the sink
+#[derive(Clone, Default)]
+pub(crate) struct CircuitBreakers(Arc<DashMap<String, Breaker>>);
+
+impl CircuitBreakers {
+    pub fn is_open(&self, id: &str) -> bool {
+        self.0.get(id).is_some_and(|breaker| breaker.open)
+    }
+
+    /// Opens the circuit after `threshold` failures in a row and closes it on a success, returns
+    /// the new state if it changed
+    pub fn record(&self, id: &str, ok: bool, threshold: u32) -> Option<CircuitState> {
+        let mut breaker = self.0.entry(id.to_string()).or_default();
+        if ok {
+            breaker.failures = 0;
+            return std::mem::take(&mut breaker.open).then_some(CircuitState::Closed);
+        }
+        breaker.failures = breaker.failures.saturating_add(1);
+        if breaker.open || breaker.failures < threshold {
+            return None;
+        }
+        breaker.open = true;
+        Some(CircuitState::Open)
+    }
+}
+
+/// Posted to `alert_webhook_url` when the circuit of a sink opens or closes
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct CircuitAlert<'a> {
+    /// Always `circuit_breaker`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Id of the sink, its url for a webhook
+    sink: &'a str,
+    state: CircuitState,
+    /// Summary for chat webhooks, e.g. Slack shows only this
+    text: String,
+}
+
+impl Sinks {
+    /// Records the outcome of a delivery or health check when the breaker is on, and announces a
+    /// change of the circuit
+    pub(crate) async fn record_health(&self, id: &str, ok: bool) {
+        let (threshold, url) = {
+            let settings = self.settings.read().unwrap();
+            (
+                settings.circuit_breaker_failures,
+                settings.alert_webhook_url.clone(),
+            )
+        };
+        let state = match threshold.filter(|failures| *failures > 0) {
+            Some(threshold) => match self.breakers.record(id, ok, threshold) {
+                Some(state) => state,
+                None => return,
+            },
+            None => return,
+        };
+        metrics::set(
+            "emitter_sink_circuit_open",
+            &[("sink", id)],
+            (state == CircuitState::Open) as u8 as f64,
+        );
+        let text = match state {
+            CircuitState::Open => format!("emitter: circuit of sink {} opened", id),
+            CircuitState::Closed => format!("emitter: circuit of sink {} closed", id),
+        };
+        log::warn!("{}", text);
+        if let Some(url) = url {
+            let alert = CircuitAlert {
+                kind: "circuit_breaker",
+                sink: id,
+                state,
+                text,
+            };
+            if let Err(e) = self
+                .post(
+                    &url,
+                    None,
+                    Compression::None,
+                    BodyFormat::Json,
+                    None,
+                    &alert,
+                )
+                .await
+            {
+                log::warn!("Failed to post circuit alert, sink: {}, error: {:?}", id, e);
+            }
+        }
+    }
+
+    /// A webhook is healthy if it answers a HEAD request without a server error, a callback if its
+    /// consumer is registered
+    pub(crate) async fn check(&self, sink: &SinkConfig) -> Option<bool> {
+        match sink {
+            SinkConfig::Stdout { .. } => None,
+            SinkConfig::Webhook { url, .. } => {
+                let timeout = self
+                    .settings
+                    .read()
+                    .unwrap()
+                    .webhook_timeout_secs
+                    .map(Duration::from_secs);
+                let mut request = self.http.head(url);
+                if let Some(timeout) = timeout {
+                    request = request.timeout(timeout);
+                }
+                Some(
+                    matches!(request.send().await, Ok(response) if !response.status().is_server_error()),
+                )
+            }
+            SinkConfig::Callback { name, .. } => Some(self.consumers.get(name).is_ok()),
+        }
+    }
+}
+
+/// Health-checks the sinks of every registration each `health_check_interval_secs` while the
+/// circuit breaker is on, an open circuit closes once its sink is healthy
+pub(crate) async fn run(state: Arc<DashMap<RpcSearchKey, Registration>>, sinks: Sinks) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        // re-read every tick, the settings may change on reload
+        let every = {
+            let settings = sinks.settings.read().unwrap();
+            match settings.circuit_breaker_failures {
+                Some(failures) if failures > 0 => Duration::from_secs(
+                    settings
+                        .health_check_interval_secs
+                        .unwrap_or(HEALTH_CHECK_INTERVAL_SECS)
+                        .max(1),
+                ),
+                _ => continue,
+            }
+        };
+        if last.elapsed() < every {
+            continue;
+        }
+        last = Instant::now();
+
+        let mut checked = Vec::new();
+        for sink in state
+            .iter()
+            .flat_map(|kv| kv.value().sinks())
+            .collect::<Vec<_>>()
+        {
+            if !checked.iter().any(|c: &SinkConfig| c.id() == sink.id()) {
+                checked.push(sink);
+            }
+        }
+        let checks = checked.iter().map(|sink| {
+            let sinks = &sinks;
+            async move {
+                if let Some(ok) = sinks.check(sink).await {
+                    sinks.record_health(&sink.id(), ok).await;
+                }
+            }
+        });
+        futures::future::join_all(checks).await;
+    }
+}
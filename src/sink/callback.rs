@@ -0,0 +1,55 @@
+// implemented and added by the code embedding the emitter, not by the emitter itself
+#![allow(dead_code)]
+
+use ckb_jsonrpc_types::{CellInfo, HeaderView, OutPoint};
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+
+use std::{io, sync::Arc};
+
+use crate::rpc_server::RpcSearchKey;
+
+/// The cells of one block matched by a registration
+pub(crate) struct Cells<'a> {
+    /// Position of the event in the event log
+    pub seq: u64,
+    pub inputs: &'a [OutPoint],
+    pub outputs: &'a [CellInfo],
+}
+
+/// Handles the emitted cells in the emitter's process instead of a network sink, for code embedding
+/// the emitter. A `callback` sink names the consumer its registration's events go to
+pub(crate) trait CellConsumer: Send + Sync {
+    /// The next event of the registration waits until the returned future completes, a slow consumer
+    /// slows its scan down. An error fails the delivery, the event is retried on the next scan
+    fn on_cells<'a>(
+        &'a self,
+        key: &'a RpcSearchKey,
+        block: &'a HeaderView,
+        cells: Cells<'a>,
+    ) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// The consumers by name, added by the embedding code before it registers their sinks
+#[derive(Clone, Default)]
+pub(crate) struct CellConsumers(Arc<DashMap<String, Arc<dyn CellConsumer>>>);
+
+impl CellConsumers {
+    /// Replaces the consumer of the same name
+    pub fn add(&self, name: impl Into<String>, consumer: impl CellConsumer + 'static) {
+        self.0.insert(name.into(), Arc::new(consumer));
+    }
+
+    /// A sink naming no added consumer fails like an unreachable webhook
+    pub fn get(&self, name: &str) -> io::Result<Arc<dyn CellConsumer>> {
+        self.0
+            .get(name)
+            .map(|consumer| consumer.clone())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no cell consumer named {}", name),
+                )
+            })
+    }
+}
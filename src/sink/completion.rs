@@ -0,0 +1,29 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use super::Heartbeat;
+
+/// Why a registration completed
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionReason {
+    /// It logged its `complete_after` events, it is deleted
+    Events,
+    /// It scanned its `end_block`, it stays listed as completed
+    EndBlock,
+}
+
+/// Sent to the sinks of a registration and the unix socket once it completed. Typed `completion`,
+/// its tip is the final scan tip
+#[derive(Serialize, JsonSchema)]
+pub struct Completion<'a> {
+    #[serde(flatten)]
+    pub heartbeat: Heartbeat<'a>,
+    pub reason: CompletionReason,
+    /// Matched cells and transactions of all its events, see `Stats`
+    pub cells: u64,
+    pub transactions: u64,
+    /// Since it was registered, unset for registrations from before the emitter recorded it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+}
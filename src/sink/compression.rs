@@ -0,0 +1,37 @@
+use flate2::{write::GzEncoder, Compression as GzLevel};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::io::{self, Write};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn compress(&self, payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(payload),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+                encoder.write_all(&payload)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(payload.as_slice(), 0),
+        }
+    }
+
+    /// Value of the http `Content-Encoding` header for compressed bodies
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+}
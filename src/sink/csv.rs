@@ -0,0 +1,141 @@
+use ckb_jsonrpc_types::{CellInfo, OutPoint};
+use ckb_types::{packed, prelude::Unpack, H256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{DataEncoding, Event};
+
+/// A column of the csv and tsv stdout formats, every matched cell is a row
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvColumn {
+    /// `created` for the outputs, `spent` for the inputs
+    Kind,
+    BlockNumber,
+    /// With `index`, the out point of the cell
+    TxHash,
+    Index,
+    /// In shannons
+    Capacity,
+    LockHash,
+    TypeHash,
+    Data,
+}
+
+impl CsvColumn {
+    fn name(self) -> &'static str {
+        match self {
+            CsvColumn::Kind => "kind",
+            CsvColumn::BlockNumber => "block_number",
+            CsvColumn::TxHash => "tx_hash",
+            CsvColumn::Index => "index",
+            CsvColumn::Capacity => "capacity",
+            CsvColumn::LockHash => "lock_hash",
+            CsvColumn::TypeHash => "type_hash",
+            CsvColumn::Data => "data",
+        }
+    }
+}
+
+/// Of a sink without its own columns, in this order
+pub(crate) const DEFAULT_COLUMNS: &[CsvColumn] = &[
+    CsvColumn::Kind,
+    CsvColumn::BlockNumber,
+    CsvColumn::TxHash,
+    CsvColumn::Index,
+    CsvColumn::Capacity,
+    CsvColumn::LockHash,
+    CsvColumn::TypeHash,
+    CsvColumn::Data,
+];
+
+/// The header is printed before the first row the process prints
+static HEADER_PRINTED: AtomicBool = AtomicBool::new(false);
+
+/// A matched cell of an event, the details of a spent one are only known with `with_input_cells`
+struct Row<'a> {
+    kind: &'static str,
+    out_point: Option<&'a OutPoint>,
+    cell: Option<&'a CellInfo>,
+}
+
+/// Prints a row per matched cell, the outputs first, fields are split by `separator`
+pub(crate) fn print(
+    separator: char,
+    columns: Option<&[CsvColumn]>,
+    encoding: DataEncoding,
+    event: &Event,
+) {
+    let columns = columns.unwrap_or(DEFAULT_COLUMNS);
+    if !HEADER_PRINTED.swap(true, Ordering::Relaxed) {
+        let header = columns
+            .iter()
+            .map(|column| column.name())
+            .collect::<Vec<_>>();
+        println!("{}", header.join(&separator.to_string()));
+    }
+    let submit = event.submit;
+    // out points and input cells line up with the cells unless a plugin changed the event
+    let output_points =
+        (submit.output_points.len() == submit.outputs.len()).then_some(&submit.output_points);
+    let input_cells =
+        (submit.input_cells.len() == submit.inputs.len()).then_some(&submit.input_cells);
+    let created = submit.outputs.iter().enumerate().map(|(i, cell)| Row {
+        kind: "created",
+        out_point: output_points.map(|points| &points[i]),
+        cell: Some(cell),
+    });
+    let spent = submit.inputs.iter().enumerate().map(|(i, out_point)| Row {
+        kind: "spent",
+        out_point: Some(out_point),
+        cell: input_cells.map(|cells| &cells[i]),
+    });
+    for row in created.chain(spent) {
+        let fields = columns
+            .iter()
+            .map(|column| field(*column, &row, event, encoding))
+            .collect::<Vec<_>>();
+        println!("{}", fields.join(&separator.to_string()));
+    }
+}
+
+/// Empty when the row doesn't know it
+fn field(column: CsvColumn, row: &Row, event: &Event, encoding: DataEncoding) -> String {
+    let script_hash = |script: packed::Script| {
+        let hash: H256 = script.calc_script_hash().unpack();
+        format!("{:#x}", hash)
+    };
+    match column {
+        CsvColumn::Kind => row.kind.to_string(),
+        CsvColumn::BlockNumber => event.submit.header.inner.number.value().to_string(),
+        CsvColumn::TxHash => row.out_point.map_or(String::new(), |out_point| {
+            format!("{:#x}", out_point.tx_hash)
+        }),
+        CsvColumn::Index => row.out_point.map_or(String::new(), |out_point| {
+            out_point.index.value().to_string()
+        }),
+        CsvColumn::Capacity => row.cell.map_or(String::new(), |cell| {
+            cell.output.capacity.value().to_string()
+        }),
+        CsvColumn::LockHash => row.cell.map_or(String::new(), |cell| {
+            script_hash(cell.output.lock.clone().into())
+        }),
+        CsvColumn::TypeHash => row
+            .cell
+            .and_then(|cell| cell.output.type_.clone())
+            .map_or(String::new(), |script| script_hash(script.into())),
+        CsvColumn::Data => {
+            row.cell
+                .and_then(|cell| cell.data.as_ref())
+                .map_or(String::new(), |data| {
+                    let bytes = data.content.as_bytes();
+                    match encoding {
+                        DataEncoding::Hex => format!("0x{}", faster_hex::hex_string(bytes)),
+                        DataEncoding::Base64 => base64::encode(bytes),
+                    }
+                })
+        }
+    }
+}
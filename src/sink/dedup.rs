@@ -0,0 +1,90 @@
+use ckb_jsonrpc_types::OutPoint;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::{rpc_client::CellType, Submit};
+
+/// A cell consumed or created by an emitted event
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EmittedCell {
+    pub out_point: OutPoint,
+    pub io_type: CellType,
+}
+
+/// The last emitted cells of a registration, oldest first, bounded to `capacity`
+pub(crate) struct DedupWindow {
+    cells: VecDeque<EmittedCell>,
+    capacity: usize,
+    /// The cells of the window and the ones claimed by filtered submits
+    seen: HashSet<EmittedCell>,
+}
+
+impl DedupWindow {
+    pub fn new(cells: Vec<EmittedCell>, capacity: usize) -> Self {
+        let mut window = DedupWindow {
+            seen: cells.iter().cloned().collect(),
+            cells: VecDeque::with_capacity(capacity),
+            capacity,
+        };
+        window.extend(cells);
+        window
+    }
+
+    pub fn cells(&self) -> Vec<EmittedCell> {
+        self.cells.iter().cloned().collect()
+    }
+
+    /// Remembers the cells of a delivered event
+    pub fn extend(&mut self, cells: Vec<EmittedCell>) {
+        self.cells.extend(cells);
+        // the capacity may have been lowered since the window was stored
+        while self.cells.len() > self.capacity {
+            self.cells.pop_front();
+        }
+    }
+
+    /// Removes the cells of the submit which are in the window or were left in a submit filtered
+    /// before, returns the remaining ones
+    pub fn filter(&mut self, submit: &mut Submit) -> Vec<EmittedCell> {
+        let mut emitted = Vec::new();
+        let inputs = std::mem::take(&mut submit.inputs);
+        // the resolved input cells and spent out points, if any, stay in the order of the inputs
+        let mut input_cells = std::mem::take(&mut submit.input_cells).into_iter();
+        let resolved = input_cells.len() == inputs.len();
+        let mut spent_points = std::mem::take(&mut submit.spent_points).into_iter();
+        let spent_known = spent_points.len() == inputs.len();
+        for out_point in inputs {
+            let input_cell = input_cells.next().filter(|_| resolved);
+            let spent_point = spent_points.next().filter(|_| spent_known);
+            let cell = EmittedCell {
+                out_point: out_point.clone(),
+                io_type: CellType::Input,
+            };
+            if self.seen.insert(cell.clone()) {
+                emitted.push(cell);
+                submit.inputs.push(out_point);
+                submit.input_cells.extend(input_cell);
+                submit.spent_points.extend(spent_point);
+            }
+        }
+        // out points are only known to the scan fetching the cells
+        if submit.output_points.len() != submit.outputs.len() {
+            return emitted;
+        }
+        let outputs = std::mem::take(&mut submit.outputs);
+        let out_points = std::mem::take(&mut submit.output_points);
+        for (output, out_point) in outputs.into_iter().zip(out_points) {
+            let cell = EmittedCell {
+                out_point: out_point.clone(),
+                io_type: CellType::Output,
+            };
+            if self.seen.insert(cell.clone()) {
+                emitted.push(cell);
+                submit.outputs.push(output);
+                submit.output_points.push(out_point);
+            }
+        }
+        emitted
+    }
+}
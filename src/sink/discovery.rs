@@ -0,0 +1,66 @@
+use ckb_types::{packed, prelude::Unpack, H256};
+
+use std::collections::HashSet;
+
+use crate::{rpc_client::ScriptType, Submit};
+
+/// The hashes of the scripts a discovering registration emitted, and the ones new since they were loaded
+pub(crate) struct Discovery {
+    script_type: ScriptType,
+    seen: HashSet<H256>,
+    discovered: Vec<H256>,
+}
+
+impl Discovery {
+    pub fn new(script_type: ScriptType, seen: Vec<H256>) -> Self {
+        Discovery {
+            script_type,
+            seen: seen.into_iter().collect(),
+            discovered: Vec::new(),
+        }
+    }
+
+    /// The scripts discovered so far, to store once the events are logged
+    pub fn discovered(&self) -> &[H256] {
+        &self.discovered
+    }
+
+    /// Keeps the first output of each script not seen before and drops the inputs, which spend
+    /// cells of seen scripts. Returns whether an output is left
+    pub fn filter(&mut self, submit: &mut Submit) -> bool {
+        submit.inputs.clear();
+        submit.input_cells.clear();
+        submit.spent_points.clear();
+        submit.since.clear();
+        submit.cheques.clear();
+        submit.multisigs.clear();
+        submit.correlations.clear();
+        let outputs = std::mem::take(&mut submit.outputs);
+        let mut out_points = std::mem::take(&mut submit.output_points).into_iter();
+        let known = out_points.len() == outputs.len();
+        let cellbase_outputs = std::mem::take(&mut submit.cellbase_outputs);
+        for (index, output) in outputs.into_iter().enumerate() {
+            let out_point = out_points.next().filter(|_| known);
+            let script = match self.script_type {
+                ScriptType::Lock => Some(output.output.lock.clone()),
+                ScriptType::Type => output.output.type_.clone(),
+            };
+            let hash: H256 = match script {
+                Some(script) => packed::Script::from(script).calc_script_hash().unpack(),
+                None => continue,
+            };
+            if !self.seen.insert(hash.clone()) {
+                continue;
+            }
+            self.discovered.push(hash);
+            if cellbase_outputs.contains(&(index as u32).into()) {
+                submit
+                    .cellbase_outputs
+                    .push((submit.outputs.len() as u32).into());
+            }
+            submit.outputs.push(output);
+            submit.output_points.extend(out_point);
+        }
+        !submit.outputs.is_empty()
+    }
+}
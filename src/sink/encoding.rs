@@ -0,0 +1,66 @@
+use schemars::JsonSchema;
+use serde::{ser::Error, Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+/// How cell data and script args are written in the json sent to a sink
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DataEncoding {
+    /// 0x prefixed, as the ckb rpc writes them
+    #[default]
+    Hex,
+    /// Standard base64 with padding, a third shorter
+    Base64,
+}
+
+impl DataEncoding {
+    pub fn is_hex(&self) -> bool {
+        *self == DataEncoding::Hex
+    }
+
+    /// Serializes the payload with its `args` and data `content` fields in this encoding
+    pub fn encode<T: Serialize>(self, payload: &T) -> Encoded<'_, T> {
+        Encoded(self, payload)
+    }
+}
+
+/// A payload serialized in an encoding, hex ones are serialized as they are
+pub(crate) struct Encoded<'a, T>(DataEncoding, &'a T);
+
+impl<T: Serialize> Serialize for Encoded<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            DataEncoding::Hex => self.1.serialize(serializer),
+            DataEncoding::Base64 => {
+                let mut value = serde_json::to_value(self.1).map_err(S::Error::custom)?;
+                to_base64(&mut value);
+                value.serialize(serializer)
+            }
+        }
+    }
+}
+
+fn to_base64(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match field {
+                    Value::String(hex) if name == "args" || name == "content" => {
+                        if let Some(bytes) = hex.strip_prefix("0x").and_then(decode_hex) {
+                            *hex = base64::encode(bytes);
+                        }
+                    }
+                    field => to_base64(field),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(to_base64),
+        _ => {}
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![0; hex.len() / 2];
+    faster_hex::hex_decode(hex.as_bytes(), &mut bytes).ok()?;
+    Some(bytes)
+}
@@ -0,0 +1,126 @@
+use dashmap::DashMap;
+
+use std::{collections::HashMap, sync::Arc};
+
+use super::{Heartbeat, Sinks};
+use crate::{
+    cell_process::{now_ms, SCAN_INTERVAL},
+    global_state::Registration,
+    rpc_client::{IndexerTip, RpcClient},
+    rpc_server::RpcSearchKey,
+    tip_poller::IndexerTipWatch,
+};
+
+/// Sends a `finality` checkpoint to the sink of every registration and to the unix socket once
+/// its events up to a block are `finality_depth` blocks deep, checked every scan interval. The
+/// block is the last one scanned, before the scan tip, or the last one deep enough before that,
+/// a failed checkpoint is sent again on the next check
+pub(crate) async fn run(
+    state: Arc<DashMap<RpcSearchKey, Registration>>,
+    client: RpcClient,
+    indexer_tip: IndexerTipWatch,
+    sinks: Sinks,
+) {
+    let mut interval = tokio::time::interval(SCAN_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut sent = HashMap::<RpcSearchKey, u64>::new();
+    loop {
+        interval.tick().await;
+        // re-read every tick, the depth may change on reload
+        let depth = match sinks.settings.read().unwrap().finality_depth {
+            Some(depth) => depth,
+            None => continue,
+        };
+        let tip = match indexer_tip.get().await {
+            Ok(tip) => tip,
+            Err(_) => continue,
+        };
+        let deepest = tip.block_number.value().saturating_sub(depth);
+        let registrations = state
+            .iter()
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect::<Vec<_>>();
+        sent.retain(|key, _| state.contains_key(key));
+        // registrations behind the scan tips share the headers
+        let mut headers = HashMap::<u64, IndexerTip>::new();
+        for (key, registration) in &registrations {
+            let number = match final_block(&registration.scan_tip.load(), deepest) {
+                Some(number) => number,
+                None => continue,
+            };
+            if sent.get(key).is_some_and(|sent| *sent >= number) {
+                continue;
+            }
+            let block = if let Some(block) = headers.get(&number) {
+                block.clone()
+            } else {
+                match client.get_header_by_number(number.into()).await {
+                    Ok(header) => {
+                        let block = IndexerTip {
+                            block_hash: header.hash,
+                            block_number: header.inner.number,
+                        };
+                        headers.insert(number, block.clone());
+                        block
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to get final block {}, error: {:?}", number, e);
+                        continue;
+                    }
+                }
+            };
+            let checkpoint = Heartbeat {
+                kind: "finality",
+                key,
+                tip: block,
+                timestamp: now_ms(),
+            };
+            match sinks
+                .deliver_heartbeat(&registration.sinks(), &checkpoint)
+                .await
+            {
+                Ok(()) => {
+                    sent.insert(key.clone(), number);
+                }
+                Err(e) => log::warn!(
+                    "Failed to deliver finality checkpoint, key: {:?}, error: {:?}",
+                    key,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// The block to checkpoint below `scan_tip`, at most `deepest`, none before any block is scanned
+fn final_block(scan_tip: &IndexerTip, deepest: u64) -> Option<u64> {
+    if scan_tip.is_parked() {
+        return None;
+    }
+    // the scan tip block itself is scanned next
+    let scanned = scan_tip.block_number.value().checked_sub(1)?;
+    Some(deepest.min(scanned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_types::H256;
+
+    #[test]
+    fn checkpoint_is_below_the_scan_tip() {
+        let tip = |number: u64| IndexerTip {
+            block_hash: H256([1; 32]),
+            block_number: number.into(),
+        };
+        // the scan tip block isn't scanned yet
+        assert_eq!(final_block(&tip(10), 100), Some(9));
+        assert_eq!(final_block(&tip(10), 5), Some(5));
+        assert_eq!(final_block(&tip(0), 100), None);
+        let parked = IndexerTip {
+            block_hash: H256::default(),
+            ..tip(10)
+        };
+        assert_eq!(final_block(&parked, 100), None);
+    }
+}
@@ -0,0 +1,361 @@
+//! Formats of the webhook bodies. Each is a `Serializer` of the event's json, a new one is an
+//! implementation and a `BodyFormat` variant naming it
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::io;
+
+/// Turns the json of an event, heartbeat or notice into a request body
+pub(crate) trait Serializer: Send + Sync {
+    /// Sent as the `Content-Type` of the bodies
+    fn content_type(&self) -> &'static str;
+
+    fn serialize(&self, value: &Value) -> io::Result<Vec<u8>>;
+}
+
+/// The format of the bodies a webhook gets
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyFormat {
+    #[default]
+    Json,
+    /// RFC 8949, the json's data model
+    Cbor,
+    Msgpack,
+    /// A `google.protobuf.Value`, numbers are doubles
+    Protobuf,
+}
+
+impl BodyFormat {
+    pub fn is_json(&self) -> bool {
+        *self == BodyFormat::Json
+    }
+
+    pub fn serializer(self) -> &'static dyn Serializer {
+        match self {
+            BodyFormat::Json => &Json,
+            BodyFormat::Cbor => &Cbor,
+            BodyFormat::Msgpack => &Msgpack,
+            BodyFormat::Protobuf => &Protobuf,
+        }
+    }
+
+    /// Json bodies are written without building their json value first
+    pub fn encode(self, body: &impl Serialize) -> io::Result<Vec<u8>> {
+        match self {
+            BodyFormat::Json => Ok(serde_json::to_vec(body)?),
+            _ => self.serializer().serialize(&serde_json::to_value(body)?),
+        }
+    }
+}
+
+struct Json;
+
+impl Serializer for Json {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn serialize(&self, value: &Value) -> io::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+struct Cbor;
+
+impl Cbor {
+    fn head(out: &mut Vec<u8>, major: u8, n: u64) {
+        let major = major << 5;
+        match n {
+            0..=23 => out.push(major | n as u8),
+            24..=0xff => out.extend([major | 24, n as u8]),
+            0x100..=0xffff => {
+                out.push(major | 25);
+                out.extend((n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(major | 26);
+                out.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(major | 27);
+                out.extend(n.to_be_bytes());
+            }
+        }
+    }
+
+    fn write(out: &mut Vec<u8>, value: &Value) {
+        match value {
+            Value::Null => out.push(0xf6),
+            Value::Bool(false) => out.push(0xf4),
+            Value::Bool(true) => out.push(0xf5),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(n), _) => Cbor::head(out, 0, n),
+                (None, Some(n)) => Cbor::head(out, 1, !n as u64),
+                _ => {
+                    out.push(0xfb);
+                    out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+                }
+            },
+            Value::String(s) => {
+                Cbor::head(out, 3, s.len() as u64);
+                out.extend(s.as_bytes());
+            }
+            Value::Array(values) => {
+                Cbor::head(out, 4, values.len() as u64);
+                for value in values {
+                    Cbor::write(out, value);
+                }
+            }
+            Value::Object(map) => {
+                Cbor::head(out, 5, map.len() as u64);
+                for (key, value) in map {
+                    Cbor::head(out, 3, key.len() as u64);
+                    out.extend(key.as_bytes());
+                    Cbor::write(out, value);
+                }
+            }
+        }
+    }
+}
+
+impl Serializer for Cbor {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn serialize(&self, value: &Value) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Cbor::write(&mut out, value);
+        Ok(out)
+    }
+}
+
+struct Msgpack;
+
+/// The fix form of lengths below `fix_max`, else the first of the 8, 16 and 32 bit forms
+/// the length fits in
+struct Head {
+    fix: u8,
+    fix_max: usize,
+    codes: [Option<u8>; 3],
+}
+
+const STR: Head = Head {
+    fix: 0xa0,
+    fix_max: 32,
+    codes: [Some(0xd9), Some(0xda), Some(0xdb)],
+};
+const ARRAY: Head = Head {
+    fix: 0x90,
+    fix_max: 16,
+    codes: [None, Some(0xdc), Some(0xdd)],
+};
+const MAP: Head = Head {
+    fix: 0x80,
+    fix_max: 16,
+    codes: [None, Some(0xde), Some(0xdf)],
+};
+
+impl Msgpack {
+    fn head(out: &mut Vec<u8>, head: &Head, n: usize) -> io::Result<()> {
+        match head.codes {
+            _ if n < head.fix_max => out.push(head.fix | n as u8),
+            [Some(code), _, _] if n <= 0xff => out.extend([code, n as u8]),
+            [_, Some(code), _] if n <= 0xffff => {
+                out.push(code);
+                out.extend((n as u16).to_be_bytes());
+            }
+            [_, _, Some(code)] if n <= 0xffff_ffff => {
+                out.push(code);
+                out.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "value too large for msgpack",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn write(out: &mut Vec<u8>, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Null => out.push(0xc0),
+            Value::Bool(false) => out.push(0xc2),
+            Value::Bool(true) => out.push(0xc3),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(n), _) if n < 0x80 => out.push(n as u8),
+                (Some(n), _) if n <= 0xff => out.extend([0xcc, n as u8]),
+                (Some(n), _) if n <= 0xffff => {
+                    out.push(0xcd);
+                    out.extend((n as u16).to_be_bytes());
+                }
+                (Some(n), _) if n <= 0xffff_ffff => {
+                    out.push(0xce);
+                    out.extend((n as u32).to_be_bytes());
+                }
+                (Some(n), _) => {
+                    out.push(0xcf);
+                    out.extend(n.to_be_bytes());
+                }
+                (None, Some(n)) if n >= -32 => out.push(n as i8 as u8),
+                (None, Some(n)) => {
+                    out.push(0xd3);
+                    out.extend(n.to_be_bytes());
+                }
+                _ => {
+                    out.push(0xcb);
+                    out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+                }
+            },
+            Value::String(s) => {
+                Msgpack::head(out, &STR, s.len())?;
+                out.extend(s.as_bytes());
+            }
+            Value::Array(values) => {
+                Msgpack::head(out, &ARRAY, values.len())?;
+                for value in values {
+                    Msgpack::write(out, value)?;
+                }
+            }
+            Value::Object(map) => {
+                Msgpack::head(out, &MAP, map.len())?;
+                for (key, value) in map {
+                    Msgpack::head(out, &STR, key.len())?;
+                    out.extend(key.as_bytes());
+                    Msgpack::write(out, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serializer for Msgpack {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn serialize(&self, value: &Value) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Msgpack::write(&mut out, value)?;
+        Ok(out)
+    }
+}
+
+struct Protobuf;
+
+impl Protobuf {
+    fn varint(out: &mut Vec<u8>, mut n: u64) {
+        while n >= 0x80 {
+            out.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    fn bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+        Protobuf::varint(out, field << 3 | 2);
+        Protobuf::varint(out, bytes.len() as u64);
+        out.extend(bytes);
+    }
+
+    /// The fields of a `google.protobuf.Value`
+    fn value(value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        match value {
+            Value::Null => out.extend([1 << 3, 0]),
+            Value::Number(n) => {
+                out.push(2 << 3 | 1);
+                out.extend(n.as_f64().unwrap_or_default().to_le_bytes());
+            }
+            Value::String(s) => Protobuf::bytes(&mut out, 3, s.as_bytes()),
+            Value::Bool(b) => out.extend([4 << 3, u8::from(*b)]),
+            Value::Object(map) => {
+                // a `Struct` of `fields` map entries
+                let mut fields = Vec::new();
+                for (key, value) in map {
+                    let mut entry = Vec::new();
+                    Protobuf::bytes(&mut entry, 1, key.as_bytes());
+                    Protobuf::bytes(&mut entry, 2, &Protobuf::value(value));
+                    Protobuf::bytes(&mut fields, 1, &entry);
+                }
+                Protobuf::bytes(&mut out, 5, &fields);
+            }
+            Value::Array(values) => {
+                let mut list = Vec::new();
+                for value in values {
+                    Protobuf::bytes(&mut list, 1, &Protobuf::value(value));
+                }
+                Protobuf::bytes(&mut out, 6, &list);
+            }
+        }
+        out
+    }
+}
+
+impl Serializer for Protobuf {
+    fn content_type(&self) -> &'static str {
+        "application/x-protobuf"
+    }
+
+    fn serialize(&self, value: &Value) -> io::Result<Vec<u8>> {
+        Ok(Protobuf::value(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_formats_encode_the_json() {
+        let body = serde_json::json!({"seq": 300, "ok": true, "key": ["a", null, -2]});
+        assert_eq!(
+            BodyFormat::Json.encode(&body).unwrap(),
+            serde_json::to_vec(&body).unwrap()
+        );
+        // the map keys are sorted
+        assert_eq!(
+            BodyFormat::Cbor.encode(&body).unwrap(),
+            [
+                &[0xa3, 0x63][..],
+                b"key",
+                &[0x83, 0x61, b'a', 0xf6, 0x21, 0x62],
+                b"ok",
+                &[0xf5, 0x63],
+                b"seq",
+                &[0x19, 0x01, 0x2c]
+            ]
+            .concat()
+        );
+        assert_eq!(
+            BodyFormat::Msgpack.encode(&body).unwrap(),
+            [
+                &[0x83, 0xa3][..],
+                b"key",
+                &[0x93, 0xa1, b'a', 0xc0, 0xfe, 0xa2],
+                b"ok",
+                &[0xc3, 0xa3],
+                b"seq",
+                &[0xcd, 0x01, 0x2c]
+            ]
+            .concat()
+        );
+        let list = [
+            &[0x0a, 0x03, 0x1a, 0x01, b'a'][..],
+            &[0x0a, 0x02, 0x08, 0x00],
+        ]
+        .concat();
+        assert_eq!(
+            BodyFormat::Protobuf
+                .encode(&serde_json::json!(["a", null]))
+                .unwrap(),
+            [&[0x32, list.len() as u8][..], &list].concat()
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::Sinks;
+use crate::{global_state::Registration, rpc_client::IndexerTip, rpc_server::RpcSearchKey};
+
+/// Tells consumers of an idle registration the emitter is still running, and as a `finality`
+/// checkpoint up to which block its events are final
+#[derive(Serialize, JsonSchema)]
+pub struct Heartbeat<'a> {
+    /// `heartbeat`, `finality` or `completion`, events have no type
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub key: &'a RpcSearchKey,
+    /// Scan tip of the registration, for a checkpoint the last block deep enough
+    pub tip: IndexerTip,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+}
+
+/// Sends a heartbeat to the sink of every registration and to the unix socket each
+/// `heartbeat_interval_secs`, a failed one is logged and not retried
+pub(crate) async fn run(state: Arc<DashMap<RpcSearchKey, Registration>>, sinks: Sinks) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        // re-read every tick, the interval may change on reload
+        let every = match sinks.settings.read().unwrap().heartbeat_interval_secs {
+            Some(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => continue,
+        };
+        if last.elapsed() < every {
+            continue;
+        }
+        last = Instant::now();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let registrations = state
+            .iter()
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect::<Vec<_>>();
+        let beats = registrations.iter().map(|(key, registration)| {
+            let heartbeat = Heartbeat {
+                kind: "heartbeat",
+                key,
+                tip: registration.scan_tip.load(),
+                timestamp,
+            };
+            let sinks = &sinks;
+            async move {
+                if let Err(e) = sinks
+                    .deliver_heartbeat(&registration.sinks(), &heartbeat)
+                    .await
+                {
+                    log::warn!(
+                        "Failed to deliver heartbeat, key: {:?}, error: {:?}",
+                        key,
+                        e
+                    );
+                }
+            }
+        });
+        futures::future::join_all(beats).await;
+    }
+}
@@ -0,0 +1,1028 @@
+use ckb_jsonrpc_types::BlockNumber;
+use ckb_types::H256;
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::{ser::Error as _, Deserialize, Serialize, Serializer};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    event_log::LoggedEvent,
+    lineage,
+    live_cells::LiveSet,
+    location, metrics, network,
+    plugin::Plugin,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    schema,
+    script_info::ScriptNames,
+    sealing::{self, Encrypted, Signature},
+    stats::{Emitted, Stats},
+    storage::Storage,
+    summary::{self, SummaryMode},
+    Submit,
+};
+
+pub(crate) use breaker::CircuitBreakers;
+#[allow(unused_imports)]
+pub(crate) use callback::{CellConsumer, CellConsumers, Cells};
+pub(crate) use completion::{Completion, CompletionReason};
+pub(crate) use compression::Compression;
+pub(crate) use csv::CsvColumn;
+pub(crate) use dedup::{DedupWindow, EmittedCell};
+pub(crate) use discovery::Discovery;
+pub(crate) use encoding::DataEncoding;
+pub(crate) use format::BodyFormat;
+pub(crate) use heartbeat::Heartbeat;
+pub(crate) use retry::SinkRetry;
+pub(crate) use size_limit::{Part, SizeLimit, TruncatedData};
+pub(crate) use stdout::StdoutFormat;
+pub(crate) use transform::Transform;
+pub(crate) use unix_socket::UnixSocketSink;
+
+pub(crate) mod breaker;
+mod callback;
+mod completion;
+mod compression;
+mod csv;
+mod dedup;
+mod discovery;
+mod encoding;
+pub(crate) mod finality;
+mod format;
+pub(crate) mod heartbeat;
+mod retry;
+pub(crate) mod size_limit;
+mod stdout;
+mod transform;
+mod unix_socket;
+mod webhook;
+
+/// Where the events of a single registration are delivered
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum SinkConfig {
+    Stdout {
+        #[serde(default)]
+        format: StdoutFormat,
+        #[serde(default, skip_serializing_if = "DataEncoding::is_hex")]
+        encoding: DataEncoding,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<BTreeMap<String, serde_json::Value>>")]
+        transform: Option<Transform>,
+        /// Of the csv and tsv formats, all of them when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        columns: Option<Vec<CsvColumn>>,
+    },
+    Webhook {
+        url: String,
+        /// Signs every request body with HMAC-SHA256 when set
+        secret: Option<String>,
+        #[serde(default)]
+        compression: Compression,
+        #[serde(default, skip_serializing_if = "DataEncoding::is_hex")]
+        encoding: DataEncoding,
+        /// Of the request bodies, json when unset
+        #[serde(default, skip_serializing_if = "BodyFormat::is_json")]
+        format: BodyFormat,
+        /// Reshapes the json of every event, see `Transform`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<BTreeMap<String, serde_json::Value>>")]
+        transform: Option<Transform>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<SinkRetry>,
+        /// Bounds the body of every event, unbounded when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size_limit: Option<SizeLimit>,
+    },
+    /// A consumer in the emitter's process, see `CellConsumer`. It gets no heartbeats
+    Callback {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<SinkRetry>,
+    },
+}
+
+impl SinkConfig {
+    /// `stdout`, `webhook` or `callback`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SinkConfig::Stdout { .. } => "stdout",
+            SinkConfig::Webhook { .. } => "webhook",
+            SinkConfig::Callback { .. } => "callback",
+        }
+    }
+
+    /// Identifies the destination, delivery cursors are kept per destination
+    pub fn id(&self) -> String {
+        match self {
+            SinkConfig::Stdout { .. } => "stdout".to_string(),
+            SinkConfig::Webhook { url, .. } => url.clone(),
+            SinkConfig::Callback { name, .. } => format!("callback:{}", name),
+        }
+    }
+
+    /// Consumers take the cells as they are
+    pub fn encoding(&self) -> DataEncoding {
+        match self {
+            SinkConfig::Stdout { encoding, .. } | SinkConfig::Webhook { encoding, .. } => *encoding,
+            SinkConfig::Callback { .. } => DataEncoding::Hex,
+        }
+    }
+
+    pub fn transform(&self) -> Option<&Transform> {
+        match self {
+            SinkConfig::Stdout { transform, .. } | SinkConfig::Webhook { transform, .. } => {
+                transform.as_ref()
+            }
+            SinkConfig::Callback { .. } => None,
+        }
+    }
+
+    /// Of the sinks which can fail, stdout never does
+    pub fn retry(&self) -> Option<&SinkRetry> {
+        match self {
+            SinkConfig::Webhook { retry, .. } | SinkConfig::Callback { retry, .. } => {
+                retry.as_ref()
+            }
+            SinkConfig::Stdout { .. } => None,
+        }
+    }
+
+    /// Stdout and consumers take no bodies
+    pub fn body_format(&self) -> BodyFormat {
+        match self {
+            SinkConfig::Webhook { format, .. } => *format,
+            SinkConfig::Stdout { .. } | SinkConfig::Callback { .. } => BodyFormat::Json,
+        }
+    }
+
+    pub fn size_limit(&self) -> Option<&SizeLimit> {
+        match self {
+            SinkConfig::Webhook { size_limit, .. } => size_limit.as_ref(),
+            SinkConfig::Stdout { .. } | SinkConfig::Callback { .. } => None,
+        }
+    }
+
+    /// Hides credentials before the config is shown to rpc clients
+    pub fn redacted(&self) -> Self {
+        let mut sink = self.clone();
+        if let SinkConfig::Webhook { secret, .. } = &mut sink {
+            *secret = secret.as_ref().map(|_| "***".to_string());
+        }
+        sink
+    }
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        SinkConfig::Stdout {
+            format: StdoutFormat::default(),
+            encoding: DataEncoding::default(),
+            transform: None,
+            columns: None,
+        }
+    }
+}
+
+/// Version of the event envelope, bumped only on breaking changes of the envelope or payload
+pub const EVENT_VERSION: u32 = 1;
+
+/// A block worth of matched cells, tagged with the registration it belongs to. Serialized as
+/// `{version, key_id, seq, block, payload}` for every sink
+pub struct Event<'a> {
+    /// Position of the event in the event log
+    pub seq: u64,
+    pub key: &'a RpcSearchKey,
+    pub submit: &'a Submit,
+    /// Of an event split to keep under a webhook's `size_limit`
+    pub part: Option<Part>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(crate) struct Envelope<'a> {
+    version: u32,
+    /// Sha256 of the search key's json
+    key_id: String,
+    /// Position of the event in the event log
+    seq: u64,
+    /// `mainnet`, `testnet` or `devnet` and the first 4 bytes of the genesis hash, e.g.
+    /// `mainnet:0x92b197aa`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain: Option<&'static str>,
+    block: Block<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part: Option<Part>,
+    /// Its json as a string when events are signed, unset when they are encrypted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<Sealable<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encrypted: Option<Encrypted>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<Signature>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+enum Sealable<'a> {
+    Plain(Payload<'a>),
+    Signed(String),
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Block<'a> {
+    #[schemars(with = "schema::Hex")]
+    number: BlockNumber,
+    #[schemars(with = "schema::Hex")]
+    hash: &'a H256,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Payload<'a> {
+    key: &'a RpcSearchKey,
+    #[serde(flatten)]
+    submit: &'a Submit,
+}
+
+impl Serialize for Event<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let key_id = self.key.id();
+        let payload = Payload {
+            key: self.key,
+            submit: self.submit,
+        };
+        let (payload, encrypted, signature) = match sealing::sealer() {
+            Some(sealer) => {
+                let mut prefix = format!(
+                    "{}.{}.{}.{:#x}.",
+                    EVENT_VERSION, key_id, self.seq, self.submit.header.hash
+                );
+                if let Some(part) = self.part {
+                    prefix.push_str(&format!("{}/{}.", part.index, part.count));
+                }
+                let json = serde_json::to_string(&payload).map_err(S::Error::custom)?;
+                let (json, encrypted, signature) =
+                    sealer.seal(&prefix, json).map_err(S::Error::custom)?;
+                // a payload left unsigned goes as the object it was
+                let payload = match (json, &signature) {
+                    (Some(json), Some(_)) => Some(Sealable::Signed(json)),
+                    (Some(_), None) => Some(Sealable::Plain(payload)),
+                    (None, _) => None,
+                };
+                (payload, encrypted, signature)
+            }
+            None => (Some(Sealable::Plain(payload)), None, None),
+        };
+        Envelope {
+            version: EVENT_VERSION,
+            key_id,
+            seq: self.seq,
+            chain: network::chain(),
+            block: Block {
+                number: self.submit.header.inner.number,
+                hash: &self.submit.header.hash,
+            },
+            part: self.part,
+            payload,
+            encrypted,
+            signature,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Position of the last event delivered to a sink, the event's block and its index within the block
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeliveryCursor {
+    pub block_number: u64,
+    pub index: u32,
+}
+
+/// Delivery settings shared by all registrations, they can change while the emitter runs
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SinkSettings {
+    /// Signs webhooks which have no secret of their own
+    pub webhook_secret: Option<String>,
+    pub webhook_timeout_secs: Option<u64>,
+    /// Paces the deliveries of every registration, unlimited when unset
+    pub max_events_per_second: Option<u32>,
+    /// Cells of the last events remembered per registration, a cell already emitted as input or output
+    /// is left out of later events and events left without cells are dropped. Off when unset
+    pub dedup_window: Option<usize>,
+    /// Seconds between heartbeats sent to every registration's sink and the unix socket, none when unset
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Confirmations after which the scanned blocks are final, every registration's sink and the unix
+    /// socket get a `finality` checkpoint as they pass it. None when unset
+    pub finality_depth: Option<u64>,
+    /// Gets an alert when a registration's lag exceeds its `set_lag_alert` threshold and when it recovers,
+    /// Slack incoming webhooks take it as is. Registrations are still marked degraded when unset
+    pub alert_webhook_url: Option<String>,
+    /// Names of script code hashes in events, added to the known ones
+    pub scripts: HashMap<String, H256>,
+    /// Sinks a registration can name with `sink_profile` instead of passing one to `register`
+    pub profiles: HashMap<String, SinkConfig>,
+    /// Whether the events of a registration reach each sink in block order, strict by default
+    pub ordering: DeliveryOrdering,
+    /// Failed deliveries and health checks in a row after which a sink's circuit opens, its
+    /// deliveries then fail at once until a health check passes. Off when unset
+    pub circuit_breaker_failures: Option<u32>,
+    /// Seconds between health checks of the sinks with the circuit breaker on, 30 when unset
+    pub health_check_interval_secs: Option<u64>,
+}
+
+/// Events of a registration sent to a sink at once with relaxed ordering
+const RELAXED_IN_FLIGHT: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOrdering {
+    /// One event after another, the next one once the last was delivered, a failed one holds up
+    /// the rest until it is retried
+    #[default]
+    Strict,
+    /// Up to `RELAXED_IN_FLIGHT` events at once, which may arrive in any order. The cursor only
+    /// moves past the events delivered before the first failed one, those after it are delivered again
+    Relaxed,
+}
+
+/// Logged events with their positions, the cells the dedup window takes once delivered and
+/// the stats to count
+type Logged = Vec<(
+    (DeliveryCursor, Vec<EmittedCell>, Option<Emitted>),
+    LoggedEvent,
+)>;
+
+/// The delivery of logged events failed, they are delivered from the event log from `from` on
+/// once the registration resumes like after a spill
+#[derive(Debug)]
+pub(crate) struct Undelivered {
+    /// Seq of the first logged event some sink misses
+    pub from: u64,
+    /// Events the submit logged
+    pub logged: usize,
+    error: io::Error,
+}
+
+impl Undelivered {
+    /// The logged events `e` was returned for
+    pub fn of(e: &io::Error) -> Option<&Undelivered> {
+        e.get_ref().and_then(|e| e.downcast_ref::<Undelivered>())
+    }
+}
+
+impl std::fmt::Display for Undelivered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Undelivered {}
+
+/// A sink of the registration being submitted to
+struct Target<'a> {
+    sink: &'a SinkConfig,
+    id: String,
+    delivered: Option<DeliveryCursor>,
+    /// Failed in this submit
+    failed: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct Sinks {
+    pub storage: Arc<dyn Storage>,
+    pub unix_socket: Option<UnixSocketSink>,
+    pub http: reqwest::Client,
+    pub settings: Arc<RwLock<SinkSettings>>,
+    pub consumers: CellConsumers,
+    /// Transforms or drops the events before they are logged
+    pub plugin: Option<Arc<Plugin>>,
+    /// The last failed delivery per registration and sink, counts the retries
+    pub failed: Arc<DashMap<(RpcSearchKey, String), FailedDelivery>>,
+    pub breakers: CircuitBreakers,
+}
+
+/// A delivery retried on the next scan
+#[derive(Clone, Copy)]
+pub(crate) struct FailedDelivery {
+    pub position: DeliveryCursor,
+    /// Events of the batch waiting for the sink, the failed one included
+    pub queued: usize,
+}
+
+impl Sinks {
+    /// Logs the events in block order, then delivers them to every sink of the registration and
+    /// the unix socket, in block order unless the ordering is relaxed. Events up to a sink's delivery cursor were delivered to it before a retry or
+    /// restart and are skipped for it. A failing sink doesn't hold up the others, its error is
+    /// returned once the rest got the events. With a dedup window cells emitted before are removed first,
+    /// then the plugin transforms the events. `summarized` events keep only their summary as with
+    /// `SummaryMode::Instead`, whatever the key's mode. Returns the events logged, once they are
+    /// logged a failure is an `Undelivered` error and their cells count as emitted
+    pub async fn submit(
+        &self,
+        key: &RpcSearchKey,
+        sinks: &[SinkConfig],
+        submits: HashMap<H256, Submit>,
+        summarized: bool,
+    ) -> io::Result<usize> {
+        let mut targets = self.targets(key, sinks)?;
+        let mut window = self.dedup_window(key)?;
+        let events = self.log(key, &targets, submits, summarized, &mut window, None)?;
+        let mut stats = self.storage.load_stats(key)?;
+        // counted once logged, whatever the deliveries make of them
+        for ((_, _, emitted), _) in &events {
+            if let Some(emitted) = emitted {
+                stats.count(emitted.clone());
+            }
+        }
+        let error = self
+            .deliver_logged(key, &mut targets, &events, &mut window, &mut stats)
+            .await;
+        let stored = self.storage.store_stats(key, &stats);
+        let error = match (error, stored) {
+            (Ok(None), Ok(())) => return Ok(events.len()),
+            (Ok(Some(e)), _) | (Err(e), _) | (_, Err(e)) => e,
+        };
+        let missing = |position: &DeliveryCursor| {
+            targets
+                .iter()
+                .any(|target| Some(*position) > target.delivered)
+        };
+        let from = match events
+            .iter()
+            .find(|((position, _, _), _)| missing(position))
+        {
+            Some((_, logged)) => logged.seq,
+            None => return Err(error),
+        };
+        if let Some(window) = &mut window {
+            for ((position, cells, _), _) in &events {
+                if missing(position) {
+                    window.extend(cells.clone());
+                }
+            }
+            self.storage.store_dedup_window(key, &window.cells())?;
+        }
+        Err(io::Error::new(
+            error.kind(),
+            Undelivered {
+                from,
+                logged: events.len(),
+                error,
+            },
+        ))
+    }
+
+    /// Logs the events as `submit` does without delivering them, `resume` delivers them from the
+    /// log once the sinks catch up. The scan tip past them is stored with them, they aren't
+    /// logged again after a restart. Their cells count as emitted right away. Returns the events
+    /// logged
+    pub fn spill(
+        &self,
+        key: &RpcSearchKey,
+        sinks: &[SinkConfig],
+        submits: HashMap<H256, Submit>,
+        summarized: bool,
+        tip: &IndexerTip,
+    ) -> io::Result<usize> {
+        let targets = self.targets(key, sinks)?;
+        let mut window = self.dedup_window(key)?;
+        let events = self.log(key, &targets, submits, summarized, &mut window, Some(tip))?;
+        let mut stats = self.storage.load_stats(key)?;
+        for ((_, cells, emitted), _) in &events {
+            if let Some(emitted) = emitted {
+                stats.count(emitted.clone());
+            }
+            if let Some(window) = &mut window {
+                window.extend(cells.clone());
+            }
+        }
+        if let Some(window) = &window {
+            self.storage.store_dedup_window(key, &window.cells())?;
+        }
+        self.storage.store_stats(key, &stats)?;
+        Ok(events.len())
+    }
+
+    /// Delivers up to `max` of the key's logged events from seq `from` on, those `spill` left in
+    /// the log. Returns the seq to go on from, `None` once every sink has them all
+    pub async fn resume(
+        &self,
+        key: &RpcSearchKey,
+        sinks: &[SinkConfig],
+        from: u64,
+        max: usize,
+    ) -> io::Result<Option<u64>> {
+        let mut targets = self.targets(key, sinks)?;
+        let logged = self
+            .storage
+            .read_events(from, u64::MAX, max, &|event| event.key == *key)?;
+        let mut last: Option<DeliveryCursor> = None;
+        let events = logged
+            .into_iter()
+            .map(|logged| {
+                let block_number = logged.submit.header.inner.number.value();
+                let position = DeliveryCursor {
+                    block_number,
+                    index: match last {
+                        Some(last) if last.block_number == block_number => last.index + 1,
+                        _ => 0,
+                    },
+                };
+                last = Some(position);
+                ((position, Vec::new(), None), logged)
+            })
+            .collect::<Vec<_>>();
+        let mut stats = self.storage.load_stats(key)?;
+        let error = self
+            .deliver_logged(key, &mut targets, &events, &mut None, &mut stats)
+            .await;
+        self.storage.store_stats(key, &stats)?;
+        if let Some(e) = error? {
+            return Err(e);
+        }
+        Ok(match events.last() {
+            Some((_, logged)) if events.len() == max => Some(logged.seq + 1),
+            _ => None,
+        })
+    }
+
+    fn targets<'a>(
+        &self,
+        key: &RpcSearchKey,
+        sinks: &'a [SinkConfig],
+    ) -> io::Result<Vec<Target<'a>>> {
+        let mut targets = Vec::with_capacity(sinks.len());
+        for sink in sinks {
+            let id = sink.id();
+            let delivered = self.storage.load_delivery_cursor(key, &id)?;
+            targets.push(Target {
+                sink,
+                id,
+                delivered,
+                failed: false,
+            });
+        }
+        Ok(targets)
+    }
+
+    fn dedup_window(&self, key: &RpcSearchKey) -> io::Result<Option<DedupWindow>> {
+        let dedup_window = self.settings.read().unwrap().dedup_window;
+        Ok(match dedup_window.filter(|capacity| *capacity > 0) {
+            Some(capacity) => Some(DedupWindow::new(
+                self.storage.load_dedup_window(key)?,
+                capacity,
+            )),
+            None => None,
+        })
+    }
+
+    /// Prepares the events in block order, skipping those every sink has, and logs them, with the
+    /// scan tip past them if given
+    fn log(
+        &self,
+        key: &RpcSearchKey,
+        targets: &[Target<'_>],
+        submits: HashMap<H256, Submit>,
+        summarized: bool,
+        window: &mut Option<DedupWindow>,
+        tip: Option<&IndexerTip>,
+    ) -> io::Result<Logged> {
+        let mut submits = submits.into_values().collect::<Vec<_>>();
+        submits.sort_by_key(|sub| sub.header.inner.number.value());
+        let behind = targets
+            .iter()
+            .map(|target| target.delivered)
+            .min()
+            .flatten();
+        let script_names = ScriptNames::new(&self.settings.read().unwrap().scripts);
+        let track_lineage = key.track_lineage == Some(true);
+        let mut links = Vec::new();
+        let mut discovery = match key.discover {
+            Some(true) => Some(Discovery::new(
+                key.script_type.clone(),
+                self.storage.load_discovered_scripts(key)?,
+            )),
+            _ => None,
+        };
+        let mut live_set = match key.track_live_cells {
+            Some(true) => Some(LiveSet::new(self.storage.load_live_cells(key)?)),
+            _ => None,
+        };
+        let mut last: Option<DeliveryCursor> = None;
+        let mut pending = Vec::with_capacity(submits.len());
+        for mut submit in submits {
+            let block_number = submit.header.inner.number.value();
+            let position = DeliveryCursor {
+                block_number,
+                index: match last {
+                    Some(last) if last.block_number == block_number => last.index + 1,
+                    _ => 0,
+                },
+            };
+            last = Some(position);
+            if Some(position) <= behind {
+                continue;
+            }
+            let cells = match window.as_mut() {
+                Some(window) => window.filter(&mut submit),
+                None => Vec::new(),
+            };
+            // every fetched event has cells, one left without was emitted before
+            if window.is_some() && cells.is_empty() {
+                continue;
+            }
+            if let Some(discovery) = &mut discovery {
+                // blocks with uncles or an epoch are events without cells too
+                if !discovery.filter(&mut submit)
+                    && submit.uncles.is_empty()
+                    && submit.epoch.is_none()
+                {
+                    continue;
+                }
+            }
+            if track_lineage {
+                links.extend(lineage::links(&submit));
+            }
+            if let Some(live_set) = &mut live_set {
+                live_set.apply(&mut submit, &script_names);
+            }
+            // an event some sink has was logged and counted before
+            let emitted = targets
+                .iter()
+                .all(|target| Some(position) > target.delivered)
+                .then(|| Emitted::new(&submit));
+            if key.transactions_only == Some(true) {
+                submit.transactions = summary::transaction_hashes(&submit);
+                summary::strip_cells(&mut submit);
+            }
+            if key.summary == Some(SummaryMode::Instead) || summarized {
+                summary::strip_cells(&mut submit);
+            }
+            location::locate(&mut submit);
+            submit.output_scripts = submit
+                .outputs
+                .iter()
+                .map(|cell| script_names.cell(&cell.output))
+                .collect();
+            submit.input_scripts = submit
+                .input_cells
+                .iter()
+                .map(|cell| script_names.cell(&cell.output))
+                .collect();
+            let submit = match &self.plugin {
+                Some(plugin) => match plugin.transform(key, submit)? {
+                    Some(submit) => submit,
+                    None => continue,
+                },
+                None => submit,
+            };
+            pending.push(((position, cells, emitted), submit));
+        }
+        let (positions, submits): (Vec<_>, Vec<_>) = pending.into_iter().unzip();
+        if !links.is_empty() {
+            self.storage.store_cell_links(key, &links)?;
+        }
+        if let Some(discovery) = &discovery {
+            self.storage
+                .store_discovered_scripts(key, discovery.discovered())?;
+        }
+        if let Some(live_set) = &live_set {
+            let (added, removed) = live_set.changes();
+            self.storage.update_live_cells(key, &added, &removed)?;
+        }
+
+        let logged = match tip {
+            Some(tip) => self.storage.append_events_at(key, submits, tip)?,
+            None => self.storage.append_events(key, submits)?,
+        };
+        Ok(positions.into_iter().zip(logged).collect())
+    }
+
+    /// Delivers logged events to the targets missing them, the error of the first failing sink
+    /// is returned once the rest got them
+    async fn deliver_logged(
+        &self,
+        key: &RpcSearchKey,
+        targets: &mut [Target<'_>],
+        events: &Logged,
+        window: &mut Option<DedupWindow>,
+        stats: &mut Stats,
+    ) -> io::Result<Option<io::Error>> {
+        let registration = key.metric_label();
+        let mut error = None;
+        let batch = events.len();
+        let in_flight = match self.settings.read().unwrap().ordering {
+            DeliveryOrdering::Strict => 1,
+            DeliveryOrdering::Relaxed => RELAXED_IN_FLIGHT,
+        };
+        for (c, chunk) in events.chunks(in_flight).enumerate() {
+            let chunk = chunk
+                .iter()
+                .map(|((position, cells, _), logged)| {
+                    let event = logged.as_event();
+                    let bytes = serde_json::to_vec(&event)?.len() as u64;
+                    io::Result::Ok((*position, cells, event, bytes))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            // the events of the chunk a sink misses are sent at once, their results applied in order
+            let mut results = Vec::with_capacity(targets.len());
+            for target in targets.iter() {
+                let deliveries = chunk.iter().map(|(position, _, event, _)| async move {
+                    if Some(*position) <= target.delivered || target.failed {
+                        return None;
+                    }
+                    let started = Instant::now();
+                    Some(
+                        self.deliver(target.sink, event)
+                            .await
+                            .map(|()| started.elapsed()),
+                    )
+                });
+                results.push(futures::future::join_all(deliveries).await);
+            }
+            for (j, (position, cells, event, bytes)) in chunk.into_iter().enumerate() {
+                let n = c * in_flight + j;
+                let mut missing = targets
+                    .iter()
+                    .filter(|target| Some(position) > target.delivered)
+                    .count();
+                let mut streamed = false;
+                for (i, target) in targets.iter_mut().enumerate() {
+                    if Some(position) <= target.delivered {
+                        continue;
+                    }
+                    // a sink which failed in this batch gets the rest on the next scan, in order
+                    if target.failed {
+                        continue;
+                    }
+                    let result = match results[i][j].take() {
+                        Some(result) => result,
+                        None => continue,
+                    };
+                    let labels = [
+                        ("sink_type", target.sink.kind()),
+                        ("sink", target.id.as_str()),
+                        ("registration", registration.as_str()),
+                    ];
+                    let failed = (key.clone(), target.id.clone());
+                    if self
+                        .failed
+                        .get(&failed)
+                        .is_some_and(|failed| failed.position == position)
+                    {
+                        metrics::inc("emitter_delivery_retries_total", &labels);
+                    }
+                    let elapsed = match result {
+                        Ok(elapsed) => elapsed,
+                        Err(e) => {
+                            metrics::inc("emitter_delivery_failures_total", &labels);
+                            self.failed.insert(
+                                failed,
+                                FailedDelivery {
+                                    position,
+                                    queued: batch - n,
+                                },
+                            );
+                            target.failed = true;
+                            error = Some(e);
+                            continue;
+                        }
+                    };
+                    metrics::observe(
+                        "emitter_delivery_duration_seconds",
+                        &labels,
+                        elapsed.as_secs_f64(),
+                    );
+                    self.failed.remove(&failed);
+                    stats.bytes_delivered += bytes;
+                    missing -= 1;
+                    // remembered once every sink has the event, before the last cursor moves,
+                    // an event redelivered after a crash in between is dropped
+                    if let Some(window) = window.as_mut().filter(|_| missing == 0) {
+                        window.extend(cells.clone());
+                        self.storage.store_dedup_window(key, &window.cells())?;
+                    }
+                    self.storage
+                        .store_delivery_cursor(key, &target.id, position)?;
+                    target.delivered = Some(position);
+                    // the registering client's sink streams to the unix socket, which gets every event once
+                    streamed |= i == 0;
+                }
+
+                let max_events_per_second = self.settings.read().unwrap().max_events_per_second;
+                if let Some(rate) = max_events_per_second.filter(|rate| *rate > 0) {
+                    tokio::time::sleep(Duration::from_secs(1) / rate).await;
+                }
+
+                if let Some(unix_socket) = self.unix_socket.as_ref().filter(|_| streamed) {
+                    unix_socket.send(&event);
+                }
+            }
+        }
+        Ok(error)
+    }
+
+    /// Makes the attempts the sink's retry policy allows, a single one without a policy. With the
+    /// sink's circuit open none is made, the event stays in the event log for a later scan
+    pub async fn deliver(&self, sink: &SinkConfig, event: &Event<'_>) -> io::Result<()> {
+        let id = sink.id();
+        if self.breakers.is_open(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("circuit of sink {} is open", id),
+            ));
+        }
+        let default = SinkRetry::default();
+        let retry = sink.retry().unwrap_or(&default);
+        let mut attempt = 0;
+        let result = loop {
+            match self.attempt(sink, retry.timeout(), event).await {
+                Err(e) if attempt + 1 < retry.max_attempts() && retry.retryable(&e) => {
+                    log::debug!("retry delivery to {}: {}", id, e);
+                    tokio::time::sleep(retry.delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        if !matches!(sink, SinkConfig::Stdout { .. }) {
+            self.record_health(&id, result.is_ok()).await;
+        }
+        result
+    }
+
+    /// Each attempt may take `timeout` if set
+    async fn attempt(
+        &self,
+        sink: &SinkConfig,
+        timeout: Option<Duration>,
+        event: &Event<'_>,
+    ) -> io::Result<()> {
+        match sink {
+            SinkConfig::Stdout {
+                format,
+                encoding,
+                transform,
+                columns,
+            } => stdout::print(
+                *format,
+                *encoding,
+                transform.as_ref(),
+                columns.as_deref(),
+                event,
+            ),
+            SinkConfig::Webhook {
+                url,
+                secret,
+                compression,
+                encoding,
+                format,
+                transform,
+                size_limit,
+                ..
+            } => {
+                let body = |event: &Event<'_>| -> io::Result<serde_json::Value> {
+                    let value = serde_json::to_value(event)?;
+                    Ok(match transform {
+                        Some(transform) => transform.apply(&value),
+                        None => value,
+                    })
+                };
+                let parts = match size_limit {
+                    Some(limit) => size_limit::fit(event, limit, |event| {
+                        Ok(format.encode(&encoding.encode(&body(event)?))?.len())
+                    })?,
+                    None => None,
+                };
+                let parts = match &parts {
+                    Some(parts) => parts
+                        .iter()
+                        .map(|(part, submit)| Event {
+                            seq: event.seq,
+                            key: event.key,
+                            submit,
+                            part: *part,
+                        })
+                        .collect(),
+                    None => vec![Event { ..*event }],
+                };
+                for part in &parts {
+                    self.post(
+                        url,
+                        secret.as_deref(),
+                        *compression,
+                        *format,
+                        timeout,
+                        &encoding.encode(&body(part)?),
+                    )
+                    .await?
+                }
+            }
+            SinkConfig::Callback { name, .. } => {
+                let cells = Cells {
+                    seq: event.seq,
+                    inputs: &event.submit.inputs,
+                    outputs: &event.submit.outputs,
+                };
+                let consumer = self.consumers.get(name)?;
+                let delivery = consumer.on_cells(event.key, &event.submit.header, cells);
+                match timeout {
+                    Some(timeout) => {
+                        tokio::time::timeout(timeout, delivery)
+                            .await
+                            .map_err(|_| {
+                                io::Error::new(io::ErrorKind::TimedOut, "consumer timed out")
+                            })??
+                    }
+                    None => delivery.await?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Streamed to the unix socket once, every sink is tried and the last error returned
+    pub async fn deliver_heartbeat(
+        &self,
+        sinks: &[SinkConfig],
+        heartbeat: &Heartbeat<'_>,
+    ) -> io::Result<()> {
+        self.deliver_notice(sinks, heartbeat, heartbeat).await
+    }
+
+    /// As a heartbeat, the compact stdout line shows `heartbeat` and the rest get `body`
+    pub async fn deliver_notice(
+        &self,
+        sinks: &[SinkConfig],
+        heartbeat: &Heartbeat<'_>,
+        body: &impl Serialize,
+    ) -> io::Result<()> {
+        if let Some(unix_socket) = &self.unix_socket {
+            unix_socket.send(body);
+        }
+        let mut error = None;
+        for sink in sinks {
+            match sink {
+                SinkConfig::Stdout {
+                    format, encoding, ..
+                } => stdout::print_heartbeat(*format, *encoding, heartbeat, body),
+                SinkConfig::Webhook {
+                    url,
+                    secret,
+                    compression,
+                    encoding,
+                    format,
+                    ..
+                } => {
+                    if let Err(e) = self
+                        .post(
+                            url,
+                            secret.as_deref(),
+                            *compression,
+                            *format,
+                            None,
+                            &encoding.encode(body),
+                        )
+                        .await
+                    {
+                        error = Some(e);
+                    }
+                }
+                SinkConfig::Callback { .. } => {}
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Without a secret of its own the body is signed with `webhook_secret`, without a timeout
+    /// `webhook_timeout_secs` applies
+    pub async fn post(
+        &self,
+        url: &str,
+        secret: Option<&str>,
+        compression: Compression,
+        format: BodyFormat,
+        timeout: Option<Duration>,
+        body: &impl Serialize,
+    ) -> io::Result<()> {
+        let (default_secret, default_timeout) = {
+            let settings = self.settings.read().unwrap();
+            (
+                settings.webhook_secret.clone(),
+                settings.webhook_timeout_secs.map(Duration::from_secs),
+            )
+        };
+        let timeout = timeout.or(default_timeout);
+        let secret = secret.or(default_secret.as_deref());
+        webhook::post(&self.http, url, secret, timeout, compression, format, body).await
+    }
+}
@@ -0,0 +1,63 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::{io, time::Duration};
+
+use super::webhook::WebhookStatus;
+use crate::rpc_client::RetryPolicy;
+
+/// How a sink retries a failed delivery within a scan, once its attempts are used up the delivery
+/// waits for the next scan like that of a sink without a policy
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(default)]
+pub struct SinkRetry {
+    /// Of every delivery, the first one included, 1 when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Before the second attempt, doubled on every further one, 200 when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_delay_ms: Option<u64>,
+    /// 5000 when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_delay_ms: Option<u64>,
+    /// Webhook response statuses worth another attempt, 429 and the 5xx ones when unset. Other
+    /// statuses fail the delivery at once, failed connections and timeouts are always retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_statuses: Option<Vec<u16>>,
+    /// Seconds each attempt may take, `webhook_timeout_secs` for webhooks and unlimited for
+    /// callbacks when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+impl SinkRetry {
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(1).max(1)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
+    }
+
+    /// Before the attempt after `attempt`, counted from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            retries: self.max_attempts() - 1,
+            base_delay_ms: self.base_delay_ms.unwrap_or(defaults.base_delay_ms),
+            max_delay_ms: self.max_delay_ms.unwrap_or(defaults.max_delay_ms),
+            jitter: true,
+        }
+        .delay(attempt)
+    }
+
+    pub fn retryable(&self, e: &io::Error) -> bool {
+        match WebhookStatus::of(e) {
+            Some(status) => match &self.retry_statuses {
+                Some(statuses) => statuses.contains(&status),
+                None => status == 429 || (500..600).contains(&status),
+            },
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Keeps the request bodies of a webhook under a size, for receivers with body limits
+
+use ckb_jsonrpc_types::{OutPoint, Uint32};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::io;
+
+use super::Event;
+use crate::{schema, Submit};
+
+/// The largest body, before compression, and what is done with an event exceeding it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct SizeLimit {
+    pub max_bytes: usize,
+    #[serde(default)]
+    pub oversize: Oversize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Oversize {
+    /// The data of the largest cells is left out, each listed in `truncated_data` to be fetched
+    #[default]
+    Truncate,
+    /// The cells are sent in parts, each a request with the event's `part`
+    Split,
+}
+
+/// The data of a cell left out of an event
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct TruncatedData {
+    /// `outputs` or `input_cells`
+    pub field: String,
+    #[schemars(with = "schema::Hex")]
+    pub index: Uint32,
+    /// Of the cell, unset for an output without its location
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::OutPoint>")]
+    pub out_point: Option<OutPoint>,
+    #[schemars(with = "schema::Hex")]
+    pub data_hash: H256,
+    /// Of the data, in bytes
+    pub size: usize,
+}
+
+/// One of the requests an event is split into
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// From 0
+    pub index: u32,
+    pub count: u32,
+    /// Indexes in the whole event of the first of `outputs` and `inputs` in the part, the indexes
+    /// of `cellbase_outputs` are of the whole event
+    pub first_output: u32,
+    pub first_input: u32,
+}
+
+/// Submits to send in place of an event's, with their part if split
+pub(crate) type Fitted = Vec<(Option<Part>, Submit)>;
+
+/// The submits to send instead of the event's when its body exceeds the limit. Truncated and
+/// split events may still exceed it, with data too large or a large cell
+pub(crate) fn fit(
+    event: &Event<'_>,
+    limit: &SizeLimit,
+    size: impl Fn(&Event<'_>) -> io::Result<usize>,
+) -> io::Result<Option<Fitted>> {
+    if size(event)? <= limit.max_bytes {
+        return Ok(None);
+    }
+    // the skipped fields aren't part of the body anyway
+    let submit = serde_json::from_value(serde_json::to_value(event.submit)?)?;
+    let fitted = match limit.oversize {
+        Oversize::Truncate => vec![(None, truncate(event, submit, limit.max_bytes, size)?)],
+        Oversize::Split => split(event, submit, limit.max_bytes, size)?,
+    };
+    Ok(Some(fitted))
+}
+
+fn truncate(
+    event: &Event<'_>,
+    mut submit: Submit,
+    max_bytes: usize,
+    size: impl Fn(&Event<'_>) -> io::Result<usize>,
+) -> io::Result<Submit> {
+    let length = |cell: &ckb_jsonrpc_types::CellInfo| {
+        cell.data
+            .as_ref()
+            .map_or(0, |data| data.content.as_bytes().len())
+    };
+    let mut cells = submit
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(index, cell)| (length(cell), "outputs", index))
+        .chain(
+            submit
+                .input_cells
+                .iter()
+                .enumerate()
+                .map(|(index, cell)| (length(cell), "input_cells", index)),
+        )
+        .filter(|(length, _, _)| *length > 0)
+        .collect::<Vec<_>>();
+    cells.sort_by_key(|(length, _, _)| std::cmp::Reverse(*length));
+    for (length, field, index) in cells {
+        let (cell, out_point) = match field {
+            "outputs" => (
+                &mut submit.outputs[index],
+                submit.output_locations.get(index).map(|location| OutPoint {
+                    tx_hash: location.tx_hash.clone(),
+                    index: location.index,
+                }),
+            ),
+            _ => (
+                &mut submit.input_cells[index],
+                submit.inputs.get(index).cloned(),
+            ),
+        };
+        let data = cell.data.take().unwrap();
+        submit.truncated_data.push(TruncatedData {
+            field: field.to_string(),
+            index: (index as u32).into(),
+            out_point,
+            data_hash: data.hash,
+            size: length,
+        });
+        if size(&part_event(event, &submit, None))? <= max_bytes {
+            break;
+        }
+    }
+    Ok(submit)
+}
+
+/// The first part has every field but the cells, the others the header and their cells
+fn split(
+    event: &Event<'_>,
+    mut submit: Submit,
+    max_bytes: usize,
+    size: impl Fn(&Event<'_>) -> io::Result<usize>,
+) -> io::Result<Fitted> {
+    let mut outputs = Cells {
+        cells: std::mem::take(&mut submit.outputs),
+        scripts: std::mem::take(&mut submit.output_scripts),
+        locations: std::mem::take(&mut submit.output_locations),
+        points: Vec::new(),
+    };
+    let mut inputs = Cells {
+        points: std::mem::take(&mut submit.inputs),
+        locations: std::mem::take(&mut submit.input_locations),
+        cells: std::mem::take(&mut submit.input_cells),
+        scripts: std::mem::take(&mut submit.input_scripts),
+    };
+    let dummy = Part {
+        index: u32::MAX,
+        count: u32::MAX,
+        first_output: u32::MAX,
+        first_input: u32::MAX,
+    };
+    let base = size(&part_event(event, &submit, Some(dummy)))?;
+    let budget = max_bytes.saturating_sub(base);
+
+    // the number of outputs and inputs of each part
+    let mut counts = vec![(0, 0)];
+    let mut used = 0;
+    let sizes = (0..outputs.len())
+        .map(|i| outputs.size(i).map(|size| (size, true)))
+        .chain((0..inputs.len()).map(|i| inputs.size(i).map(|size| (size, false))))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (cell_size, output) in sizes {
+        let (part_outputs, part_inputs) = counts.last_mut().unwrap();
+        if used + cell_size > budget && *part_outputs + *part_inputs > 0 {
+            counts.push((0, 0));
+            used = 0;
+        }
+        let (part_outputs, part_inputs) = counts.last_mut().unwrap();
+        if output {
+            *part_outputs += 1;
+        } else {
+            *part_inputs += 1;
+        }
+        used += cell_size;
+    }
+
+    let count = counts.len() as u32;
+    let request_id = submit.request_id.clone();
+    let (mut first_output, mut first_input) = (0, 0);
+    let mut parts = Vec::with_capacity(counts.len());
+    for (index, (part_outputs, part_inputs)) in counts.into_iter().enumerate() {
+        let mut part = match index {
+            0 => std::mem::replace(&mut submit, Submit::new(event.submit.header.clone())),
+            _ => {
+                let mut part = Submit::new(event.submit.header.clone());
+                part.request_id = request_id.clone();
+                part
+            }
+        };
+        let taken = outputs.take(part_outputs);
+        (part.outputs, part.output_scripts, part.output_locations) =
+            (taken.cells, taken.scripts, taken.locations);
+        let taken = inputs.take(part_inputs);
+        (
+            part.inputs,
+            part.input_locations,
+            part.input_cells,
+            part.input_scripts,
+        ) = (taken.points, taken.locations, taken.cells, taken.scripts);
+        parts.push((
+            Some(Part {
+                index: index as u32,
+                count,
+                first_output,
+                first_input,
+            }),
+            part,
+        ));
+        first_output += part_outputs as u32;
+        first_input += part_inputs as u32;
+    }
+    Ok(parts)
+}
+
+fn part_event<'a>(event: &Event<'a>, submit: &'a Submit, part: Option<Part>) -> Event<'a> {
+    Event {
+        seq: event.seq,
+        key: event.key,
+        submit,
+        part,
+    }
+}
+
+/// The cells of one side of an event with the lists in the same order, those a key doesn't
+/// have are empty
+struct Cells {
+    cells: Vec<ckb_jsonrpc_types::CellInfo>,
+    scripts: Vec<crate::script_info::CellScripts>,
+    locations: Vec<crate::location::CellLocation>,
+    points: Vec<OutPoint>,
+}
+
+impl Cells {
+    fn len(&self) -> usize {
+        self.cells.len().max(self.points.len())
+    }
+
+    /// Of the cell's entries in the json, with the commas
+    fn size(&self, i: usize) -> io::Result<usize> {
+        let mut size = 0;
+        if let Some(cell) = self.cells.get(i) {
+            size += serde_json::to_vec(cell)?.len() + 1;
+        }
+        if let Some(scripts) = self.scripts.get(i) {
+            size += serde_json::to_vec(scripts)?.len() + 1;
+        }
+        if let Some(location) = self.locations.get(i) {
+            size += serde_json::to_vec(location)?.len() + 1;
+        }
+        if let Some(point) = self.points.get(i) {
+            size += serde_json::to_vec(point)?.len() + 1;
+        }
+        Ok(size)
+    }
+
+    /// Removes the first `n` cells
+    fn take(&mut self, n: usize) -> Cells {
+        fn front<T>(list: &mut Vec<T>, n: usize) -> Vec<T> {
+            let rest = list.split_off(n.min(list.len()));
+            std::mem::replace(list, rest)
+        }
+        Cells {
+            cells: front(&mut self.cells, n),
+            scripts: front(&mut self.scripts, n),
+            locations: front(&mut self.locations, n),
+            points: front(&mut self.points, n),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    csv::{self, CsvColumn},
+    DataEncoding, Event, Heartbeat, Transform,
+};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StdoutFormat {
+    /// One pretty printed json document per event
+    #[default]
+    Json,
+    /// One json document per line, ready to be piped into `jq`
+    Ndjson,
+    /// One short human readable line per event
+    Compact,
+    /// A comma separated row per matched cell under a header line, see `CsvColumn`
+    Csv,
+    /// As `csv`, separated by tabs
+    Tsv,
+}
+
+pub(crate) fn print_heartbeat(
+    format: StdoutFormat,
+    encoding: DataEncoding,
+    heartbeat: &Heartbeat,
+    body: &impl Serialize,
+) {
+    match format {
+        StdoutFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&encoding.encode(body)).unwrap()
+        ),
+        StdoutFormat::Ndjson => {
+            println!("{}", serde_json::to_string(&encoding.encode(body)).unwrap())
+        }
+        // a table has no row for it
+        StdoutFormat::Csv | StdoutFormat::Tsv => {}
+        StdoutFormat::Compact => println!(
+            "{} tip {} {:#x} key: {:#x} {:?}",
+            heartbeat.kind,
+            heartbeat.tip.block_number.value(),
+            heartbeat.tip.block_hash,
+            heartbeat.key.script.code_hash,
+            heartbeat.key.script_type,
+        ),
+    }
+}
+
+/// The compact line shows the key's args in hex whatever the encoding, it and the tables aren't transformed
+pub(crate) fn print(
+    format: StdoutFormat,
+    encoding: DataEncoding,
+    transform: Option<&Transform>,
+    columns: Option<&[CsvColumn]>,
+    event: &Event,
+) {
+    let transformed = transform
+        .filter(|_| matches!(format, StdoutFormat::Json | StdoutFormat::Ndjson))
+        .map(|transform| transform.apply(&serde_json::to_value(event).unwrap()));
+    match (format, transformed) {
+        (StdoutFormat::Json, Some(transformed)) => println!(
+            "{}",
+            serde_json::to_string_pretty(&encoding.encode(&transformed)).unwrap()
+        ),
+        (StdoutFormat::Ndjson, Some(transformed)) => println!(
+            "{}",
+            serde_json::to_string(&encoding.encode(&transformed)).unwrap()
+        ),
+        (StdoutFormat::Json, None) => println!(
+            "{}",
+            serde_json::to_string_pretty(&encoding.encode(event)).unwrap()
+        ),
+        (StdoutFormat::Ndjson, None) => println!(
+            "{}",
+            serde_json::to_string(&encoding.encode(event)).unwrap()
+        ),
+        (StdoutFormat::Csv, _) => csv::print(',', columns, encoding, event),
+        (StdoutFormat::Tsv, _) => csv::print('\t', columns, encoding, event),
+        (StdoutFormat::Compact, _) => {
+            let capacity: u64 = event
+                .submit
+                .outputs
+                .iter()
+                .map(|cell| cell.output.capacity.value())
+                .sum();
+            println!(
+                "block {} {:#x} inputs: {} outputs: {} capacity: {} key: {:#x} {:?} 0x{}",
+                event.submit.header.inner.number.value(),
+                event.submit.header.hash,
+                event.submit.inputs.len(),
+                event.submit.outputs.len(),
+                capacity,
+                event.key.script.code_hash,
+                event.key.script_type,
+                event
+                    .key
+                    .script
+                    .args
+                    .as_bytes()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>(),
+            )
+        }
+    }
+}
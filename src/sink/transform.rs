@@ -0,0 +1,264 @@
+use bech32::{ToBase32, Variant};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+
+/// Reshapes the json of an event before it is sent to a sink. Every field of the object is set to the
+/// result of its expression on the event, or to the object of nested expressions, e.g.
+/// `{"block": ".block.number", "locks": ".payload.outputs[].output.lock | address"}`
+#[derive(Serialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Transform(BTreeMap<String, Field>);
+
+#[derive(Serialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(untagged)]
+enum Field {
+    Expr(Expr),
+    Object(BTreeMap<String, Field>),
+}
+
+/// A path into the event followed by functions, `.a.b[0]` selects, `[]` maps the rest of the path
+/// and the functions over an array. A missing field is null
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct Expr {
+    source: String,
+    path: Vec<Step>,
+    functions: Vec<Function>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum Step {
+    Field(String),
+    Index(usize),
+    Each,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum Function {
+    /// Mainnet full address of a script
+    Address,
+    TestnetAddress,
+    /// Of an array, an object or a string
+    Length,
+}
+
+impl Transform {
+    pub fn apply(&self, event: &Value) -> Value {
+        apply(&self.0, event)
+    }
+}
+
+fn apply(fields: &BTreeMap<String, Field>, event: &Value) -> Value {
+    Value::Object(
+        fields
+            .iter()
+            .map(|(name, field)| {
+                let value = match field {
+                    Field::Expr(expr) => expr.eval(&expr.path, event),
+                    Field::Object(fields) => apply(fields, event),
+                };
+                (name.clone(), value)
+            })
+            .collect(),
+    )
+}
+
+impl Expr {
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = source.split('|').map(str::trim);
+        let path = parse_path(parts.next().unwrap_or_default())?;
+        let functions = parts
+            .map(|name| match name {
+                "address" => Ok(Function::Address),
+                "testnet_address" => Ok(Function::TestnetAddress),
+                "length" => Ok(Function::Length),
+                _ => Err(format!("unknown function {:?} in {:?}", name, source)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Expr {
+            source: source.to_string(),
+            path,
+            functions,
+        })
+    }
+
+    fn eval(&self, path: &[Step], value: &Value) -> Value {
+        match path.split_first() {
+            None => self
+                .functions
+                .iter()
+                .fold(value.clone(), |value, function| function.call(&value)),
+            Some((Step::Field(name), rest)) => {
+                self.eval(rest, value.get(name).unwrap_or(&Value::Null))
+            }
+            Some((Step::Index(index), rest)) => {
+                self.eval(rest, value.get(index).unwrap_or(&Value::Null))
+            }
+            Some((Step::Each, rest)) => match value {
+                Value::Array(items) => {
+                    Value::Array(items.iter().map(|item| self.eval(rest, item)).collect())
+                }
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Step>, String> {
+    let invalid = || format!("invalid path {:?}", path);
+    let mut rest = path.strip_prefix('.').ok_or_else(invalid)?;
+    let mut steps = Vec::new();
+    // `.` alone is the whole event, `.[0]` and `.a` start with a step
+    while !rest.is_empty() {
+        if let Some(index) = rest.strip_prefix('[') {
+            let (index, after) = index.split_once(']').ok_or_else(invalid)?;
+            steps.push(match index {
+                "" => Step::Each,
+                index => Step::Index(index.parse().map_err(|_| invalid())?),
+            });
+            rest = after;
+        } else {
+            let name = rest.strip_prefix('.').unwrap_or(rest);
+            if !steps.is_empty() && name.len() == rest.len() {
+                return Err(invalid());
+            }
+            let end = name
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(name.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            steps.push(Step::Field(name[..end].to_string()));
+            rest = &name[end..];
+        }
+    }
+    Ok(steps)
+}
+
+impl Function {
+    fn call(self, value: &Value) -> Value {
+        match self {
+            Function::Address => address("ckb", value).map_or(Value::Null, Value::String),
+            Function::TestnetAddress => address("ckt", value).map_or(Value::Null, Value::String),
+            Function::Length => match value {
+                Value::Array(items) => items.len().into(),
+                Value::Object(fields) => fields.len().into(),
+                Value::String(s) => s.len().into(),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+/// The full format address of a script's json, none for anything else
+fn address(hrp: &str, script: &Value) -> Option<String> {
+    let script: ckb_jsonrpc_types::Script = serde_json::from_value(script.clone()).ok()?;
+    let hash_type = match script.hash_type {
+        ckb_jsonrpc_types::ScriptHashType::Data => 0,
+        ckb_jsonrpc_types::ScriptHashType::Type => 1,
+        ckb_jsonrpc_types::ScriptHashType::Data1 => 2,
+    };
+    let mut payload = vec![0x00];
+    payload.extend_from_slice(script.code_hash.as_bytes());
+    payload.push(hash_type);
+    payload.extend_from_slice(script.args.as_bytes());
+    bech32::encode(hrp, payload.to_base32(), Variant::Bech32m).ok()
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Transform {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        fn fields(value: Value) -> Result<BTreeMap<String, Field>, String> {
+            match value {
+                Value::Object(object) => object
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let field = match value {
+                            Value::String(source) => Field::Expr(Expr::parse(&source)?),
+                            object @ Value::Object(_) => Field::Object(fields(object)?),
+                            _ => {
+                                return Err(format!(
+                                    "{} is neither an expression nor an object",
+                                    name
+                                ))
+                            }
+                        };
+                        Ok((name, field))
+                    })
+                    .collect(),
+                _ => Err("a transform is an object".to_string()),
+            }
+        }
+        fields(Value::deserialize(deserializer)?)
+            .map(Transform)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(template: Value) -> Result<Transform, serde_json::Error> {
+        serde_json::from_value(template)
+    }
+
+    #[test]
+    fn selects_maps_and_formats_addresses() {
+        let event = serde_json::json!({
+            "block": {"number": "0x5"},
+            "payload": {"outputs": [{"output": {"lock": {
+                "code_hash": "0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8",
+                "hash_type": "type",
+                "args": "0xb39bbc0b3673c7d36450bc14cfcdad2d559c6c64",
+            }}}, {"output": {}}]},
+        });
+        let transform = transform(serde_json::json!({
+            "number": ".block.number",
+            "missing": ".block.hash",
+            "cells": {
+                "count": ".payload.outputs | length",
+                "first": ".payload.outputs[0].output.lock.args",
+                "addresses": ".payload.outputs[].output.lock | address",
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            transform.apply(&event),
+            serde_json::json!({
+                "number": "0x5",
+                "missing": null,
+                "cells": {
+                    "count": 2,
+                    "first": "0xb39bbc0b3673c7d36450bc14cfcdad2d559c6c64",
+                    "addresses": [
+                        "ckb1qzda0cr08m85hc8jlnfp3zer7xulejywt49kt2rr0vthywaa50xwsqdnnw7qkdnnclfkg59uzn8umtfd2kwxceqxwquc4",
+                        null,
+                    ],
+                },
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(&transform).unwrap()["cells"]["count"],
+            ".payload.outputs | length"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for source in ["block", ".a..b", ".a[x]", ".a[0]b", ".a | hex"] {
+            assert!(
+                transform(serde_json::json!({ "f": source })).is_err(),
+                "{}",
+                source
+            );
+        }
+        assert!(transform(serde_json::json!({ "f": 1 })).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, net::UnixStream, sync::broadcast};
+
+use std::{
+    collections::VecDeque,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::Compression;
+use crate::{metrics, rpc_http};
+
+/// Frames buffered per connected client before it starts missing events
+const CLIENT_BUFFER: usize = 1024;
+
+/// Streams events to every client connected to a unix domain socket.
+///
+/// Each event is written as a big-endian `u32` byte length followed by the json payload,
+/// compressed with the configured codec.
+#[derive(Clone)]
+pub(crate) struct UnixSocketSink {
+    tx: broadcast::Sender<Arc<Vec<u8>>>,
+    compression: Compression,
+    /// Frames sent so far
+    sent: Arc<AtomicU64>,
+    /// Frames sent so far as of each client's last read, skipped ones count as read
+    clients: Arc<Mutex<Vec<Arc<AtomicU64>>>>,
+    /// Byte lengths of the last frames the channel can hold, newest last
+    sizes: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl UnixSocketSink {
+    pub fn bind<P: AsRef<Path>>(path: P, compression: Compression) -> io::Result<Self> {
+        let listener = rpc_http::bind_unix(path)?;
+        let (tx, _) = broadcast::channel(CLIENT_BUFFER);
+
+        let (sent, clients) = (
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(Mutex::new(Vec::new())),
+        );
+
+        let (accept_tx, accept_sent, accept_clients) = (tx.clone(), sent.clone(), clients.clone());
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let rx = accept_tx.subscribe();
+                        let read = Arc::new(AtomicU64::new(accept_sent.load(Ordering::Acquire)));
+                        accept_clients.lock().unwrap().push(read.clone());
+                        metrics::spawn("unix_socket_client", serve_client(stream, rx, read));
+                    }
+                    Err(e) => log::warn!("Failed to accept unix socket client, error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(UnixSocketSink {
+            tx,
+            compression,
+            sent,
+            clients,
+            sizes: Arc::new(Mutex::new(VecDeque::with_capacity(CLIENT_BUFFER))),
+        })
+    }
+
+    /// Frames buffered for each connected client
+    pub fn queued(&self) -> Vec<u64> {
+        let sent = self.sent.load(Ordering::Acquire);
+        let mut clients = self.clients.lock().unwrap();
+        // a served client holds the other reference
+        clients.retain(|read| Arc::strong_count(read) > 1);
+        clients
+            .iter()
+            .map(|read| sent.saturating_sub(read.load(Ordering::Acquire)))
+            .map(|queued| queued.min(CLIENT_BUFFER as u64))
+            .collect()
+    }
+
+    /// Bytes of the newest `queued` frames, the ones a client this far behind keeps buffered
+    pub fn buffered_bytes(&self, queued: u64) -> usize {
+        let sizes = self.sizes.lock().unwrap();
+        sizes.iter().rev().take(queued as usize).sum()
+    }
+
+    /// Events and heartbeats, every client gets the frame unless it lags behind
+    pub fn send(&self, event: &impl Serialize) {
+        let payload = match self
+            .compression
+            .compress(serde_json::to_vec(event).unwrap())
+        {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to compress unix socket event, error: {:?}", e);
+                return;
+            }
+        };
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+
+        {
+            let mut sizes = self.sizes.lock().unwrap();
+            if sizes.len() == CLIENT_BUFFER {
+                sizes.pop_front();
+            }
+            sizes.push_back(frame.len());
+        }
+        // no connected client is not an error, the event is simply not streamed
+        let _ignore = self.tx.send(Arc::new(frame));
+        self.sent.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+async fn serve_client(
+    mut stream: UnixStream,
+    mut rx: broadcast::Receiver<Arc<Vec<u8>>>,
+    read: Arc<AtomicU64>,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(frame) => {
+                read.fetch_add(1, Ordering::AcqRel);
+                if let Err(e) = stream.write_all(&frame).await {
+                    log::info!("Unix socket client disconnected, error: {:?}", e);
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                read.fetch_add(n, Ordering::AcqRel);
+                log::warn!("Unix socket client too slow, skipped {} events", n)
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::Serialize;
+use sha2::Sha256;
+
+use std::{io, time::Duration};
+
+use super::{BodyFormat, Compression};
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body when a secret is set
+pub const SIGNATURE_HEADER: &str = "X-Emitter-Signature";
+
+pub(crate) async fn post(
+    client: &Client,
+    url: &str,
+    secret: Option<&str>,
+    timeout: Option<Duration>,
+    compression: Compression,
+    format: BodyFormat,
+    event: &impl Serialize,
+) -> io::Result<()> {
+    let body = compression.compress(format.encode(event)?)?;
+
+    let mut req = client.post(url).header(
+        reqwest::header::CONTENT_TYPE,
+        format.serializer().content_type(),
+    );
+    if let Some(encoding) = compression.content_encoding() {
+        req = req.header(reqwest::header::CONTENT_ENCODING, encoding);
+    }
+    if let Some(timeout) = timeout {
+        req = req.timeout(timeout);
+    }
+    if let Some(secret) = secret {
+        req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+    }
+
+    let resp = req
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, format!("{:?}", e)))?;
+
+    if !resp.status().is_success() {
+        return Err(io::Error::other(WebhookStatus {
+            url: url.to_string(),
+            status: resp.status().as_u16(),
+        }));
+    }
+    Ok(())
+}
+
+/// A webhook answered with an unsuccessful status
+#[derive(Debug)]
+pub struct WebhookStatus {
+    pub url: String,
+    pub status: u16,
+}
+
+impl WebhookStatus {
+    /// The status `e` was returned for
+    pub fn of(e: &io::Error) -> Option<u16> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<WebhookStatus>())
+            .map(|status| status.status)
+    }
+}
+
+impl std::fmt::Display for WebhookStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "webhook {} responded with {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for WebhookStatus {}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    faster_hex::hex_string(&mac.finalize().into_bytes())
+}
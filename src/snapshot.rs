@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{read, rename, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    cipher::Cipher,
+    global_state::Registration,
+    rpc_server::RpcSearchKey,
+    storage::{Cursor, Storage},
+};
+
+/// Bumped on every incompatible change of the snapshot layout
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Backend independent copy of the registrations with their scan tips and the consumer cursors
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub registrations: Vec<(RpcSearchKey, Registration)>,
+    pub cursors: Vec<SnapshotCursor>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotCursor {
+    pub consumer_id: String,
+    pub key: RpcSearchKey,
+    pub seq: u64,
+}
+
+impl Snapshot {
+    pub fn new(registrations: Vec<(RpcSearchKey, Registration)>, cursors: Vec<Cursor>) -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            registrations,
+            cursors: cursors
+                .into_iter()
+                .map(|(consumer_id, key, seq)| SnapshotCursor {
+                    consumer_id,
+                    key,
+                    seq,
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes a snapshot of a store which isn't served by a running instance
+    pub(crate) fn take(storage: &dyn Storage) -> io::Result<Self> {
+        Ok(Snapshot::new(
+            storage.load_registrations()?,
+            storage.load_cursors()?,
+        ))
+    }
+
+    /// Writes the snapshot into a fresh store, refuses to overwrite existing registrations
+    pub(crate) fn restore(self, storage: &dyn Storage) -> io::Result<()> {
+        if !storage.load_registrations()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "snapshots can only be restored into an empty store",
+            ));
+        }
+        let cursors = self
+            .cursors
+            .into_iter()
+            .map(|c| (c.consumer_id, c.key, c.seq))
+            .collect::<Vec<_>>();
+        storage.save_registrations(&self.registrations)?;
+        storage.save_cursors(&cursors)
+    }
+
+    /// Writes the snapshot as json, sealed with `cipher` if given
+    pub(crate) fn write<P: AsRef<Path>>(&self, path: P, cipher: Option<&Cipher>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        let mut content = serde_json::to_vec(self)?;
+        if let Some(cipher) = cipher {
+            content = cipher.seal(&content)?;
+        }
+        file.write_all(&content)?;
+        file.sync_all()?;
+        rename(tmp, path)
+    }
+
+    /// Reads a snapshot sealed with `cipher`, or a plain json one so a plain store can be imported into an
+    /// encrypted one
+    pub(crate) fn read<P: AsRef<Path>>(path: P, cipher: Option<&Cipher>) -> io::Result<Self> {
+        let content = read(path)?;
+        let snapshot: Snapshot = match (cipher, serde_json::from_slice(&content)) {
+            (_, Ok(snapshot)) => snapshot,
+            (Some(cipher), Err(_)) => serde_json::from_slice(&cipher.open(&content)?)?,
+            (None, Err(err)) => return Err(err.into()),
+        };
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported snapshot version {}, expected {}",
+                    snapshot.version, SNAPSHOT_VERSION
+                ),
+            ));
+        }
+        Ok(snapshot)
+    }
+}
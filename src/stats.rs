@@ -0,0 +1,74 @@
+//! Counters of what each registration emitted, persisted after every submit, for the `stats` rpc
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+use crate::{summary::transaction_hashes, Submit};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Logged events, stats kept before the count have only the later ones
+    #[serde(default)]
+    pub events: u64,
+    /// Inputs and outputs of the logged events
+    pub cells: u64,
+    /// With matched cells
+    pub transactions: u64,
+    /// Of the json events, once per sink they were delivered to
+    pub bytes_delivered: u64,
+    /// By the UTC day of the blocks, `YYYY-MM-DD`
+    pub days: BTreeMap<String, DayStats>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DayStats {
+    pub cells: u64,
+    pub transactions: u64,
+}
+
+/// The counts of an event, taken before its cells may be stripped
+#[derive(Clone)]
+pub(crate) struct Emitted {
+    day: String,
+    cells: u64,
+    transactions: u64,
+}
+
+impl Emitted {
+    pub fn new(submit: &Submit) -> Self {
+        Emitted {
+            day: date(submit.header.inner.timestamp.value()),
+            cells: (submit.inputs.len() + submit.outputs.len()) as u64,
+            transactions: transaction_hashes(submit).len() as u64,
+        }
+    }
+}
+
+impl Stats {
+    pub fn count(&mut self, emitted: Emitted) {
+        self.events += 1;
+        self.cells += emitted.cells;
+        self.transactions += emitted.transactions;
+        let day = self.days.entry(emitted.day).or_default();
+        day.cells += emitted.cells;
+        day.transactions += emitted.transactions;
+    }
+}
+
+/// The UTC date of a unix timestamp in milliseconds
+fn date(timestamp_ms: u64) -> String {
+    // days to the proleptic gregorian calendar, counting 400 year eras from 0000-03-01
+    let days = (timestamp_ms / 86_400_000) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
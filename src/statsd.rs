@@ -0,0 +1,208 @@
+//! Sends the metrics served on `/metrics` to a statsd agent over udp, for stacks without prometheus
+
+use tokio::net::{lookup_host, UdpSocket};
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    config::StatsdSettings,
+    lifecycle::DebugTasks,
+    metrics::{self, Labels, Sample},
+};
+
+/// udp payloads are kept under a common mtu
+const MAX_DATAGRAM: usize = 1400;
+
+/// The values last sent by metric name, suffix and labels
+type Sent = HashMap<(&'static str, &'static str, Labels), f64>;
+
+/// Each interval sends the counters as the increase since the last one, the gauges as they are and
+/// the histograms as the increase of `_count` and `_sum`
+pub(crate) async fn run(settings: StatsdSettings, tasks: DebugTasks) -> io::Result<()> {
+    let address = match &settings.address {
+        Some(address) => address.clone(),
+        None => return Ok(()),
+    };
+    let target = lookup_host(&address).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("statsd address {} doesn't resolve", address),
+        )
+    })?;
+    // bound in the family of the agent, an ipv6 one isn't reachable from an ipv4 socket
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    log::info!("send statsd metrics to {}", address);
+
+    let mut sent = Sent::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.interval_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        tasks.sample_metrics();
+        let mut lines = Vec::new();
+        for (name, labels, sample) in metrics::snapshot() {
+            match sample {
+                Sample::Counter(value) => {
+                    let delta = increase(&mut sent, name, "", &labels, value as f64);
+                    lines.push(line(&settings, name, "", &labels, delta, "c"));
+                }
+                Sample::Gauge(value) => lines.push(line(&settings, name, "", &labels, value, "g")),
+                Sample::Histogram { sum, count } => {
+                    let count = increase(&mut sent, name, "_count", &labels, count as f64);
+                    lines.push(line(&settings, name, "_count", &labels, count, "c"));
+                    let sum = increase(&mut sent, name, "_sum", &labels, sum);
+                    lines.push(line(&settings, name, "_sum", &labels, sum, "c"));
+                }
+            }
+        }
+        for datagram in datagrams(&lines) {
+            if let Err(e) = socket.send(datagram.as_bytes()).await {
+                log::warn!("Failed to send statsd metrics, error: {:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Since the last interval, a counter which went back was reset and counts from zero
+fn increase(
+    sent: &mut Sent,
+    name: &'static str,
+    suffix: &'static str,
+    labels: &Labels,
+    value: f64,
+) -> f64 {
+    let last = sent
+        .insert((name, suffix, labels.clone()), value)
+        .unwrap_or(0.0);
+    if value >= last {
+        value - last
+    } else {
+        value
+    }
+}
+
+fn line(
+    settings: &StatsdSettings,
+    name: &str,
+    suffix: &str,
+    labels: &Labels,
+    value: f64,
+    kind: &str,
+) -> String {
+    if settings.dogstatsd {
+        let tags = labels
+            .iter()
+            // a comma starts the next tag and a pipe the next field
+            .map(|(label, value)| {
+                let value = value.replace(|c: char| c == ',' || c == '|' || c.is_whitespace(), "_");
+                format!("{}:{}", label, value)
+            })
+            .collect::<Vec<_>>();
+        let tags = match tags.is_empty() {
+            true => String::new(),
+            false => format!("|#{}", tags.join(",")),
+        };
+        format!(
+            "{}{}{}:{}|{}{}",
+            settings.prefix, name, suffix, value, kind, tags
+        )
+    } else {
+        let mut name = format!("{}{}{}", settings.prefix, name, suffix);
+        for (_, value) in labels {
+            name.push('.');
+            name.extend(value.chars().map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            }));
+        }
+        format!("{}:{}|{}", name, value, kind)
+    }
+}
+
+/// Lines joined by newlines into datagrams of up to `MAX_DATAGRAM` bytes
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(dogstatsd: bool) -> StatsdSettings {
+        StatsdSettings {
+            prefix: "ckb.".to_string(),
+            dogstatsd,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn labels_are_tags_or_name_parts() {
+        let labels = vec![
+            ("method", "get_tip".to_string()),
+            ("key", "a,b|c d".to_string()),
+        ];
+        assert_eq!(
+            line(&settings(true), "calls", "_count", &labels, 2.0, "c"),
+            "ckb.calls_count:2|c|#method:get_tip,key:a_b_c_d"
+        );
+        assert_eq!(
+            line(&settings(false), "calls", "_count", &labels, 2.0, "c"),
+            "ckb.calls_count.get_tip.a_b_c_d:2|c"
+        );
+        assert_eq!(
+            line(&settings(true), "up", "", &vec![], 1.0, "g"),
+            "ckb.up:1|g"
+        );
+    }
+
+    #[test]
+    fn lines_are_split_into_datagrams() {
+        let lines = vec!["a".repeat(1000), "b".repeat(300), "c".repeat(200)];
+        assert!(datagrams(&[]).is_empty());
+        let split = datagrams(&lines);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0], format!("{}\n{}", lines[0], lines[1]));
+        assert_eq!(split[1], lines[2]);
+    }
+
+    #[test]
+    fn increase_is_kept_per_suffix_and_restarts_on_reset() {
+        let mut sent = Sent::new();
+        let labels = vec![("method", "get_tip".to_string())];
+        assert_eq!(increase(&mut sent, "calls", "_count", &labels, 3.0), 3.0);
+        assert_eq!(increase(&mut sent, "calls", "_sum", &labels, 0.5), 0.5);
+        assert_eq!(increase(&mut sent, "calls", "_count", &labels, 5.0), 2.0);
+        assert_eq!(increase(&mut sent, "calls", "_sum", &labels, 1.5), 1.0);
+        // the counter went back, the process restarted its count
+        assert_eq!(increase(&mut sent, "calls", "_count", &labels, 1.0), 1.0);
+    }
+}
@@ -0,0 +1,144 @@
+//! `GET /status`, one json overview of the emitter for ops dashboards and TUIs, made of the
+//! answers of the rpc methods, the metrics and the recent log
+
+use jsonrpsee::core::server::rpc_module::Methods;
+use serde_json::{json, Map, Value};
+
+use std::collections::BTreeMap;
+
+use crate::{
+    logging,
+    metrics::{self, Sample},
+    rpc_http::local_call,
+    rpc_server::RpcSearchKey,
+    sink::SinkConfig,
+};
+
+/// The node, every registration with its lag and health, every sink and the recent errors
+pub(crate) async fn overview(methods: &Methods) -> Value {
+    let server = local_call(methods, "server_info", json!([])).await.ok();
+    let tip = local_call(methods, "get_tip", json!([])).await;
+    let tip_number = tip.as_ref().ok().and_then(|tip| hex(&tip["block_number"]));
+    let node = match &tip {
+        Ok(tip) => json!({ "reachable": true, "tip": tip, "error": null }),
+        Err(e) => json!({ "reachable": false, "tip": null, "error": e }),
+    };
+
+    let info = local_call(methods, "info", json!([])).await;
+    let entries = info.as_ref().ok().and_then(Value::as_array).cloned();
+    let backlog = local_call(methods, "backlog", json!([]))
+        .await
+        .ok()
+        .and_then(|backlog| backlog.as_array().cloned())
+        .unwrap_or_default();
+
+    let mut sinks = BTreeMap::<String, Map<String, Value>>::new();
+    let mut registrations = Vec::new();
+    let mut health_counts = BTreeMap::<&str, u64>::new();
+    for entry in entries.iter().flatten() {
+        let (key, registration) = (&entry[0], &entry[1]);
+        let id = serde_json::from_value::<RpcSearchKey>(key.clone())
+            .map(|key| key.id())
+            .ok();
+        let scan_tip = hex(&registration["block_number"]);
+        let health = health(registration);
+        *health_counts.entry(health).or_default() += 1;
+        let queued = backlog.iter().find(|backlog| backlog["key"] == *key);
+
+        let mut registration_sinks = Vec::new();
+        let subscribers = registration["subscribers"].as_array().into_iter().flatten();
+        for sink in std::iter::once(&registration["sink"]).chain(subscribers.map(|s| &s["sink"])) {
+            if let Ok(sink) = serde_json::from_value::<SinkConfig>(sink.clone()) {
+                let sink_id = sink.id();
+                let entry = sinks.entry(sink_id.clone()).or_default();
+                entry.insert("sink".to_string(), json!(sink_id));
+                let used_by = entry.entry("registrations").or_insert(json!(0));
+                *used_by = json!(used_by.as_u64().unwrap_or_default() + 1);
+                registration_sinks.push(sink_id);
+            }
+        }
+        registrations.push(json!({
+            "id": id,
+            "search_key": key,
+            "label": registration["label"],
+            "client": registration["client"],
+            "health": health,
+            "status": registration["status"],
+            "scan_tip": scan_tip,
+            "lag_blocks": tip_number.zip(scan_tip).map(|(tip, scan)| tip.saturating_sub(scan)),
+            "sync_percentage": registration["sync_percentage"],
+            "secs_since_advance": registration["secs_since_advance"],
+            "scan_failure": registration["scan_failure"],
+            "sinks": registration_sinks,
+            "pending_events": queued.map_or(json!(0), |queued| queued["pending_events"].clone()),
+            "retry_queues": queued.map_or(json!({}), |queued| queued["retry_queues"].clone()),
+        }));
+    }
+
+    let mut failures = BTreeMap::<String, u64>::new();
+    for (name, labels, sample) in metrics::snapshot() {
+        let sink = labels.iter().find(|(label, _)| *label == "sink");
+        match (name, sink, sample) {
+            ("emitter_sink_circuit_open", Some((_, sink)), Sample::Gauge(open)) => {
+                let entry = sinks.entry(sink.clone()).or_default();
+                entry.insert("sink".to_string(), json!(sink));
+                entry.insert("circuit_open".to_string(), json!(open > 0.0));
+            }
+            ("emitter_delivery_failures_total", Some((_, sink)), Sample::Counter(count)) => {
+                *failures.entry(sink.clone()).or_default() += count;
+            }
+            _ => {}
+        }
+    }
+    let sinks = sinks
+        .into_iter()
+        .map(|(id, mut sink)| {
+            sink.entry("registrations").or_insert(json!(0));
+            sink.entry("circuit_open").or_insert(json!(false));
+            sink.insert(
+                "delivery_failures".to_string(),
+                json!(failures.get(&id).copied().unwrap_or_default()),
+            );
+            Value::Object(sink)
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "version": server.as_ref().map(|server| server["version"].clone()),
+        "uptime_secs": entries
+            .iter()
+            .flatten()
+            .next()
+            .map(|entry| entry[1]["uptime_secs"].clone()),
+        "node": node,
+        "summary": {
+            "registrations": registrations.len(),
+            "health": health_counts,
+        },
+        "registrations": registrations,
+        "registrations_error": info.err(),
+        "sinks": sinks,
+        "recent_errors": logging::recent_errors(),
+    })
+}
+
+/// `failing` while scans fail or once the task panicked, then its completion, wait or lag status
+fn health(registration: &Value) -> &'static str {
+    if !registration["scan_failure"].is_null() || !registration["scan_panic"].is_null() {
+        "failing"
+    } else if !registration["completed_at"].is_null() {
+        "completed"
+    } else if registration["pending"] == true {
+        "pending"
+    } else {
+        match registration["status"].as_str() {
+            Some("degraded") => "degraded",
+            Some("paused") => "paused",
+            _ => "ok",
+        }
+    }
+}
+
+fn hex(value: &Value) -> Option<u64> {
+    u64::from_str_radix(value.as_str()?.trim_start_matches("0x"), 16).ok()
+}
@@ -0,0 +1,558 @@
+//! Behaviour every storage backend has to share, run against each compiled in backend
+
+use ckb_jsonrpc_types::{JsonBytes, Script};
+use ckb_types::{
+    core::{EpochNumberWithFraction, HeaderBuilder},
+    prelude::*,
+    H256,
+};
+
+use std::collections::HashSet;
+
+use super::*;
+use crate::{
+    cipher::Cipher,
+    global_state::Status,
+    lineage::{CellLink, LinkKind, TxRef},
+    live_cells::LiveCell,
+    rpc_client::{CellType, ScriptType},
+    scheduler::Priority,
+    sink::{DeliveryCursor, EmittedCell, SinkConfig},
+    stats::{Emitted, Stats},
+    ScanTip,
+};
+
+pub(crate) fn key(args: u8) -> RpcSearchKey {
+    RpcSearchKey {
+        script: Script {
+            code_hash: H256::default(),
+            hash_type: ckb_jsonrpc_types::ScriptHashType::Type,
+            args: JsonBytes::from_vec(vec![args]),
+        },
+        script_type: ScriptType::Lock,
+        filter: None,
+        with_data: None,
+        with_uncles: None,
+        with_epochs: None,
+        with_witnesses: None,
+        with_input_cells: None,
+        track_lineage: None,
+        track_live_cells: None,
+        discover: None,
+        correlate: None,
+        summary: None,
+        transactions_only: None,
+        namespace: None,
+    }
+}
+
+fn tip(number: u64) -> IndexerTip {
+    IndexerTip {
+        block_hash: H256::from([number as u8; 32]),
+        block_number: number.into(),
+    }
+}
+
+pub(crate) fn submit(number: u64) -> Submit {
+    Submit::new(
+        HeaderBuilder::default()
+            .number(number.pack())
+            .compact_target(1u32.pack())
+            .epoch(EpochNumberWithFraction::new(0, 0, 1).pack())
+            .build()
+            .into(),
+    )
+}
+
+fn registration(number: u64) -> Registration {
+    Registration {
+        scan_tip: ScanTip::new(tip(number)),
+        sink: SinkConfig::default(),
+        sink_profile: None,
+        label: None,
+        client: None,
+        scan_failure: None,
+        scan_panic: None,
+        lag_alert: None,
+        status: Status::Ok,
+        priority: Priority::Normal,
+        scan_parallelism: None,
+        max_blocks_per_second: None,
+        activate_at: None,
+        complete_after: None,
+        end_block: None,
+        registered_at: None,
+        request_id: None,
+        completed_at: None,
+        spilled_from: None,
+        subscribers: Vec::new(),
+    }
+}
+
+fn tips(storage: &dyn Storage) -> Vec<(RpcSearchKey, u64)> {
+    let mut res: Vec<_> = storage
+        .load_registrations()
+        .unwrap()
+        .into_iter()
+        .map(|(key, registration)| (key, registration.scan_tip.load().block_number.value()))
+        .collect();
+    res.sort_by_key(|(key, _)| key.script.args.as_bytes().to_vec());
+    res
+}
+
+fn seqs(events: &[LoggedEvent]) -> Vec<u64> {
+    events.iter().map(|e| e.seq).collect()
+}
+
+fn registrations(storage: &dyn Storage) {
+    assert!(storage.load_registrations().unwrap().is_empty());
+
+    storage
+        .save_registrations(&[(key(1), registration(10)), (key(2), registration(20))])
+        .unwrap();
+    assert_eq!(tips(storage), vec![(key(1), 10), (key(2), 20)]);
+
+    // saving replaces the whole set
+    storage
+        .save_registrations(&[(key(2), registration(21))])
+        .unwrap();
+    assert_eq!(tips(storage), vec![(key(2), 21)]);
+
+    storage.store_scan_tip(&key(2), &tip(30)).unwrap();
+    assert_eq!(tips(storage), vec![(key(2), 30)]);
+}
+
+fn events(storage: &dyn Storage) {
+    assert!(storage
+        .read_events(0, u64::MAX, usize::MAX, &|_| true)
+        .unwrap()
+        .is_empty());
+
+    let first = storage
+        .append_events(&key(1), vec![submit(5), submit(10)])
+        .unwrap();
+    assert_eq!(seqs(&first), vec![0, 1]);
+    let second = storage
+        .append_events_at(&key(2), vec![submit(15)], &tip(40))
+        .unwrap();
+    assert_eq!(seqs(&second), vec![2]);
+    assert_eq!(tips(storage), vec![(key(2), 40)]);
+    assert!(storage
+        .append_events(&key(1), Vec::new())
+        .unwrap()
+        .is_empty());
+
+    let all = storage
+        .read_events(0, u64::MAX, usize::MAX, &|_| true)
+        .unwrap();
+    assert_eq!(seqs(&all), vec![0, 1, 2]);
+    assert_eq!(all[1].key, key(1));
+    assert_eq!(all[1].submit.header.inner.number.value(), 10);
+    assert_eq!(all[2].key, key(2));
+
+    let range = storage.read_events(1, 2, usize::MAX, &|_| true).unwrap();
+    assert_eq!(seqs(&range), vec![1, 2]);
+    let limited = storage.read_events(0, u64::MAX, 1, &|_| true).unwrap();
+    assert_eq!(seqs(&limited), vec![0]);
+    let filtered = storage
+        .read_events(0, u64::MAX, usize::MAX, &|e| e.key == key(2))
+        .unwrap();
+    assert_eq!(seqs(&filtered), vec![2]);
+    assert!(storage
+        .read_events(3, u64::MAX, usize::MAX, &|_| true)
+        .unwrap()
+        .is_empty());
+}
+
+fn cursors(storage: &dyn Storage) {
+    assert!(storage.load_cursors().unwrap().is_empty());
+
+    storage
+        .save_cursors(&[("a".to_string(), key(1), 3), ("b".to_string(), key(1), 5)])
+        .unwrap();
+    storage
+        .save_cursors(&[("a".to_string(), key(1), 4), ("b".to_string(), key(1), 5)])
+        .unwrap();
+
+    let loaded: HashSet<_> = storage.load_cursors().unwrap().into_iter().collect();
+    let expected: HashSet<_> = [("a".to_string(), key(1), 4), ("b".to_string(), key(1), 5)]
+        .into_iter()
+        .collect();
+    assert_eq!(loaded, expected);
+
+    // saving replaces the whole set, a dropped consumer's cursor is gone
+    storage
+        .save_cursors(&[("a".to_string(), key(1), 4)])
+        .unwrap();
+    assert_eq!(
+        storage.load_cursors().unwrap(),
+        vec![("a".to_string(), key(1), 4)]
+    );
+}
+
+fn delivery_cursors(storage: &dyn Storage) {
+    assert_eq!(
+        storage.load_delivery_cursor(&key(1), "stdout").unwrap(),
+        None
+    );
+
+    let cursor = |block_number, index| DeliveryCursor {
+        block_number,
+        index,
+    };
+    storage
+        .store_delivery_cursor(&key(1), "stdout", cursor(10, 0))
+        .unwrap();
+    storage
+        .store_delivery_cursor(&key(1), "stdout", cursor(15, 1))
+        .unwrap();
+    storage
+        .store_delivery_cursor(&key(1), "http://hook", cursor(5, 0))
+        .unwrap();
+
+    assert_eq!(
+        storage.load_delivery_cursor(&key(1), "stdout").unwrap(),
+        Some(cursor(15, 1))
+    );
+    assert_eq!(
+        storage
+            .load_delivery_cursor(&key(1), "http://hook")
+            .unwrap(),
+        Some(cursor(5, 0))
+    );
+    assert_eq!(
+        storage.load_delivery_cursor(&key(2), "stdout").unwrap(),
+        None
+    );
+}
+
+fn emitted(index: u32) -> EmittedCell {
+    EmittedCell {
+        out_point: ckb_jsonrpc_types::OutPoint {
+            tx_hash: H256::default(),
+            index: index.into(),
+        },
+        io_type: CellType::Output,
+    }
+}
+
+fn dedup_windows(storage: &dyn Storage) {
+    assert!(storage.load_dedup_window(&key(1)).unwrap().is_empty());
+    storage
+        .store_dedup_window(&key(1), &[emitted(0), emitted(1)])
+        .unwrap();
+    storage
+        .store_dedup_window(&key(1), &[emitted(1), emitted(2)])
+        .unwrap();
+    assert_eq!(
+        storage.load_dedup_window(&key(1)).unwrap(),
+        vec![emitted(1), emitted(2)]
+    );
+    assert!(storage.load_dedup_window(&key(2)).unwrap().is_empty());
+}
+
+fn link(index: u32, kind: LinkKind) -> CellLink {
+    CellLink {
+        out_point: emitted(index).out_point,
+        kind,
+        tx: TxRef {
+            tx_hash: H256::from([index as u8 + 1; 32]),
+            index: 0.into(),
+            block_number: 10.into(),
+            block_hash: H256::default(),
+        },
+    }
+}
+
+fn cell_links(storage: &dyn Storage) {
+    assert!(storage
+        .load_cell_links(&emitted(0).out_point)
+        .unwrap()
+        .is_empty());
+    storage
+        .store_cell_links(
+            &key(1),
+            &[link(0, LinkKind::CreatedBy), link(1, LinkKind::CreatedBy)],
+        )
+        .unwrap();
+    storage
+        .store_cell_links(&key(1), &[link(0, LinkKind::ConsumedBy)])
+        .unwrap();
+    storage
+        .store_cell_links(&key(2), &[link(0, LinkKind::CreatedBy)])
+        .unwrap();
+    let mut links = storage.load_cell_links(&emitted(0).out_point).unwrap();
+    links.sort_by_key(|(key, link)| (key.script.args.as_bytes().to_vec(), link.kind as u8));
+    assert_eq!(
+        links,
+        vec![
+            (key(1), link(0, LinkKind::CreatedBy)),
+            (key(1), link(0, LinkKind::ConsumedBy)),
+            (key(2), link(0, LinkKind::CreatedBy)),
+        ]
+    );
+    assert_eq!(
+        storage.load_cell_links(&emitted(1).out_point).unwrap(),
+        vec![(key(1), link(1, LinkKind::CreatedBy))]
+    );
+}
+
+fn live_cell(index: u32) -> LiveCell {
+    LiveCell {
+        out_point: emitted(index).out_point,
+        capacity: (u64::from(index) * 100).into(),
+        block_number: 10.into(),
+        udt: None,
+    }
+}
+
+fn live_cells(storage: &dyn Storage) {
+    assert!(storage.load_live_cells(&key(1)).unwrap().is_empty());
+    storage
+        .update_live_cells(&key(1), &[live_cell(0), live_cell(1)], &[])
+        .unwrap();
+    storage
+        .update_live_cells(&key(1), &[live_cell(2)], &[live_cell(0).out_point])
+        .unwrap();
+    storage
+        .update_live_cells(&key(2), &[live_cell(0)], &[])
+        .unwrap();
+    assert_eq!(
+        crate::live_cells::sorted(storage.load_live_cells(&key(1)).unwrap()),
+        vec![live_cell(1), live_cell(2)]
+    );
+    assert_eq!(
+        storage.load_live_cells(&key(2)).unwrap(),
+        vec![live_cell(0)]
+    );
+}
+
+fn discovered_scripts(storage: &dyn Storage) {
+    assert!(storage.load_discovered_scripts(&key(1)).unwrap().is_empty());
+    let scripts = [H256::from([1; 32]), H256::from([2; 32])];
+    storage
+        .store_discovered_scripts(&key(1), &scripts[..1])
+        .unwrap();
+    storage.store_discovered_scripts(&key(1), &scripts).unwrap();
+    let mut stored = storage.load_discovered_scripts(&key(1)).unwrap();
+    stored.sort();
+    assert_eq!(stored, scripts);
+    assert!(storage.load_discovered_scripts(&key(2)).unwrap().is_empty());
+}
+
+fn stats(storage: &dyn Storage) {
+    assert_eq!(storage.load_stats(&key(1)).unwrap(), Stats::default());
+    let mut stats = Stats {
+        cells: 2,
+        transactions: 1,
+        bytes_delivered: 100,
+        ..Default::default()
+    };
+    stats.count(Emitted::new(&submit(1)));
+    storage.store_stats(&key(1), &stats).unwrap();
+    stats.bytes_delivered += 50;
+    storage.store_stats(&key(1), &stats).unwrap();
+    assert_eq!(storage.load_stats(&key(1)).unwrap(), stats);
+    assert_eq!(storage.load_stats(&key(2)).unwrap(), Stats::default());
+}
+
+fn truncation(storage: &dyn Storage) {
+    let (first, last) = storage.event_bounds().unwrap().unwrap();
+    assert_eq!(first, 0);
+
+    storage.truncate_events(u64::MAX).unwrap();
+    let (first, newest) = storage.event_bounds().unwrap().unwrap();
+    assert_eq!(newest, last);
+    let retained = storage
+        .read_events(0, u64::MAX, usize::MAX, &|_| true)
+        .unwrap();
+    assert_eq!(retained.first().map(|e| e.seq), Some(first));
+    assert_eq!(retained.last().map(|e| e.seq), Some(last));
+
+    let appended = storage.append_events(&key(1), vec![submit(25)]).unwrap();
+    assert_eq!(seqs(&appended), vec![last + 1]);
+}
+
+/// Runs the whole suite on an empty store, durable stores are reopened over the same data afterwards
+fn conformance(open: &dyn Fn() -> Arc<dyn Storage>, durable: bool) {
+    let storage = open();
+    registrations(storage.as_ref());
+    events(storage.as_ref());
+    cursors(storage.as_ref());
+    delivery_cursors(storage.as_ref());
+    dedup_windows(storage.as_ref());
+    cell_links(storage.as_ref());
+    live_cells(storage.as_ref());
+    discovered_scripts(storage.as_ref());
+    stats(storage.as_ref());
+
+    if !durable {
+        truncation(storage.as_ref());
+        return;
+    }
+    drop(storage);
+    let storage = open();
+    assert_eq!(tips(storage.as_ref()), vec![(key(2), 40)]);
+    assert_eq!(
+        seqs(
+            &storage
+                .read_events(0, u64::MAX, usize::MAX, &|_| true)
+                .unwrap()
+        ),
+        vec![0, 1, 2]
+    );
+    // seqs continue after a restart
+    let appended = storage.append_events(&key(1), vec![submit(20)]).unwrap();
+    assert_eq!(seqs(&appended), vec![3]);
+    assert_eq!(storage.load_cursors().unwrap().len(), 1);
+    assert_eq!(
+        storage.load_delivery_cursor(&key(1), "stdout").unwrap(),
+        Some(DeliveryCursor {
+            block_number: 15,
+            index: 1
+        })
+    );
+    assert_eq!(
+        storage.load_dedup_window(&key(1)).unwrap(),
+        vec![emitted(1), emitted(2)]
+    );
+    assert_eq!(
+        storage
+            .load_cell_links(&emitted(0).out_point)
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(storage.load_live_cells(&key(1)).unwrap().len(), 2);
+    assert_eq!(storage.load_discovered_scripts(&key(1)).unwrap().len(), 2);
+    assert_eq!(storage.load_stats(&key(1)).unwrap().bytes_delivered, 150);
+
+    truncation(storage.as_ref());
+    drop(storage);
+    let storage = open();
+    let appended = storage.append_events(&key(1), vec![submit(30)]).unwrap();
+    assert_eq!(seqs(&appended), vec![5]);
+}
+
+fn backend(name: &'static str, cipher: Option<Cipher>, log_compression: Option<i32>) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    // sled releases its file lock from a background thread after the drop, retry shortly
+    let reopen = || {
+        for _ in 0..50 {
+            match open(name, &path, cipher.clone(), log_compression) {
+                Ok(storage) => return storage,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(20)),
+            }
+        }
+        open(name, &path, cipher.clone(), log_compression).unwrap()
+    };
+    conformance(&reopen, true);
+}
+
+#[test]
+fn memory() {
+    conformance(&|| open("memory", "", None, None).unwrap(), false);
+}
+
+#[test]
+fn file() {
+    backend("file", None, None);
+}
+
+#[test]
+fn file_encrypted() {
+    let key = "11".repeat(32);
+    backend("file", Some(Cipher::from_hex(&key).unwrap()), None);
+}
+
+#[test]
+fn file_compressed() {
+    backend("file", None, Some(3));
+}
+
+#[test]
+fn file_compressed_keeps_plain_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = open("file", dir.path(), None, None).unwrap();
+    storage.append_events(&key(1), vec![submit(5)]).unwrap();
+    drop(storage);
+
+    let storage = open("file", dir.path(), None, Some(19)).unwrap();
+    storage.append_events(&key(1), vec![submit(6)]).unwrap();
+    drop(storage);
+    let storage = open("file", dir.path(), None, None).unwrap();
+    let events = storage
+        .read_events(0, u64::MAX, usize::MAX, &|_| true)
+        .unwrap();
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| event.submit.header.inner.number.value())
+            .collect::<Vec<_>>(),
+        vec![5, 6]
+    );
+    assert!(open("memory", "", None, Some(3)).is_err());
+}
+
+#[test]
+fn file_wrong_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let sealed = |byte: &str| Some(Cipher::from_hex(&byte.repeat(32)).unwrap());
+
+    let storage = open("file", dir.path(), sealed("11"), None).unwrap();
+    storage
+        .save_registrations(&[(key(1), registration(10))])
+        .unwrap();
+    storage.append_events(&key(1), vec![submit(5)]).unwrap();
+    drop(storage);
+
+    let err = open("file", dir.path(), sealed("22"), None).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    // an encrypted log must not be mistaken for a torn one and cut off
+    assert!(open("file", dir.path(), None, None).is_err());
+    let storage = open("file", dir.path(), sealed("11"), None).unwrap();
+    assert_eq!(tips(storage.as_ref()), vec![(key(1), 10)]);
+}
+
+#[test]
+fn migrate_unversioned() {
+    let dir = tempfile::tempdir().unwrap();
+    // scan state of the first releases, bare scan tips only
+    let legacy = serde_json::to_vec(&[(key(1), tip(10))]).unwrap();
+    std::fs::write(dir.path().join("scan_state"), legacy).unwrap();
+
+    let err = open("file", dir.path(), None, None).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    assert_eq!(migrate("file", dir.path(), None).unwrap(), 0);
+    assert_eq!(migrate("file", dir.path(), None).unwrap(), STATE_VERSION);
+    assert!(dir.path().join("pre-migrate-v0.snapshot").exists());
+
+    let storage = open("file", dir.path(), None, None).unwrap();
+    assert_eq!(tips(storage.as_ref()), vec![(key(1), 10)]);
+}
+
+#[cfg(feature = "rocksdb")]
+#[test]
+fn rocksdb() {
+    backend("rocksdb", None, None);
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled() {
+    backend("sled", None, None);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite() {
+    backend("sqlite", None, None);
+}
+
+#[test]
+fn unknown_backend() {
+    let err = open("nope", "", None, None).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,447 @@
+use std::{
+    fs::{copy, create_dir_all, read, remove_file, rename, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use ckb_jsonrpc_types::OutPoint;
+use ckb_types::H256;
+use serde::{Deserialize, Serialize};
+
+use super::{Cursor, Storage};
+use crate::{
+    cipher::Cipher,
+    event_log::{EventLog, LoggedEvent},
+    global_state::Registration,
+    lineage::CellLink,
+    live_cells::LiveCell,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    sink::{DeliveryCursor, EmittedCell},
+    stats::Stats,
+    ScanTip, Submit,
+};
+
+/// Json snapshots of the registrations and cursors next to a segmented event log,
+/// every file and log record is sealed when a cipher is given
+pub(crate) struct FileStorage {
+    path: PathBuf,
+    event_log: EventLog,
+    cipher: Option<Cipher>,
+    // serializes the read-modify-write of the registrations snapshot
+    registrations_lock: Mutex<()>,
+    delivery_cursors_lock: Mutex<()>,
+    dedup_windows_lock: Mutex<()>,
+    cell_links_lock: Mutex<()>,
+    live_cells_lock: Mutex<()>,
+    discovered_scripts_lock: Mutex<()>,
+    stats_lock: Mutex<()>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CursorEntry {
+    consumer_id: String,
+    key: RpcSearchKey,
+    seq: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeliveryCursorEntry {
+    key: RpcSearchKey,
+    sink: String,
+    #[serde(flatten)]
+    cursor: DeliveryCursor,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DedupWindowEntry {
+    key: RpcSearchKey,
+    cells: Vec<EmittedCell>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LiveCellsEntry {
+    key: RpcSearchKey,
+    cells: Vec<LiveCell>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiscoveredScriptsEntry {
+    key: RpcSearchKey,
+    scripts: Vec<H256>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StatsEntry {
+    key: RpcSearchKey,
+    stats: Stats,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CellLinkEntry {
+    key: RpcSearchKey,
+    #[serde(flatten)]
+    link: CellLink,
+}
+
+impl FileStorage {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        cipher: Option<Cipher>,
+        log_compression: Option<i32>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        create_dir_all(&path)?;
+        let event_log = EventLog::open(path.join("event_log"), cipher.clone(), log_compression)?;
+        Ok(FileStorage {
+            path,
+            event_log,
+            cipher,
+            registrations_lock: Mutex::new(()),
+            delivery_cursors_lock: Mutex::new(()),
+            dedup_windows_lock: Mutex::new(()),
+            cell_links_lock: Mutex::new(()),
+            live_cells_lock: Mutex::new(()),
+            discovered_scripts_lock: Mutex::new(()),
+            stats_lock: Mutex::new(()),
+        })
+    }
+
+    /// Contents of a dumped file, `None` if it doesn't exist
+    fn read_dump(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let raw = match read(self.path.join(name)) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match &self.cipher {
+            Some(cipher) => cipher.open(&raw).map(Some),
+            None => Ok(Some(raw)),
+        }
+    }
+
+    fn delivery_cursors(&self) -> io::Result<Vec<DeliveryCursorEntry>> {
+        Ok(self
+            .read_dump("delivery_cursors")?
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default())
+    }
+
+    fn dedup_windows(&self) -> io::Result<Vec<DedupWindowEntry>> {
+        Ok(self
+            .read_dump("dedup_windows")?
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default())
+    }
+
+    fn cell_links(&self) -> io::Result<Vec<CellLinkEntry>> {
+        Ok(self
+            .read_dump("cell_links")?
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default())
+    }
+
+    fn live_cells(&self) -> io::Result<Vec<LiveCellsEntry>> {
+        Ok(self
+            .read_dump("live_cells")?
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default())
+    }
+
+    fn discovered_scripts(&self) -> io::Result<Vec<DiscoveredScriptsEntry>> {
+        Ok(self
+            .read_dump("discovered_scripts")?
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default())
+    }
+
+    fn stats(&self) -> io::Result<Vec<StatsEntry>> {
+        Ok(self
+            .read_dump("stats")?
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default())
+    }
+
+    fn dump<T: Serialize>(&self, name: &str, value: &T) -> io::Result<()> {
+        // dump file to a temporary sub-directory
+        let tmp_dir = self.path.join("tmp");
+        create_dir_all(&tmp_dir)?;
+        let tmp_file = tmp_dir.join(name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_file)?;
+        let mut content = serde_json::to_vec(value)?;
+        if let Some(cipher) = &self.cipher {
+            content = cipher.seal(&content)?;
+        }
+        file.write_all(&content)?;
+        file.sync_all()?;
+        move_file(tmp_file, self.path.join(name))
+    }
+}
+
+impl Storage for FileStorage {
+    fn load_registrations(&self) -> io::Result<Vec<(RpcSearchKey, Registration)>> {
+        match self.read_dump("scan_state")? {
+            // never fall back to no registrations, the next dump would overwrite them
+            Some(raw) => serde_json::from_slice(&raw).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid {:?}: {}", self.path.join("scan_state"), e),
+                )
+            }),
+            None => {
+                log::warn!(
+                    "State db not found, file: {:?}",
+                    self.path.join("scan_state")
+                );
+                Ok(Default::default())
+            }
+        }
+    }
+
+    fn save_registrations(&self, registrations: &[(RpcSearchKey, Registration)]) -> io::Result<()> {
+        let _guard = self.registrations_lock.lock().unwrap();
+        self.dump("scan_state", &registrations)
+    }
+
+    fn store_scan_tip(&self, key: &RpcSearchKey, tip: &IndexerTip) -> io::Result<()> {
+        // scan tips are part of the registrations snapshot, rewrite it with the new tip
+        let _guard = self.registrations_lock.lock().unwrap();
+        let mut registrations = self.load_registrations()?;
+        match registrations.iter_mut().find(|(k, _)| k == key) {
+            Some((_, registration)) => registration.scan_tip = ScanTip::new(tip.clone()),
+            None => return Ok(()),
+        }
+        self.dump("scan_state", &registrations)
+    }
+
+    fn append_events(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        self.event_log.append(key, submits)
+    }
+
+    fn read_events(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        self.event_log.read_filtered(from, to, max, filter)
+    }
+
+    fn event_bounds(&self) -> io::Result<Option<(u64, u64)>> {
+        self.event_log.bounds()
+    }
+
+    fn truncate_events(&self, before: u64) -> io::Result<()> {
+        // whole segments are dropped, so up to a segment of older events survives
+        self.event_log.truncate(before)
+    }
+
+    fn load_cursors(&self) -> io::Result<Vec<Cursor>> {
+        match self.read_dump("consumer_cursors")? {
+            Some(raw) => {
+                let entries: Vec<CursorEntry> = serde_json::from_slice(&raw).unwrap_or_default();
+                Ok(entries
+                    .into_iter()
+                    .map(|e| (e.consumer_id, e.key, e.seq))
+                    .collect())
+            }
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn save_cursors(&self, cursors: &[Cursor]) -> io::Result<()> {
+        let entries = cursors
+            .iter()
+            .map(|(consumer_id, key, seq)| CursorEntry {
+                consumer_id: consumer_id.clone(),
+                key: key.clone(),
+                seq: *seq,
+            })
+            .collect::<Vec<_>>();
+        self.dump("consumer_cursors", &entries)
+    }
+
+    fn load_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+    ) -> io::Result<Option<DeliveryCursor>> {
+        Ok(self
+            .delivery_cursors()?
+            .into_iter()
+            .find(|e| &e.key == key && e.sink == sink)
+            .map(|e| e.cursor))
+    }
+
+    fn store_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+        cursor: DeliveryCursor,
+    ) -> io::Result<()> {
+        let _guard = self.delivery_cursors_lock.lock().unwrap();
+        let mut entries = self.delivery_cursors()?;
+        match entries.iter_mut().find(|e| &e.key == key && e.sink == sink) {
+            Some(entry) => entry.cursor = cursor,
+            None => entries.push(DeliveryCursorEntry {
+                key: key.clone(),
+                sink: sink.to_string(),
+                cursor,
+            }),
+        }
+        self.dump("delivery_cursors", &entries)
+    }
+
+    fn load_dedup_window(&self, key: &RpcSearchKey) -> io::Result<Vec<EmittedCell>> {
+        Ok(self
+            .dedup_windows()?
+            .into_iter()
+            .find(|e| &e.key == key)
+            .map(|e| e.cells)
+            .unwrap_or_default())
+    }
+
+    fn store_dedup_window(&self, key: &RpcSearchKey, cells: &[EmittedCell]) -> io::Result<()> {
+        let _guard = self.dedup_windows_lock.lock().unwrap();
+        let mut entries = self.dedup_windows()?;
+        match entries.iter_mut().find(|e| &e.key == key) {
+            Some(entry) => entry.cells = cells.to_vec(),
+            None => entries.push(DedupWindowEntry {
+                key: key.clone(),
+                cells: cells.to_vec(),
+            }),
+        }
+        self.dump("dedup_windows", &entries)
+    }
+
+    fn load_cell_links(&self, out_point: &OutPoint) -> io::Result<Vec<(RpcSearchKey, CellLink)>> {
+        Ok(self
+            .cell_links()?
+            .into_iter()
+            .filter(|e| &e.link.out_point == out_point)
+            .map(|e| (e.key, e.link))
+            .collect())
+    }
+
+    fn store_cell_links(&self, key: &RpcSearchKey, links: &[CellLink]) -> io::Result<()> {
+        let _guard = self.cell_links_lock.lock().unwrap();
+        let mut entries = self.cell_links()?;
+        for link in links {
+            match entries.iter_mut().find(|e| {
+                &e.key == key && e.link.out_point == link.out_point && e.link.kind == link.kind
+            }) {
+                Some(entry) => entry.link = link.clone(),
+                None => entries.push(CellLinkEntry {
+                    key: key.clone(),
+                    link: link.clone(),
+                }),
+            }
+        }
+        self.dump("cell_links", &entries)
+    }
+
+    fn load_live_cells(&self, key: &RpcSearchKey) -> io::Result<Vec<LiveCell>> {
+        Ok(self
+            .live_cells()?
+            .into_iter()
+            .find(|e| &e.key == key)
+            .map(|e| e.cells)
+            .unwrap_or_default())
+    }
+
+    fn update_live_cells(
+        &self,
+        key: &RpcSearchKey,
+        added: &[LiveCell],
+        removed: &[OutPoint],
+    ) -> io::Result<()> {
+        let _guard = self.live_cells_lock.lock().unwrap();
+        let mut entries = self.live_cells()?;
+        let entry = match entries.iter().position(|e| &e.key == key) {
+            Some(i) => &mut entries[i],
+            None => {
+                entries.push(LiveCellsEntry {
+                    key: key.clone(),
+                    cells: Vec::new(),
+                });
+                entries.last_mut().unwrap()
+            }
+        };
+        entry.cells.retain(|cell| {
+            !removed.contains(&cell.out_point)
+                && !added.iter().any(|added| added.out_point == cell.out_point)
+        });
+        entry.cells.extend_from_slice(added);
+        self.dump("live_cells", &entries)
+    }
+
+    fn load_discovered_scripts(&self, key: &RpcSearchKey) -> io::Result<Vec<H256>> {
+        Ok(self
+            .discovered_scripts()?
+            .into_iter()
+            .find(|e| &e.key == key)
+            .map(|e| e.scripts)
+            .unwrap_or_default())
+    }
+
+    fn store_discovered_scripts(&self, key: &RpcSearchKey, scripts: &[H256]) -> io::Result<()> {
+        let _guard = self.discovered_scripts_lock.lock().unwrap();
+        let mut entries = self.discovered_scripts()?;
+        let entry = match entries.iter().position(|e| &e.key == key) {
+            Some(i) => &mut entries[i],
+            None => {
+                entries.push(DiscoveredScriptsEntry {
+                    key: key.clone(),
+                    scripts: Vec::new(),
+                });
+                entries.last_mut().unwrap()
+            }
+        };
+        for script in scripts {
+            if !entry.scripts.contains(script) {
+                entry.scripts.push(script.clone());
+            }
+        }
+        self.dump("discovered_scripts", &entries)
+    }
+
+    fn load_stats(&self, key: &RpcSearchKey) -> io::Result<Stats> {
+        Ok(self
+            .stats()?
+            .into_iter()
+            .find(|e| &e.key == key)
+            .map(|e| e.stats)
+            .unwrap_or_default())
+    }
+
+    fn store_stats(&self, key: &RpcSearchKey, stats: &Stats) -> io::Result<()> {
+        let _guard = self.stats_lock.lock().unwrap();
+        let mut entries = self.stats()?;
+        match entries.iter_mut().find(|e| &e.key == key) {
+            Some(entry) => entry.stats = stats.clone(),
+            None => entries.push(StatsEntry {
+                key: key.clone(),
+                stats: stats.clone(),
+            }),
+        }
+        self.dump("stats", &entries)
+    }
+}
+
+fn move_file<P: AsRef<Path>>(src: P, dst: P) -> Result<(), std::io::Error> {
+    if rename(&src, &dst).is_err() {
+        copy(&src, &dst)?;
+        remove_file(&src)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    sync::Mutex,
+};
+
+use ckb_jsonrpc_types::OutPoint;
+use ckb_types::H256;
+
+use super::{Cursor, Storage};
+use crate::{
+    event_log::LoggedEvent,
+    global_state::Registration,
+    lineage::{CellLink, LinkKind},
+    live_cells::LiveCell,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    sink::{DeliveryCursor, EmittedCell},
+    stats::Stats,
+    ScanTip, Submit,
+};
+
+/// Keeps everything in process memory, nothing survives a restart
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    registrations: Mutex<Vec<(RpcSearchKey, Registration)>>,
+    // events are kept serialized by seq, `Submit` can't be cloned
+    events: Mutex<BTreeMap<u64, Vec<u8>>>,
+    cursors: Mutex<Vec<Cursor>>,
+    delivery_cursors: Mutex<HashMap<(RpcSearchKey, String), DeliveryCursor>>,
+    dedup_windows: Mutex<HashMap<RpcSearchKey, Vec<EmittedCell>>>,
+    cell_links: Mutex<HashMap<OutPoint, CellLinks>>,
+    live_cells: Mutex<HashMap<RpcSearchKey, HashMap<OutPoint, LiveCell>>>,
+    discovered_scripts: Mutex<HashMap<RpcSearchKey, HashSet<H256>>>,
+    stats: Mutex<HashMap<RpcSearchKey, Stats>>,
+}
+
+/// The links of a cell, one of each kind per registration
+type CellLinks = HashMap<(RpcSearchKey, LinkKind), CellLink>;
+
+impl Storage for MemoryStorage {
+    fn load_registrations(&self) -> io::Result<Vec<(RpcSearchKey, Registration)>> {
+        Ok(self
+            .registrations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, registration)| {
+                let mut registration = registration.clone();
+                // detach from the live scan tips of the caller
+                registration.scan_tip = ScanTip::new(registration.scan_tip.load());
+                (key.clone(), registration)
+            })
+            .collect())
+    }
+
+    fn save_registrations(&self, registrations: &[(RpcSearchKey, Registration)]) -> io::Result<()> {
+        *self.registrations.lock().unwrap() = registrations
+            .iter()
+            .map(|(key, registration)| {
+                let mut registration = registration.clone();
+                registration.scan_tip = ScanTip::new(registration.scan_tip.load());
+                (key.clone(), registration)
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn store_scan_tip(&self, key: &RpcSearchKey, tip: &IndexerTip) -> io::Result<()> {
+        if let Some((_, registration)) = self
+            .registrations
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|(k, _)| k == key)
+        {
+            registration.scan_tip = ScanTip::new(tip.clone());
+        }
+        Ok(())
+    }
+
+    fn append_events(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut stored = self.events.lock().unwrap();
+        let next_seq = stored.keys().next_back().map(|s| s + 1).unwrap_or_default();
+        let mut events = Vec::with_capacity(submits.len());
+        for (i, submit) in submits.into_iter().enumerate() {
+            let event = LoggedEvent {
+                seq: next_seq + i as u64,
+                key: key.clone(),
+                submit,
+            };
+            stored.insert(event.seq, serde_json::to_vec(&event)?);
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    fn read_events(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let stored = self.events.lock().unwrap();
+        let mut events = Vec::new();
+        for (seq, raw) in stored.range(from..) {
+            if *seq > to {
+                break;
+            }
+            let event: LoggedEvent = serde_json::from_slice(raw)?;
+            if filter(&event) {
+                events.push(event);
+                if events.len() >= max {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn event_bounds(&self) -> io::Result<Option<(u64, u64)>> {
+        let stored = self.events.lock().unwrap();
+        Ok(stored
+            .keys()
+            .next()
+            .zip(stored.keys().next_back())
+            .map(|(first, last)| (*first, *last)))
+    }
+
+    fn truncate_events(&self, before: u64) -> io::Result<()> {
+        let mut stored = self.events.lock().unwrap();
+        if let Some(last) = stored.keys().next_back().copied() {
+            *stored = stored.split_off(&before.min(last));
+        }
+        Ok(())
+    }
+
+    fn load_cursors(&self) -> io::Result<Vec<Cursor>> {
+        Ok(self.cursors.lock().unwrap().clone())
+    }
+
+    fn save_cursors(&self, cursors: &[Cursor]) -> io::Result<()> {
+        *self.cursors.lock().unwrap() = cursors.to_vec();
+        Ok(())
+    }
+
+    fn load_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+    ) -> io::Result<Option<DeliveryCursor>> {
+        Ok(self
+            .delivery_cursors
+            .lock()
+            .unwrap()
+            .get(&(key.clone(), sink.to_string()))
+            .copied())
+    }
+
+    fn store_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+        cursor: DeliveryCursor,
+    ) -> io::Result<()> {
+        self.delivery_cursors
+            .lock()
+            .unwrap()
+            .insert((key.clone(), sink.to_string()), cursor);
+        Ok(())
+    }
+
+    fn load_dedup_window(&self, key: &RpcSearchKey) -> io::Result<Vec<EmittedCell>> {
+        Ok(self
+            .dedup_windows
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn store_dedup_window(&self, key: &RpcSearchKey, cells: &[EmittedCell]) -> io::Result<()> {
+        self.dedup_windows
+            .lock()
+            .unwrap()
+            .insert(key.clone(), cells.to_vec());
+        Ok(())
+    }
+
+    fn load_cell_links(&self, out_point: &OutPoint) -> io::Result<Vec<(RpcSearchKey, CellLink)>> {
+        Ok(self
+            .cell_links
+            .lock()
+            .unwrap()
+            .get(out_point)
+            .map(|links| {
+                links
+                    .iter()
+                    .map(|((key, _), link)| (key.clone(), link.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn store_cell_links(&self, key: &RpcSearchKey, links: &[CellLink]) -> io::Result<()> {
+        let mut cell_links = self.cell_links.lock().unwrap();
+        for link in links {
+            cell_links
+                .entry(link.out_point.clone())
+                .or_default()
+                .insert((key.clone(), link.kind), link.clone());
+        }
+        Ok(())
+    }
+
+    fn load_live_cells(&self, key: &RpcSearchKey) -> io::Result<Vec<LiveCell>> {
+        Ok(self
+            .live_cells
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|cells| cells.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn update_live_cells(
+        &self,
+        key: &RpcSearchKey,
+        added: &[LiveCell],
+        removed: &[OutPoint],
+    ) -> io::Result<()> {
+        let mut live_cells = self.live_cells.lock().unwrap();
+        let cells = live_cells.entry(key.clone()).or_default();
+        for cell in added {
+            cells.insert(cell.out_point.clone(), cell.clone());
+        }
+        for out_point in removed {
+            cells.remove(out_point);
+        }
+        Ok(())
+    }
+
+    fn load_discovered_scripts(&self, key: &RpcSearchKey) -> io::Result<Vec<H256>> {
+        Ok(self
+            .discovered_scripts
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|scripts| scripts.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn store_discovered_scripts(&self, key: &RpcSearchKey, scripts: &[H256]) -> io::Result<()> {
+        self.discovered_scripts
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .extend(scripts.iter().cloned());
+        Ok(())
+    }
+
+    fn load_stats(&self, key: &RpcSearchKey) -> io::Result<Stats> {
+        Ok(self
+            .stats
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn store_stats(&self, key: &RpcSearchKey, stats: &Stats) -> io::Result<()> {
+        self.stats
+            .lock()
+            .unwrap()
+            .insert(key.clone(), stats.clone());
+        Ok(())
+    }
+}
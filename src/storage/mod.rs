@@ -0,0 +1,218 @@
+use std::{io, path::Path, sync::Arc};
+
+use ckb_jsonrpc_types::OutPoint;
+use ckb_types::H256;
+
+use crate::{
+    cipher::Cipher,
+    event_log::LoggedEvent,
+    global_state::Registration,
+    lineage::CellLink,
+    live_cells::LiveCell,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    sink::{DeliveryCursor, EmittedCell},
+    stats::Stats,
+    Submit,
+};
+
+#[cfg(feature = "rocksdb")]
+pub(crate) use self::rocksdb::RocksdbStorage;
+#[cfg(feature = "sled")]
+pub(crate) use self::sled::SledStorage;
+#[cfg(feature = "sqlite")]
+pub(crate) use self::sqlite::SqliteStorage;
+pub(crate) use file::FileStorage;
+pub(crate) use memory::MemoryStorage;
+pub(crate) use version::{migrate, STATE_VERSION};
+
+#[cfg(test)]
+pub(crate) mod conformance;
+mod file;
+mod memory;
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod version;
+
+/// A consumer's last acked seq of a registration
+pub type Cursor = (String, RpcSearchKey, u64);
+
+/// Durable home of the registrations, their scan tips, the event log and the consumer cursors
+pub(crate) trait Storage: Send + Sync {
+    fn load_registrations(&self) -> io::Result<Vec<(RpcSearchKey, Registration)>>;
+
+    /// Replaces all persisted registrations
+    fn save_registrations(&self, registrations: &[(RpcSearchKey, Registration)]) -> io::Result<()>;
+
+    /// Persists the scan tip of a single registration after it advanced
+    fn store_scan_tip(&self, key: &RpcSearchKey, tip: &IndexerTip) -> io::Result<()>;
+
+    /// Atomically appends the events of a scan and assigns them consecutive seqs
+    fn append_events(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+    ) -> io::Result<Vec<LoggedEvent>>;
+
+    /// Appends the events as `append_events` does and persists the scan tip past them, in one
+    /// write where the backend batches them
+    fn append_events_at(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+        tip: &IndexerTip,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let events = self.append_events(key, submits)?;
+        self.store_scan_tip(key, tip)?;
+        Ok(events)
+    }
+
+    /// Reads at most `max` events with `from <= seq <= to` accepted by `filter`, in seq order
+    fn read_events(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>>;
+
+    /// Seqs of the oldest and the newest retained event, `None` if nothing is logged
+    fn event_bounds(&self) -> io::Result<Option<(u64, u64)>>;
+
+    /// Drops events with `seq < before`, the newest event is always kept so seqs keep counting up.
+    /// Backends may keep some older events when they drop them in larger units
+    fn truncate_events(&self, before: u64) -> io::Result<()>;
+
+    fn load_cursors(&self) -> io::Result<Vec<Cursor>>;
+
+    /// Replaces all persisted consumer cursors
+    fn save_cursors(&self, cursors: &[Cursor]) -> io::Result<()>;
+
+    /// Last event of the registration delivered to the sink with this id
+    fn load_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+    ) -> io::Result<Option<DeliveryCursor>>;
+
+    /// Durably records the last event of the registration delivered to the sink
+    fn store_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+        cursor: DeliveryCursor,
+    ) -> io::Result<()>;
+
+    /// Cells last emitted for the registration, oldest first, empty unless deduplication is on
+    fn load_dedup_window(&self, key: &RpcSearchKey) -> io::Result<Vec<EmittedCell>>;
+
+    /// Durably replaces the cells remembered to deduplicate the registration's events
+    fn store_dedup_window(&self, key: &RpcSearchKey, cells: &[EmittedCell]) -> io::Result<()>;
+
+    /// The links of every registration to the cell at the out point, in any order
+    fn load_cell_links(&self, out_point: &OutPoint) -> io::Result<Vec<(RpcSearchKey, CellLink)>>;
+
+    /// Durably records the links of the registration's emitted cells, each one replaces the
+    /// registration's link of the same kind to the cell
+    fn store_cell_links(&self, key: &RpcSearchKey, links: &[CellLink]) -> io::Result<()>;
+
+    /// The live cells of the registration, in any order, empty unless it tracks them
+    fn load_live_cells(&self, key: &RpcSearchKey) -> io::Result<Vec<LiveCell>>;
+
+    /// Durably adds and removes live cells of the registration
+    fn update_live_cells(
+        &self,
+        key: &RpcSearchKey,
+        added: &[LiveCell],
+        removed: &[OutPoint],
+    ) -> io::Result<()>;
+
+    /// Hashes of the scripts the registration discovered, in any order, empty unless it discovers
+    fn load_discovered_scripts(&self, key: &RpcSearchKey) -> io::Result<Vec<H256>>;
+
+    /// Durably adds script hashes to the ones the registration discovered
+    fn store_discovered_scripts(&self, key: &RpcSearchKey, scripts: &[H256]) -> io::Result<()>;
+
+    /// What the registration emitted, zero until its first event
+    fn load_stats(&self, key: &RpcSearchKey) -> io::Result<Stats>;
+
+    /// Durably replaces the registration's stats
+    fn store_stats(&self, key: &RpcSearchKey, stats: &Stats) -> io::Result<()>;
+}
+
+/// Opens the storage backend by name, `memory`, `file`, `rocksdb`, `sled` or `sqlite`,
+/// only the file backend encrypts at rest, the others keep search keys in plain lookup keys,
+/// and only it compresses its event log with zstd at `log_compression`.
+/// New stores are stamped with `STATE_VERSION`, stores of other versions are refused
+pub(crate) fn open<P: AsRef<Path>>(
+    backend: &str,
+    path: P,
+    cipher: Option<Cipher>,
+    log_compression: Option<i32>,
+) -> io::Result<Arc<dyn Storage>> {
+    let path = path.as_ref();
+    if log_compression.is_some() && backend != "file" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("storage backend {} doesn't compress its event log", backend),
+        ));
+    }
+    if backend == "memory" {
+        return open_backend(backend, path, cipher, None);
+    }
+    let fresh = version::is_fresh(path)?;
+    if !fresh {
+        version::check(path)?;
+    }
+    let storage = open_backend(backend, path, cipher, log_compression)?;
+    if fresh {
+        version::stamp(path)?;
+    }
+    Ok(storage)
+}
+
+fn open_backend<P: AsRef<Path>>(
+    backend: &str,
+    path: P,
+    cipher: Option<Cipher>,
+    log_compression: Option<i32>,
+) -> io::Result<Arc<dyn Storage>> {
+    if cipher.is_some() && !matches!(backend, "file" | "memory") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("storage backend {} doesn't support encryption", backend),
+        ));
+    }
+    match backend {
+        "memory" => Ok(Arc::new(MemoryStorage::default())),
+        "file" => Ok(Arc::new(FileStorage::open(path, cipher, log_compression)?)),
+        #[cfg(feature = "rocksdb")]
+        "rocksdb" => Ok(Arc::new(RocksdbStorage::open(
+            path.as_ref().join("rocksdb"),
+        )?)),
+        #[cfg(feature = "sled")]
+        "sled" => Ok(Arc::new(SledStorage::open(path.as_ref().join("sled"))?)),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            std::fs::create_dir_all(&path)?;
+            Ok(Arc::new(SqliteStorage::open(
+                path.as_ref().join("emitter.sqlite"),
+            )?))
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported storage backend: {}", other),
+        )),
+    }
+}
+
+/// Backend used when none is configured, rocksdb when it is compiled in
+pub const DEFAULT_BACKEND: &str = if cfg!(feature = "rocksdb") {
+    "rocksdb"
+} else {
+    "file"
+};
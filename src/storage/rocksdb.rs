@@ -0,0 +1,477 @@
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch, DB,
+};
+
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ckb_jsonrpc_types::OutPoint;
+use ckb_types::{packed, prelude::Entity, H256};
+
+use super::{Cursor, Storage};
+use crate::{
+    event_log::LoggedEvent,
+    global_state::Registration,
+    lineage::CellLink,
+    live_cells::LiveCell,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    sink::{DeliveryCursor, EmittedCell},
+    stats::Stats,
+    ScanTip, Submit,
+};
+
+const CF_REGISTRATIONS: &str = "registrations";
+const CF_SCAN_TIPS: &str = "scan_tips";
+const CF_EVENTS: &str = "events";
+const CF_CURSORS: &str = "consumer_cursors";
+const CF_DELIVERY_CURSORS: &str = "delivery_cursors";
+const CF_DEDUP_WINDOWS: &str = "dedup_windows";
+const CF_CELL_LINKS: &str = "cell_links";
+const CF_LIVE_CELLS: &str = "live_cells";
+const CF_DISCOVERED_SCRIPTS: &str = "discovered_scripts";
+const CF_STATS: &str = "stats";
+
+/// Keys are json encoded search keys, except for the events which are keyed by big-endian seq
+pub(crate) struct RocksdbStorage {
+    db: DB,
+    // next seq of the event log, the lock also orders concurrent appends
+    next_seq: Arc<Mutex<u64>>,
+}
+
+fn to_io(e: rocksdb::Error) -> io::Error {
+    io::Error::other(e.into_string())
+}
+
+impl RocksdbStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let cfs = [
+            CF_REGISTRATIONS,
+            CF_SCAN_TIPS,
+            CF_EVENTS,
+            CF_CURSORS,
+            CF_DELIVERY_CURSORS,
+            CF_DEDUP_WINDOWS,
+            CF_CELL_LINKS,
+            CF_LIVE_CELLS,
+            CF_DISCOVERED_SCRIPTS,
+            CF_STATS,
+        ]
+        .into_iter()
+        .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        let db = DB::open_cf_descriptors(&opts, path, cfs).map_err(to_io)?;
+
+        let next_seq = {
+            let cf = db.cf_handle(CF_EVENTS).unwrap();
+            match db.iterator_cf(cf, IteratorMode::End).next() {
+                Some(item) => {
+                    let (k, _) = item.map_err(to_io)?;
+                    u64::from_be_bytes(k.as_ref().try_into().unwrap()) + 1
+                }
+                None => 0,
+            }
+        };
+
+        Ok(RocksdbStorage {
+            db,
+            next_seq: Arc::new(Mutex::new(next_seq)),
+        })
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        self.db.cf_handle(name).unwrap()
+    }
+
+    /// Seq of the first event in iteration order
+    fn edge_seq(&self, mode: IteratorMode) -> io::Result<Option<u64>> {
+        match self.db.iterator_cf(self.cf(CF_EVENTS), mode).next() {
+            Some(item) => {
+                let (k, _) = item.map_err(to_io)?;
+                Ok(Some(u64::from_be_bytes(k.as_ref().try_into().unwrap())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the events with consecutive seqs in the batch, with what else it holds
+    fn append(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+        mut batch: WriteBatch,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let mut events = Vec::with_capacity(submits.len());
+
+        for (i, submit) in submits.into_iter().enumerate() {
+            let event = LoggedEvent {
+                seq: *next_seq + i as u64,
+                key: key.clone(),
+                submit,
+            };
+            batch.put_cf(
+                self.cf(CF_EVENTS),
+                event.seq.to_be_bytes(),
+                serde_json::to_vec(&event)?,
+            );
+            events.push(event);
+        }
+        self.db.write(batch).map_err(to_io)?;
+        *next_seq += events.len() as u64;
+
+        Ok(events)
+    }
+}
+
+impl Storage for RocksdbStorage {
+    fn load_registrations(&self) -> io::Result<Vec<(RpcSearchKey, Registration)>> {
+        let mut res = Vec::new();
+        for item in self
+            .db
+            .iterator_cf(self.cf(CF_REGISTRATIONS), IteratorMode::Start)
+        {
+            let (k, v) = item.map_err(to_io)?;
+            let key: RpcSearchKey = serde_json::from_slice(&k)?;
+            let mut registration: Registration = serde_json::from_slice(&v)?;
+
+            // the scan tip moves far more often than the rest, it's kept in its own column family
+            if let Some(tip) = self.db.get_cf(self.cf(CF_SCAN_TIPS), &k).map_err(to_io)? {
+                let tip: IndexerTip = serde_json::from_slice(&tip)?;
+                registration.scan_tip = ScanTip::new(tip);
+            }
+            res.push((key, registration));
+        }
+        Ok(res)
+    }
+
+    fn save_registrations(&self, registrations: &[(RpcSearchKey, Registration)]) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        let mut keep = std::collections::HashSet::with_capacity(registrations.len());
+
+        for (key, registration) in registrations {
+            let k = serde_json::to_vec(key)?;
+            batch.put_cf(
+                self.cf(CF_REGISTRATIONS),
+                &k,
+                serde_json::to_vec(registration)?,
+            );
+            batch.put_cf(
+                self.cf(CF_SCAN_TIPS),
+                &k,
+                serde_json::to_vec(&registration.scan_tip.load())?,
+            );
+            keep.insert(k);
+        }
+        for item in self
+            .db
+            .iterator_cf(self.cf(CF_REGISTRATIONS), IteratorMode::Start)
+        {
+            let (k, _) = item.map_err(to_io)?;
+            if !keep.contains(k.as_ref()) {
+                batch.delete_cf(self.cf(CF_REGISTRATIONS), &k);
+                batch.delete_cf(self.cf(CF_SCAN_TIPS), &k);
+            }
+        }
+        self.db.write(batch).map_err(to_io)
+    }
+
+    fn store_scan_tip(&self, key: &RpcSearchKey, tip: &IndexerTip) -> io::Result<()> {
+        self.db
+            .put_cf(
+                self.cf(CF_SCAN_TIPS),
+                serde_json::to_vec(key)?,
+                serde_json::to_vec(tip)?,
+            )
+            .map_err(to_io)
+    }
+
+    fn append_events(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        self.append(key, submits, WriteBatch::default())
+    }
+
+    fn append_events_at(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+        tip: &IndexerTip,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            self.cf(CF_SCAN_TIPS),
+            serde_json::to_vec(key)?,
+            serde_json::to_vec(tip)?,
+        );
+        self.append(key, submits, batch)
+    }
+
+    fn read_events(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut events = Vec::new();
+        let start = from.to_be_bytes();
+        let mode = IteratorMode::From(&start[..], Direction::Forward);
+
+        for item in self.db.iterator_cf(self.cf(CF_EVENTS), mode) {
+            let (k, v) = item.map_err(to_io)?;
+            if u64::from_be_bytes(k.as_ref().try_into().unwrap()) > to {
+                break;
+            }
+            let event: LoggedEvent = serde_json::from_slice(&v)?;
+            if filter(&event) {
+                events.push(event);
+                if events.len() >= max {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn event_bounds(&self) -> io::Result<Option<(u64, u64)>> {
+        let first = self.edge_seq(IteratorMode::Start)?;
+        let last = self.edge_seq(IteratorMode::End)?;
+        Ok(first.zip(last))
+    }
+
+    fn truncate_events(&self, before: u64) -> io::Result<()> {
+        // the next seq is recovered from the newest event, never drop it
+        let next_seq = *self.next_seq.lock().unwrap();
+        if next_seq == 0 {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(
+            self.cf(CF_EVENTS),
+            0u64.to_be_bytes(),
+            before.min(next_seq - 1).to_be_bytes(),
+        );
+        self.db.write(batch).map_err(to_io)
+    }
+
+    fn load_cursors(&self) -> io::Result<Vec<Cursor>> {
+        let mut res = Vec::new();
+        for item in self
+            .db
+            .iterator_cf(self.cf(CF_CURSORS), IteratorMode::Start)
+        {
+            let (k, v) = item.map_err(to_io)?;
+            let (consumer_id, key): (String, RpcSearchKey) = serde_json::from_slice(&k)?;
+            res.push((
+                consumer_id,
+                key,
+                u64::from_be_bytes(v.as_ref().try_into().unwrap()),
+            ));
+        }
+        Ok(res)
+    }
+
+    fn save_cursors(&self, cursors: &[Cursor]) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        let mut keep = std::collections::HashSet::with_capacity(cursors.len());
+
+        for (consumer_id, key, seq) in cursors {
+            let k = serde_json::to_vec(&(consumer_id, key))?;
+            batch.put_cf(self.cf(CF_CURSORS), &k, seq.to_be_bytes());
+            keep.insert(k);
+        }
+        for item in self
+            .db
+            .iterator_cf(self.cf(CF_CURSORS), IteratorMode::Start)
+        {
+            let (k, _) = item.map_err(to_io)?;
+            if !keep.contains(k.as_ref()) {
+                batch.delete_cf(self.cf(CF_CURSORS), &k);
+            }
+        }
+        self.db.write(batch).map_err(to_io)
+    }
+
+    fn load_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+    ) -> io::Result<Option<DeliveryCursor>> {
+        match self
+            .db
+            .get_cf(
+                self.cf(CF_DELIVERY_CURSORS),
+                serde_json::to_vec(&(key, sink))?,
+            )
+            .map_err(to_io)?
+        {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+        cursor: DeliveryCursor,
+    ) -> io::Result<()> {
+        self.db
+            .put_cf(
+                self.cf(CF_DELIVERY_CURSORS),
+                serde_json::to_vec(&(key, sink))?,
+                serde_json::to_vec(&cursor)?,
+            )
+            .map_err(to_io)
+    }
+
+    fn load_dedup_window(&self, key: &RpcSearchKey) -> io::Result<Vec<EmittedCell>> {
+        match self
+            .db
+            .get_cf(self.cf(CF_DEDUP_WINDOWS), serde_json::to_vec(key)?)
+            .map_err(to_io)?
+        {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn store_dedup_window(&self, key: &RpcSearchKey, cells: &[EmittedCell]) -> io::Result<()> {
+        self.db
+            .put_cf(
+                self.cf(CF_DEDUP_WINDOWS),
+                serde_json::to_vec(key)?,
+                serde_json::to_vec(cells)?,
+            )
+            .map_err(to_io)
+    }
+
+    fn load_cell_links(&self, out_point: &OutPoint) -> io::Result<Vec<(RpcSearchKey, CellLink)>> {
+        let prefix = cell_links_prefix(out_point);
+        let mode = IteratorMode::From(&prefix[..], Direction::Forward);
+        let mut res = Vec::new();
+        for item in self.db.iterator_cf(self.cf(CF_CELL_LINKS), mode) {
+            let (k, v) = item.map_err(to_io)?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            res.push(serde_json::from_slice(&v)?);
+        }
+        Ok(res)
+    }
+
+    fn store_cell_links(&self, key: &RpcSearchKey, links: &[CellLink]) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        for link in links {
+            let mut k = cell_links_prefix(&link.out_point);
+            k.extend(serde_json::to_vec(&(key, link.kind))?);
+            batch.put_cf(self.cf(CF_CELL_LINKS), k, serde_json::to_vec(&(key, link))?);
+        }
+        self.db.write(batch).map_err(to_io)
+    }
+
+    fn load_live_cells(&self, key: &RpcSearchKey) -> io::Result<Vec<LiveCell>> {
+        let prefix = key.id().into_bytes();
+        let mode = IteratorMode::From(&prefix[..], Direction::Forward);
+        let mut res = Vec::new();
+        for item in self.db.iterator_cf(self.cf(CF_LIVE_CELLS), mode) {
+            let (k, v) = item.map_err(to_io)?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            res.push(serde_json::from_slice(&v)?);
+        }
+        Ok(res)
+    }
+
+    fn update_live_cells(
+        &self,
+        key: &RpcSearchKey,
+        added: &[LiveCell],
+        removed: &[OutPoint],
+    ) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        for out_point in removed {
+            batch.delete_cf(self.cf(CF_LIVE_CELLS), live_cell_key(key, out_point));
+        }
+        for cell in added {
+            batch.put_cf(
+                self.cf(CF_LIVE_CELLS),
+                live_cell_key(key, &cell.out_point),
+                serde_json::to_vec(cell)?,
+            );
+        }
+        self.db.write(batch).map_err(to_io)
+    }
+
+    fn load_discovered_scripts(&self, key: &RpcSearchKey) -> io::Result<Vec<H256>> {
+        let prefix = key.id().into_bytes();
+        let mode = IteratorMode::From(&prefix[..], Direction::Forward);
+        let mut res = Vec::new();
+        for item in self.db.iterator_cf(self.cf(CF_DISCOVERED_SCRIPTS), mode) {
+            let (k, _) = item.map_err(to_io)?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            res.push(
+                H256::from_slice(&k[prefix.len()..])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+            );
+        }
+        Ok(res)
+    }
+
+    fn store_discovered_scripts(&self, key: &RpcSearchKey, scripts: &[H256]) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        for script in scripts {
+            let mut k = key.id().into_bytes();
+            k.extend_from_slice(script.as_bytes());
+            batch.put_cf(self.cf(CF_DISCOVERED_SCRIPTS), k, []);
+        }
+        self.db.write(batch).map_err(to_io)
+    }
+
+    fn load_stats(&self, key: &RpcSearchKey) -> io::Result<Stats> {
+        match self
+            .db
+            .get_cf(self.cf(CF_STATS), serde_json::to_vec(key)?)
+            .map_err(to_io)?
+        {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(Stats::default()),
+        }
+    }
+
+    fn store_stats(&self, key: &RpcSearchKey, stats: &Stats) -> io::Result<()> {
+        self.db
+            .put_cf(
+                self.cf(CF_STATS),
+                serde_json::to_vec(key)?,
+                serde_json::to_vec(stats)?,
+            )
+            .map_err(to_io)
+    }
+}
+
+/// The live cells of a registration share its id, the digest of the search key has a fixed length
+fn live_cell_key(key: &RpcSearchKey, out_point: &OutPoint) -> Vec<u8> {
+    let mut k = key.id().into_bytes();
+    k.extend_from_slice(packed::OutPoint::from(out_point.clone()).as_slice());
+    k
+}
+
+/// The links of a cell share the out point in molecule encoding, followed by the search key and kind
+fn cell_links_prefix(out_point: &OutPoint) -> Vec<u8> {
+    packed::OutPoint::from(out_point.clone())
+        .as_slice()
+        .to_vec()
+}
@@ -0,0 +1,395 @@
+use sled::{Batch, Db, Tree};
+
+use std::{
+    collections::HashSet,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ckb_jsonrpc_types::OutPoint;
+use ckb_types::{packed, prelude::Entity, H256};
+
+use super::{Cursor, Storage};
+use crate::{
+    event_log::LoggedEvent,
+    global_state::Registration,
+    lineage::CellLink,
+    live_cells::LiveCell,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    sink::{DeliveryCursor, EmittedCell},
+    stats::Stats,
+    ScanTip, Submit,
+};
+
+/// Same layout as the rocksdb backend, one tree per column family
+pub(crate) struct SledStorage {
+    db: Db,
+    registrations: Tree,
+    scan_tips: Tree,
+    events: Tree,
+    cursors: Tree,
+    delivery_cursors: Tree,
+    dedup_windows: Tree,
+    cell_links: Tree,
+    live_cells: Tree,
+    discovered_scripts: Tree,
+    stats: Tree,
+    // next seq of the event log, the lock also orders concurrent appends
+    next_seq: Arc<Mutex<u64>>,
+}
+
+fn to_io(e: sled::Error) -> io::Error {
+    match e {
+        sled::Error::Io(e) => e,
+        other => io::Error::other(other.to_string()),
+    }
+}
+
+fn seq_of(k: &[u8]) -> u64 {
+    u64::from_be_bytes(k.try_into().unwrap())
+}
+
+impl SledStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let db = sled::open(path).map_err(to_io)?;
+        let registrations = db.open_tree("registrations").map_err(to_io)?;
+        let scan_tips = db.open_tree("scan_tips").map_err(to_io)?;
+        let events = db.open_tree("events").map_err(to_io)?;
+        let cursors = db.open_tree("consumer_cursors").map_err(to_io)?;
+        let delivery_cursors = db.open_tree("delivery_cursors").map_err(to_io)?;
+        let dedup_windows = db.open_tree("dedup_windows").map_err(to_io)?;
+        let cell_links = db.open_tree("cell_links").map_err(to_io)?;
+        let live_cells = db.open_tree("live_cells").map_err(to_io)?;
+        let discovered_scripts = db.open_tree("discovered_scripts").map_err(to_io)?;
+        let stats = db.open_tree("stats").map_err(to_io)?;
+
+        let next_seq = match events.last().map_err(to_io)? {
+            Some((k, _)) => seq_of(&k) + 1,
+            None => 0,
+        };
+
+        Ok(SledStorage {
+            db,
+            registrations,
+            scan_tips,
+            events,
+            cursors,
+            delivery_cursors,
+            dedup_windows,
+            cell_links,
+            live_cells,
+            discovered_scripts,
+            stats,
+            next_seq: Arc::new(Mutex::new(next_seq)),
+        })
+    }
+}
+
+impl Storage for SledStorage {
+    fn load_registrations(&self) -> io::Result<Vec<(RpcSearchKey, Registration)>> {
+        let mut res = Vec::new();
+        for item in self.registrations.iter() {
+            let (k, v) = item.map_err(to_io)?;
+            let key: RpcSearchKey = serde_json::from_slice(&k)?;
+            let mut registration: Registration = serde_json::from_slice(&v)?;
+
+            if let Some(tip) = self.scan_tips.get(&k).map_err(to_io)? {
+                let tip: IndexerTip = serde_json::from_slice(&tip)?;
+                registration.scan_tip = ScanTip::new(tip);
+            }
+            res.push((key, registration));
+        }
+        Ok(res)
+    }
+
+    fn save_registrations(&self, registrations: &[(RpcSearchKey, Registration)]) -> io::Result<()> {
+        let mut registration_batch = Batch::default();
+        let mut tip_batch = Batch::default();
+        let mut keep = HashSet::with_capacity(registrations.len());
+
+        for (key, registration) in registrations {
+            let k = serde_json::to_vec(key)?;
+            registration_batch.insert(k.clone(), serde_json::to_vec(registration)?);
+            tip_batch.insert(
+                k.clone(),
+                serde_json::to_vec(&registration.scan_tip.load())?,
+            );
+            keep.insert(k);
+        }
+        for item in self.registrations.iter() {
+            let (k, _) = item.map_err(to_io)?;
+            if !keep.contains(k.as_ref()) {
+                registration_batch.remove(k.clone());
+                tip_batch.remove(k);
+            }
+        }
+        self.registrations
+            .apply_batch(registration_batch)
+            .map_err(to_io)?;
+        self.scan_tips.apply_batch(tip_batch).map_err(to_io)?;
+        self.db.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn store_scan_tip(&self, key: &RpcSearchKey, tip: &IndexerTip) -> io::Result<()> {
+        self.scan_tips
+            .insert(serde_json::to_vec(key)?, serde_json::to_vec(tip)?)
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    fn append_events(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let mut batch = Batch::default();
+        let mut events = Vec::with_capacity(submits.len());
+
+        for (i, submit) in submits.into_iter().enumerate() {
+            let event = LoggedEvent {
+                seq: *next_seq + i as u64,
+                key: key.clone(),
+                submit,
+            };
+            batch.insert(&event.seq.to_be_bytes(), serde_json::to_vec(&event)?);
+            events.push(event);
+        }
+        self.events.apply_batch(batch).map_err(to_io)?;
+        self.events.flush().map_err(to_io)?;
+        *next_seq += events.len() as u64;
+
+        Ok(events)
+    }
+
+    fn read_events(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut events = Vec::new();
+        for item in self.events.range(from.to_be_bytes()..) {
+            let (k, v) = item.map_err(to_io)?;
+            if seq_of(&k) > to {
+                break;
+            }
+            let event: LoggedEvent = serde_json::from_slice(&v)?;
+            if filter(&event) {
+                events.push(event);
+                if events.len() >= max {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn event_bounds(&self) -> io::Result<Option<(u64, u64)>> {
+        let first = self.events.first().map_err(to_io)?;
+        let last = self.events.last().map_err(to_io)?;
+        Ok(first
+            .zip(last)
+            .map(|((first, _), (last, _))| (seq_of(&first), seq_of(&last))))
+    }
+
+    fn truncate_events(&self, before: u64) -> io::Result<()> {
+        let last = match self.events.last().map_err(to_io)? {
+            Some((k, _)) => seq_of(&k),
+            None => return Ok(()),
+        };
+        let mut batch = Batch::default();
+        for item in self.events.range(..before.min(last).to_be_bytes()) {
+            let (k, _) = item.map_err(to_io)?;
+            batch.remove(k);
+        }
+        self.events.apply_batch(batch).map_err(to_io)?;
+        self.events.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_cursors(&self) -> io::Result<Vec<Cursor>> {
+        let mut res = Vec::new();
+        for item in self.cursors.iter() {
+            let (k, v) = item.map_err(to_io)?;
+            let (consumer_id, key): (String, RpcSearchKey) = serde_json::from_slice(&k)?;
+            res.push((consumer_id, key, seq_of(&v)));
+        }
+        Ok(res)
+    }
+
+    fn save_cursors(&self, cursors: &[Cursor]) -> io::Result<()> {
+        let mut batch = Batch::default();
+        // replaces the whole set, the cursors of dropped consumers go
+        for item in self.cursors.iter() {
+            let (k, _) = item.map_err(to_io)?;
+            batch.remove(k);
+        }
+        for (consumer_id, key, seq) in cursors {
+            batch.insert(serde_json::to_vec(&(consumer_id, key))?, &seq.to_be_bytes());
+        }
+        self.cursors.apply_batch(batch).map_err(to_io)?;
+        self.cursors.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+    ) -> io::Result<Option<DeliveryCursor>> {
+        match self
+            .delivery_cursors
+            .get(serde_json::to_vec(&(key, sink))?)
+            .map_err(to_io)?
+        {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+        cursor: DeliveryCursor,
+    ) -> io::Result<()> {
+        self.delivery_cursors
+            .insert(
+                serde_json::to_vec(&(key, sink))?,
+                serde_json::to_vec(&cursor)?,
+            )
+            .map_err(to_io)?;
+        self.delivery_cursors.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_dedup_window(&self, key: &RpcSearchKey) -> io::Result<Vec<EmittedCell>> {
+        match self
+            .dedup_windows
+            .get(serde_json::to_vec(key)?)
+            .map_err(to_io)?
+        {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn store_dedup_window(&self, key: &RpcSearchKey, cells: &[EmittedCell]) -> io::Result<()> {
+        self.dedup_windows
+            .insert(serde_json::to_vec(key)?, serde_json::to_vec(cells)?)
+            .map_err(to_io)?;
+        self.dedup_windows.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_cell_links(&self, out_point: &OutPoint) -> io::Result<Vec<(RpcSearchKey, CellLink)>> {
+        let mut res = Vec::new();
+        for item in self.cell_links.scan_prefix(cell_links_prefix(out_point)) {
+            let (_, v) = item.map_err(to_io)?;
+            res.push(serde_json::from_slice(&v)?);
+        }
+        Ok(res)
+    }
+
+    fn store_cell_links(&self, key: &RpcSearchKey, links: &[CellLink]) -> io::Result<()> {
+        let mut batch = Batch::default();
+        for link in links {
+            let mut k = cell_links_prefix(&link.out_point);
+            k.extend(serde_json::to_vec(&(key, link.kind))?);
+            batch.insert(k, serde_json::to_vec(&(key, link))?);
+        }
+        self.cell_links.apply_batch(batch).map_err(to_io)?;
+        self.cell_links.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_live_cells(&self, key: &RpcSearchKey) -> io::Result<Vec<LiveCell>> {
+        let mut res = Vec::new();
+        for item in self.live_cells.scan_prefix(key.id()) {
+            let (_, v) = item.map_err(to_io)?;
+            res.push(serde_json::from_slice(&v)?);
+        }
+        Ok(res)
+    }
+
+    fn update_live_cells(
+        &self,
+        key: &RpcSearchKey,
+        added: &[LiveCell],
+        removed: &[OutPoint],
+    ) -> io::Result<()> {
+        let mut batch = Batch::default();
+        for out_point in removed {
+            batch.remove(live_cell_key(key, out_point));
+        }
+        for cell in added {
+            batch.insert(
+                live_cell_key(key, &cell.out_point),
+                serde_json::to_vec(cell)?,
+            );
+        }
+        self.live_cells.apply_batch(batch).map_err(to_io)?;
+        self.live_cells.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_discovered_scripts(&self, key: &RpcSearchKey) -> io::Result<Vec<H256>> {
+        let prefix = key.id();
+        let mut res = Vec::new();
+        for item in self.discovered_scripts.scan_prefix(&prefix) {
+            let (k, _) = item.map_err(to_io)?;
+            res.push(script_hash_of(&k[prefix.len()..])?);
+        }
+        Ok(res)
+    }
+
+    fn store_discovered_scripts(&self, key: &RpcSearchKey, scripts: &[H256]) -> io::Result<()> {
+        let mut batch = Batch::default();
+        for script in scripts {
+            let mut k = key.id().into_bytes();
+            k.extend_from_slice(script.as_bytes());
+            batch.insert(k, &[]);
+        }
+        self.discovered_scripts.apply_batch(batch).map_err(to_io)?;
+        self.discovered_scripts.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_stats(&self, key: &RpcSearchKey) -> io::Result<Stats> {
+        match self.stats.get(serde_json::to_vec(key)?).map_err(to_io)? {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(Stats::default()),
+        }
+    }
+
+    fn store_stats(&self, key: &RpcSearchKey, stats: &Stats) -> io::Result<()> {
+        self.stats
+            .insert(serde_json::to_vec(key)?, serde_json::to_vec(stats)?)
+            .map_err(to_io)?;
+        self.stats.flush().map_err(to_io)?;
+        Ok(())
+    }
+}
+
+fn script_hash_of(bytes: &[u8]) -> io::Result<H256> {
+    H256::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// The live cells of a registration share its id, the digest of the search key has a fixed length
+fn live_cell_key(key: &RpcSearchKey, out_point: &OutPoint) -> Vec<u8> {
+    let mut k = key.id().into_bytes();
+    k.extend_from_slice(packed::OutPoint::from(out_point.clone()).as_slice());
+    k
+}
+
+/// The links of a cell share the out point in molecule encoding, followed by the search key and kind
+fn cell_links_prefix(out_point: &OutPoint) -> Vec<u8> {
+    packed::OutPoint::from(out_point.clone())
+        .as_slice()
+        .to_vec()
+}
@@ -0,0 +1,603 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use std::{collections::HashSet, io, path::Path, sync::Mutex};
+
+use ckb_jsonrpc_types::OutPoint;
+use ckb_types::H256;
+
+use super::{Cursor, Storage};
+use crate::{
+    event_log::LoggedEvent,
+    global_state::Registration,
+    lineage::CellLink,
+    live_cells::LiveCell,
+    rpc_client::IndexerTip,
+    rpc_server::RpcSearchKey,
+    sink::{DeliveryCursor, EmittedCell},
+    stats::Stats,
+    ScanTip, Submit,
+};
+
+/// Schema migrations, the `user_version` pragma records how many of them are applied.
+/// Append new ones at the end, never edit an applied one.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE registrations (
+        search_key TEXT PRIMARY KEY NOT NULL,
+        registration TEXT NOT NULL
+    );
+    CREATE TABLE scan_tips (
+        search_key TEXT PRIMARY KEY NOT NULL,
+        block_number INTEGER NOT NULL,
+        block_hash TEXT NOT NULL
+    );
+    CREATE TABLE events (
+        seq INTEGER PRIMARY KEY NOT NULL,
+        search_key TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        block_hash TEXT NOT NULL,
+        event TEXT NOT NULL
+    );
+    CREATE INDEX events_by_key ON events (search_key, seq);
+    CREATE TABLE consumer_cursors (
+        consumer_id TEXT NOT NULL,
+        search_key TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        PRIMARY KEY (consumer_id, search_key)
+    );
+"#,
+    r#"
+    CREATE TABLE delivery_cursors (
+        search_key TEXT NOT NULL,
+        sink TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        event_index INTEGER NOT NULL,
+        PRIMARY KEY (search_key, sink)
+    );
+"#,
+    r#"
+    CREATE TABLE dedup_windows (
+        search_key TEXT PRIMARY KEY NOT NULL,
+        cells TEXT NOT NULL
+    );
+"#,
+    r#"
+    CREATE TABLE cell_links (
+        out_point TEXT NOT NULL,
+        search_key TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        tx TEXT NOT NULL,
+        PRIMARY KEY (out_point, search_key, kind)
+    );
+"#,
+    r#"
+    CREATE TABLE live_cells (
+        search_key TEXT NOT NULL,
+        out_point TEXT NOT NULL,
+        cell TEXT NOT NULL,
+        PRIMARY KEY (search_key, out_point)
+    );
+"#,
+    r#"
+    CREATE TABLE discovered_scripts (
+        search_key TEXT NOT NULL,
+        script_hash TEXT NOT NULL,
+        PRIMARY KEY (search_key, script_hash)
+    );
+"#,
+    r#"
+    CREATE TABLE stats (
+        search_key TEXT PRIMARY KEY NOT NULL,
+        stats TEXT NOT NULL
+    );
+"#,
+];
+
+/// Keeps every value in plain columns or json text, so the store can be inspected with `sqlite3`
+pub(crate) struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+fn json<T: serde::Serialize>(value: &T) -> io::Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+impl SqliteStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut conn = Connection::open(path).map_err(to_io)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(to_io)?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> io::Result<()> {
+    let applied: usize = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(to_io)?;
+
+    if applied > MIGRATIONS.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "sqlite store is at schema version {}, this binary only knows {}",
+                applied,
+                MIGRATIONS.len()
+            ),
+        ));
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction().map_err(to_io)?;
+        tx.execute_batch(migration).map_err(to_io)?;
+        tx.pragma_update(None, "user_version", version + 1)
+            .map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+        log::info!("Migrated sqlite store to schema version {}", version + 1);
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load_registrations(&self) -> io::Result<Vec<(RpcSearchKey, Registration)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT r.search_key, r.registration, t.block_number, t.block_hash
+                 FROM registrations r LEFT JOIN scan_tips t ON r.search_key = t.search_key",
+            )
+            .map_err(to_io)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<u64>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .map_err(to_io)?;
+
+        let mut res = Vec::new();
+        for row in rows {
+            let (key, registration, block_number, block_hash) = row.map_err(to_io)?;
+            let key: RpcSearchKey = serde_json::from_str(&key)?;
+            let mut registration: Registration = serde_json::from_str(&registration)?;
+
+            if let (Some(block_number), Some(block_hash)) = (block_number, block_hash) {
+                registration.scan_tip = ScanTip::new(IndexerTip {
+                    block_hash: serde_json::from_value(block_hash.into())?,
+                    block_number: block_number.into(),
+                });
+            }
+            res.push((key, registration));
+        }
+        Ok(res)
+    }
+
+    fn save_registrations(&self, registrations: &[(RpcSearchKey, Registration)]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+
+        let mut keep = HashSet::with_capacity(registrations.len());
+        for (key, registration) in registrations {
+            let k = json(key)?;
+            let tip = registration.scan_tip.load();
+            tx.execute(
+                "INSERT OR REPLACE INTO registrations (search_key, registration) VALUES (?1, ?2)",
+                params![k, json(registration)?],
+            )
+            .map_err(to_io)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO scan_tips (search_key, block_number, block_hash)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    k,
+                    tip.block_number.value(),
+                    format!("{:#x}", tip.block_hash)
+                ],
+            )
+            .map_err(to_io)?;
+            keep.insert(k);
+        }
+
+        let stored = {
+            let mut stmt = tx
+                .prepare("SELECT search_key FROM registrations")
+                .map_err(to_io)?;
+            let rows = stmt
+                .query_map([], |row| row.get::<_, String>(0))
+                .map_err(to_io)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(to_io)?
+        };
+        for k in stored.into_iter().filter(|k| !keep.contains(k)) {
+            tx.execute("DELETE FROM registrations WHERE search_key = ?1", [&k])
+                .map_err(to_io)?;
+            tx.execute("DELETE FROM scan_tips WHERE search_key = ?1", [&k])
+                .map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn store_scan_tip(&self, key: &RpcSearchKey, tip: &IndexerTip) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO scan_tips (search_key, block_number, block_hash)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    json(key)?,
+                    tip.block_number.value(),
+                    format!("{:#x}", tip.block_hash)
+                ],
+            )
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    fn append_events(
+        &self,
+        key: &RpcSearchKey,
+        submits: Vec<Submit>,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+
+        let next_seq: u64 = tx
+            .query_row("SELECT MAX(seq) FROM events", [], |row| {
+                row.get::<_, Option<u64>>(0)
+            })
+            .optional()
+            .map_err(to_io)?
+            .flatten()
+            .map(|seq| seq + 1)
+            .unwrap_or_default();
+
+        let k = json(key)?;
+        let mut events = Vec::with_capacity(submits.len());
+        for (i, submit) in submits.into_iter().enumerate() {
+            let event = LoggedEvent {
+                seq: next_seq + i as u64,
+                key: key.clone(),
+                submit,
+            };
+            tx.execute(
+                "INSERT INTO events (seq, search_key, block_number, block_hash, event)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    event.seq,
+                    k,
+                    event.submit.header.inner.number.value(),
+                    format!("{:#x}", event.submit.header.hash),
+                    json(&event)?
+                ],
+            )
+            .map_err(to_io)?;
+            events.push(event);
+        }
+        tx.commit().map_err(to_io)?;
+
+        Ok(events)
+    }
+
+    fn read_events(
+        &self,
+        from: u64,
+        to: u64,
+        max: usize,
+        filter: &dyn Fn(&LoggedEvent) -> bool,
+    ) -> io::Result<Vec<LoggedEvent>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT event FROM events WHERE seq >= ?1 AND seq <= ?2 ORDER BY seq")
+            .map_err(to_io)?;
+        // sqlite integers are signed, clamp the open ended ranges
+        let rows = stmt
+            .query_map(
+                params![from.min(i64::MAX as u64), to.min(i64::MAX as u64)],
+                |row| row.get::<_, String>(0),
+            )
+            .map_err(to_io)?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            let event: LoggedEvent = serde_json::from_str(&row.map_err(to_io)?)?;
+            if filter(&event) {
+                events.push(event);
+                if events.len() >= max {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn event_bounds(&self) -> io::Result<Option<(u64, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT MIN(seq), MAX(seq) FROM events", [], |row| {
+            Ok(row
+                .get::<_, Option<u64>>(0)?
+                .zip(row.get::<_, Option<u64>>(1)?))
+        })
+        .map_err(to_io)
+    }
+
+    fn truncate_events(&self, before: u64) -> io::Result<()> {
+        // the newest event is kept, the next seq is derived from it
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM events WHERE seq < ?1 AND seq < (SELECT MAX(seq) FROM events)",
+                [before.min(i64::MAX as u64)],
+            )
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_cursors(&self) -> io::Result<Vec<Cursor>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT consumer_id, search_key, seq FROM consumer_cursors")
+            .map_err(to_io)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u64>(2)?,
+                ))
+            })
+            .map_err(to_io)?;
+
+        let mut res = Vec::new();
+        for row in rows {
+            let (consumer_id, key, seq) = row.map_err(to_io)?;
+            res.push((consumer_id, serde_json::from_str(&key)?, seq));
+        }
+        Ok(res)
+    }
+
+    fn save_cursors(&self, cursors: &[Cursor]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+        tx.execute("DELETE FROM consumer_cursors", [])
+            .map_err(to_io)?;
+        for (consumer_id, key, seq) in cursors {
+            tx.execute(
+                "INSERT OR REPLACE INTO consumer_cursors (consumer_id, search_key, seq)
+                 VALUES (?1, ?2, ?3)",
+                params![consumer_id, json(key)?, seq],
+            )
+            .map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn load_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+    ) -> io::Result<Option<DeliveryCursor>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT block_number, event_index FROM delivery_cursors
+                 WHERE search_key = ?1 AND sink = ?2",
+                params![json(key)?, sink],
+                |row| {
+                    Ok(DeliveryCursor {
+                        block_number: row.get(0)?,
+                        index: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(to_io)
+    }
+
+    fn store_delivery_cursor(
+        &self,
+        key: &RpcSearchKey,
+        sink: &str,
+        cursor: DeliveryCursor,
+    ) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO delivery_cursors (search_key, sink, block_number, event_index)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![json(key)?, sink, cursor.block_number, cursor.index],
+            )
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_dedup_window(&self, key: &RpcSearchKey) -> io::Result<Vec<EmittedCell>> {
+        let cells: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT cells FROM dedup_windows WHERE search_key = ?1",
+                params![json(key)?],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(to_io)?;
+        match cells {
+            Some(cells) => Ok(serde_json::from_str(&cells)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn store_dedup_window(&self, key: &RpcSearchKey, cells: &[EmittedCell]) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO dedup_windows (search_key, cells) VALUES (?1, ?2)",
+                params![json(key)?, json(&cells)?],
+            )
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    fn load_cell_links(&self, out_point: &OutPoint) -> io::Result<Vec<(RpcSearchKey, CellLink)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT search_key, kind, tx FROM cell_links WHERE out_point = ?1")
+            .map_err(to_io)?;
+        let rows = stmt
+            .query_map(params![json(out_point)?], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(to_io)?;
+        let mut res = Vec::new();
+        for row in rows {
+            let (key, kind, tx) = row.map_err(to_io)?;
+            res.push((
+                serde_json::from_str(&key)?,
+                CellLink {
+                    out_point: out_point.clone(),
+                    kind: serde_json::from_str(&kind)?,
+                    tx: serde_json::from_str(&tx)?,
+                },
+            ));
+        }
+        Ok(res)
+    }
+
+    fn store_cell_links(&self, key: &RpcSearchKey, links: &[CellLink]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+        for link in links {
+            tx.execute(
+                "INSERT OR REPLACE INTO cell_links (out_point, search_key, kind, tx)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    json(&link.out_point)?,
+                    json(key)?,
+                    json(&link.kind)?,
+                    json(&link.tx)?
+                ],
+            )
+            .map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn load_live_cells(&self, key: &RpcSearchKey) -> io::Result<Vec<LiveCell>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT cell FROM live_cells WHERE search_key = ?1")
+            .map_err(to_io)?;
+        let rows = stmt
+            .query_map(params![json(key)?], |row| row.get::<_, String>(0))
+            .map_err(to_io)?;
+        let mut res = Vec::new();
+        for row in rows {
+            res.push(serde_json::from_str(&row.map_err(to_io)?)?);
+        }
+        Ok(res)
+    }
+
+    fn update_live_cells(
+        &self,
+        key: &RpcSearchKey,
+        added: &[LiveCell],
+        removed: &[OutPoint],
+    ) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+        let search_key = json(key)?;
+        for out_point in removed {
+            tx.execute(
+                "DELETE FROM live_cells WHERE search_key = ?1 AND out_point = ?2",
+                params![search_key, json(out_point)?],
+            )
+            .map_err(to_io)?;
+        }
+        for cell in added {
+            tx.execute(
+                "INSERT OR REPLACE INTO live_cells (search_key, out_point, cell) VALUES (?1, ?2, ?3)",
+                params![search_key, json(&cell.out_point)?, json(cell)?],
+            )
+            .map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn load_discovered_scripts(&self, key: &RpcSearchKey) -> io::Result<Vec<H256>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT script_hash FROM discovered_scripts WHERE search_key = ?1")
+            .map_err(to_io)?;
+        let rows = stmt
+            .query_map(params![json(key)?], |row| row.get::<_, String>(0))
+            .map_err(to_io)?;
+        let mut res = Vec::new();
+        for row in rows {
+            res.push(serde_json::from_str(&row.map_err(to_io)?)?);
+        }
+        Ok(res)
+    }
+
+    fn store_discovered_scripts(&self, key: &RpcSearchKey, scripts: &[H256]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+        let search_key = json(key)?;
+        for script in scripts {
+            tx.execute(
+                "INSERT OR IGNORE INTO discovered_scripts (search_key, script_hash) VALUES (?1, ?2)",
+                params![search_key, json(script)?],
+            )
+            .map_err(to_io)?;
+        }
+        tx.commit().map_err(to_io)
+    }
+
+    fn load_stats(&self, key: &RpcSearchKey) -> io::Result<Stats> {
+        let stats: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT stats FROM stats WHERE search_key = ?1",
+                params![json(key)?],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(to_io)?;
+        match stats {
+            Some(stats) => Ok(serde_json::from_str(&stats)?),
+            None => Ok(Stats::default()),
+        }
+    }
+
+    fn store_stats(&self, key: &RpcSearchKey, stats: &Stats) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO stats (search_key, stats) VALUES (?1, ?2)",
+                params![json(key)?, json(stats)?],
+            )
+            .map_err(to_io)?;
+        Ok(())
+    }
+}
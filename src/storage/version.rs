@@ -0,0 +1,105 @@
+use std::{
+    fs::{read_dir, read_to_string, write},
+    io,
+    path::Path,
+};
+
+use super::Storage;
+use crate::{cipher::Cipher, snapshot::Snapshot};
+
+/// Version of the persisted state layout, an incompatible change bumps it and appends a migration
+pub const STATE_VERSION: u32 = 1;
+
+/// Kept in the store path next to the data of every backend but memory
+const VERSION_FILE: &str = "VERSION";
+
+/// `MIGRATIONS[n]` upgrades a store from version `n` to `n + 1`
+const MIGRATIONS: &[fn(&dyn Storage) -> io::Result<()>] = &[v0_to_v1];
+
+/// Stores of the first releases are unversioned and hold bare scan tips in the file backend,
+/// rewrite the registrations in the current layout with their default sink
+fn v0_to_v1(storage: &dyn Storage) -> io::Result<()> {
+    let registrations = storage.load_registrations()?;
+    storage.save_registrations(&registrations)
+}
+
+fn read_version(path: &Path) -> io::Result<Option<u32>> {
+    match read_to_string(path.join(VERSION_FILE)) {
+        Ok(version) => version.trim().parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid state version in {:?}", path.join(VERSION_FILE)),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// No store was created at `path` yet
+pub(super) fn is_fresh(path: &Path) -> io::Result<bool> {
+    if read_version(path)?.is_some() {
+        return Ok(false);
+    }
+    match read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+pub(super) fn stamp(path: &Path) -> io::Result<()> {
+    write(path.join(VERSION_FILE), format!("{}\n", STATE_VERSION))
+}
+
+/// Refuses stores of other versions, an older one has to be migrated first
+pub(super) fn check(path: &Path) -> io::Result<()> {
+    let version = read_version(path)?.unwrap_or_default();
+    if version == STATE_VERSION {
+        return Ok(());
+    }
+    let hint = if version > STATE_VERSION {
+        "it was written by a newer emitter"
+    } else {
+        "run `emitter migrate` to upgrade it"
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "store {:?} has state version {} instead of {}, {}",
+            path, version, STATE_VERSION, hint
+        ),
+    ))
+}
+
+/// Upgrades the store in place to `STATE_VERSION`, a snapshot of it is written to
+/// `pre-migrate-v<version>.snapshot` in the store path first, returns the version it was at
+pub(crate) fn migrate<P: AsRef<Path>>(
+    backend: &str,
+    path: P,
+    cipher: Option<Cipher>,
+) -> io::Result<u32> {
+    let path = path.as_ref();
+    if is_fresh(path)? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no store at {:?}", path),
+        ));
+    }
+    let from = read_version(path)?.unwrap_or_default();
+    if from >= STATE_VERSION {
+        return check(path).map(|_| from);
+    }
+
+    // the records a migration writes are read whatever their compression
+    let storage = super::open_backend(backend, path, cipher.clone(), None)?;
+    Snapshot::take(storage.as_ref())?.write(
+        path.join(format!("pre-migrate-v{}.snapshot", from)),
+        cipher.as_ref(),
+    )?;
+    for migration in &MIGRATIONS[from as usize..] {
+        migration(storage.as_ref())?;
+    }
+    stamp(path)?;
+    Ok(from)
+}
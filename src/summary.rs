@@ -0,0 +1,69 @@
+use ckb_jsonrpc_types::{Uint32, Uint64};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{fee::CapacityChange, schema, Submit};
+
+/// Whether the events of a key summarizing its blocks still list the cells
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryMode {
+    Alongside,
+    /// The events have the summary instead of the cells
+    Instead,
+}
+
+/// The matched cells of a block, counted before deduplication and the plugin
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct BlockSummary {
+    #[schemars(with = "schema::Hex")]
+    pub created: Uint32,
+    #[schemars(with = "schema::Hex")]
+    pub spent: Uint32,
+    /// Of the spent cells, in shannons
+    #[schemars(with = "schema::Hex")]
+    pub capacity_in: Uint64,
+    /// Of the created cells, in shannons
+    #[schemars(with = "schema::Hex")]
+    pub capacity_out: Uint64,
+    /// With matched cells
+    #[schemars(with = "schema::Hex")]
+    pub transactions: Uint32,
+}
+
+impl BlockSummary {
+    pub fn new(submit: &Submit, change: &CapacityChange, transactions: usize) -> Self {
+        BlockSummary {
+            created: (submit.outputs.len() as u32).into(),
+            spent: (submit.inputs.len() as u32).into(),
+            capacity_in: change.spent,
+            capacity_out: change.created,
+            transactions: (transactions as u32).into(),
+        }
+    }
+}
+
+/// Of the transactions with emitted cells in a block, in the order of their first cell
+pub(crate) fn transaction_hashes(submit: &Submit) -> Vec<H256> {
+    let mut hashes = Vec::new();
+    for out_point in submit.inputs.iter().chain(&submit.output_points) {
+        if !hashes.contains(&out_point.tx_hash) {
+            hashes.push(out_point.tx_hash.clone());
+        }
+    }
+    hashes
+}
+
+/// Leaves the summary, the transaction hashes and the block of an event, without its cells
+pub(crate) fn strip_cells(submit: &mut Submit) {
+    submit.inputs.clear();
+    submit.outputs.clear();
+    submit.input_cells.clear();
+    submit.since.clear();
+    submit.cheques.clear();
+    submit.multisigs.clear();
+    submit.cellbase_outputs.clear();
+    submit.witnesses.clear();
+    submit.correlations.clear();
+}
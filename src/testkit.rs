@@ -0,0 +1,259 @@
+//! A simulated ckb node growing a chain at a set block rate and cell density with injected reorgs,
+//! deterministic for a seed, so whole emitters can be soak tested without a real node
+#![cfg_attr(not(feature = "testkit"), allow(dead_code))]
+
+use ckb_types::packed;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use hyper::{server::conn::Http, service::service_fn, Body, Request, Response};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+use std::{convert::Infallible, io, time::Duration};
+
+use crate::{
+    bench::synthetic_lock,
+    mock_node::{cell_tx, MockNode},
+    rpc_client::Transport,
+};
+
+/// What the simulated chain looks like
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SimSettings {
+    /// Transactions per block, each creates a cell
+    pub cells_per_block: u32,
+    /// Cells are locked by this many synthetic locks, see `bench --keys`
+    pub locks: u64,
+    /// Chance of every transaction to spend a live cell as well
+    pub spend_ratio: f64,
+    /// Blocks between reorgs, none when unset
+    pub reorg_every: Option<u64>,
+    /// Blocks a reorg replaces
+    pub reorg_depth: u64,
+    pub seed: u64,
+}
+
+impl Default for SimSettings {
+    fn default() -> Self {
+        SimSettings {
+            cells_per_block: 10,
+            locks: 16,
+            spend_ratio: 0.5,
+            reorg_every: None,
+            reorg_depth: 3,
+            seed: 0,
+        }
+    }
+}
+
+/// Grows the chain of a mock node, the same settings give the same blocks
+pub(crate) struct SimChain {
+    node: MockNode,
+    settings: SimSettings,
+    rng: StdRng,
+    /// Cells not spent yet with the block creating them
+    live: Vec<(packed::OutPoint, u64)>,
+    /// Blocks appended since the last reorg
+    since_reorg: u64,
+}
+
+impl SimChain {
+    pub fn new(settings: SimSettings) -> Self {
+        SimChain {
+            node: MockNode::new(),
+            settings,
+            rng: StdRng::seed_from_u64(settings.seed),
+            live: Vec::new(),
+            since_reorg: 0,
+        }
+    }
+
+    pub fn node(&self) -> &MockNode {
+        &self.node
+    }
+
+    /// Appends the next block, a reorg due replaces the last `reorg_depth` blocks with a new
+    /// branch first
+    pub fn advance(&mut self) {
+        if self
+            .settings
+            .reorg_every
+            .is_some_and(|every| every > 0 && self.since_reorg >= every)
+        {
+            let depth = self.settings.reorg_depth;
+            let tip = self.node.tip().block_number.value();
+            let keep = tip.saturating_sub(depth);
+            log::info!("simulated reorg of blocks {} to {}", keep + 1, tip);
+            self.node.fork(depth);
+            self.live.retain(|(_, block)| *block <= keep);
+            for _ in 0..tip - keep {
+                self.push();
+            }
+            self.since_reorg = 0;
+        }
+        self.push();
+        self.since_reorg += 1;
+    }
+
+    fn push(&mut self) {
+        let number = self.node.tip().block_number.value() + 1;
+        let mut txs = Vec::with_capacity(self.settings.cells_per_block as usize);
+        let mut created = Vec::with_capacity(txs.capacity());
+        for _ in 0..self.settings.cells_per_block {
+            let lock = synthetic_lock(self.rng.gen_range(0..self.settings.locks.max(1)));
+            // a cell is spent in a later block than the one creating it
+            let inputs = if !self.live.is_empty() && self.rng.gen_bool(self.settings.spend_ratio) {
+                let index = self.rng.gen_range(0..self.live.len());
+                vec![self.live.swap_remove(index).0]
+            } else {
+                Vec::new()
+            };
+            let tx = cell_tx(&lock, inputs);
+            created.push((packed::OutPoint::new(tx.hash(), 0), number));
+            txs.push(tx);
+        }
+        self.node.push_block(txs);
+        self.live.extend(created);
+    }
+
+    /// Appends a block every `interval`, forever
+    pub async fn run(mut self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.advance();
+        }
+    }
+}
+
+/// Answers the json-rpc calls of the emitter, and batches of them, over http from the node
+pub(crate) async fn serve(node: MockNode, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let node = node.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request: Request<Body>| {
+                let node = node.clone();
+                async move { Ok::<_, Infallible>(answer(&node, request).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                log::debug!("simulated node connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer(node: &MockNode, request: Request<Body>) -> Response<Body> {
+    let result = async {
+        let body = hyper::body::to_bytes(request.into_body())
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let call: Value = serde_json::from_slice(&body)?;
+        let answer = match call {
+            Value::Array(calls) => {
+                let mut answers = Vec::with_capacity(calls.len());
+                for call in &calls {
+                    answers.push(serde_json::from_slice::<Value>(&node.send(call).await?)?);
+                }
+                serde_json::to_vec(&answers)?.into()
+            }
+            call => node.send(&call).await?,
+        };
+        io::Result::Ok(answer)
+    }
+    .await;
+    match result {
+        Ok(answer) => Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(answer))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(400)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+    }
+}
+
+pub(crate) fn command() -> Command {
+    let number = |name: &'static str, long: &'static str, help: &'static str| {
+        Arg::new(name)
+            .long(long)
+            .value_parser(clap::value_parser!(u64))
+            .help(help)
+            .action(ArgAction::Set)
+    };
+
+    Command::new("simnode")
+        .about("Serves a simulated ckb node growing a deterministic chain, for soak tests against --ckb-uri")
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .default_value("127.0.0.1:8114")
+                .help("Address the node's rpc is served at over http")
+                .action(ArgAction::Set),
+        )
+        .arg(number(
+            "blocks",
+            "blocks", "Blocks generated before serving, history to backfill").default_value("0"))
+        .arg(number(
+            "block_interval_ms",
+            "block-interval-ms", "Milliseconds between new blocks").default_value("1000"))
+        .arg(number(
+            "cells_per_block",
+            "cells-per-block", "Transactions per block, each creates a cell").default_value("10"))
+        .arg(number(
+            "locks",
+            "locks", "Synthetic locks of the cells, those of `bench --keys`").default_value("16"))
+        .arg(
+            Arg::new("spend_ratio")
+                .long("spend-ratio")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0.5")
+                .help("Chance of a transaction to spend a live cell too")
+                .action(ArgAction::Set),
+        )
+        .arg(number(
+            "reorg_every",
+            "reorg-every", "Blocks between injected reorgs, none if omitted"))
+        .arg(number(
+            "reorg_depth",
+            "reorg-depth", "Blocks an injected reorg replaces").default_value("3"))
+        .arg(number(
+            "seed",
+            "seed", "Seed of the chain, the same seed and settings give the same blocks").default_value("0"))
+}
+
+pub(crate) async fn execute(matches: &ArgMatches) -> io::Result<()> {
+    let number = |name: &str| matches.get_one::<u64>(name).copied();
+    let spend_ratio = *matches.get_one::<f64>("spend_ratio").unwrap();
+    if !(0.0..=1.0).contains(&spend_ratio) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--spend-ratio must be between 0 and 1",
+        ));
+    }
+    let mut chain = SimChain::new(SimSettings {
+        cells_per_block: number("cells_per_block").unwrap() as u32,
+        locks: number("locks").unwrap(),
+        spend_ratio,
+        reorg_every: number("reorg_every"),
+        reorg_depth: number("reorg_depth").unwrap(),
+        seed: number("seed").unwrap(),
+    });
+    for _ in 0..number("blocks").unwrap() {
+        chain.advance();
+    }
+    let listen = matches.get_one::<String>("listen").unwrap();
+    let listener = TcpListener::bind(listen).await?;
+    log::info!(
+        "simulated ckb node at http://{} from block {}",
+        listener.local_addr()?,
+        chain.node().tip().block_number.value()
+    );
+    let node = chain.node().clone();
+    tokio::spawn(chain.run(Duration::from_millis(
+        number("block_interval_ms").unwrap().max(1),
+    )));
+    serve(node, listener).await
+}
@@ -0,0 +1,101 @@
+use tokio::sync::watch;
+
+use std::{io, time::Duration};
+
+use crate::{
+    metrics,
+    rpc_client::{BlockUnavailable, IndexerTip, RpcClient},
+};
+
+/// How often the indexer tip is polled for all scans
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The last polled indexer tip, or why the last poll failed
+#[derive(Clone)]
+pub(crate) enum Polled {
+    Pending,
+    Tip(IndexerTip),
+    Failed(String),
+}
+
+/// Receives every polled indexer tip
+#[derive(Clone)]
+pub(crate) struct IndexerTipWatch(watch::Receiver<Polled>);
+
+impl IndexerTipWatch {
+    /// Polls the indexer tip once for every scan instead of each scan asking the node. With
+    /// `verifiers` a tip is only sent once they all agree on it, until then the scans keep the last one
+    pub fn spawn(client: RpcClient, verifiers: Vec<RpcClient>) -> Self {
+        let (tx, rx) = watch::channel(Polled::Pending);
+        metrics::spawn("tip_poller", async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                let polled = match client.get_indexer_tip().await {
+                    Ok(tip) => match verified(&verifiers, &tip).await {
+                        Ok(true) => Polled::Tip(tip),
+                        Ok(false) => continue,
+                        Err(e) => Polled::Failed(format!("verifying the tip: {}", e)),
+                    },
+                    Err(e) => Polled::Failed(e.to_string()),
+                };
+                if tx.send(polled).is_err() {
+                    return;
+                }
+            }
+        });
+        IndexerTipWatch(rx)
+    }
+
+    /// Tips sent by the test instead of polled
+    #[cfg(test)]
+    pub fn channel() -> (watch::Sender<Polled>, Self) {
+        let (tx, rx) = watch::channel(Polled::Pending);
+        (tx, IndexerTipWatch(rx))
+    }
+
+    /// The last polled tip, waits for the first poll. A failed poll is an error until the next one succeeds
+    pub async fn get(&self) -> io::Result<IndexerTip> {
+        let mut rx = self.0.clone();
+        loop {
+            match &*rx.borrow_and_update() {
+                Polled::Pending => {}
+                Polled::Tip(tip) => return Ok(tip.clone()),
+                Polled::Failed(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("polling the indexer tip failed: {}", e),
+                    ))
+                }
+            }
+            rx.changed()
+                .await
+                .map_err(|_| io::Error::other("indexer tip poller stopped"))?;
+        }
+    }
+}
+
+/// Whether every verifier has the block of `tip` at its height. One which hasn't synced that far
+/// yet agrees on no tip, a different block is logged as a fork or a compromised node
+pub(crate) async fn verified(verifiers: &[RpcClient], tip: &IndexerTip) -> io::Result<bool> {
+    for verifier in verifiers {
+        let hash = match verifier.get_header_by_number(tip.block_number).await {
+            Ok(header) => header.hash,
+            Err(e) if BlockUnavailable::of(&e).is_some() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if hash != tip.block_hash {
+            log::warn!(
+                "{} has block {:#x} at {}, the ckb node {:#x}, the scans don't advance",
+                verifier.origin(),
+                hash,
+                tip.block_number.value(),
+                tip.block_hash
+            );
+            metrics::inc("emitter_tip_mismatches_total", &[]);
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,63 @@
+use ckb_jsonrpc_types::{BlockNumber, BlockView, HeaderView, Script};
+use ckb_types::{packed, prelude::Entity, H256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::io;
+
+use crate::{
+    rpc_client::{BlockUnavailable, RpcClient},
+    schema,
+};
+
+/// An uncle of a block, listed by the events of keys asking for them
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Uncle {
+    #[schemars(with = "schema::Hex")]
+    pub hash: H256,
+    #[schemars(with = "schema::Hex")]
+    pub number: BlockNumber,
+    /// Of the uncle's cellbase, unset if the node doesn't have the uncle's transactions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Script>")]
+    pub miner_lock: Option<Script>,
+}
+
+/// The uncles of the block of `header`. Its block is only fetched if the header commits to
+/// uncles or an extension
+pub(crate) async fn uncles(client: &RpcClient, header: &HeaderView) -> io::Result<Vec<Uncle>> {
+    if header.inner.extra_hash == H256::default() {
+        return Ok(Vec::new());
+    }
+    let block = client
+        .get_block(header.hash.clone())
+        .await?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                BlockUnavailable(header.inner.number.value()),
+            )
+        })?;
+    let mut uncles = Vec::with_capacity(block.uncles.len());
+    for uncle in block.uncles {
+        let hash = uncle.header.hash;
+        let miner_lock = client
+            .get_block(hash.clone())
+            .await?
+            .and_then(|block| miner_lock(&block));
+        uncles.push(Uncle {
+            hash,
+            number: uncle.header.inner.number,
+            miner_lock,
+        });
+    }
+    Ok(uncles)
+}
+
+/// The lock of the cellbase witness, the miner's
+fn miner_lock(block: &BlockView) -> Option<Script> {
+    let witness = block.transactions.first()?.inner.witnesses.first()?;
+    packed::CellbaseWitness::from_slice(witness.as_bytes())
+        .ok()
+        .map(|witness| witness.lock().into())
+}
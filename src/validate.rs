@@ -0,0 +1,166 @@
+use ckb_jsonrpc_types::Uint64;
+use serde::Serialize;
+
+use std::io;
+
+use crate::{
+    capabilities::IndexerCapabilities,
+    rpc_client::{Order, RpcClient, SearchKey, SearchKeyFilter},
+    rpc_server::RpcSearchKey,
+};
+
+/// What `validate` found out about a search key, it would be registered without errors
+#[derive(Serialize, Default, Debug)]
+pub struct Validation {
+    /// `register` refuses the key for these
+    pub errors: Vec<String>,
+    /// The key is accepted but likely not what was meant
+    pub warnings: Vec<String>,
+}
+
+/// Warnings about the filter found without asking the node: ranges no cell can be in, and the
+/// filters the emitter checks, which a query of the indexer doesn't verify
+pub(crate) fn filter_warnings(key: &RpcSearchKey, indexer: &IndexerCapabilities) -> Vec<String> {
+    let filter = match &key.filter {
+        Some(filter) => filter,
+        None => return Vec::new(),
+    };
+    let mut warnings = Vec::new();
+    let ranges: [(&str, &Option<[Uint64; 2]>); 4] = [
+        ("script_len_range", &filter.script_len_range),
+        ("output_data_len_range", &filter.output_data_len_range),
+        ("output_capacity_range", &filter.output_capacity_range),
+        ("fee_range", &filter.fee_range),
+    ];
+    for (name, range) in ranges {
+        if let Some([start, end]) = range {
+            if start.value() >= end.value() {
+                warnings.push(format!(
+                    "{} is empty, its end is exclusive, no cell matches",
+                    name
+                ));
+            }
+        }
+    }
+    let checked = [
+        ("cell_dep", filter.cell_dep.is_some()),
+        ("header_dep", filter.header_dep.is_some()),
+        ("since", filter.since.is_some()),
+        ("fee_range", filter.fee_range.is_some()),
+        ("data_pattern", filter.data_pattern.is_some()),
+        ("args_pattern", filter.args_pattern.is_some()),
+        ("min_capacity_change", filter.min_capacity_change.is_some()),
+        ("min_transactions", filter.min_transactions.is_some()),
+        (
+            "output_data",
+            filter
+                .output_data_filter()
+                .is_some_and(|(_, mode)| !indexer.filters_output_data(mode)),
+        ),
+    ];
+    for (name, _) in checked.into_iter().filter(|(_, set)| *set) {
+        warnings.push(format!(
+            "{} is checked by the emitter on the fetched transactions, the indexer query doesn't verify it matches",
+            name
+        ));
+    }
+    warnings
+}
+
+/// Why the indexer refuses the key's filter, none if it takes it. The fields it refuses each on
+/// their own are named, else the combination of them all
+pub(crate) async fn refused_filter(
+    client: &RpcClient,
+    key: &RpcSearchKey,
+) -> io::Result<Option<String>> {
+    let search_key = key.clone().into_key(None, client.indexer());
+    let e = match probe(client, search_key.clone()).await? {
+        Ok(()) => return Ok(None),
+        Err(e) => e,
+    };
+    let filter = search_key.filter.clone().unwrap_or_default();
+    let length_name = match key.filter.as_ref().and_then(|f| f.script_len_range) {
+        Some(_) => "script_len_range",
+        None => "has_type_script",
+    };
+    let fields = [
+        (
+            "script".to_string(),
+            SearchKeyFilter {
+                script: filter.script.clone(),
+                ..Default::default()
+            },
+            filter.script.is_some(),
+        ),
+        (
+            length_name.to_string(),
+            SearchKeyFilter {
+                script_len_range: filter.script_len_range,
+                ..Default::default()
+            },
+            filter.script_len_range.is_some(),
+        ),
+        (
+            "output_data_len_range".to_string(),
+            SearchKeyFilter {
+                output_data_len_range: filter.output_data_len_range,
+                ..Default::default()
+            },
+            filter.output_data_len_range.is_some(),
+        ),
+        (
+            "output_capacity_range".to_string(),
+            SearchKeyFilter {
+                output_capacity_range: filter.output_capacity_range,
+                ..Default::default()
+            },
+            filter.output_capacity_range.is_some(),
+        ),
+        (
+            format!(
+                "output_data in {:?} mode",
+                filter.output_data_filter_mode.unwrap_or_default()
+            )
+            .to_lowercase(),
+            SearchKeyFilter {
+                output_data: filter.output_data.clone(),
+                output_data_filter_mode: filter.output_data_filter_mode,
+                ..Default::default()
+            },
+            filter.output_data.is_some(),
+        ),
+    ];
+    let mut set = Vec::new();
+    let mut refused = Vec::new();
+    for (name, alone, _) in fields.into_iter().filter(|(_, _, is_set)| *is_set) {
+        let alone = SearchKey {
+            filter: Some(alone),
+            ..search_key.clone()
+        };
+        if probe(client, alone).await?.is_err() {
+            refused.push(name.clone());
+        }
+        set.push(name);
+    }
+    Ok(Some(match (refused.is_empty(), set.len()) {
+        (false, _) => format!("the indexer doesn't support {}: {}", refused.join(", "), e),
+        (true, 2..) => format!(
+            "the indexer refuses the combination of {}: {}",
+            set.join(", "),
+            e
+        ),
+        (true, _) => format!("the indexer refused the key: {}", e),
+    }))
+}
+
+/// The error the indexer answered the key with
+async fn probe(client: &RpcClient, key: SearchKey) -> io::Result<Result<(), io::Error>> {
+    match client
+        .get_transactions(key, Order::Asc, 1.into(), None)
+        .await
+    {
+        Ok(_) => Ok(Ok(())),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(Err(e)),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,68 @@
+use ckb_jsonrpc_types::{JsonBytes, Transaction, Uint32};
+use ckb_types::H256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rpc_client::{CellType, ScriptType},
+    schema,
+};
+
+/// Which witnesses of the transactions with matched cells events list
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WitnessSelection {
+    All,
+    /// At the indexes of the matched inputs, and of the matched outputs for type keys, the
+    /// witnesses the key's script group gets when its args are the whole args
+    ScriptGroup,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct TxWitnesses {
+    #[schemars(with = "schema::Hex")]
+    pub tx_hash: H256,
+    pub witnesses: Vec<Witness>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Witness {
+    #[schemars(with = "schema::Hex")]
+    pub index: Uint32,
+    #[schemars(with = "schema::Hex")]
+    pub data: JsonBytes,
+}
+
+impl WitnessSelection {
+    /// The selected witnesses of `tx`, whose matched cells are `cells`
+    pub fn select(
+        self,
+        script_type: &ScriptType,
+        tx_hash: H256,
+        tx: &Transaction,
+        cells: &[(CellType, Uint32)],
+    ) -> TxWitnesses {
+        let mut indexes = match self {
+            WitnessSelection::All => (0..tx.witnesses.len() as u32).collect(),
+            WitnessSelection::ScriptGroup => cells
+                .iter()
+                .filter(|(ty, _)| *ty == CellType::Input || *script_type == ScriptType::Type)
+                .map(|(_, index)| index.value())
+                .collect::<Vec<_>>(),
+        };
+        indexes.sort_unstable();
+        indexes.dedup();
+        TxWitnesses {
+            tx_hash,
+            witnesses: indexes
+                .into_iter()
+                .filter_map(|index| {
+                    Some(Witness {
+                        index: index.into(),
+                        data: tx.witnesses.get(index as usize)?.clone(),
+                    })
+                })
+                .collect(),
+        }
+    }
+}